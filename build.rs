@@ -4,6 +4,10 @@ use std::io::Write;
 use std::path::Path;
 use std::process::Command;
 
+#[allow(dead_code)]
+#[path = "src/render/world_mapping.rs"]
+mod world_mapping;
+
 fn get_vulkan_sdk_path() -> String {
     let vulkan_sdk_path =
         std::env::var("VULKAN_SDK").expect("The environment variable $VULKAN_SDK is blank.");
@@ -45,6 +49,19 @@ fn gen_material_code() {
         index: i32,
         albedo: (i32, i32, i32),
         emission: (i32, i32, i32),
+        // 0 is a perfect mirror, 255 only scatters light diffusely.
+        roughness: i32,
+        // Rays pass through transparent materials, tinted by their albedo.
+        transparent: bool,
+        // How the emission changes over time and how many times a second it repeats.
+        waveform: String,
+        frequency: f32,
+        // Which sound is played when walking on the material, see FootstepSound.
+        footstep: String,
+        // The color of the bits which fly off when the material is broken.
+        particles: (i32, i32, i32),
+        // How many seconds it takes to break the material.
+        hardness: f32,
     }
 
     let mut correct_index = 0;
@@ -70,10 +87,74 @@ fn gen_material_code() {
             let (r, g, b) = parse_rgb(&item[4], &item[5], &item[6]);
             (r * mul, g * mul, b * mul)
         };
+        // Older material lists have no roughness column, treat those materials as fully rough.
+        let roughness = match item.get(8).map(str::trim) {
+            Some(roughness) if roughness.len() > 0 => parse_number(roughness, 0x00, 0xFF),
+            _ => 0xFF,
+        };
+        let transparent = match item.get(9).map(str::trim) {
+            Some(transparent) if transparent.len() > 0 => parse_number(transparent, 0, 1) != 0,
+            _ => false,
+        };
+        let waveform = match item.get(10).map(str::trim) {
+            Some(waveform) if waveform.len() > 0 => waveform,
+            _ => "steady",
+        };
+        let waveform = match waveform {
+            "steady" => "Steady",
+            "flicker" => "Flicker",
+            "pulse" => "Pulse",
+            _ => panic!(
+                "The waveform '{}' in materials.csv should be steady, flicker or pulse.",
+                waveform
+            ),
+        };
+        let frequency = match item.get(11).map(str::trim) {
+            Some(frequency) if frequency.len() > 0 => frequency
+                .parse()
+                .expect("Malformed frequency in materials.csv"),
+            _ => 0.0,
+        };
+        // Older material lists have no gameplay columns, treat those materials like stone.
+        let footstep = match item.get(12).map(str::trim) {
+            Some(footstep) if footstep.len() > 0 => footstep,
+            _ => "stone",
+        };
+        let footstep = match footstep {
+            "none" => "None",
+            "grass" => "Grass",
+            "soil" => "Soil",
+            "stone" => "Stone",
+            "snow" => "Snow",
+            "glass" => "Glass",
+            "splash" => "Splash",
+            _ => panic!(
+                "The footstep '{}' in materials.csv should be none, grass, soil, stone, snow, \
+                 glass or splash.",
+                footstep
+            ),
+        };
+        let particles = match item.get(13).map(str::trim) {
+            Some(r) if r.len() > 0 => parse_rgb(r, &item[14], &item[15]),
+            _ => albedo,
+        };
+        let hardness = match item.get(16).map(str::trim) {
+            Some(hardness) if hardness.len() > 0 => hardness
+                .parse()
+                .expect("Malformed hardness in materials.csv"),
+            _ => 0.0,
+        };
         materials.push(Material {
             index,
             albedo,
             emission,
+            roughness,
+            transparent,
+            waveform: waveform.to_owned(),
+            frequency,
+            footstep: footstep.to_owned(),
+            particles,
+            hardness,
         });
         correct_index += 1;
     }
@@ -111,6 +192,39 @@ fn gen_material_code() {
     }
     writeln!(glsl_header, "\t}}\n}}\n").unwrap();
 
+    writeln!(
+        glsl_header,
+        "float get_material_roughness(uint material) {{"
+    )
+    .unwrap();
+    writeln!(glsl_header, "\tswitch(material) {{").unwrap();
+    for material in &materials {
+        writeln!(
+            glsl_header,
+            "\t\tcase {}: return {};",
+            material.index,
+            material.roughness as f32 / 255.0,
+        )
+        .unwrap();
+    }
+    writeln!(glsl_header, "\t}}\n}}\n").unwrap();
+
+    // Materials which glow are given an emitter slot, slot 0 is for materials which do not. The
+    // slot is stored in 3 bits of packed materials, see EMITTER_SLOTS in constants.rs.
+    let mut emitters = Vec::new();
+    let mut emitter_slots = Vec::new();
+    for material in &materials {
+        if material.emission == (0, 0, 0) {
+            emitter_slots.push(0);
+        } else {
+            emitters.push(material);
+            emitter_slots.push(emitters.len());
+        }
+    }
+    if emitters.len() > 7 {
+        panic!("materials.csv has more than 7 materials with emission.");
+    }
+
     let mut rust_materials = File::create("src/render/GEN_MATERIALS.rs")
         .expect("Failed to open src/render/GEN_MATERIALS.rs for writing");
     writeln!(
@@ -120,7 +234,12 @@ fn gen_material_code() {
 pub struct Material {{
     pub albedo: (u16, u16, u16),
     pub emission: (u16, u16, u16),
+    pub roughness: u16,
     pub solid: bool,
+    pub transparent: bool,
+    // 0 for materials which do not glow, otherwise one more than the index of their entry in
+    // EMITTERS.
+    pub emitter: u16,
 }}
 
 impl Material {{
@@ -128,7 +247,10 @@ impl Material {{
         Self {{
             albedo: (0, 0, 0),
             emission: (0, 0, 0),
+            roughness: 127,
             solid: false,
+            transparent: false,
+            emitter: 0,
         }}
     }}
 
@@ -136,7 +258,10 @@ impl Material {{
         Self {{
             albedo: (0, 0, 0),
             emission: (0, 0, 0),
+            roughness: 127,
             solid: true,
+            transparent: false,
+            emitter: 0,
         }}
     }}
 
@@ -147,6 +272,8 @@ impl Material {{
 		self.emission.0 += other.emission.0;
 		self.emission.1 += other.emission.1;
         self.emission.2 += other.emission.2;
+        self.roughness += other.roughness;
+        self.emitter = self.emitter.max(other.emitter);
 	}}
 
 	pub fn divide(&mut self, factor: u16) {{
@@ -156,6 +283,7 @@ impl Material {{
 		self.emission.0 /= factor;
 		self.emission.1 /= factor;
         self.emission.2 /= factor;
+        self.roughness /= factor;
     }}
 
     pub fn pack(&self) -> u32 {{
@@ -163,8 +291,11 @@ impl Material {{
         let ag = (self.albedo.1) as u32;
         let ab = (self.albedo.2) as u32;
         let albedo = ar << 14 | ag << 7 | ab;
+        let roughness = (self.roughness as u32) << 21;
         let solid = if self.solid {{ 1 }} else {{ 0 }};
-        (solid << 15) | albedo
+        let transparent = if self.transparent {{ 1 }} else {{ 0 }};
+        let emitter = (self.emitter as u32) << 29;
+        emitter | (transparent << 28) | (solid << 15) | albedo | roughness
     }}
 
     pub fn unpack(packed: u32) -> Self {{
@@ -174,11 +305,17 @@ impl Material {{
             (packed >> 0 & 0x7F) as u16,
         );
         let emission = (0, 0, 0);
+        let roughness = (packed >> 21 & 0x7F) as u16;
         let solid = packed >> 15 & 0b1 != 0;
+        let transparent = packed >> 28 & 0b1 != 0;
+        let emitter = (packed >> 29 & 0b111) as u16;
         Self {{
             albedo,
             emission,
+            roughness,
             solid,
+            transparent,
+            emitter,
         }}
     }}
 }}
@@ -199,7 +336,10 @@ impl Material {{
                 "\tMaterial {{\n",
                 "\t\talbedo:   ({:.9}, {:.9}, {:.9}),\n",
                 "\t\temission: ({:.9}, {:.9}, {:.9}),\n",
+                "\t\troughness: {},\n",
                 "\t\tsolid: {},\n",
+                "\t\ttransparent: {},\n",
+                "\t\temitter: {},\n",
                 "\t}},",
             ),
             material.albedo.0 / 2,
@@ -208,13 +348,110 @@ impl Material {{
             material.emission.0 / 2,
             material.emission.1 / 2,
             material.emission.2 / 2,
+            material.roughness / 2,
             index != 0,
+            material.transparent,
+            emitter_slots[index],
+        )
+        .unwrap();
+    }
+    writeln!(rust_materials, "];",).unwrap();
+
+    writeln!(rust_materials, "\n#[rustfmt::skip]").unwrap();
+    writeln!(
+        rust_materials,
+        "pub const EMITTERS: [crate::render::emission::Emitter; {}] = [",
+        emitters.len()
+    )
+    .unwrap();
+    for material in &emitters {
+        writeln!(
+            rust_materials,
+            concat!(
+                "\tcrate::render::emission::Emitter {{\n",
+                "\t\temission: ({:?}, {:?}, {:?}),\n",
+                "\t\twaveform: crate::render::emission::Waveform::{},\n",
+                "\t\tfrequency: {:?},\n",
+                "\t}},",
+            ),
+            material.emission.0 as f32 / 255.0,
+            material.emission.1 as f32 / 255.0,
+            material.emission.2 as f32 / 255.0,
+            material.waveform,
+            material.frequency,
+        )
+        .unwrap();
+    }
+    writeln!(rust_materials, "];",).unwrap();
+
+    writeln!(rust_materials, "\n#[rustfmt::skip]").unwrap();
+    writeln!(
+        rust_materials,
+        "pub const MATERIAL_INFO: [crate::render::material_info::MaterialInfo; {}] = [",
+        materials.len()
+    )
+    .unwrap();
+    for material in &materials {
+        writeln!(
+            rust_materials,
+            concat!(
+                "\tcrate::render::material_info::MaterialInfo {{\n",
+                "\t\tfootstep: crate::render::material_info::FootstepSound::{},\n",
+                "\t\tparticles: ({:?}, {:?}, {:?}),\n",
+                "\t\thardness: {:?},\n",
+                "\t}},",
+            ),
+            material.footstep,
+            material.particles.0 as f32 / 255.0,
+            material.particles.1 as f32 / 255.0,
+            material.particles.2 as f32 / 255.0,
+            material.hardness,
         )
         .unwrap();
     }
     writeln!(rust_materials, "];",).unwrap();
 }
 
+fn gen_world_mapping_code() {
+    println!("cargo:rerun-if-changed=src/render/world_mapping.rs");
+    fs::write(
+        "shaders/glsl/GEN_WORLD_MAPPING.glsl",
+        world_mapping::glsl_source(),
+    )
+    .expect("Failed to write shaders/glsl/GEN_WORLD_MAPPING.glsl");
+}
+
+// Shaders which mention LIGHTING_FORMAT are compiled once for each of these, with the macro set
+// to the image format qualifier. Each variant is saved as {name}.{suffix}.spirv. Must match
+// LightingFormat in settings.rs.
+const LIGHTING_FORMATS: &[(&str, &str)] = &[
+    ("unorm16", "rgba16"),
+    ("float16", "rgba16f"),
+    ("packed", "r11f_g11f_b10f"),
+];
+// Likewise for shaders which mention OUTPUT_FORMAT, which write the image shown on screen. Shaders
+// which mention both get a variant for each pair, saved as {name}.{lighting}.{output}.spirv. Must
+// match OutputFormat::uses_float_output in settings.rs.
+const OUTPUT_FORMATS: &[(&str, &str)] = &[("unorm8", "rgba8"), ("float16", "rgba16f")];
+
+/// Adds a variant for each format to each of the given variants, which are file name suffixes
+/// paired with the arguments for glslc.
+fn add_format_variants(
+    variants: Vec<(String, Vec<String>)>,
+    formats: &[(&str, &str)],
+    macro_name: &str,
+) -> Vec<(String, Vec<String>)> {
+    let mut result = vec![];
+    for (suffix, defines) in variants {
+        for (format_suffix, qualifier) in formats {
+            let mut defines = defines.clone();
+            defines.push(format!("-D{}={}", macro_name, qualifier));
+            result.push((format!("{}.{}", suffix, format_suffix), defines));
+        }
+    }
+    result
+}
+
 fn compile_shaders() {
     let vulkan_sdk_path = get_vulkan_sdk_path();
 
@@ -248,31 +485,47 @@ fn compile_shaders() {
         let file_name = file_name.expect("Failed to get file name for shader source.");
         let file_name = file_name.to_str().unwrap().to_owned();
         let source = format!("shaders/glsl/{}", file_name);
-        let target = format!("shaders/spirv/{}.spirv", file_name);
+        let text = fs::read_to_string(&source).expect("Failed to read shader source.");
+        let mut variants = vec![(String::new(), vec![])];
+        if text.contains("LIGHTING_FORMAT") {
+            variants = add_format_variants(variants, LIGHTING_FORMATS, "LIGHTING_FORMAT");
+        }
+        if text.contains("OUTPUT_FORMAT") {
+            variants = add_format_variants(variants, OUTPUT_FORMATS, "OUTPUT_FORMAT");
+        }
+        let variants: Vec<(String, Vec<String>)> = variants
+            .into_iter()
+            .map(|(suffix, defines)| {
+                let target = format!("shaders/spirv/{}{}.spirv", file_name, suffix);
+                (target, defines)
+            })
+            .collect();
 
         let source_modified = meta
             .modified()
             .expect("Failed to read modification date of source file.");
-        let requires_compile = if let Result::Ok(target_file) = File::open(&target) {
-            // If the output file exists, we require recompilation if it was modified earlier
-            // than its corresponding source file.
-            let target_meta = target_file
-                .metadata()
-                .expect("Failed to read metadata of spirv file.");
-            let target_modified = target_meta
-                .modified()
-                .expect("Failed to read modification date of target file.");
-            target_modified < source_modified
-        } else {
-            // Otherwise, if the output does not exist, we need to compile no matter what.
-            true
-        };
+        for (target, defines) in variants {
+            let requires_compile = if let Result::Ok(target_file) = File::open(&target) {
+                // If the output file exists, we require recompilation if it was modified earlier
+                // than its corresponding source file.
+                let target_meta = target_file
+                    .metadata()
+                    .expect("Failed to read metadata of spirv file.");
+                let target_modified = target_meta
+                    .modified()
+                    .expect("Failed to read modification date of target file.");
+                target_modified < source_modified
+            } else {
+                // Otherwise, if the output does not exist, we need to compile no matter what.
+                true
+            };
 
-        if requires_compile {
-            required_compiles.push((source, target));
-        }
+            if requires_compile {
+                required_compiles.push((source.clone(), target, defines));
+            }
 
-        total_shaders += 1;
+            total_shaders += 1;
+        }
     }
 
     println!(
@@ -283,13 +536,14 @@ fn compile_shaders() {
 
     let compiler_path = Path::new(&vulkan_sdk_path);
     let compiler_path = compiler_path.join("bin/glslc");
-    for (index, (source, target)) in required_compiles.iter().enumerate() {
+    for (index, (source, target, defines)) in required_compiles.iter().enumerate() {
         println!(
             "Compiling shader {} of {}.",
             index + 1,
             required_compiles.len()
         );
         let compile_result = Command::new(compiler_path.clone())
+            .args(defines)
             .args(&[source, "-o", target])
             .output()
             .expect("Failed to run shader compiler! Check that your $VULKAN_SDK is correct.");
@@ -306,5 +560,6 @@ fn compile_shaders() {
 
 fn main() {
     gen_material_code();
+    gen_world_mapping_code();
     compile_shaders();
 }
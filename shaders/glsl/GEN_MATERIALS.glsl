@@ -7,6 +7,9 @@ vec3 get_material_albedo(uint material) {
 		case 4: return vec3(0.4, 0.4, 0.4);
 		case 5: return vec3(0.4862745, 0.21176471, 0.17254902);
 		case 6: return vec3(0.8666667, 0.9137255, 0.90588236);
+		case 7: return vec3(0.76862746, 0.8784314, 0.9098039);
+		case 8: return vec3(0.3529412, 0.15686275, 0.54901963);
+		case 9: return vec3(0.2509804, 0.5019608, 0.8627451);
 	}
 }
 
@@ -19,6 +22,24 @@ vec3 get_material_emission(uint material) {
 		case 4: return vec3(0, 0, 0);
 		case 5: return vec3(0, 0, 0);
 		case 6: return vec3(0, 0, 0);
+		case 7: return vec3(0, 0, 0);
+		case 8: return vec3(1.6470588, 0.7058824, 3);
+		case 9: return vec3(0, 0, 0);
+	}
+}
+
+float get_material_roughness(uint material) {
+	switch(material) {
+		case 0: return 1;
+		case 1: return 1;
+		case 2: return 1;
+		case 3: return 1;
+		case 4: return 1;
+		case 5: return 1;
+		case 6: return 0.1882353;
+		case 7: return 1;
+		case 8: return 1;
+		case 9: return 0.0627451;
 	}
 }
 
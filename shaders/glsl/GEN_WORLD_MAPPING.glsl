@@ -0,0 +1,16 @@
+// Generated by build.rs from src/render/world_mapping.rs, edit that instead.
+
+vec3 world_image_offset() {
+    return vec3(ROOT_BLOCK_WIDTH / 2)
+        + vec3(uniform_data.region_offset & ivec3(ROOT_BLOCK_WIDTH - 1));
+}
+
+vec3 world_image_texel(vec3 position) {
+    return mod(position + world_image_offset(), float(ROOT_BLOCK_WIDTH));
+}
+
+vec3 world_image_position(vec3 texel) {
+    return vec3(uniform_data.lr - ivec3(ROOT_BLOCK_WIDTH / 2))
+        + mod(texel - world_image_offset() - vec3(uniform_data.lr)
+            + float(ROOT_BLOCK_WIDTH / 2), float(ROOT_BLOCK_WIDTH));
+}
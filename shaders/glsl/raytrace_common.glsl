@@ -0,0 +1,674 @@
+// Shared by the kernels of the raytrace stage, which are run in this order:
+// - sun_heightmap.comp finds the highest block in each column of the world, if enabled.
+// - raygen.comp writes one primary ray per pixel to a ray queue.
+// - traverse.comp finds what each ray in a queue hits.
+// - shade.comp works out the light each hit contributes and queues the rays that continue each
+//   path. Traversal and shading are repeated RAY_QUEUE_PASSES times.
+// - resolve.comp writes the light accumulated for each pixel to the lighting buffers.
+// - fog.comp finds the color of the fog at a lower resolution, blended with previous frames.
+// Keeping them separate lets each kernel run at full occupancy instead of every thread waiting
+// on the longest path in its work group.
+
+#include "GEN_MATERIALS.glsl"
+
+layout(set = 0, binding = 0) uniform usampler3D world;
+layout(set = 0, binding = 1) uniform usampler3D minefield;
+layout(set = 0, binding = 2) uniform sampler2D blue_noise;
+// TODO: Make this more compact.
+layout(set = 0, binding = 3) uniform UniformData {
+    float sun_angle;
+    // Incremented every frame, wrapping around when it overflows.
+    uint frame_index;
+    // How many blocks the world images span along each axis.
+    uint root_block_width;
+    vec3 origin, forward, up, right;
+    // For some reason doing mat3 still loads 16 elements but the rust bindings give it 9, making
+    // the whole thing go out of order. So transmit each individual column instead.
+    vec3 old_origin, old_transform_c0, old_transform_c1, old_transform_c2;
+    // All other positions are relative to this block, so that they stay precise far away from the
+    // origin of the world.
+    ivec3 region_offset;
+    ivec3 lr;
+    ivec3 lso;
+    // Relative to region_offset. Only valid if has_selection is not zero.
+    ivec3 selected_block;
+    uint has_selection;
+    // Half the angle the sun covers in the sky, in radians.
+    float sun_angular_radius;
+    // Whether sun_heightmap.comp has filled in the heightmap this frame.
+    uint use_sun_heightmap;
+    // Whether surfaces are shaded with smooth_normal instead of the normals of their faces.
+    uint smooth_normals;
+    // A bit for each axis along which the world wraps around at the edges of the loaded region.
+    uint wrap_axes;
+    // See DebugView in debug_view.rs.
+    uint debug_view;
+    // Whether rays which leave the loaded region are traced over the distant terrain heightmap.
+    uint distant_terrain;
+    // The block at the -X -Y corner of the distant terrain heightmap, in world coordinates.
+    ivec2 distant_terrain_origin;
+    int distant_terrain_max_height;
+    // From the time of day track, both default to 1.
+    float sun_intensity;
+    float fog_density;
+    // The color of the whole sky when A is not zero, in which case there is no sun. See
+    // Sky::to_uniform in time_of_day.rs.
+    vec4 overcast;
+    // Indexed by the emitter slot of packed materials, slot 0 is always black. Must match
+    // EMITTER_SLOTS in constants.rs.
+    vec4 emitter_colors[8];
+    // Whether light_volume.comp keeps the light volume up to date.
+    uint light_volume;
+    // Restyles every material, see Palette::to_uniform in palette.rs.
+    vec4 palette[4];
+    // How many entries of probe_positions are in use.
+    uint probe_count;
+    // The fraction of glossy reflections near a probe which trace a ray instead of sampling it.
+    float reflection_ray_budget;
+    // Where each reflection probe was captured from. Must match MAX_PROBES in constants.rs.
+    vec4 probe_positions[8];
+} uniform_data;
+#define ROOT_BLOCK_WIDTH (uniform_data.root_block_width)
+// Two bits for each chunk of the world images, laid out like them with one entry for every
+// chunk-sized cube of texels. Written by TerrainUploadManager::write_chunk_occupancy.
+layout(set = 0, binding = 4) readonly buffer ChunkOccupancy {
+    uint chunks[];
+} chunk_occupancy;
+
+// Must match occupancy_bits in terrain_upload.rs.
+const uint CHUNK_MIXED = 0;
+const uint CHUNK_EMPTY = 1;
+const uint CHUNK_SOLID = 2;
+// Must match MAX_CHUNK_LOD in constants.rs.
+const int CHUNK_SIZE_SHIFT = 6;
+
+// Must match WorldWrap::seamless_axes in wrap.rs.
+bvec3 wrapped_axes() {
+    uint axes = uniform_data.wrap_axes;
+    return bvec3((axes & 1) != 0, (axes & 2) != 0, (axes & 4) != 0);
+}
+
+// True if a position is not inside the region of the world stored in the world images. Positions
+// never leave the region along axes the world wraps around on.
+bool outside_loaded_region(vec3 position) {
+    vec3 from_center = abs(position - vec3(uniform_data.lr)) * vec3(not(wrapped_axes()));
+    return any(greaterThanEqual(from_center, vec3(ROOT_BLOCK_WIDTH / 2)));
+}
+
+// world_image_offset, world_image_texel and world_image_position map between positions and texels
+// of the world images, see world_mapping.rs.
+#include "GEN_WORLD_MAPPING.glsl"
+
+// Must match LIGHT_VOLUME_CELL_SIZE in constants.rs.
+const int LIGHT_VOLUME_CELL_SIZE = 4;
+// Block light is stored divided by this, so that bright emitters fit in the light volume.
+const float BLOCK_LIGHT_SCALE = 4.0;
+
+// Returns which cell of the light volume holds the light around a position. The light volume is
+// laid out like the world images, with one texel for every LIGHT_VOLUME_CELL_SIZE blocks.
+ivec3 light_volume_cell(vec3 position) {
+    return ivec3(floor(world_image_texel(position))) / LIGHT_VOLUME_CELL_SIZE;
+}
+
+// Must match PROBE_SIZE in constants.rs.
+const int PROBE_SIZE = 32;
+// Probes further than this many blocks from a surface are not used for its reflections.
+const float PROBE_RANGE = 48.0;
+
+// Returns the direction the center of a texel of a probe's cubemap looks in. Each probe has six
+// layers of the reflection probe images, one for each face in the order +X -X +Y -Y +Z -Z.
+vec3 probe_texel_direction(ivec2 texel, int face) {
+    vec2 uv = (vec2(texel) + 0.5) / float(PROBE_SIZE) * 2.0 - 1.0;
+    int axis = face / 2;
+    vec3 direction;
+    direction[axis] = face % 2 == 0 ? 1.0 : -1.0;
+    direction[(axis + 1) % 3] = uv.x;
+    direction[(axis + 2) % 3] = uv.y;
+    return normalize(direction);
+}
+
+// The inverse of probe_texel_direction, returns the layer of the reflection probe images and the
+// texel in it which a direction from the given probe lands on.
+ivec3 probe_texel(uint probe, vec3 direction) {
+    vec3 size = abs(direction);
+    int axis = size.x >= size.y && size.x >= size.z ? 0 : (size.y >= size.z ? 1 : 2);
+    int face = axis * 2 + (direction[axis] < 0.0 ? 1 : 0);
+    vec2 uv = vec2(direction[(axis + 1) % 3], direction[(axis + 2) % 3]) / size[axis];
+    ivec2 texel = clamp(ivec2((uv * 0.5 + 0.5) * float(PROBE_SIZE)), 0, PROBE_SIZE - 1);
+    return ivec3(texel, int(probe) * 6 + face);
+}
+
+// Returns the index of the closest probe in range of a position, or -1 if there is none.
+int nearest_probe(vec3 position) {
+    int nearest = -1;
+    float nearest_distance = PROBE_RANGE;
+    for (uint probe = 0; probe < uniform_data.probe_count; probe++) {
+        float distance = length(uniform_data.probe_positions[probe].xyz - position);
+        if (distance < nearest_distance) {
+            nearest = int(probe);
+            nearest_distance = distance;
+        }
+    }
+    return nearest;
+}
+
+const uint EMPTY_CHUNK_INDEX = 0xFFFF;
+const uint UNLOADED_CHUNK_INDEX = 0xFFFE;
+const uint REQUEST_LOAD_CHUNK_INDEX = 0xFFFD;
+
+const uint NOISE_SIZE = 512;
+// Must match WORK_LIST_GROUP_SIZE in constants.rs.
+const uint WORK_LIST_GROUP_SIZE = 64;
+
+const uint NORMAL_x = 0;
+const uint NORMAL_y = 2;
+const uint NORMAL_z = 4;
+
+// Lighting values are divided by this before being added to the lighting buffer. This gives
+// room for HDR and accumulation of multiple samples.
+const float LIGHTING_SCALE = 16.0;
+const uint MAX_SAMPLES = 8;
+// Light is summed with atomics, which only work on integers, so it is stored in fixed point with
+// this many steps per unit. Each pixel has an RGB sum for its lighting followed by one for its
+// reflection.
+const float ACCUMULATOR_SCALE = 4096.0;
+const uint ACCUMULATORS_PER_PIXEL = 6;
+// How wide the outline drawn around the selected block is, in blocks.
+const float SELECTION_OUTLINE_WIDTH = 0.04;
+// Surfaces at least this rough don't trace a reflection ray, the diffuse rays already cover them.
+const float MAX_GLOSSY_ROUGHNESS = 0.95;
+// How much light a surface reflects when looked at head on. This is typical for non-metals.
+const float BASE_REFLECTANCE = 0.04;
+
+// Written to the normal buffer for pixels that show the sky.
+const uint NORMAL_SKY = 16;
+// Set in the packed materials of blocks which rays pass through, tinted by the albedo. Must match
+// Material::pack in build.rs. The 3 bits above it hold the emitter slot.
+const uint MATERIAL_TRANSPARENT = 1 << 28;
+
+const float PI = 3.1415926535897932384626433832795;
+
+struct HitResult {
+    vec3 albedo;
+    vec3 emission;
+    bool air;
+    float distance;
+    uint normal;
+    vec3 position;
+    float roughness;
+    uint material;
+    // The product of the albedos of the transparent blocks the ray passed through.
+    vec3 tint;
+    // How many times trace_ray stepped through the minefield, for the ray statistics.
+    uint steps;
+};
+
+// One entry of a ray queue. Must match RAY_SIZE in constants.rs.
+struct Ray {
+    // Replaced with where the ray hit by the traversal kernel.
+    vec3 origin;
+    // The x coordinate is in the lower 16 bits, y is in the upper 16 bits.
+    uint pixel;
+    vec3 direction;
+    // A combination of the RAY_ constants below.
+    uint flags;
+    // How much of the light found along this ray reaches the pixel.
+    vec3 throughput;
+    // The packed material that was hit, written by the traversal kernel.
+    uint material;
+};
+
+const uint RAY_KIND_MASK = 0x3;
+const uint RAY_PRIMARY = 0;
+const uint RAY_SHADOW = 1;
+const uint RAY_DIFFUSE = 2;
+const uint RAY_REFLECTION = 3;
+// The light found along the ray belongs in the reflection buffer instead of the lighting buffer.
+const uint RAY_TARGET_REFLECTION = 1 << 2;
+// How many diffuse bounces the path has taken, including this ray.
+const uint RAY_DEPTH_SHIFT = 3;
+const uint RAY_DEPTH_MASK = 0x3;
+// The normal of the face that was hit or NORMAL_SKY, written by the traversal kernel.
+const uint RAY_HIT_SHIFT = 8;
+const uint RAY_HIT_MASK = 0x1F;
+
+vec4 noise_value;
+
+uint pack_pixel(ivec2 pixel) {
+    return uint(pixel.x) | (uint(pixel.y) << 16);
+}
+
+ivec2 unpack_pixel(uint packed_pixel) {
+    return ivec2(packed_pixel & 0xFFFF, packed_pixel >> 16);
+}
+
+// Mixes the bits of an integer so that similar inputs give unrelated outputs. This is the
+// lowbias32 hash from https://nullprogram.com/blog/2018/07/31/.
+uint hash(uint x) {
+    x ^= x >> 16;
+    x *= 0x7FEB352Du;
+    x ^= x >> 15;
+    x *= 0x846CA68Bu;
+    x ^= x >> 16;
+    return x;
+}
+
+// Returns a value between 0 and 1 from the top 24 bits of a hash.
+float hash_to_unit(uint hashed) {
+    return float(hashed >> 8) / float(1 << 24);
+}
+
+// Returns a seed that is different for every pixel, frame and bounce. The blue noise value is
+// mixed in as well so that pixels which hash to similar seeds still get different values.
+uint get_pixel_seed(ivec2 pixel, uint bounce, vec4 blue_noise_value) {
+    uint seed = hash(uniform_data.frame_index) ^ hash(pack_pixel(pixel));
+    seed = hash(seed + bounce);
+    return hash(seed ^ packUnorm4x8(blue_noise_value));
+}
+
+// Picks new random values for the given pixel. Every pixel gets its own seed, so the values are
+// not correlated across the frame or with previous frames and bounces.
+void load_noise(ivec2 pixel, uint bounce) {
+    // Which part of the blue noise texture is used changes every frame.
+    uint frame_seed = hash(uniform_data.frame_index);
+    vec2 noise_offset = vec2(frame_seed % NOISE_SIZE, (frame_seed >> 16) % NOISE_SIZE);
+    noise_offset += vec2(pixel) + vec2(bounce * 2.0);
+    vec4 blue_noise_value = texture(blue_noise, mod(noise_offset, vec2(NOISE_SIZE)));
+
+    uint seed = get_pixel_seed(pixel, bounce, blue_noise_value);
+    for (int channel = 0; channel < 4; channel++) {
+        seed = hash(seed);
+        noise_value[channel] = hash_to_unit(seed);
+    }
+}
+
+vec3 get_sun_direction() {
+    float angle = uniform_data.sun_angle;
+    return normalize(vec3(cos(angle) * 0.5 + (angle - 0.5) * 0.5, sin(angle), cos(angle)));
+}
+
+void unpack_material(uint packed_material, inout HitResult result) {
+    result.material = packed_material;
+    // Packed materials only store which emitter slot they use, so that animating their emission
+    // only needs the uniform to change.
+    result.emission = uniform_data.emitter_colors[packed_material >> 29 & 0x7].rgb;
+    result.albedo.r = (packed_material >> 14 & 0x7F) / (0x7F + 0.0);
+    result.albedo.g = (packed_material >> 7 & 0x7F) / (0x7F + 0.0);
+    result.albedo.b = (packed_material >> 0 & 0x7F) / (0x7F + 0.0);
+    result.roughness = (packed_material >> 21 & 0x7F) / (0x7F + 0.0);
+    vec4 palette[4] = uniform_data.palette;
+    mat3 palette_matrix = mat3(palette[0].rgb, palette[1].rgb, palette[2].rgb);
+    result.albedo = clamp(palette_matrix * result.albedo + palette[3].rgb, 0.0, 1.0);
+    if (palette[3].a >= 0.0) {
+        result.roughness = palette[3].a;
+    }
+}
+
+// Rebuilds what trace_ray returned from a ray that has been through the traversal kernel.
+HitResult get_hit(Ray ray) {
+    HitResult result;
+    result.position = ray.origin;
+    result.normal = ray.flags >> RAY_HIT_SHIFT & RAY_HIT_MASK;
+    result.air = result.normal == NORMAL_SKY;
+    result.roughness = 1.0;
+    result.material = 0;
+    result.albedo = vec3(0);
+    result.emission = vec3(0);
+    // The traversal kernel already applied this to the throughput of the ray.
+    result.tint = vec3(1);
+    result.steps = 0;
+    if (!result.air) {
+        unpack_material(ray.material, result);
+    }
+    return result;
+}
+
+uint get_chunk_occupancy(vec3 tex_pos) {
+    int root_chunks = int(ROOT_BLOCK_WIDTH) >> CHUNK_SIZE_SHIFT;
+    ivec3 slot = ivec3(floor(tex_pos)) >> CHUNK_SIZE_SHIFT;
+    int index = (slot.z * root_chunks + slot.y) * root_chunks + slot.x;
+    return chunk_occupancy.chunks[index / 16] >> (index % 16 * 2) & 3;
+}
+
+uint get_step(vec3 tex_pos) {
+    // Chunks which are all the same are handled without touching the minefield. A step of one
+    // more than the size of a chunk goes straight to its far side.
+    uint occupancy = get_chunk_occupancy(tex_pos);
+    if (occupancy == CHUNK_EMPTY) {
+        return CHUNK_SIZE_SHIFT + 1;
+    } else if (occupancy == CHUNK_SOLID) {
+        return 0;
+    }
+    return texture(minefield, tex_pos).r;
+}
+
+HitResult trace_ray(vec3 origin, vec3 direction) {
+    direction = normalize(direction);
+    HitResult result;
+    result.position = origin;
+    result.roughness = 1.0;
+    result.material = 0;
+    result.tint = vec3(1);
+
+    // How much to travel along the ray to move 1 unit in a particular axis.
+    vec3 length_per_axis = vec3(1) / vec3(abs(direction));
+    ivec3 normals = ivec3(
+        direction.x > 0 ? NORMAL_x + 1 : NORMAL_x,
+        direction.y > 0 ? NORMAL_y + 1 : NORMAL_y,
+        direction.z > 0 ? NORMAL_z + 1 : NORMAL_z
+    );
+    vec3 muls = vec3(
+        direction.x > 0 ? -1 : 1,
+        direction.y > 0 ? -1 : 1,
+        direction.z > 0 ? -1 : 1
+    );
+
+    // TODO: Investigate high lag when sticking my head in a block.
+
+    // Positions are relative to region_offset, so it is added back in to find where they are stored
+    // in the world images.
+    vec3 pos_offset = world_image_offset();
+    uint current_step = get_step(mod((result.position + pos_offset), ROOT_BLOCK_WIDTH));
+    uint step_size = (1 << current_step) / 2;
+
+    // Rays which wrap around the world without hitting anything run out of steps, and are treated
+    // as if they reached the sky.
+    result.air = true;
+
+    uint limit = 2048;
+    vec3 length_to_next_voxel, lookup_offset;
+    // For some reason, using a non-infinite loop boosts performance even though
+    // the limit of the loop is never reached.
+    for (; limit > 0; limit--) {
+        // Things that don't increase performance: 
+        // - pre-multiplying result.position 
+        // - only setting the result normal if a new value gets hit.
+        // - distributing the addition of the offset in any way.
+        //    - although this point might be worth investigating now that there's three offsets.
+        length_to_next_voxel = (vec3(0.0001) + mod((result.position + pos_offset) * muls, vec3(step_size))) * length_per_axis;
+        if (length_to_next_voxel.x < length_to_next_voxel.y) {
+            if (length_to_next_voxel.x < length_to_next_voxel.z) { 
+                result.position += direction * length_to_next_voxel.x;
+                result.normal = normals.x;
+            } else {
+                result.position += direction * length_to_next_voxel.z;
+                result.normal = normals.z;
+            }
+        } else {
+            if (length_to_next_voxel.y < length_to_next_voxel.z) { 
+                result.position += direction * length_to_next_voxel.y;
+                result.normal = normals.y;
+            } else {
+                result.position += direction * length_to_next_voxel.z;
+                result.normal = normals.z;
+            }
+        }
+        current_step = get_step(mod((result.position + pos_offset), ROOT_BLOCK_WIDTH));
+        // Rays carry on through the other side of the region along axes the world wraps around
+        // on, since the lookups above already wrap.
+        if (outside_loaded_region(result.position)) {
+            // We hit the sky.
+            result.air = true;
+            break;
+        } else if (current_step <= 0) {
+            // We encountered a block inside the minefield.
+            // TODO: I don't think we need to use the textureLod function here.
+            uint packed_material = textureLod(
+                world, 
+                mod((result.position + pos_offset) / vec3(ROOT_BLOCK_WIDTH), 1.0), 
+                0.0
+            ).r;
+            unpack_material(packed_material, result);
+            if ((packed_material & MATERIAL_TRANSPARENT) != 0) {
+                // Pass through the block, then carry on one block at a time since the minefield
+                // doesn't skip over it.
+                result.tint *= result.albedo;
+                step_size = 1;
+                continue;
+            }
+            result.air = false;
+            break;
+        }
+        step_size = (1 << current_step) / 2;
+    }
+    result.steps = 2048 - limit;
+
+    result.distance = length(origin - result.position);
+
+    float offset_amount = 0.001;
+
+    if (result.normal == NORMAL_x) {
+        result.position += vec3(offset_amount, 0, 0);
+    } else if (result.normal == NORMAL_x + 1) {
+        result.position -= vec3(offset_amount, 0, 0);
+    } else if (result.normal == NORMAL_y) {
+        result.position += vec3(0, offset_amount, 0);
+    } else if (result.normal == NORMAL_y + 1) {
+        result.position -= vec3(0, offset_amount, 0);
+    } else if (result.normal == NORMAL_z) {
+        result.position += vec3(0, 0, offset_amount);
+    } else if (result.normal == NORMAL_z + 1) {
+        result.position -= vec3(0, 0, offset_amount);
+    }
+
+    return result;
+}
+
+// Finds two directions perpendicular to the given direction and to each other.
+void make_basis(vec3 direction, out vec3 tangent, out vec3 bitangent) {
+    vec3 helper = abs(direction.z) < 0.9 ? vec3(0, 0, 1) : vec3(1, 0, 0);
+    tangent = normalize(cross(helper, direction));
+    bitangent = cross(direction, tangent);
+}
+
+// Picks a random point on the disc of the sun to trace towards, so that shadows get softer further
+// away from whatever casts them.
+vec3 sun_sample_direction(vec3 sun_direction) {
+    vec3 tangent, bitangent;
+    make_basis(sun_direction, tangent, bitangent);
+    float radius = tan(uniform_data.sun_angular_radius) * sqrt(noise_value.r);
+    float angle = PI * 2.0 * noise_value.g;
+    vec3 offset = (tangent * cos(angle) + bitangent * sin(angle)) * radius;
+    return normalize(sun_direction + offset);
+}
+
+// Picks a direction around the given normal, more likely the closer it is to the normal.
+vec3 diffuse_direction_around(vec3 normal) {
+    float theta1 = PI * 2.0 * noise_value.r;
+    float theta2 = acos(1.0 - 2.0 * noise_value.g);
+    // Random point on sphere.
+    vec3 direction = vec3(
+        sin(theta1) * sin(theta2),
+        cos(theta1) * sin(theta2),
+        cos(theta2)
+    );
+    return normalize(direction + normal);
+}
+
+vec3 debug_normal(uint normal) {
+    vec3 color = vec3(1);
+    if (normal % 2 == 1) {
+        normal -= 1;
+        color *= 0.5;
+    }
+    if (normal == NORMAL_x) {
+        color *= vec3(1, 0, 0);
+    } else if (normal == NORMAL_y) {
+        color *= vec3(0, 1, 0);
+    } else if (normal == NORMAL_z) {
+        color *= vec3(0, 0, 1);
+    }
+    return color;
+}
+
+vec3 world_space_normal(uint normal) {
+    vec3 world_space = vec3(1.0);
+    if (normal % 2 == 1) {
+        normal -= 1;
+        world_space *= -1.0;
+    }
+    if (normal == NORMAL_x) {
+        world_space *= vec3(1, 0, 0);
+    } else if (normal == NORMAL_y) {
+        world_space *= vec3(0, 1, 0);
+    } else if (normal == NORMAL_z) {
+        world_space *= vec3(0, 0, 1);
+    }
+    return world_space;
+}
+
+vec3 diffuse_direction(HitResult from) {
+    return diffuse_direction_around(world_space_normal(from.normal));
+}
+
+// Estimates a normal which varies smoothly over the terrain, pointing away from whichever of the
+// blocks around the hit are solid. Falls back to the normal of the face that was hit where the
+// estimate points into the face, like on thin walls.
+vec3 smooth_normal(HitResult hit) {
+    vec3 face = world_space_normal(hit.normal);
+    vec3 pos_offset = world_image_offset();
+    // The hit position is nudged out of the block that was hit, so this is the empty block in
+    // front of the face.
+    vec3 center = floor(hit.position) + vec3(0.5);
+    vec3 gradient = vec3(0);
+    for (int dz = -1; dz <= 1; dz++) {
+        for (int dy = -1; dy <= 1; dy++) {
+            for (int dx = -1; dx <= 1; dx++) {
+                vec3 offset = vec3(dx, dy, dz);
+                if (offset == vec3(0)) {
+                    continue;
+                }
+                if (get_step(mod(center + offset + pos_offset, ROOT_BLOCK_WIDTH)) == 0) {
+                    // Closer blocks count for more.
+                    gradient -= offset / dot(offset, offset);
+                }
+            }
+        }
+    }
+    if (dot(gradient, face) <= 0.0) {
+        return face;
+    }
+    return normalize(gradient);
+}
+
+vec3 encode_world_space_normal(uint normal) {
+    return world_space_normal(normal) * 0.5 + vec3(0.5);
+}
+
+vec2 encode_screen_space_normal(uint normal, vec3 right, vec3 up) {
+    vec3 world_space = world_space_normal(normal);
+    vec2 screen_space = vec2(
+        dot(world_space, right),
+        dot(world_space, up)
+    );
+    return screen_space * 0.5 + vec2(0.5);
+}
+
+// True if the hit lies on one of the edges of the selected block.
+bool on_selection_outline(HitResult hit) {
+    if (uniform_data.has_selection == 0 || hit.air) {
+        return false;
+    }
+    // The hit position is nudged slightly out of the block that was hit, so move it back in.
+    vec3 inside = hit.position - world_space_normal(hit.normal) * 0.01;
+    if (ivec3(floor(inside)) != uniform_data.selected_block) {
+        return false;
+    }
+    vec3 in_block = inside - floor(inside);
+    vec3 edge_distance = min(in_block, vec3(1.0) - in_block);
+    // The hit is always close to an edge along the axis of the face it is on, so it is on an edge
+    // of the block if it is also close to an edge along a second axis.
+    int close_axes = int(edge_distance.x < SELECTION_OUTLINE_WIDTH)
+        + int(edge_distance.y < SELECTION_OUTLINE_WIDTH)
+        + int(edge_distance.z < SELECTION_OUTLINE_WIDTH);
+    return close_axes >= 2;
+}
+
+bool is_overcast() {
+    return uniform_data.overcast.a != 0.0;
+}
+
+vec3 sun_color(vec3 sun_direction) {
+    if (is_overcast()) {
+        return vec3(0.0);
+    }
+    float horizon = length(sun_direction.xy);
+    float sun_amount = min(1.0 - horizon, 0.02) * 50.0;
+    vec3 main_color = vec3(0.9647, 0.7843, 0.8824) * 2.0;
+    vec3 sunset_color = vec3(0.7412, 0.2157, 0.1686) * 2.0;
+    vec3 color;
+    if (sun_direction.z >= 0.0) {
+        color = mix(sunset_color, main_color, sun_amount);
+    } else {
+        color = mix(sunset_color, vec3(0), sun_amount * 2);
+    }
+    return color * uniform_data.sun_intensity;
+}
+
+vec3 sample_sky(vec3 direction, vec3 sun_direction, vec3 sunlight, bool include_sun) {
+    if (is_overcast()) {
+        return uniform_data.overcast.rgb;
+    }
+    if (direction.z < 0.0) {
+        // direction = normalize(vec3(direction.xy, 0.0));
+    }
+    vec3 bright_color = vec3(0.5294, 0.8275, 0.9647);
+    vec3 dark_color = vec3(0.0863, 0.1294, 0.2196);
+    float sunlight_amount = clamp((sunlight.x + sunlight.y + sunlight.z) * 0.2 - 0.02, 0.0, 1.0);
+    float horizon = pow(length(direction.xy), mix(40.0, 10.0, sunlight_amount));
+    float sun_amount = 1.0 - 0.5 * length(sun_direction - direction);
+    float sun_halo_amount = pow(sun_amount, mix(5.0, 1.0, sunlight_amount));
+    float bright_amount = min(horizon + sun_halo_amount * 0.5, 1.0);
+    vec3 color = mix(dark_color, bright_color, bright_amount * max(sunlight_amount, 0.1));
+    color += sunlight * pow(sun_amount, 5.0) * 0.5;
+    if (sun_amount > 0.98 && include_sun) {
+        color += sunlight;
+    }
+    return color;
+}
+
+// Picks a direction to reflect the incoming ray in by sampling a microfacet normal from the GGX
+// distribution, so that smoother surfaces reflect in a tighter cone.
+vec3 glossy_direction(HitResult from, vec3 incoming) {
+    vec3 normal = world_space_normal(from.normal);
+    float alpha = from.roughness * from.roughness;
+    float phi = PI * 2.0 * noise_value.b;
+    float cos_theta = sqrt((1.0 - noise_value.a) / (1.0 + (alpha * alpha - 1.0) * noise_value.a));
+    float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    vec3 tangent, bitangent;
+    make_basis(normal, tangent, bitangent);
+    vec3 half_vector = (tangent * cos(phi) + bitangent * sin(phi)) * sin_theta + normal * cos_theta;
+    return reflect(incoming, half_vector);
+}
+
+// How much of the reflection reaches the camera, using Schlick's approximation.
+float reflectance(HitResult from, vec3 incoming) {
+    float cos_theta = max(dot(-incoming, world_space_normal(from.normal)), 0.0);
+    float fresnel = BASE_REFLECTANCE + (1.0 - BASE_REFLECTANCE) * pow(1.0 - cos_theta, 5.0);
+    // Rough surfaces scatter most of their reflection diffusely instead.
+    return fresnel * (1.0 - from.roughness);
+}
+
+// How far the surface moved since last frame. Every voxel is static for now, moving objects
+// should return their own motion here so that the temporal stage can follow them.
+vec3 surface_motion(HitResult hit) {
+    return vec3(0.0);
+}
+
+// Finds where a surface was on screen last frame, relative to the pixel it is seen through now.
+vec4 compute_motion(ivec2 pixel, ivec2 size, HitResult hit) {
+    vec3 relative = hit.position - surface_motion(hit) - uniform_data.old_origin;
+    mat3 old_transform = mat3(
+        uniform_data.old_transform_c0,
+        uniform_data.old_transform_c1,
+        uniform_data.old_transform_c2
+    );
+    // {screenx * depth, screeny * depth, depth}
+    vec3 old_screen = old_transform * relative;
+    if (hit.air || old_screen.z <= 0.0) {
+        return vec4(0.0);
+    }
+    vec2 old_pixel = (old_screen.xy / old_screen.z + vec2(1)) / 2 * size;
+    return vec4(old_pixel - pixel, length(relative), 1.0);
+}
+
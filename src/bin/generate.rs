@@ -69,7 +69,7 @@ impl StatTracker {
 }
 
 fn main() {
-    let mut game = game::Game::new();
+    let mut game = game::Game::new(&[]);
     let radius = RADIUS;
     let num_items = (radius * 2).pow(3);
     let mut stat_tracker = StatTracker::new(num_items);
@@ -2,30 +2,123 @@ extern crate raytrace;
 
 use raytrace::*;
 use std::time::Instant;
-use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::event::{
+    DeviceEvent, ElementState, Event, KeyboardInput, MouseScrollDelta, WindowEvent,
+};
 use winit::event_loop::{ControlFlow, EventLoop};
 
+/// Reads the render settings from the settings file, with the ones given on the command line
+/// taking priority.
+fn load_render_settings(
+    config: &config::ConfigFile,
+    command_line: &config::CommandLine,
+    detected_quality: render::QualityPreset,
+) -> render::RenderSettings {
+    let mut settings = render::RenderSettings::from_config(config, detected_quality);
+    settings.apply_command_line(command_line);
+    settings
+}
+
+/// Moves the camera to where a shot of a shot matrix is taken from and asks for the shot to be
+/// rendered. The shot is skipped if its session can't be loaded.
+fn start_matrix_shot(game: &mut game::Game, shot: &shot_matrix::MatrixShot, samples: u32) {
+    if let Err(err) = game.load_session(&shot.session) {
+        println!(
+            "WARNING: Skipping {:?}, failed to load {:?}.",
+            shot.output, shot.session
+        );
+        println!("Caused by: {}", err);
+        return;
+    }
+    if let Some(sun_angle) = shot.sun_angle {
+        game.set_sun_angle(sun_angle);
+    }
+    if let Some(directory) = shot.output.parent() {
+        if let Err(err) = std::fs::create_dir_all(directory) {
+            println!("WARNING: Failed to create {:?}.", directory);
+            println!("Caused by: {}", err);
+        }
+    }
+    game.request_beauty_shot(render::BeautyShotRequest {
+        path: shot.output.clone(),
+        frames: samples,
+        downsample: 1,
+    });
+}
+
 fn main() {
-    let mut game = game::Game::new();
+    telemetry::install_panic_hook();
+    let command_line = match config::CommandLine::parse_with_env(std::env::args().skip(1)) {
+        Ok(command_line) => command_line,
+        Err(err) => panic!("Invalid command line arguments:\n{}", err),
+    };
+    let config_path = config::ConfigFile::get_default_path();
+    let config = config::ConfigFile::load(&config_path);
+    let mut config_watcher = config::ConfigWatcher::new(config_path);
+    let mut game = game::Game::new(&command_line.positional);
+    if let Some(still) = &command_line.render_still {
+        if let Err(err) = game.load_session(&still.session) {
+            panic!("Failed to load session from {:?}:\n{}", still.session, err);
+        }
+        game.request_beauty_shot(render::BeautyShotRequest {
+            path: still.output.clone(),
+            frames: still.samples,
+            downsample: still.scale,
+        });
+    }
+    // Stored in reverse so that the next shot can be popped off the end.
+    let (mut matrix_shots, matrix_samples) = match &command_line.shot_matrix {
+        Some(path) => match shot_matrix::ShotMatrix::load(path) {
+            Ok(matrix) => {
+                println!("Rendering a matrix of {} shots.", matrix.shots.len());
+                (matrix.shots.into_iter().rev().collect(), matrix.samples)
+            }
+            Err(err) => panic!("Failed to load shot matrix from {:?}:\n{}", path, err),
+        },
+        None => (Vec::new(), 0),
+    };
+    if !command_line.headless {
+        game.enable_audio(game::audio::AudioSettings::from_config(&config));
+    }
+    if command_line.frames.is_some() {
+        // Smoke tests should exercise the whole pipeline, not just the menu.
+        game.skip_menu();
+    }
+    game.apply_config(&config);
     let event_loop = EventLoop::new();
-    println!("Creating renderer (and world.)");
+    println!("Creating renderer.");
     let instance_timer = Instant::now();
-    let (_core, mut pipeline) = render::create_instance(&event_loop, &mut game);
+    // The window does not depend on the quality preset, so any preset will do until the device
+    // it is detected from has been created.
+    let window_settings = load_render_settings(&config, &command_line, render::QualityPreset::High);
+    let core = render::create_core(&event_loop, &window_settings);
+    let detected_quality = render::detect_quality(&core);
+    let mut render_settings = load_render_settings(&config, &command_line, detected_quality);
+    if render_settings.quality == render::QualityPreset::Auto {
+        println!("Using the {} quality preset.", detected_quality);
+    }
+    game.set_max_fps(render_settings.max_fps);
+    game.set_denoise_schedule(render_settings.denoise_schedule.clone());
+    let mut pipeline = render::create_pipeline(core.clone(), &mut game, &mut render_settings);
     println!("Created in {}s.", instance_timer.elapsed().as_secs_f32());
     let mut frame_timer = Instant::now();
+    let mut frame_limiter = util::FrameLimiter::new();
+    // Set to the opposite of what the initial state wants so that it gets applied on the first
+    // frame.
+    let mut mouse_captured = !game.get_state().captures_mouse();
     let mut performance_buffer = util::RingBufferAverage::new(120);
+    let mut report = report::PerformanceReport::new();
+    let mut frames_drawn: u64 = 0;
     event_loop.run(move |event, _, control_flow| match event {
         Event::WindowEvent { event, .. } => match event {
             WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+            WindowEvent::Resized(..) => pipeline.on_window_resized(),
             WindowEvent::KeyboardInput { input, .. } => match input {
                 KeyboardInput {
                     virtual_keycode,
                     state,
                     ..
                 } => match (virtual_keycode, state) {
-                    (Some(VirtualKeyCode::Escape), ElementState::Pressed) => {
-                        *control_flow = ControlFlow::Exit;
-                    }
                     (Some(code), ElementState::Pressed) => {
                         game.borrow_controls_mut().on_pressed(code);
                     }
@@ -35,22 +128,141 @@ fn main() {
                     _ => {}
                 },
             },
+            WindowEvent::MouseInput { state, button, .. } => match state {
+                ElementState::Pressed => game.borrow_controls_mut().on_mouse_pressed(button),
+                ElementState::Released => game.borrow_controls_mut().on_mouse_released(button),
+            },
+            WindowEvent::MouseWheel { delta, .. } => {
+                let notches = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y,
+                    // Roughly how many pixels a notch scrolls by on most platforms.
+                    MouseScrollDelta::PixelDelta(position) => (position.y / 40.0) as f32,
+                };
+                game.borrow_controls_mut().on_scroll(notches);
+            }
             WindowEvent::CursorMoved { position, .. } => game.on_mouse_move(position.x, position.y),
             _ => {}
         },
+        Event::DeviceEvent {
+            event: DeviceEvent::ModifiersChanged(modifiers),
+            ..
+        } => game.borrow_controls_mut().on_modifiers_changed(modifiers),
         Event::MainEventsCleared => {
-            let millis = frame_timer.elapsed().as_millis();
+            let frame_time = frame_timer.elapsed();
             frame_timer = Instant::now();
+            let millis = frame_time.as_millis();
 
             performance_buffer.push_sample(millis);
+            report.push_frame_time(frame_time.as_secs_f32() * 1000.0);
+            telemetry::record(|telemetry| {
+                telemetry.record_frame(frame_time.as_secs_f32() * 1000.0);
+                telemetry.set_camera(game.describe_session());
+                telemetry.set_streaming(pipeline.describe_streaming());
+            });
             print!("\r");
             print!("{}ms / {}ms", performance_buffer.average(), performance_buffer.max());
             print!("               ");
             use std::io::Write;
             std::io::stdout().flush().unwrap();
-            game.tick((millis as f64 / 1000.0) as f32);
-            pipeline.draw_frame(&mut game);
-            game.borrow_controls_mut().tick();
+            // Shots of a matrix are rendered with the settings file as it was when it started.
+            let watch_config = command_line.shot_matrix.is_none();
+            if let Some(config) = config_watcher.poll().filter(|_| watch_config) {
+                println!("\nReloading settings.");
+                game.apply_config(&config);
+                let new_settings = load_render_settings(&config, &command_line, detected_quality);
+                render_settings = render::reload_settings(
+                    &core,
+                    &mut pipeline,
+                    &mut game,
+                    &render_settings,
+                    &new_settings,
+                );
+            }
+            let shot_saved = !game.has_beauty_shot_request() && !pipeline.is_taking_beauty_shot();
+            if command_line.shot_matrix.is_some() && shot_saved {
+                let shot = match matrix_shots.pop() {
+                    Some(shot) => shot,
+                    None => {
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                };
+                let shot_config = config.with_overrides(&shot.settings);
+                let new_settings =
+                    load_render_settings(&shot_config, &command_line, detected_quality);
+                render_settings = render::reload_settings(
+                    &core,
+                    &mut pipeline,
+                    &mut game,
+                    &render_settings,
+                    &new_settings,
+                );
+                start_matrix_shot(&mut game, &shot, matrix_samples);
+            }
+            // Waiting for the previous frame comes first so that the input the frame is rendered
+            // with is as fresh as it can be, see RenderSettings::late_latch.
+            let late_latch = render_settings.late_latch;
+            let frame_started = late_latch && pipeline.begin_frame(&mut game);
+            game.update(frame_time.as_secs_f32());
+            pipeline.on_game_updated();
+            if game.should_quit() {
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+            let capture = game.get_state().captures_mouse();
+            if capture != mouse_captured {
+                mouse_captured = capture;
+                if let Err(err) = core.window.set_cursor_grab(capture) {
+                    println!("WARNING: Failed to change whether the mouse is captured.");
+                    println!("Caused by: {}", err);
+                }
+                core.window.set_cursor_visible(!capture);
+            }
+            // No frame is submitted while the window is minimized or the swapchain is out of date.
+            if frame_started || (!late_latch && pipeline.begin_frame(&mut game)) {
+                pipeline.finish_frame(&mut game);
+                frames_drawn += 1;
+            }
+            let still_saved = !game.has_beauty_shot_request() && !pipeline.is_taking_beauty_shot();
+            if command_line.render_still.is_some() && still_saved {
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+            if Some(frames_drawn) == command_line.frames {
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+            frame_limiter.wait(game.get_max_fps());
+        }
+        Event::LoopDestroyed => {
+            game.save_and_wait();
+            report.gpu_stage_timings = pipeline.get_gpu_stage_timings();
+            report.ray_statistics = pipeline.get_total_ray_statistics().get_report_values();
+            report.chunks_generated = game.borrow_world().get_chunks_generated();
+            report.bytes_uploaded = pipeline.get_bytes_uploaded();
+            let path = report::PerformanceReport::get_default_path();
+            match report.save(&path) {
+                Ok(()) => println!("\nSaved performance report to {:?}.", path),
+                Err(err) => {
+                    println!(
+                        "\nWARNING: Failed to save performance report to {:?}.",
+                        path
+                    );
+                    println!("Caused by: {}", err);
+                }
+            }
+            if command_line.frames.is_some() {
+                let errors = render::get_validation_error_count();
+                if errors > 0 {
+                    println!("Smoke test failed, {} validation errors occurred.", errors);
+                    std::process::exit(1);
+                }
+                if frames_drawn == 0 {
+                    println!("Smoke test failed, no frames were drawn.");
+                    std::process::exit(1);
+                }
+                println!("Smoke test passed after {} frames.", frames_drawn);
+            }
         }
         _ => (),
     });
@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often ConfigWatcher checks whether the settings file was modified.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+/// Environment variables whose names start with this override settings and command line options,
+/// so that scripts can change them without editing any files.
+const ENV_PREFIX: &str = "RAYTRACE_";
+
+/// Returns every environment variable starting with ENV_PREFIX, with the prefix removed and the
+/// rest of the name in lowercase. RAYTRACE_WINDOW_WIDTH becomes window_width, for example.
+fn get_env_overrides(vars: impl IntoIterator<Item = (String, String)>) -> Vec<(String, String)> {
+    vars.into_iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name.len() > ENV_PREFIX.len())
+        .map(|(name, value)| (name[ENV_PREFIX.len()..].to_lowercase(), value))
+        .collect()
+}
+
+/// Like std::env::vars, but skips variables which are not valid unicode instead of panicking.
+fn get_env_vars() -> impl Iterator<Item = (String, String)> {
+    std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+}
+
+/// Settings loaded from a plain text file containing one `key = value` pair per line. Blank lines
+/// and lines starting with # are ignored.
+pub struct ConfigFile {
+    values: HashMap<String, String>,
+}
+
+impl ConfigFile {
+    pub fn get_default_path() -> PathBuf {
+        dirs::config_dir()
+            .expect("System somehow doesn't have a config dir?")
+            .join("raytrace")
+            .join("settings.txt")
+    }
+
+    /// Loads settings from the given file, then applies overrides from the environment. If the
+    /// file does not exist, every setting which is not overridden will use its default value.
+    pub fn load(path: &Path) -> ConfigFile {
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(err) => {
+                if err.kind() != ErrorKind::NotFound {
+                    println!("WARNING: Failed to read settings from {:?}.", path);
+                    println!("Caused by: {}", err);
+                }
+                Self::parse("")
+            }
+        };
+        config.apply_env_overrides(get_env_vars());
+        config
+    }
+
+    pub fn parse(text: &str) -> ConfigFile {
+        let mut values = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            match parts.next() {
+                Some(value) if key.len() > 0 => {
+                    values.insert(key.to_owned(), value.trim().to_owned());
+                }
+                _ => println!(
+                    "WARNING: Line {} of the settings file is not a key = value pair.",
+                    index + 1
+                ),
+            }
+        }
+        ConfigFile { values }
+    }
+
+    /// Replaces settings with the values of environment variables named after them, for example
+    /// RAYTRACE_VALIDATION for validation. Variables without the prefix are ignored.
+    pub fn apply_env_overrides(&mut self, vars: impl IntoIterator<Item = (String, String)>) {
+        for (key, value) in get_env_overrides(vars) {
+            self.values.insert(key, value.trim().to_owned());
+        }
+    }
+
+    /// Returns a copy of these settings with some of them replaced.
+    pub fn with_overrides(&self, overrides: &[(String, String)]) -> ConfigFile {
+        let mut values = self.values.clone();
+        for (key, value) in overrides {
+            values.insert(key.clone(), value.clone());
+        }
+        ConfigFile { values }
+    }
+
+    /// Returns the name of every setting in the file, in no particular order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(|key| key.as_str())
+    }
+
+    /// Returns the value of a setting, or the provided default if it was not specified or could
+    /// not be parsed.
+    pub fn get<T: FromStr>(&self, key: &str, default: T) -> T {
+        let value = match self.values.get(key) {
+            Some(value) => value,
+            None => return default,
+        };
+        match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                println!(
+                    "WARNING: Invalid value '{}' for setting '{}', using the default instead.",
+                    value, key
+                );
+                default
+            }
+        }
+    }
+}
+
+fn get_modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reloads a settings file whenever it is modified, so that settings can be tuned without
+/// restarting the game.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    // None if the file did not exist.
+    modified_time: Option<SystemTime>,
+    last_check: Instant,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf) -> ConfigWatcher {
+        ConfigWatcher {
+            modified_time: get_modified_time(&path),
+            path,
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Returns the new contents of the file if it was modified, created or deleted since the
+    /// last call. This is cheap enough to call every frame.
+    pub fn poll(&mut self) -> Option<ConfigFile> {
+        if self.last_check.elapsed() < WATCH_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+        let modified_time = get_modified_time(&self.path);
+        if modified_time == self.modified_time {
+            return None;
+        }
+        self.modified_time = modified_time;
+        Some(ConfigFile::load(&self.path))
+    }
+}
+
+/// How many times larger than the window render-still draws images if --scale is not given.
+const DEFAULT_STILL_SCALE: u32 = 2;
+/// Larger scales rarely fit in GPU memory, and the image is limited to the largest size the GPU
+/// supports anyway.
+const MAX_STILL_SCALE: u32 = 8;
+/// How many frames render-still blends together if --samples is not given.
+const DEFAULT_STILL_SAMPLES: u32 = 256;
+
+/// Options for the render-still subcommand, which renders a saved session to an image file
+/// without showing a window and then exits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderStill {
+    /// A file saved with the save_session console command.
+    pub session: PathBuf,
+    pub output: PathBuf,
+    /// The image is rendered this many times larger than the window along each axis, then scaled
+    /// back down.
+    pub scale: u32,
+    /// How many frames are blended together.
+    pub samples: u32,
+}
+
+/// Parses a size like 1280x720.
+fn parse_resolution(text: &str) -> Result<(u32, u32), String> {
+    let mut parts = text.splitn(2, 'x');
+    let width = parts.next().and_then(|width| width.parse().ok());
+    let height = parts.next().and_then(|height| height.parse().ok());
+    match (width, height) {
+        (Some(width), Some(height)) => Ok((width, height)),
+        _ => Err(format!("'{}' is not a size like 1280x720.", text)),
+    }
+}
+
+/// Options given on the command line.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandLine {
+    /// Exit after rendering this many frames, to test that the renderer works. Turns on the
+    /// validation layers so that the test can fail on validation errors.
+    pub frames: Option<u64>,
+    /// Keep the window hidden.
+    pub headless: bool,
+    /// The size of the window, given as WIDTHxHEIGHT. Overrides the settings file.
+    pub resolution: Option<(u32, u32)>,
+    /// Set if the first argument is render-still. Its arguments are not included in positional.
+    pub render_still: Option<RenderStill>,
+    /// Renders every shot of a ShotMatrix file without showing a window, then exits.
+    pub shot_matrix: Option<PathBuf>,
+    /// Every argument which is not an option, in order.
+    pub positional: Vec<String>,
+}
+
+impl CommandLine {
+    /// Parses the arguments after the name of the program.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CommandLine, String> {
+        let mut result = CommandLine::default();
+        let mut args = args.into_iter();
+        let mut scale = None;
+        let mut samples = None;
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "--frames" => {
+                    let count = args.next().ok_or("--frames requires a number of frames.")?;
+                    // Zero frames would never be reached, so the game would never exit.
+                    match count.parse() {
+                        Ok(count) if count > 0 => result.frames = Some(count),
+                        _ => return Err(format!("'{}' is not a number of frames.", count)),
+                    }
+                }
+                "--scale" => {
+                    let value = args.next().ok_or("--scale requires a number.")?;
+                    match value.parse() {
+                        Ok(value) if value >= 1 && value <= MAX_STILL_SCALE => scale = Some(value),
+                        _ => {
+                            return Err(format!(
+                                "'{}' is not a scale from 1 to {}.",
+                                value, MAX_STILL_SCALE
+                            ))
+                        }
+                    }
+                }
+                "--samples" => {
+                    let value = args
+                        .next()
+                        .ok_or("--samples requires a number of frames.")?;
+                    match value.parse() {
+                        Ok(value) if value > 0 => samples = Some(value),
+                        _ => return Err(format!("'{}' is not a number of frames.", value)),
+                    }
+                }
+                "--headless" => result.headless = true,
+                "--shot-matrix" => {
+                    let path = args.next().ok_or("--shot-matrix requires a file.")?;
+                    result.shot_matrix = Some(PathBuf::from(path));
+                }
+                "--resolution" => {
+                    let size = args.next().ok_or("--resolution requires a size.")?;
+                    result.resolution = Some(parse_resolution(&size)?);
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option '{}'.", arg)),
+                _ => result.positional.push(arg),
+            }
+        }
+        if result.positional.first().map(|arg| &arg[..]) == Some("render-still") {
+            if result.positional.len() != 3 {
+                return Err(
+                    "Usage: render-still <session> <output> [--scale N] [--samples N]".into(),
+                );
+            }
+            result.render_still = Some(RenderStill {
+                session: PathBuf::from(&result.positional[1]),
+                output: PathBuf::from(&result.positional[2]),
+                scale: scale.unwrap_or(DEFAULT_STILL_SCALE),
+                samples: samples.unwrap_or(DEFAULT_STILL_SAMPLES),
+            });
+            result.positional.clear();
+            // There is nothing to look at until the image is saved.
+            result.headless = true;
+        } else if scale.is_some() || samples.is_some() {
+            return Err("--scale and --samples can only be used with render-still.".to_owned());
+        }
+        if result.shot_matrix.is_some() {
+            if result.render_still.is_some() {
+                return Err("--shot-matrix can't be used with render-still.".to_owned());
+            }
+            result.headless = true;
+        }
+        Ok(result)
+    }
+
+    /// Parses the arguments after the name of the program, using RAYTRACE_FRAMES and
+    /// RAYTRACE_HEADLESS from the environment for options which were not given.
+    pub fn parse_with_env(args: impl IntoIterator<Item = String>) -> Result<CommandLine, String> {
+        let mut result = Self::parse(args)?;
+        result.apply_env_overrides(get_env_vars())?;
+        Ok(result)
+    }
+
+    fn apply_env_overrides(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), String> {
+        for (key, value) in get_env_overrides(vars) {
+            match &key[..] {
+                "frames" if self.frames.is_none() => match value.trim().parse() {
+                    Ok(count) if count > 0 => self.frames = Some(count),
+                    _ => return Err(format!("'{}' is not a number of frames.", value)),
+                },
+                "headless" => match value.trim().parse::<bool>() {
+                    Ok(headless) => self.headless |= headless,
+                    Err(_) => return Err(format!("'{}' is not true or false.", value)),
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_key_value_pairs() {
+        let config = ConfigFile::parse("# Comment\nwidth = 640\n\n  name=big world \nbad = x");
+        assert_eq!(config.get("width", 0u32), 640);
+        assert_eq!(config.get("name", String::new()), "big world");
+        assert_eq!(config.get("bad", 12u32), 12);
+        assert_eq!(config.get("missing", 3u32), 3);
+        let mut keys: Vec<_> = config.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["bad", "name", "width"]);
+        let overridden = config.with_overrides(&[("width".to_owned(), "320".to_owned())]);
+        assert_eq!(overridden.get("width", 0u32), 320);
+        assert_eq!(overridden.get("name", String::new()), "big world");
+    }
+
+    #[test]
+    fn parse_command_line() {
+        let args = |text: &str| {
+            text.split_whitespace()
+                .map(|arg| arg.to_owned())
+                .collect::<Vec<_>>()
+        };
+        let command_line =
+            CommandLine::parse(args("1 --frames 300 -2.5 --headless --resolution 640x480"))
+                .unwrap();
+        assert_eq!(command_line.frames, Some(300));
+        assert!(command_line.headless);
+        assert_eq!(command_line.resolution, Some((640, 480)));
+        assert_eq!(command_line.positional, vec!["1", "-2.5"]);
+        assert_eq!(
+            CommandLine::parse(args("")).unwrap(),
+            CommandLine::default()
+        );
+        assert!(CommandLine::parse(args("--frames")).is_err());
+        assert!(CommandLine::parse(args("--frames many")).is_err());
+        assert!(CommandLine::parse(args("--frames 0")).is_err());
+        assert!(CommandLine::parse(args("--fast")).is_err());
+        assert!(CommandLine::parse(args("--resolution 640")).is_err());
+        assert!(CommandLine::parse(args("--resolution 640x-1")).is_err());
+    }
+
+    #[test]
+    fn parse_render_still() {
+        let args = |text: &str| {
+            text.split_whitespace()
+                .map(|arg| arg.to_owned())
+                .collect::<Vec<_>>()
+        };
+        let command_line =
+            CommandLine::parse(args("render-still a.txt b.png --scale 4 --samples 32")).unwrap();
+        assert_eq!(
+            command_line.render_still,
+            Some(RenderStill {
+                session: PathBuf::from("a.txt"),
+                output: PathBuf::from("b.png"),
+                scale: 4,
+                samples: 32,
+            })
+        );
+        assert!(command_line.headless);
+        assert!(command_line.positional.is_empty());
+        let command_line = CommandLine::parse(args("render-still a.txt b.png")).unwrap();
+        assert_eq!(
+            command_line.render_still.unwrap().scale,
+            DEFAULT_STILL_SCALE
+        );
+        assert!(CommandLine::parse(args("render-still a.txt")).is_err());
+        assert!(CommandLine::parse(args("render-still a.txt b.png --scale 9")).is_err());
+        assert!(CommandLine::parse(args("1 2 3 --samples 8")).is_err());
+    }
+
+    #[test]
+    fn parse_shot_matrix() {
+        let args = |text: &str| {
+            text.split_whitespace()
+                .map(|arg| arg.to_owned())
+                .collect::<Vec<_>>()
+        };
+        let command_line = CommandLine::parse(args("--shot-matrix matrix.txt")).unwrap();
+        assert_eq!(command_line.shot_matrix, Some(PathBuf::from("matrix.txt")));
+        assert!(command_line.headless);
+        assert!(CommandLine::parse(args("--shot-matrix")).is_err());
+        let both = "render-still a.txt b.png --shot-matrix matrix.txt";
+        assert!(CommandLine::parse(args(both)).is_err());
+    }
+
+    #[test]
+    fn apply_env_overrides() {
+        let vars = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let mut config = ConfigFile::parse("window_width = 640\nvsync = true");
+        config.apply_env_overrides(vars(&[
+            ("RAYTRACE_WINDOW_WIDTH", "320"),
+            ("RAYTRACE_VALIDATION", "false"),
+            ("RAYTRACE_", "ignored"),
+            ("WINDOW_HEIGHT", "ignored"),
+        ]));
+        assert_eq!(config.get("window_width", 0u32), 320);
+        assert_eq!(config.get("validation", true), false);
+        assert_eq!(config.get("vsync", false), true);
+        assert_eq!(config.get("window_height", 5u32), 5);
+
+        let mut command_line = CommandLine::default();
+        let overrides = vars(&[("RAYTRACE_FRAMES", "60"), ("RAYTRACE_HEADLESS", "true")]);
+        command_line.apply_env_overrides(overrides).unwrap();
+        assert_eq!(command_line.frames, Some(60));
+        assert!(command_line.headless);
+        // Options given on the command line take priority.
+        command_line.frames = Some(10);
+        command_line
+            .apply_env_overrides(vars(&[("RAYTRACE_FRAMES", "60")]))
+            .unwrap();
+        assert_eq!(command_line.frames, Some(10));
+        let invalid = vars(&[("RAYTRACE_HEADLESS", "yes")]);
+        assert!(command_line.apply_env_overrides(invalid).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+use crate::config::ConfigFile;
+use crate::render::material_info::{FootstepSound, MaterialInfo};
+use crate::render::Material;
+
+// Every sound is generated procedurally, so no audio files need to be shipped.
+const SAMPLE_RATE: u32 = 44100;
+
+/// Volumes chosen by the user, each ranging from 0 to 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioSettings {
+    pub master_volume: f32,
+    /// Volume of background sounds like wind.
+    pub ambient_volume: f32,
+    /// Volume of footsteps and UI sounds.
+    pub effects_volume: f32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            master_volume: 0.8,
+            ambient_volume: 0.5,
+            effects_volume: 0.7,
+        }
+    }
+}
+
+impl AudioSettings {
+    pub fn from_config(config: &ConfigFile) -> AudioSettings {
+        let default = Self::default();
+        let volume = |key, default: f32| config.get(key, default).max(0.0).min(1.0);
+        AudioSettings {
+            master_volume: volume("master_volume", default.master_volume),
+            ambient_volume: volume("ambient_volume", default.ambient_volume),
+            effects_volume: volume("effects_volume", default.effects_volume),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UiSound {
+    /// A hotbar slot was picked.
+    Select,
+    /// Something was switched on or off.
+    Toggle,
+}
+
+/// Xorshift generator, cheap enough to run for every sample on the audio thread.
+#[derive(Clone)]
+struct Noise(u32);
+
+impl Noise {
+    /// Returns a random value from -1 to 1.
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / std::u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+/// Low passed noise which slowly swells and fades, played forever.
+#[cfg_attr(not(feature = "audio"), allow(dead_code))]
+struct Wind {
+    noise: Noise,
+    filtered: f32,
+    gust_phase: f32,
+}
+
+#[cfg_attr(not(feature = "audio"), allow(dead_code))]
+impl Wind {
+    fn new() -> Wind {
+        Wind {
+            noise: Noise(0x1234_5678),
+            filtered: 0.0,
+            gust_phase: 0.0,
+        }
+    }
+}
+
+impl Iterator for Wind {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        // One gust every ten seconds.
+        self.gust_phase += std::f32::consts::PI * 2.0 * 0.1 / SAMPLE_RATE as f32;
+        self.gust_phase %= std::f32::consts::PI * 2.0;
+        let gust = 0.6 + 0.4 * self.gust_phase.sin();
+        self.filtered += (self.noise.next() - self.filtered) * 0.02;
+        Some(self.filtered * gust * 4.0)
+    }
+}
+
+/// How a footstep on a particular material sounds.
+#[derive(Clone, Debug, PartialEq)]
+struct Footstep {
+    /// How long the sound lasts, in seconds.
+    duration: f32,
+    /// From 0 to 1, how much of the high frequencies are kept.
+    brightness: f32,
+}
+
+/// Snow makes long crunchy footsteps, soil makes short dull thuds. Returns None for sounds which
+/// should not play anything.
+fn footstep_for(sound: FootstepSound) -> Option<Footstep> {
+    let (duration, brightness) = match sound {
+        FootstepSound::None => return None,
+        FootstepSound::Grass => (0.12, 0.15),
+        FootstepSound::Soil => (0.09, 0.08),
+        FootstepSound::Stone => (0.07, 0.5),
+        FootstepSound::Snow => (0.18, 0.45),
+        FootstepSound::Glass => (0.05, 0.9),
+        FootstepSound::Splash => (0.25, 0.3),
+    };
+    Some(Footstep {
+        duration,
+        brightness,
+    })
+}
+
+fn render_footstep(footstep: &Footstep, noise: &mut Noise) -> Vec<f32> {
+    let length = (footstep.duration * SAMPLE_RATE as f32) as usize;
+    let mut filtered = 0.0;
+    (0..length)
+        .map(|index| {
+            filtered += (noise.next() - filtered) * footstep.brightness;
+            let envelope = (-5.0 * index as f32 / length as f32).exp();
+            filtered * envelope
+        })
+        .collect()
+}
+
+/// A sine wave that fades out over its duration.
+fn render_blip(frequency: f32, duration: f32) -> impl Iterator<Item = f32> {
+    let length = (duration * SAMPLE_RATE as f32) as usize;
+    (0..length).map(move |index| {
+        let time = index as f32 / SAMPLE_RATE as f32;
+        let envelope = 1.0 - index as f32 / length as f32;
+        (time * frequency * std::f32::consts::PI * 2.0).sin() * envelope * 0.3
+    })
+}
+
+fn render_ui_sound(sound: UiSound) -> Vec<f32> {
+    match sound {
+        UiSound::Select => render_blip(880.0, 0.05).collect(),
+        UiSound::Toggle => render_blip(660.0, 0.04)
+            .chain(render_blip(990.0, 0.04))
+            .collect(),
+    }
+}
+
+#[cfg(feature = "audio")]
+mod output {
+    use super::{Wind, SAMPLE_RATE};
+    use rodio::buffer::SamplesBuffer;
+    use rodio::{Device, Sink, Source};
+    use std::time::Duration;
+
+    impl Source for Wind {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            SAMPLE_RATE
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    pub struct Output {
+        device: Device,
+        wind: Sink,
+    }
+
+    impl Output {
+        pub fn open() -> Option<Output> {
+            let device = rodio::default_output_device()?;
+            let wind = Sink::new(&device);
+            wind.set_volume(0.0);
+            wind.append(Wind::new());
+            Some(Output { device, wind })
+        }
+
+        pub fn set_wind_volume(&self, volume: f32) {
+            self.wind.set_volume(volume);
+        }
+
+        pub fn play(&self, samples: Vec<f32>) {
+            rodio::play_raw(&self.device, SamplesBuffer::new(1, SAMPLE_RATE, samples));
+        }
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+mod output {
+    /// Can never be created, so every sound is dropped.
+    pub enum Output {}
+
+    impl Output {
+        pub fn open() -> Option<Output> {
+            None
+        }
+
+        pub fn set_wind_volume(&self, _volume: f32) {
+            match *self {}
+        }
+
+        pub fn play(&self, _samples: Vec<f32>) {
+            match *self {}
+        }
+    }
+}
+
+use output::Output;
+
+pub struct Audio {
+    settings: AudioSettings,
+    // None if there is no output device or the crate was built without the audio feature.
+    output: Option<Output>,
+    noise: Noise,
+}
+
+impl Audio {
+    /// Creates an instance which never plays anything.
+    pub fn silent() -> Audio {
+        Audio {
+            settings: AudioSettings::default(),
+            output: None,
+            noise: Noise(0x9E37_79B9),
+        }
+    }
+
+    /// Starts playing ambient sounds on the default output device.
+    pub fn new(settings: AudioSettings) -> Audio {
+        let output = Output::open();
+        if output.is_none() && cfg!(feature = "audio") {
+            println!("WARNING: No audio output device found, sound will be disabled.");
+        }
+        Audio {
+            settings,
+            output,
+            ..Self::silent()
+        }
+    }
+
+    /// Changes the volumes, the wind adjusts the next time its strength is set.
+    pub fn set_settings(&mut self, settings: AudioSettings) {
+        self.settings = settings;
+    }
+
+    /// From 0 to 1, how loud the wind should be.
+    pub fn set_wind_strength(&mut self, strength: f32) {
+        if let Some(output) = &self.output {
+            let settings = &self.settings;
+            output.set_wind_volume(strength * settings.ambient_volume * settings.master_volume);
+        }
+    }
+
+    /// Plays the footstep sound set for the material in materials.csv.
+    pub fn play_footstep(&mut self, material: &Material) {
+        let footstep = footstep_for(MaterialInfo::of(material).footstep);
+        if let (Some(output), Some(footstep)) = (&self.output, footstep) {
+            let volume = self.settings.effects_volume * self.settings.master_volume;
+            let samples = render_footstep(&footstep, &mut self.noise);
+            output.play(samples.into_iter().map(|sample| sample * volume).collect());
+        }
+    }
+
+    pub fn play_ui_sound(&mut self, sound: UiSound) {
+        if let Some(output) = &self.output {
+            let volume = self.settings.effects_volume * self.settings.master_volume;
+            let samples = render_ui_sound(sound);
+            output.play(samples.into_iter().map(|sample| sample * volume).collect());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::MATERIALS;
+
+    #[test]
+    fn volumes_are_clamped() {
+        let config = ConfigFile::parse("master_volume = 3.0\nambient_volume = -1\n");
+        let settings = AudioSettings::from_config(&config);
+        assert_eq!(settings.master_volume, 1.0);
+        assert_eq!(settings.ambient_volume, 0.0);
+        assert_eq!(
+            settings.effects_volume,
+            AudioSettings::default().effects_volume
+        );
+    }
+
+    #[test]
+    fn footsteps_depend_on_material() {
+        // Snow should sound brighter than soil.
+        let footstep_on = |index| footstep_for(MaterialInfo::of(&MATERIALS[index]).footstep);
+        let soil = footstep_on(5).unwrap();
+        let snow = footstep_on(6).unwrap();
+        assert!(footstep_on(0).is_none());
+        assert!(snow.brightness > soil.brightness);
+        assert!(snow.duration > soil.duration);
+        let samples = render_footstep(&snow, &mut Noise(1));
+        assert!(samples.iter().all(|sample| sample.abs() <= 1.0));
+    }
+}
@@ -0,0 +1,89 @@
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// A line typed into the console, split on whitespace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+impl Command {
+    /// Returns None if the line is blank.
+    pub fn parse(line: &str) -> Option<Command> {
+        let mut words = line.split_whitespace().map(|word| word.to_owned());
+        let name = words.next()?;
+        Some(Command {
+            name,
+            args: words.collect(),
+        })
+    }
+
+    /// Parses the argument at the given index, or returns the default if it was not given. None
+    /// is returned if the argument could not be parsed.
+    pub fn get_arg<T: std::str::FromStr>(&self, index: usize, default: T) -> Option<T> {
+        match self.args.get(index) {
+            Some(arg) => arg.parse().ok(),
+            None => Some(default),
+        }
+    }
+}
+
+/// Reads commands from standard input on a background thread, so that the game never stalls
+/// waiting for the user to type something.
+pub struct Console {
+    lines: Receiver<String>,
+}
+
+impl Console {
+    pub fn new() -> Console {
+        let (sender, lines) = mpsc::channel();
+        thread::Builder::new()
+            .name("console".to_owned())
+            .spawn(move || {
+                let stdin = io::stdin();
+                for line in stdin.lock().lines() {
+                    match line {
+                        Ok(line) => {
+                            if sender.send(line).is_err() {
+                                // The console was dropped.
+                                return;
+                            }
+                        }
+                        Err(err) => {
+                            println!("WARNING: Failed to read from the console.");
+                            println!("Caused by: {}", err);
+                            return;
+                        }
+                    }
+                }
+            })
+            .expect("Failed to spawn console thread.");
+        Console { lines }
+    }
+
+    /// Returns every command entered since the last call.
+    pub fn poll_commands(&mut self) -> Vec<Command> {
+        self.lines
+            .try_iter()
+            .filter_map(|line| Command::parse(&line))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command() {
+        let command = Command::parse("  export_map  map.png 512 ").unwrap();
+        assert_eq!(command.name, "export_map");
+        assert_eq!(command.args, vec!["map.png", "512"]);
+        assert_eq!(command.get_arg(1, 0usize), Some(512));
+        assert_eq!(command.get_arg(2, 4usize), Some(4));
+        assert_eq!(command.get_arg::<usize>(0, 0), None);
+        assert_eq!(Command::parse("   "), None);
+    }
+}
@@ -1,15 +1,123 @@
 use std::collections::HashMap;
-use winit::event::VirtualKeyCode;
+use std::str::FromStr;
+use winit::event::{ModifiersState, MouseButton, VirtualKeyCode};
+
+/// Something the user can press to activate a control.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Binding {
+    Key(VirtualKeyCode),
+    /// Only activates while exactly the given modifiers are held. Takes priority over a plain
+    /// Key binding for the same key.
+    Chord(ModifiersState, VirtualKeyCode),
+    Mouse(MouseButton),
+    /// Each notch of the scroll wheel presses the control for a single tick.
+    ScrollUp,
+    ScrollDown,
+}
+
+impl From<VirtualKeyCode> for Binding {
+    fn from(code: VirtualKeyCode) -> Binding {
+        Binding::Key(code)
+    }
+}
+
+impl From<MouseButton> for Binding {
+    fn from(button: MouseButton) -> Binding {
+        Binding::Mouse(button)
+    }
+}
+
+/// Looks up a key by the name of its VirtualKeyCode variant, ignoring case.
+fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+    use VirtualKeyCode::*;
+    #[rustfmt::skip]
+    const KEYS: &[VirtualKeyCode] = &[
+        Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0,
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+        Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+        Insert, Home, Delete, End, PageDown, PageUp, Left, Up, Right, Down,
+        Back, Return, Space, Tab, Grave, Minus, Equals, LBracket, RBracket, Backslash,
+        Semicolon, Apostrophe, Comma, Period, Slash,
+        LShift, RShift, LControl, RControl, LAlt, RAlt,
+        Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    ];
+    KEYS.iter()
+        .find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+        .cloned()
+}
+
+/// Parses bindings written like `W`, `Ctrl+S`, `MouseRight` or `ScrollUp`, which is how they are
+/// written in the settings file.
+impl FromStr for Binding {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Binding, String> {
+        let mut parts: Vec<&str> = text.split('+').map(|part| part.trim()).collect();
+        let last = parts.pop().unwrap();
+        if parts.len() > 0 {
+            let mut modifiers = ModifiersState::empty();
+            for part in parts {
+                modifiers |= match part.to_ascii_lowercase().as_str() {
+                    "shift" => ModifiersState::SHIFT,
+                    "ctrl" => ModifiersState::CTRL,
+                    "alt" => ModifiersState::ALT,
+                    "logo" => ModifiersState::LOGO,
+                    _ => return Err(format!("'{}' is not a modifier key.", part)),
+                };
+            }
+            return match key_from_name(last) {
+                Some(key) => Ok(Binding::Chord(modifiers, key)),
+                None => Err(format!("'{}' is not a key.", last)),
+            };
+        }
+        match last.to_ascii_lowercase().as_str() {
+            "mouseleft" => return Ok(Binding::Mouse(MouseButton::Left)),
+            "mouseright" => return Ok(Binding::Mouse(MouseButton::Right)),
+            "mousemiddle" => return Ok(Binding::Mouse(MouseButton::Middle)),
+            "scrollup" => return Ok(Binding::ScrollUp),
+            "scrolldown" => return Ok(Binding::ScrollDown),
+            _ => (),
+        }
+        if let Some(key) = key_from_name(last) {
+            Ok(Binding::Key(key))
+        } else if last.len() > 5 && last[..5].eq_ignore_ascii_case("mouse") {
+            match last[5..].parse() {
+                Ok(button) => Ok(Binding::Mouse(MouseButton::Other(button))),
+                Err(_) => Err(format!("'{}' is not a mouse button.", last)),
+            }
+        } else {
+            Err(format!("'{}' is not a key or mouse button.", last))
+        }
+    }
+}
+
+/// Sent whenever a control changes state, once events have been enabled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlEvent {
+    Pressed(String),
+    Released(String),
+}
 
 struct Control {
+    name: String,
     last_state: bool,
     this_state: bool,
+    // Set for controls pressed by the scroll wheel, which get released on the next tick.
+    pulsed: bool,
 }
 
 pub struct ControlSet {
     controls: Vec<Control>,
     by_name: HashMap<String, usize>,
-    by_code: HashMap<VirtualKeyCode, usize>,
+    by_binding: HashMap<Binding, usize>,
+    modifiers: ModifiersState,
+    // Which control each held key activated, so that releasing the key releases the same control
+    // even if the modifiers changed in the meantime.
+    held_keys: HashMap<VirtualKeyCode, usize>,
+    // Scrolling that has not added up to a whole notch yet.
+    scroll_remainder: f32,
+    // None unless enable_events has been called.
+    events: Option<Vec<ControlEvent>>,
 }
 
 impl ControlSet {
@@ -17,36 +125,140 @@ impl ControlSet {
         ControlSet {
             controls: Vec::new(),
             by_name: HashMap::new(),
-            by_code: HashMap::new(),
+            by_binding: HashMap::new(),
+            modifiers: ModifiersState::empty(),
+            held_keys: HashMap::new(),
+            scroll_remainder: 0.0,
+            events: None,
         }
     }
 
-    pub fn add_control(&mut self, name: &str, binding: VirtualKeyCode) {
-        let index = self.controls.len();
-        self.controls.push(Control {
-            last_state: false,
-            this_state: false,
+    /// Binds an input to a named control. Adding the same name again gives the control an
+    /// additional binding.
+    pub fn add_control(&mut self, name: &str, binding: impl Into<Binding>) {
+        let controls = &mut self.controls;
+        let index = *self.by_name.entry(name.to_owned()).or_insert_with(|| {
+            controls.push(Control {
+                name: name.to_owned(),
+                last_state: false,
+                this_state: false,
+                pulsed: false,
+            });
+            controls.len() - 1
         });
-        self.by_name.insert(name.to_owned(), index);
-        self.by_code.insert(binding, index);
+        self.by_binding.insert(binding.into(), index);
+    }
+
+    pub fn has_control(&self, name: &str) -> bool {
+        self.by_name.contains_key(name)
+    }
+
+    /// Removes every binding of a control so that it can be given new ones with add_control.
+    pub fn clear_bindings(&mut self, name: &str) {
+        if let Some(&index) = self.by_name.get(name) {
+            self.by_binding.retain(|_, control| *control != index);
+        }
+    }
+
+    /// Starts recording a ControlEvent for every press and release. Unlike just_pressed and
+    /// just_released, events are not lost if a control is pressed and released between ticks.
+    pub fn enable_events(&mut self) {
+        if self.events.is_none() {
+            self.events = Some(Vec::new());
+        }
+    }
+
+    /// Returns every event recorded since the last call, oldest first.
+    pub fn drain_events(&mut self) -> Vec<ControlEvent> {
+        match &mut self.events {
+            Some(events) => events.drain(..).collect(),
+            None => Vec::new(),
+        }
     }
 
     // Call this before passing in any key events.
     pub fn tick(&mut self) {
-        for control in &mut self.controls {
-            control.last_state = control.this_state;
+        for index in 0..self.controls.len() {
+            self.controls[index].last_state = self.controls[index].this_state;
+            if self.controls[index].pulsed {
+                self.controls[index].pulsed = false;
+                self.set_index_state(index, false);
+            }
+        }
+    }
+
+    fn set_index_state(&mut self, index: usize, state: bool) {
+        let control = &mut self.controls[index];
+        if control.this_state == state {
+            return;
         }
+        control.this_state = state;
+        if let Some(events) = &mut self.events {
+            let name = control.name.clone();
+            events.push(if state {
+                ControlEvent::Pressed(name)
+            } else {
+                ControlEvent::Released(name)
+            });
+        }
+    }
+
+    fn set_state(&mut self, binding: Binding, state: bool) -> Option<usize> {
+        let index = *self.by_binding.get(&binding)?;
+        self.set_index_state(index, state);
+        Some(index)
+    }
+
+    pub fn on_modifiers_changed(&mut self, modifiers: ModifiersState) {
+        self.modifiers = modifiers;
     }
 
     pub fn on_pressed(&mut self, code: VirtualKeyCode) {
-        if let Some(index) = self.by_code.get(&code) {
-            self.controls[*index].this_state = true;
+        if self.held_keys.contains_key(&code) {
+            // Key repeat.
+            return;
+        }
+        let mut index = None;
+        if !self.modifiers.is_empty() {
+            index = self.set_state(Binding::Chord(self.modifiers, code), true);
+        }
+        if index.is_none() {
+            index = self.set_state(Binding::Key(code), true);
+        }
+        if let Some(index) = index {
+            self.held_keys.insert(code, index);
         }
     }
 
     pub fn on_released(&mut self, code: VirtualKeyCode) {
-        if let Some(index) = self.by_code.get(&code) {
-            self.controls[*index].this_state = false;
+        if let Some(index) = self.held_keys.remove(&code) {
+            self.set_index_state(index, false);
+        }
+    }
+
+    pub fn on_mouse_pressed(&mut self, button: MouseButton) {
+        self.set_state(Binding::Mouse(button), true);
+    }
+
+    pub fn on_mouse_released(&mut self, button: MouseButton) {
+        self.set_state(Binding::Mouse(button), false);
+    }
+
+    /// Positive values scroll up, one unit per notch.
+    pub fn on_scroll(&mut self, notches: f32) {
+        self.scroll_remainder += notches;
+        let whole_notches = self.scroll_remainder.trunc();
+        self.scroll_remainder -= whole_notches;
+        if whole_notches == 0.0 {
+            return;
+        }
+        let binding = if whole_notches > 0.0 {
+            Binding::ScrollUp
+        } else {
+            Binding::ScrollDown
+        };
+        if let Some(index) = self.set_state(binding, true) {
+            self.controls[index].pulsed = true;
         }
     }
 
@@ -59,8 +271,8 @@ impl ControlSet {
         }
     }
 
-    // True if the control was just pressed this frame.
-    pub fn is_pressed(&self, name: &str) -> bool {
+    // True if the control was pressed since the last tick.
+    pub fn just_pressed(&self, name: &str) -> bool {
         if let Some(index) = self.by_name.get(name) {
             let control = &self.controls[*index];
             control.this_state && !control.last_state
@@ -69,8 +281,8 @@ impl ControlSet {
         }
     }
 
-    // True if the control was just released this frame.
-    pub fn is_released(&self, name: &str) -> bool {
+    // True if the control was released since the last tick.
+    pub fn just_released(&self, name: &str) -> bool {
         if let Some(index) = self.by_name.get(name) {
             let control = &self.controls[*index];
             !control.this_state && control.last_state
@@ -79,3 +291,113 @@ impl ControlSet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chords_take_priority_over_keys() {
+        let mut set = ControlSet::new();
+        set.add_control(
+            "save",
+            Binding::Chord(ModifiersState::CTRL, VirtualKeyCode::S),
+        );
+        set.add_control("backward", VirtualKeyCode::S);
+
+        set.on_pressed(VirtualKeyCode::S);
+        assert!(set.is_held("backward"));
+        set.on_released(VirtualKeyCode::S);
+
+        set.on_modifiers_changed(ModifiersState::CTRL);
+        set.on_pressed(VirtualKeyCode::S);
+        assert!(set.is_held("save"));
+        assert!(!set.is_held("backward"));
+        // Letting go of Ctrl first still releases the chord.
+        set.on_modifiers_changed(ModifiersState::empty());
+        set.on_released(VirtualKeyCode::S);
+        assert!(!set.is_held("save"));
+    }
+
+    #[test]
+    fn mouse_and_multiple_bindings() {
+        let mut set = ControlSet::new();
+        set.add_control("place", MouseButton::Right);
+        set.add_control("place", VirtualKeyCode::P);
+        set.on_mouse_pressed(MouseButton::Right);
+        assert!(set.just_pressed("place"));
+        set.on_mouse_released(MouseButton::Right);
+        set.on_pressed(VirtualKeyCode::P);
+        assert!(set.is_held("place"));
+    }
+
+    #[test]
+    fn scroll_notches_pulse() {
+        let mut set = ControlSet::new();
+        set.add_control("next", Binding::ScrollDown);
+        set.on_scroll(-0.5);
+        assert!(!set.is_held("next"));
+        set.on_scroll(-0.5);
+        assert!(set.just_pressed("next"));
+        set.tick();
+        assert!(set.just_released("next"));
+        set.tick();
+        assert!(!set.is_held("next") && !set.just_released("next"));
+    }
+
+    #[test]
+    fn parse_bindings() {
+        assert_eq!("w".parse(), Ok(Binding::Key(VirtualKeyCode::W)));
+        assert_eq!("Key1".parse(), Ok(Binding::Key(VirtualKeyCode::Key1)));
+        assert_eq!(
+            "Ctrl + Shift + S".parse(),
+            Ok(Binding::Chord(
+                ModifiersState::CTRL | ModifiersState::SHIFT,
+                VirtualKeyCode::S
+            ))
+        );
+        assert_eq!("MouseRight".parse(), Ok(Binding::Mouse(MouseButton::Right)));
+        assert_eq!("Mouse4".parse(), Ok(Binding::Mouse(MouseButton::Other(4))));
+        assert_eq!("ScrollDown".parse(), Ok(Binding::ScrollDown));
+        assert!("Hyper+S".parse::<Binding>().is_err());
+        assert!("Banana".parse::<Binding>().is_err());
+    }
+
+    #[test]
+    fn rebinding_replaces_old_bindings() {
+        let mut set = ControlSet::new();
+        set.add_control("jump", VirtualKeyCode::Space);
+        set.add_control("crouch", VirtualKeyCode::C);
+        set.clear_bindings("jump");
+        set.add_control("jump", VirtualKeyCode::J);
+        set.on_pressed(VirtualKeyCode::Space);
+        assert!(!set.is_held("jump"));
+        set.on_pressed(VirtualKeyCode::J);
+        set.on_pressed(VirtualKeyCode::C);
+        assert!(set.is_held("jump") && set.is_held("crouch"));
+    }
+
+    #[test]
+    fn events_are_not_lost_between_ticks() {
+        let mut set = ControlSet::new();
+        set.add_control("jump", VirtualKeyCode::Space);
+        set.on_pressed(VirtualKeyCode::Space);
+        set.on_released(VirtualKeyCode::Space);
+        // Events are off by default.
+        assert_eq!(set.drain_events(), vec![]);
+
+        set.enable_events();
+        set.on_pressed(VirtualKeyCode::Space);
+        set.on_released(VirtualKeyCode::Space);
+        // The press happened entirely between ticks, so the edge queries miss it.
+        assert!(!set.just_pressed("jump"));
+        assert_eq!(
+            set.drain_events(),
+            vec![
+                ControlEvent::Pressed("jump".to_owned()),
+                ControlEvent::Released("jump".to_owned())
+            ]
+        );
+        assert_eq!(set.drain_events(), vec![]);
+    }
+}
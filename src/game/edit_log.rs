@@ -0,0 +1,178 @@
+use crate::render::Camera;
+use crate::util::prelude::*;
+use cgmath::{Rad, Vector3};
+use std::path::Path;
+
+/// One block being changed, along with where the camera was when it happened.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorldEdit {
+    /// Seconds of game time since the game started.
+    pub time: f32,
+    pub block: SignedCoord3D,
+    /// Packed materials, see Material::pack.
+    pub before: u32,
+    pub after: u32,
+    pub camera: Camera,
+}
+
+impl WorldEdit {
+    fn parse(line: &str) -> Option<WorldEdit> {
+        let values: Vec<&str> = line.split_whitespace().collect();
+        if values.len() != 11 {
+            return None;
+        }
+        Some(WorldEdit {
+            time: values[0].parse().ok()?,
+            block: (
+                values[1].parse().ok()?,
+                values[2].parse().ok()?,
+                values[3].parse().ok()?,
+            ),
+            before: values[4].parse().ok()?,
+            after: values[5].parse().ok()?,
+            camera: Camera {
+                origin: Vector3::new(
+                    values[6].parse().ok()?,
+                    values[7].parse().ok()?,
+                    values[8].parse().ok()?,
+                ),
+                heading: Rad(values[9].parse().ok()?),
+                pitch: Rad(values[10].parse().ok()?),
+            },
+        })
+    }
+
+    fn format(&self) -> String {
+        let camera = &self.camera;
+        format!(
+            "{} {} {} {} {} {} {} {} {} {} {}",
+            self.time,
+            self.block.0,
+            self.block.1,
+            self.block.2,
+            self.before,
+            self.after,
+            camera.origin.x,
+            camera.origin.y,
+            camera.origin.z,
+            camera.heading.0,
+            camera.pitch.0
+        )
+    }
+}
+
+/// Every block edited since the log was last cleared, in the order the edits happened. Files have
+/// one edit per line, written as `time x y z before after camera_x camera_y camera_z heading
+/// pitch`. Blank lines and lines starting with # are ignored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EditLog {
+    edits: Vec<WorldEdit>,
+}
+
+impl EditLog {
+    pub fn new() -> EditLog {
+        EditLog { edits: Vec::new() }
+    }
+
+    pub fn parse(text: &str) -> Result<EditLog, String> {
+        let mut edits = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match WorldEdit::parse(line) {
+                Some(edit) => edits.push(edit),
+                None => {
+                    return Err(format!(
+                        "Line {} should be 'time x y z before after camera_x camera_y camera_z \
+                        heading pitch'.",
+                        index + 1
+                    ))
+                }
+            }
+        }
+        // Edits are replayed in order, so a log which goes back in time can't be replayed.
+        if edits.windows(2).any(|pair| pair[1].time < pair[0].time) {
+            return Err("The edits are not in the order they happened.".to_owned());
+        }
+        Ok(EditLog { edits })
+    }
+
+    pub fn load(path: &Path) -> Result<EditLog, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Self::parse(&text)
+    }
+
+    pub fn format(&self) -> String {
+        let mut text = String::new();
+        for edit in &self.edits {
+            text.push_str(&edit.format());
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.format())
+    }
+
+    pub fn record(&mut self, edit: WorldEdit) {
+        self.edits.push(edit);
+    }
+
+    pub fn clear(&mut self) {
+        self.edits.clear();
+    }
+
+    pub fn borrow_edits(&self) -> &[WorldEdit] {
+        &self.edits
+    }
+
+    /// How many seconds passed between the first and last edit.
+    pub fn get_duration(&self) -> f32 {
+        match (self.edits.first(), self.edits.last()) {
+            (Some(first), Some(last)) => last.time - first.time,
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit_at(time: f32, x: isize) -> WorldEdit {
+        WorldEdit {
+            time,
+            block: (x, -2, 30),
+            before: 0,
+            after: 12345,
+            camera: Camera {
+                origin: Vector3::new(1.5, -2.25, 40.0),
+                heading: Rad(0.5),
+                pitch: Rad(-0.25),
+            },
+        }
+    }
+
+    #[test]
+    fn log_round_trip() {
+        let mut log = EditLog::new();
+        log.record(edit_at(1.0, 4));
+        log.record(edit_at(3.5, -7));
+        let parsed = EditLog::parse(&format!("# Tower\n\n{}", log.format())).unwrap();
+        assert_eq!(parsed, log);
+        assert_eq!(parsed.get_duration(), 2.5);
+    }
+
+    #[test]
+    fn rejects_bad_logs() {
+        assert!(EditLog::parse("1 2 3").is_err());
+        assert!(EditLog::parse("1 2 3 4 5 6 7 8 9 10 pitch").is_err());
+        let mut log = EditLog::new();
+        log.record(edit_at(3.0, 0));
+        log.record(edit_at(1.0, 0));
+        assert!(EditLog::parse(&log.format()).is_err());
+    }
+}
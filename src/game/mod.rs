@@ -1,22 +1,149 @@
-use cgmath::InnerSpace;
-use winit::event::VirtualKeyCode;
+use cgmath::{InnerSpace, Vector2, Vector3};
+use winit::event::{MouseButton, VirtualKeyCode};
 
-use crate::render::Camera;
-use crate::util;
-use crate::world::{self, ChunkStorage};
+use crate::config::ConfigFile;
+use crate::render::constants::*;
+use crate::render::material_info::MaterialInfo;
+use crate::render::{
+    tonemap, BeautyShotRequest, Camera, DebugView, DenoiseSchedule, Material, Palette,
+    PanoramaLayout, PanoramaRequest, PipCamera, StageToggles, Tonemapper,
+    DEFAULT_BEAUTY_SHOT_FRAMES, MATERIALS,
+};
+use crate::util::{self, prelude::*, FixedTimestep};
+use crate::world::{
+    self, ChunkStorage, DebrisSim, LiquidSim, RaycastHit, DEBRIS_TICK_RATE, MAX_WATER_LEVEL,
+    WATER_MATERIAL,
+};
 
-use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
 
+pub mod audio;
+pub mod console;
 pub mod control;
+pub mod edit_log;
+pub mod state;
+pub mod time_of_day;
+pub mod timelapse;
 
-use control::ControlSet;
+use audio::{Audio, AudioSettings, UiSound};
+use console::{Command, Console};
+use control::{Binding, ControlEvent, ControlSet};
+use edit_log::{EditLog, WorldEdit};
+pub use state::GameState;
+use time_of_day::{Lighting, TimeOfDayTrack};
+use timelapse::Timelapse;
+
+/// How many times per second the game is simulated, independently of the framerate.
+const TICK_RATE: f32 = 120.0;
+/// Frames that take longer than this many ticks are slowed down instead of simulating more ticks.
+const MAX_TICKS_PER_FRAME: usize = 12;
+/// How many times per second water moves. Much slower than TICK_RATE since every step stores and
+/// uploads the chunks the water moved in.
+const LIQUID_TICK_RATE: f32 = 8.0;
+/// How many seconds apart the world is saved, see ChunkStorage::autosave.
+const AUTOSAVE_INTERVAL: f32 = 30.0;
+/// How far away blocks can be selected from.
+const SELECTION_DISTANCE: f64 = 64.0;
+/// Footsteps are only played when there is ground at most this far below the camera.
+const FOOTSTEP_HEIGHT: f64 = 4.0;
+/// How far the camera moves horizontally between footsteps.
+const STEP_LENGTH: f32 = 1.5;
+/// How many frames are blended together for each frame of a timelapse if the command does not
+/// say.
+const DEFAULT_TIMELAPSE_SAMPLES: u32 = 16;
+
+/// Writes where the camera is and the time of day in the same format as the settings file.
+fn format_session(camera: &Camera, lighting: &Lighting) -> String {
+    format!(
+        "x = {}\ny = {}\nz = {}\nheading = {}\npitch = {}\nsun_angle = {}\n\
+        sun_intensity = {}\nfog_density = {}\nsky = {}\n",
+        camera.origin.x,
+        camera.origin.y,
+        camera.origin.z,
+        camera.heading.0,
+        camera.pitch.0,
+        lighting.sun_angle,
+        lighting.sun_intensity,
+        lighting.fog_density,
+        lighting.sky
+    )
+}
 
 pub struct Game {
+    state: GameState,
+    // What to switch to once the state is no longer Loading.
+    state_after_loading: GameState,
     camera: Camera,
+    // Where the camera was before the last tick.
+    previous_camera: Camera,
+    // Blended between previous_camera and camera according to how far it is to the next tick.
+    render_camera: Camera,
+    timestep: FixedTimestep,
     world: ChunkStorage,
+    liquid: LiquidSim,
+    liquid_timestep: FixedTimestep,
+    debris: DebrisSim,
+    debris_timestep: FixedTimestep,
+    // Runs on real time instead of ticks, so that edits are saved even while paused.
+    autosave_timestep: FixedTimestep,
     controls: ControlSet,
+    console: Console,
+    // The block under the crosshair.
+    selection: Option<RaycastHit>,
+    // The block being broken and for how many seconds the break control has been held on it, see
+    // MaterialInfo::hardness.
+    breaking: Option<(SignedCoord3D, f32)>,
+    // Indexes into MATERIALS which can be picked from the hotbar.
+    hotbar: Vec<usize>,
+    selected_slot: usize,
+    hud_visible: bool,
+    // Whether the HUD shows how many rays the last frame cast, see RayStatistics.
+    ray_statistics_visible: bool,
+    // Whether the HUD shows how old the input of each frame is, see LatencyMarkers.
+    latency_markers_visible: bool,
+    // Zero if the framerate is not limited.
+    max_fps: u32,
+    denoise_schedule: DenoiseSchedule,
+    // Used right of the divider instead of denoise_schedule, None when not comparing.
+    comparison_schedule: Option<DenoiseSchedule>,
+    comparison_divider: f32,
+    debug_view: DebugView,
+    tonemapper: Tonemapper,
+    // In stops, zero leaves the light the way the shade stage found it.
+    exposure: f32,
+    palette: Palette,
+    // Where the picture-in-picture view is rendered from, None when it is hidden.
+    pip_camera: Option<PipCamera>,
+    // Where reflection probes have been placed, in the order they were placed. See the probe
+    // command.
+    probes: Vec<Vector3<f64>>,
+    // Set by the probe command to have the renderer capture every probe again.
+    probe_recapture: bool,
+    // The fraction of glossy reflections which trace rays when a probe is in range, the rest
+    // sample the probe.
+    reflection_ray_budget: f32,
+    stage_toggles: StageToggles,
+    // Taken by the renderer at the start of the next frame.
+    beauty_shot_request: Option<BeautyShotRequest>,
+    panorama_request: Option<PanoramaRequest>,
+    // Set by the screenshot control, taken by the renderer once the next frame is rendered.
+    screenshot_requested: bool,
+    audio: Audio,
+    // How far the camera has moved since the last footstep.
+    step_distance: f32,
+    // Keyframes which the lighting follows while it is playing, see the time_of_day command.
+    time_of_day: Option<TimeOfDayTrack>,
+    time_of_day_time: f32,
+    time_of_day_playing: bool,
+    // Every block edited this session, or loaded with the edits command.
+    edit_log: EditLog,
+    // While this is Some, the camera follows it and blocks can't be edited.
+    timelapse: Option<Timelapse>,
 
-    sun_angle: f32,
+    lighting: Lighting,
+    // Seconds of ticks since the game started, which animated materials follow.
+    game_time: f32,
 }
 
 impl Game {
@@ -28,41 +155,812 @@ impl Game {
         set.add_control("right", VirtualKeyCode::D);
         set.add_control("forward", VirtualKeyCode::W);
         set.add_control("backward", VirtualKeyCode::S);
+        set.add_control("fast", VirtualKeyCode::LShift);
 
         set.add_control("sunup", VirtualKeyCode::R);
         set.add_control("sundown", VirtualKeyCode::F);
+        set.add_control("exposure_up", VirtualKeyCode::RBracket);
+        set.add_control("exposure_down", VirtualKeyCode::LBracket);
+
+        set.add_control("back", VirtualKeyCode::Escape);
+        set.add_control("confirm", VirtualKeyCode::Return);
+        set.add_control("menu", VirtualKeyCode::M);
+
+        set.add_control("toggle_hud", VirtualKeyCode::F1);
+        set.add_control("screenshot", VirtualKeyCode::F2);
+        set.add_control("break", MouseButton::Left);
+        set.add_control("place", MouseButton::Right);
+        let slot_keys = [
+            VirtualKeyCode::Key1,
+            VirtualKeyCode::Key2,
+            VirtualKeyCode::Key3,
+            VirtualKeyCode::Key4,
+            VirtualKeyCode::Key5,
+            VirtualKeyCode::Key6,
+            VirtualKeyCode::Key7,
+            VirtualKeyCode::Key8,
+            VirtualKeyCode::Key9,
+        ];
+        for (index, key) in slot_keys.iter().enumerate().take(MAX_HOTBAR_SLOTS) {
+            set.add_control(&format!("slot{}", index), *key);
+        }
+        set.add_control("previous_slot", Binding::ScrollUp);
+        set.add_control("next_slot", Binding::ScrollDown);
+        // Menus run once per frame instead of once per tick, so they use events instead.
+        set.enable_events();
         set
     }
 
-    pub fn new() -> Game {
-        let args: Vec<_> = env::args().collect();
+    /// The camera starts where the arguments say if they are given, in the order x y z heading
+    /// pitch sun_angle.
+    pub fn new(args: &[String]) -> Game {
         let mut result = Game {
+            state: GameState::Loading,
+            state_after_loading: GameState::MainMenu,
             camera: Camera::new(),
+            previous_camera: Camera::new(),
+            render_camera: Camera::new(),
+            timestep: FixedTimestep::new(1.0 / TICK_RATE, MAX_TICKS_PER_FRAME),
             world: ChunkStorage::new(),
+            liquid: LiquidSim::new(),
+            liquid_timestep: FixedTimestep::new(1.0 / LIQUID_TICK_RATE, 1),
+            debris: DebrisSim::new(),
+            debris_timestep: FixedTimestep::new(1.0 / DEBRIS_TICK_RATE, 1),
+            autosave_timestep: FixedTimestep::new(AUTOSAVE_INTERVAL, 1),
             controls: Self::make_controls(),
-            sun_angle: 0.0,
+            console: Console::new(),
+            selection: None,
+            breaking: None,
+            // Material 0 is air, so leave it out.
+            hotbar: (1..MATERIALS.len()).take(MAX_HOTBAR_SLOTS).collect(),
+            selected_slot: 0,
+            hud_visible: true,
+            ray_statistics_visible: false,
+            latency_markers_visible: false,
+            max_fps: 0,
+            denoise_schedule: DenoiseSchedule::default(),
+            comparison_schedule: None,
+            comparison_divider: 0.5,
+            debug_view: DebugView::Off,
+            tonemapper: Tonemapper::default(),
+            exposure: 0.0,
+            palette: Palette::Default,
+            pip_camera: None,
+            probes: Vec::new(),
+            probe_recapture: false,
+            reflection_ray_budget: 0.5,
+            stage_toggles: StageToggles::default(),
+            beauty_shot_request: None,
+            panorama_request: None,
+            screenshot_requested: false,
+            audio: Audio::silent(),
+            step_distance: 0.0,
+            time_of_day: None,
+            time_of_day_time: 0.0,
+            time_of_day_playing: false,
+            edit_log: EditLog::new(),
+            timelapse: None,
+            lighting: Lighting::default(),
+            game_time: 0.0,
         };
-        if args.len() > 1 {
-            result.camera.origin.x = args[1].parse().unwrap();
-            result.camera.origin.y = args[2].parse().unwrap();
-            result.camera.origin.z = args[3].parse().unwrap();
-            result.camera.heading.0 = args[4].parse().unwrap();
-            result.camera.pitch.0 = args[5].parse().unwrap();
-            result.sun_angle = args[6].parse().unwrap();
+        if args.len() > 0 {
+            result.camera.origin.x = args[0].parse().unwrap();
+            result.camera.origin.y = args[1].parse().unwrap();
+            result.camera.origin.z = args[2].parse().unwrap();
+            result.camera.heading.0 = args[3].parse().unwrap();
+            result.camera.pitch.0 = args[4].parse().unwrap();
+            result.lighting.sun_angle = args[5].parse().unwrap();
+            // Skip the menu so that scripted captures see the world right away.
+            result.state_after_loading = GameState::Playing;
         } else {
             result.camera.origin.x = -30.0;
             result.camera.origin.y = -128.0;
             result.camera.origin.z = 100.0;
         }
+        result.previous_camera = result.camera.clone();
+        result.render_camera = result.camera.clone();
         result
     }
 
-    // Called after all controls have been updated.
-    pub fn tick(&mut self, dt: f32) {
+    /// Sounds are muted until this is called.
+    pub fn enable_audio(&mut self, settings: AudioSettings) {
+        self.audio = Audio::new(settings);
+    }
+
+    /// Applies the parts of the settings file which the game is responsible for. This can be
+    /// called again whenever the file changes. Controls can be rebound with lines like
+    /// `bind_forward = W, Up`, which replace every default binding of the control.
+    pub fn apply_config(&mut self, config: &ConfigFile) {
+        let mut controls = Self::make_controls();
+        for key in config.keys() {
+            if !key.starts_with("bind_") {
+                continue;
+            }
+            let name = &key["bind_".len()..];
+            if !controls.has_control(name) {
+                println!("WARNING: Setting '{}' refers to an unknown control.", key);
+                continue;
+            }
+            let value: String = config.get(key, String::new());
+            let bindings: Result<Vec<Binding>, String> =
+                value.split(',').map(|binding| binding.parse()).collect();
+            match bindings {
+                Ok(bindings) => {
+                    controls.clear_bindings(name);
+                    for binding in bindings {
+                        controls.add_control(name, binding);
+                    }
+                }
+                Err(err) => {
+                    println!("WARNING: Invalid value for setting '{}'.", key);
+                    println!("Caused by: {}", err);
+                }
+            }
+        }
+        self.controls = controls;
+        self.audio.set_settings(AudioSettings::from_config(config));
+    }
+
+    fn update_audio(&mut self, dt: f32, movement: Vector3<f32>) {
+        let horizontal = Vector2::new(movement.x, movement.y).magnitude();
+        // The wind picks up higher in the mountains and when moving quickly.
+        let altitude = ((self.camera.origin.z as f32 - 50.0) / 250.0)
+            .max(0.0)
+            .min(1.0);
+        let speed = if dt > 0.0 { horizontal / dt } else { 0.0 };
+        let strength = 0.2 + altitude * 0.5 + (speed / 100.0).min(0.3);
+        self.audio.set_wind_strength(strength);
+
+        if horizontal == 0.0 {
+            return;
+        }
+        self.step_distance += horizontal;
+        if self.step_distance < STEP_LENGTH {
+            return;
+        }
+        self.step_distance = 0.0;
+        let down = Vector3::new(0.0, 0.0, -1.0);
+        if let Some(hit) = self
+            .world
+            .raycast(self.camera.origin, down, FOOTSTEP_HEIGHT)
+        {
+            let material = self.world.get_block(&hit.block);
+            self.audio.play_footstep(&material);
+        }
+    }
+
+    /// Shows the lighting denoised with another schedule right of the divider, to compare it with
+    /// the current schedule on the left.
+    fn run_compare_command(&mut self, command: &Command) {
+        if command.args.len() == 0 {
+            match &self.comparison_schedule {
+                Some(schedule) => println!("Comparing against denoiser passes: {}", schedule),
+                None => println!("Not comparing."),
+            }
+            return;
+        }
+        if command.args.len() == 1 && command.args[0] == "stop" {
+            self.comparison_schedule = None;
+            return;
+        }
+        match command.args.join(" ").parse() {
+            Ok(schedule) => self.comparison_schedule = Some(schedule),
+            Err(err) => {
+                println!("Usage: compare [stop | off | step sizes in pixels...]");
+                println!("Caused by: {}", err);
+            }
+        }
+    }
+
+    fn run_probe_command(&mut self, command: &Command) {
+        match command.args.get(0).map(|arg| &arg[..]) {
+            Some("place") => {
+                // The oldest probe makes room for the new one.
+                if self.probes.len() == MAX_PROBES {
+                    self.probes.remove(0);
+                }
+                self.probes.push(self.camera.origin);
+                println!("Placed probe {} of {}.", self.probes.len(), MAX_PROBES);
+            }
+            Some("capture") => self.probe_recapture = true,
+            Some("clear") => self.probes.clear(),
+            Some("budget") => match command.get_arg(1, 0.5) {
+                Some(budget) if budget >= 0.0 && budget <= 1.0 => {
+                    self.reflection_ray_budget = budget
+                }
+                _ => println!("Usage: probe budget [fraction of reflections traced from 0 to 1]"),
+            },
+            _ => println!("Usage: probe [place | capture | clear | budget]"),
+        }
+    }
+
+    fn run_stage_command(&mut self, command: &Command) {
+        if command.args.len() == 0 {
+            println!("Stages: {}", self.stage_toggles);
+            return;
+        }
+        let enabled = match command.args.get(1).map(|arg| &arg[..]) {
+            Some("on") => Some(true),
+            Some("off") => Some(false),
+            _ => None,
+        };
+        let result = match enabled {
+            Some(enabled) => self.stage_toggles.set(&command.args[0], enabled),
+            None => Err("The second argument should be on or off.".to_owned()),
+        };
+        if let Err(err) = result {
+            let names = StageToggles::NAMES.join(" | ");
+            println!("Usage: stage [({}) (on | off)]", names);
+            println!("Caused by: {}", err);
+        }
+    }
+
+    fn run_command(&mut self, command: &Command) {
+        match &command.name[..] {
+            "export_map" => self.export_map(command),
+            "ray_stats" => match command.args.get(0).map(|arg| &arg[..]) {
+                Some("on") => self.ray_statistics_visible = true,
+                Some("off") => self.ray_statistics_visible = false,
+                _ => println!("Usage: ray_stats [on | off]"),
+            },
+            "latency" => match command.args.get(0).map(|arg| &arg[..]) {
+                Some("on") => self.latency_markers_visible = true,
+                Some("off") => self.latency_markers_visible = false,
+                _ => println!("Usage: latency [on | off]"),
+            },
+            "max_fps" => match command.get_arg(0, 0) {
+                Some(max_fps) => self.max_fps = max_fps,
+                None => println!("Usage: max_fps [frames per second, 0 for no limit]"),
+            },
+            "denoise" => {
+                if command.args.len() == 0 {
+                    println!("Denoiser passes: {}", self.denoise_schedule);
+                    return;
+                }
+                match command.args.join(" ").parse() {
+                    Ok(schedule) => self.denoise_schedule = schedule,
+                    Err(err) => {
+                        println!("Usage: denoise [off | step sizes in pixels...]");
+                        println!("Caused by: {}", err);
+                    }
+                }
+            }
+            "debug_view" => match command.get_arg(0, DebugView::Off) {
+                Some(view) => self.debug_view = view,
+                None => {
+                    let names: Vec<_> = DebugView::ALL.iter().map(|view| view.get_name()).collect();
+                    println!("Usage: debug_view [{}]", names.join(" | "));
+                }
+            },
+            "tonemap" => match command.get_arg(0, Tonemapper::default()) {
+                Some(tonemapper) => self.tonemapper = tonemapper,
+                None => {
+                    let names: Vec<_> = Tonemapper::ALL.iter().map(|t| t.get_name()).collect();
+                    println!("Usage: tonemap [{}]", names.join(" | "));
+                }
+            },
+            "exposure" => match command.get_arg(0, 0.0f32) {
+                Some(exposure) if exposure.abs() <= tonemap::MAX_EXPOSURE => {
+                    self.exposure = exposure
+                }
+                _ => println!(
+                    "Usage: exposure [stops from -{0} to {0}]",
+                    tonemap::MAX_EXPOSURE
+                ),
+            },
+            "stage" => self.run_stage_command(command),
+            "compare" => self.run_compare_command(command),
+            "probe" => self.run_probe_command(command),
+            "compare_divider" => match command.get_arg(0, 0.5) {
+                Some(divider) if divider >= 0.0 && divider <= 1.0 => {
+                    self.comparison_divider = divider
+                }
+                _ => println!("Usage: compare_divider [fraction of the screen from 0 to 1]"),
+            },
+            "palette" => match command.get_arg(0, Palette::Default) {
+                Some(palette) => self.palette = palette,
+                None => {
+                    let names: Vec<_> = Palette::ALL.iter().map(|p| p.get_name()).collect();
+                    println!("Usage: palette [{}]", names.join(" | "));
+                }
+            },
+            "pip" => match command.args.get(0).map(String::as_str) {
+                Some("off") => self.pip_camera = None,
+                _ => match command.get_arg(0, PipCamera::Overhead) {
+                    Some(camera) => self.pip_camera = Some(camera),
+                    None => {
+                        let names: Vec<_> = PipCamera::ALL.iter().map(|c| c.get_name()).collect();
+                        println!("Usage: pip [off | {}]", names.join(" | "));
+                    }
+                },
+            },
+            "beauty_shot" => match (
+                command.args.get(0),
+                command.get_arg(1, DEFAULT_BEAUTY_SHOT_FRAMES),
+            ) {
+                (Some(path), Some(frames)) if frames > 0 => {
+                    self.beauty_shot_request = Some(BeautyShotRequest {
+                        path: PathBuf::from(path),
+                        frames,
+                        downsample: 1,
+                    });
+                }
+                _ => println!("Usage: beauty_shot <path> [frames]"),
+            },
+            "panorama" => match (
+                command.args.get(0),
+                command.get_arg(1, PanoramaLayout::Equirectangular),
+                command.get_arg(2, DEFAULT_BEAUTY_SHOT_FRAMES),
+            ) {
+                (Some(path), Some(layout), Some(frames_per_face)) if frames_per_face > 0 => {
+                    self.panorama_request = Some(PanoramaRequest {
+                        path: PathBuf::from(path),
+                        layout,
+                        frames_per_face,
+                    });
+                }
+                _ => println!(
+                    "Usage: panorama <path> [cubemap | equirectangular] [frames per face]"
+                ),
+            },
+            "explode" => self.run_explode_command(command),
+            "time_of_day" => self.run_time_of_day_command(command),
+            "sky" => {
+                if command.args.len() == 0 {
+                    println!("Sky: {}", self.lighting.sky);
+                    return;
+                }
+                match command.args.join(" ").parse() {
+                    Ok(sky) => self.lighting.sky = sky,
+                    Err(err) => {
+                        println!("Usage: sky [clear | overcast [intensity] [r g b]]");
+                        println!("Caused by: {}", err);
+                    }
+                }
+            }
+            "edits" => self.run_edits_command(command),
+            "timelapse" => self.run_timelapse_command(command),
+            "save_session" => match command.args.get(0) {
+                Some(path) => self.save_session(Path::new(path)),
+                None => println!("Usage: save_session <path>"),
+            },
+            _ => println!("WARNING: Unknown command '{}'.", command.name),
+        }
+    }
+
+    /// explode [radius] [power]
+    /// Blows a crater in the world where the crosshair is pointing, see ChunkStorage::explode.
+    fn run_explode_command(&mut self, command: &Command) {
+        let (radius, power) = match (command.get_arg(0, 8.0), command.get_arg(1, 1.0)) {
+            (Some(radius), Some(power)) if radius > 0.0 && power >= 0.0 => (radius, power),
+            _ => {
+                println!("Usage: explode [radius in blocks] [power]");
+                return;
+            }
+        };
+        let center = match &self.selection {
+            Some(hit) => hit.block,
+            None => {
+                println!("Nothing is under the crosshair to blow up.");
+                return;
+            }
+        };
+        let crater = self.world.explode(center, radius, power);
+        for block in &crater.carved {
+            self.liquid.on_block_changed(*block);
+        }
+        println!(
+            "Carved {} blocks and threw {} pieces of debris.",
+            crater.carved.len(),
+            crater.debris.len()
+        );
+        self.debris.add(crater.debris);
+    }
+
+    /// time_of_day [load <path> | seek <seconds> | play | pause | off]
+    /// Controls the track of keyframes the lighting follows, see TimeOfDayTrack.
+    fn run_time_of_day_command(&mut self, command: &Command) {
+        let usage = "Usage: time_of_day [load <path> | seek <seconds> | play | pause | off]";
+        match (command.args.get(0).map(|arg| &arg[..]), command.args.get(1)) {
+            (None, _) => match &self.time_of_day {
+                Some(track) => println!(
+                    "Time of day: {}s of {}s, {}.",
+                    self.time_of_day_time,
+                    track.get_length(),
+                    if self.time_of_day_playing { "playing" } else { "paused" }
+                ),
+                None => println!("No time of day track is loaded."),
+            },
+            (Some("load"), Some(path)) => match TimeOfDayTrack::load(Path::new(path)) {
+                Ok(track) => {
+                    self.time_of_day = Some(track);
+                    self.time_of_day_playing = true;
+                    self.seek_time_of_day(0.0);
+                }
+                Err(err) => {
+                    println!("WARNING: Failed to load time of day track from {}.", path);
+                    println!("Caused by: {}", err);
+                }
+            },
+            (Some("seek"), Some(_)) => match command.get_arg(1, 0.0) {
+                Some(time) => self.seek_time_of_day(time),
+                None => println!("{}", usage),
+            },
+            (Some("play"), None) => self.time_of_day_playing = true,
+            (Some("pause"), None) => self.time_of_day_playing = false,
+            (Some("off"), None) => {
+                self.time_of_day = None;
+                let Lighting { sun_angle, sky, .. } = self.lighting;
+                self.lighting = Lighting {
+                    sun_angle,
+                    sky,
+                    ..Default::default()
+                };
+            }
+            _ => println!("{}", usage),
+        }
+    }
+
+    /// edits [save <path> | load <path> | clear]
+    /// Saves or loads the log of edited blocks which timelapses replay, see EditLog.
+    fn run_edits_command(&mut self, command: &Command) {
+        match (command.args.first().map(|arg| &arg[..]), command.args.get(1)) {
+            (None, _) => println!(
+                "{} edits over {}s.",
+                self.edit_log.borrow_edits().len(),
+                self.edit_log.get_duration()
+            ),
+            (Some("save"), Some(path)) => match self.edit_log.save(Path::new(path)) {
+                Ok(()) => println!("Saved edits to {}.", path),
+                Err(err) => {
+                    println!("WARNING: Failed to save edits to {}.", path);
+                    println!("Caused by: {}", err);
+                }
+            },
+            // The world keeps edits between runs, so a log saved last time still matches it.
+            (Some("load"), Some(path)) => match EditLog::load(Path::new(path)) {
+                Ok(log) => self.edit_log = log,
+                Err(err) => {
+                    println!("WARNING: Failed to load edits from {}.", path);
+                    println!("Caused by: {}", err);
+                }
+            },
+            (Some("clear"), None) => self.edit_log.clear(),
+            _ => println!("Usage: edits [save <path> | load <path> | clear]"),
+        }
+    }
+
+    /// timelapse <directory> [speed] [samples] | timelapse stop
+    /// Takes every edit in the log out of the world, then puts them back at speed times the rate
+    /// they were made, saving a numbered beauty shot of every frame to the directory.
+    fn run_timelapse_command(&mut self, command: &Command) {
+        let usage = "Usage: timelapse <directory> [speed] [samples] | timelapse stop";
+        let (directory, speed, samples) = match (
+            command.args.first(),
+            command.get_arg(1, 10.0f32),
+            command.get_arg(2, DEFAULT_TIMELAPSE_SAMPLES),
+        ) {
+            (Some(stop), _, _) if stop == "stop" => {
+                self.finish_timelapse();
+                return;
+            }
+            (Some(directory), Some(speed), Some(samples)) if speed > 0.0 && samples > 0 => {
+                (Path::new(directory), speed, samples)
+            }
+            _ => {
+                println!("{}", usage);
+                return;
+            }
+        };
+        if self.timelapse.is_some() {
+            println!("WARNING: A timelapse is already being rendered.");
+            return;
+        }
+        if let Err(err) = std::fs::create_dir_all(directory) {
+            println!("WARNING: Failed to create {:?}.", directory);
+            println!("Caused by: {}", err);
+            return;
+        }
+        let timelapse = match Timelapse::new(&self.edit_log, directory, speed, samples) {
+            Some(timelapse) => timelapse,
+            None => {
+                println!("WARNING: There are no edits to replay.");
+                return;
+            }
+        };
+        for edit in self.edit_log.borrow_edits().iter().rev() {
+            self.world
+                .set_block(&edit.block, Material::unpack(edit.before));
+        }
+        println!(
+            "Rendering a timelapse of {} edits, this will take a while.",
+            self.edit_log.borrow_edits().len()
+        );
+        self.timelapse = Some(timelapse);
+    }
+
+    /// Starts rendering the next frame of the timelapse once the last one has been saved, after
+    /// moving the camera and putting back the edits made up to that frame.
+    fn update_timelapse(&mut self) {
+        let timelapse = match &mut self.timelapse {
+            Some(timelapse) => timelapse,
+            None => return,
+        };
+        if timelapse.is_capturing() {
+            return;
+        }
+        if timelapse.is_finished() {
+            println!("Finished rendering the timelapse.");
+            self.timelapse = None;
+            return;
+        }
+        for edit in timelapse.take_due_edits() {
+            self.world
+                .set_block(&edit.block, Material::unpack(edit.after));
+        }
+        let camera = timelapse.sample_camera(timelapse.get_replay_time());
+        let (path, frames) = timelapse.start_capture();
+        self.beauty_shot_request = Some(BeautyShotRequest {
+            path,
+            frames,
+            downsample: 1,
+        });
+        self.camera = camera.clone();
+        self.previous_camera = camera.clone();
+        self.render_camera = camera;
+    }
+
+    /// Puts back any edits the timelapse has not replayed yet, so that the world ends up as it
+    /// was before the timelapse started.
+    fn finish_timelapse(&mut self) {
+        let mut timelapse = match self.timelapse.take() {
+            Some(timelapse) => timelapse,
+            None => return,
+        };
+        for edit in timelapse.take_remaining_edits() {
+            self.world
+                .set_block(&edit.block, Material::unpack(edit.after));
+        }
+        println!("Stopped rendering the timelapse.");
+    }
+
+    /// Should be called by the renderer once it is done with a beauty shot, saved is false if it
+    /// could not save it.
+    pub fn on_beauty_shot_done(&mut self, saved: bool) {
+        if let Some(timelapse) = &mut self.timelapse {
+            if saved {
+                timelapse.finish_capture();
+            } else {
+                self.finish_timelapse();
+            }
+        }
+    }
+
+    /// Changes a block and records the change in the edit log. Placing water starts simulating it.
+    fn edit_block(&mut self, block: SignedCoord3D, material: &Material) {
+        let before = self.world.get_block(&block).pack();
+        self.world.set_block(&block, material.clone());
+        if material.pack() == MATERIALS[WATER_MATERIAL].pack() {
+            self.liquid.pour(block, MAX_WATER_LEVEL);
+        } else {
+            self.liquid.on_block_changed(block);
+        }
+        self.edit_log.record(WorldEdit {
+            time: self.game_time,
+            block,
+            before,
+            after: material.pack(),
+            camera: self.camera.clone(),
+        });
+    }
+
+    /// Moves to a time on the time of day track and applies its lighting, if one is loaded.
+    fn seek_time_of_day(&mut self, time: f32) {
+        if let Some(track) = &self.time_of_day {
+            self.time_of_day_time = time;
+            self.lighting = Lighting {
+                sky: self.lighting.sky,
+                ..track.sample(time)
+            };
+        }
+    }
+
+    /// Saves where the camera is and the time of day, so that render-still can draw the same view.
+    fn save_session(&self, path: &Path) {
+        match std::fs::write(path, format_session(&self.camera, &self.lighting)) {
+            Ok(()) => println!("Saved session to {:?}.", path),
+            Err(err) => {
+                println!("WARNING: Failed to save session to {:?}.", path);
+                println!("Caused by: {}", err);
+            }
+        }
+    }
+
+    /// Moves the camera to where a file saved with the save_session command says, and skips the
+    /// menu. Values missing from the file are left unchanged.
+    pub fn load_session(&mut self, path: &Path) -> io::Result<()> {
+        let session = ConfigFile::parse(&std::fs::read_to_string(path)?);
+        let origin = &mut self.camera.origin;
+        origin.x = session.get("x", origin.x);
+        origin.y = session.get("y", origin.y);
+        origin.z = session.get("z", origin.z);
+        self.camera.heading.0 = session.get("heading", self.camera.heading.0);
+        self.camera.pitch.0 = session.get("pitch", self.camera.pitch.0);
+        let lighting = &mut self.lighting;
+        lighting.sun_angle = session.get("sun_angle", lighting.sun_angle);
+        lighting.sun_intensity = session.get("sun_intensity", lighting.sun_intensity);
+        lighting.fog_density = session.get("fog_density", lighting.fog_density);
+        lighting.sky = session.get("sky", lighting.sky);
+        self.previous_camera = self.camera.clone();
+        self.render_camera = self.camera.clone();
+        self.skip_menu();
+        Ok(())
+    }
+
+    /// export_map <path> [radius] [blocks_per_pixel]
+    /// Saves a top-down map of the terrain around the camera as a PNG file.
+    fn export_map(&self, command: &Command) {
+        let (path, radius, blocks_per_pixel) = match (
+            command.args.get(0),
+            command.get_arg(1, 1024usize),
+            command.get_arg(2, 4usize),
+        ) {
+            (Some(path), Some(radius), Some(blocks_per_pixel)) if blocks_per_pixel > 0 => {
+                (path, radius, blocks_per_pixel)
+            }
+            _ => {
+                println!("Usage: export_map <path> [radius] [blocks_per_pixel]");
+                return;
+            }
+        };
+        let center = (
+            self.camera.origin.x.floor() as isize,
+            self.camera.origin.y.floor() as isize,
+        );
+        let size = radius * 2 / blocks_per_pixel;
+        match world::map::export_map(Path::new(path), center, size, blocks_per_pixel) {
+            Ok(..) => println!("Saved a {}x{} map to {}.", size, size, path),
+            Err(err) => {
+                println!("WARNING: Failed to save map to {}.", path);
+                println!("Caused by: {}", err);
+            }
+        }
+    }
+
+    /// While playing, runs however many ticks fit in the time since the last frame. Otherwise,
+    /// handles menu controls and leaves the world frozen. Then updates the camera which should be
+    /// rendered. Called after all controls have been updated.
+    pub fn update(&mut self, frame_dt: f32) {
+        for command in self.console.poll_commands() {
+            self.run_command(&command);
+        }
+        if self.state == GameState::Loading && self.world.is_settled() {
+            self.state = self.state_after_loading;
+        }
+        // The world is rewound while a timelapse is rendered, which must not end up saved.
+        if self.autosave_timestep.advance(frame_dt) > 0 && self.timelapse.is_none() {
+            self.world.autosave();
+        }
+        let events = self.controls.drain_events();
+        if self.state == GameState::Playing {
+            let dt = self.timestep.get_step();
+            for _ in 0..self.timestep.advance(frame_dt) {
+                self.previous_camera = self.camera.clone();
+                self.tick(dt);
+                // Make sure presses are only seen by a single tick.
+                self.controls.tick();
+                if self.state != GameState::Playing {
+                    break;
+                }
+            }
+        } else {
+            self.update_menu(events);
+            self.controls.tick();
+        }
+        let alpha = self.timestep.get_alpha();
+        self.render_camera = self.previous_camera.interpolate(&self.camera, alpha);
+
+        // Done once per frame rather than once per tick since it only matters for what is drawn.
+        // The crosshair is in the center of the screen, so it points straight forward.
+        let camera = &self.render_camera;
+        let forward = util::compute_triple_euler_vector(camera.heading, camera.pitch).forward;
+        let forward = Vector3::new(forward.x as f64, forward.y as f64, forward.z as f64);
+        self.selection = self
+            .world
+            .raycast(camera.origin, forward, SELECTION_DISTANCE);
+        self.update_timelapse();
+    }
+
+    fn update_menu(&mut self, events: Vec<ControlEvent>) {
+        for event in events {
+            let old_state = self.state;
+            self.state = match event {
+                ControlEvent::Pressed(name) => match &name[..] {
+                    "back" => self.state.on_back(),
+                    "confirm" => self.state.on_confirm(),
+                    "menu" => self.state.on_menu(),
+                    _ => self.state,
+                },
+                ControlEvent::Released(..) => self.state,
+            };
+            if self.state != old_state {
+                self.audio.play_ui_sound(UiSound::Select);
+            }
+            if self.state == GameState::Playing {
+                // Anything else was meant for the menu.
+                break;
+            }
+        }
+    }
+
+    fn tick(&mut self, dt: f32) {
+        self.game_time += dt;
+        if self.controls.just_pressed("back") {
+            self.state = self.state.on_back();
+            self.audio.play_ui_sound(UiSound::Select);
+            return;
+        }
+        if self.controls.just_pressed("toggle_hud") {
+            self.hud_visible = !self.hud_visible;
+            self.audio.play_ui_sound(UiSound::Toggle);
+        }
+        if self.controls.just_pressed("screenshot") {
+            self.screenshot_requested = true;
+        }
+        if self.controls.just_pressed("exposure_up") {
+            self.exposure = (self.exposure + tonemap::EXPOSURE_STEP).min(tonemap::MAX_EXPOSURE);
+        } else if self.controls.just_pressed("exposure_down") {
+            self.exposure = (self.exposure - tonemap::EXPOSURE_STEP).max(-tonemap::MAX_EXPOSURE);
+        }
+        let mut new_slot = self.selected_slot;
+        for slot in 0..self.hotbar.len() {
+            if self.controls.just_pressed(&format!("slot{}", slot)) {
+                new_slot = slot;
+            }
+        }
+        let num_slots = self.hotbar.len();
+        if self.controls.just_pressed("next_slot") {
+            new_slot = (self.selected_slot + 1) % num_slots;
+        } else if self.controls.just_pressed("previous_slot") {
+            new_slot = (self.selected_slot + num_slots - 1) % num_slots;
+        }
+        if new_slot != self.selected_slot {
+            self.selected_slot = new_slot;
+            self.audio.play_ui_sound(UiSound::Select);
+        }
+        // Edits can't be made while a timelapse is putting the old ones back.
+        if let (Some(hit), None) = (self.selection.clone(), &self.timelapse) {
+            if self.controls.just_pressed("break") {
+                self.breaking = Some((hit.block, 0.0));
+            }
+            if let Some((block, held)) = self.breaking {
+                let hardness = MaterialInfo::of(&self.world.get_block(&block)).hardness;
+                if block != hit.block || !self.controls.is_held("break") {
+                    self.breaking = None;
+                } else if held + dt >= hardness {
+                    self.breaking = None;
+                    self.edit_block(hit.block, &Material::air());
+                } else {
+                    self.breaking = Some((block, held + dt));
+                }
+            } else if self.controls.just_pressed("place") && hit.normal != (0, 0, 0) {
+                let material = self.borrow_selected_material().clone();
+                self.edit_block(hit.block.add(hit.normal), &material);
+            }
+        }
         if self.controls.is_held("sunup") {
-            self.sun_angle += dt * 1.0;
+            self.lighting.sun_angle += dt * 1.0;
         } else if self.controls.is_held("sundown") {
-            self.sun_angle -= dt * 1.0;
+            self.lighting.sun_angle -= dt * 1.0;
+        }
+        if self.time_of_day_playing {
+            self.seek_time_of_day(self.time_of_day_time + dt);
+        }
+        for _ in 0..self.liquid_timestep.advance(dt) {
+            self.liquid.step(&mut self.world);
+        }
+        for _ in 0..self.debris_timestep.advance(dt) {
+            self.debris.step(&mut self.world, self.debris_timestep.get_step());
         }
 
         let dx: f32 = if self.controls.is_held("left") {
@@ -86,13 +984,20 @@ impl Game {
         } else {
             0.0
         };
-        let amount = dt * 50.0;
+        let speed = if self.controls.is_held("fast") {
+            200.0
+        } else {
+            50.0
+        };
+        let amount = dt * speed;
         let util::TripleEulerVector { forward, up, right } =
             util::compute_triple_euler_vector(self.camera.heading, self.camera.pitch);
         let forward = forward.normalize();
         let up = up.normalize();
         let right = right.normalize();
-        self.camera.origin += amount * forward * dy + amount * up * dz + amount * right * dx;
+        let delta = amount * forward * dy + amount * up * dz + amount * right * dx;
+        self.camera.origin += Vector3::new(delta.x as f64, delta.y as f64, delta.z as f64);
+        self.update_audio(dt, delta);
     }
 
     pub fn on_mouse_move(&mut self, x: f64, y: f64) {
@@ -100,10 +1005,45 @@ impl Game {
         // self.camera.pitch.0 = ((256.0 - y) / 200.0) as f32;
     }
 
+    /// Describes where the camera is and what the world is doing, for crash reports.
+    pub fn describe_session(&self) -> String {
+        format!(
+            "{}chunks_pending = {}\nunsaved_chunks = {}\n",
+            format_session(&self.render_camera, &self.lighting),
+            self.world.get_chunks_pending(),
+            self.world.get_num_unsaved_chunks()
+        )
+    }
+
+    pub fn get_state(&self) -> GameState {
+        self.state
+    }
+
+    /// Starts playing right away instead of showing the main menu.
+    pub fn skip_menu(&mut self) {
+        if self.state == GameState::MainMenu {
+            self.state = GameState::Playing;
+        }
+        if self.state_after_loading == GameState::MainMenu {
+            self.state_after_loading = GameState::Playing;
+        }
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.state == GameState::Quitting
+    }
+
     pub fn borrow_world(&self) -> &ChunkStorage {
         &self.world
     }
 
+    /// Saves the world and waits for it to finish, for when the game is closing. A timelapse which
+    /// is still rendering is stopped first so that the world is saved the way it was left.
+    pub fn save_and_wait(&mut self) {
+        self.finish_timelapse();
+        self.world.save_and_wait();
+    }
+
     pub fn borrow_world_mut(&mut self) -> &mut ChunkStorage {
         &mut self.world
     }
@@ -112,6 +1052,130 @@ impl Game {
         &self.camera
     }
 
+    /// The camera interpolated between the last two ticks, which moves smoothly at any framerate.
+    pub fn borrow_render_camera(&self) -> &Camera {
+        &self.render_camera
+    }
+
+    pub fn borrow_selection(&self) -> Option<&RaycastHit> {
+        self.selection.as_ref()
+    }
+
+    pub fn borrow_hotbar(&self) -> &[usize] {
+        &self.hotbar
+    }
+
+    pub fn get_selected_slot(&self) -> usize {
+        self.selected_slot
+    }
+
+    /// The material picked in the hotbar.
+    pub fn borrow_selected_material(&self) -> &Material {
+        &MATERIALS[self.hotbar[self.selected_slot]]
+    }
+
+    pub fn is_hud_visible(&self) -> bool {
+        self.hud_visible
+    }
+
+    pub fn is_ray_statistics_visible(&self) -> bool {
+        self.ray_statistics_visible
+    }
+
+    pub fn are_latency_markers_visible(&self) -> bool {
+        self.latency_markers_visible
+    }
+
+    pub fn get_max_fps(&self) -> u32 {
+        self.max_fps
+    }
+
+    pub fn set_max_fps(&mut self, max_fps: u32) {
+        self.max_fps = max_fps;
+    }
+
+    pub fn get_debug_view(&self) -> DebugView {
+        self.debug_view
+    }
+
+    pub fn get_tonemapper(&self) -> Tonemapper {
+        self.tonemapper
+    }
+
+    /// In stops, see tonemap::get_exposure_scale.
+    pub fn get_exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    pub fn get_palette(&self) -> Palette {
+        self.palette
+    }
+
+    pub fn get_pip_camera(&self) -> Option<PipCamera> {
+        self.pip_camera
+    }
+
+    pub fn borrow_probes(&self) -> &[Vector3<f64>] {
+        &self.probes
+    }
+
+    /// Returns true once after the probe command asks for every probe to be captured again.
+    pub fn take_probe_recapture(&mut self) -> bool {
+        std::mem::replace(&mut self.probe_recapture, false)
+    }
+
+    pub fn get_reflection_ray_budget(&self) -> f32 {
+        self.reflection_ray_budget
+    }
+
+    pub fn get_stage_toggles(&self) -> StageToggles {
+        self.stage_toggles
+    }
+
+    /// Returns the beauty shot asked for with the beauty_shot command, if there is one which has
+    /// not been taken yet.
+    pub fn take_beauty_shot_request(&mut self) -> Option<BeautyShotRequest> {
+        self.beauty_shot_request.take()
+    }
+
+    /// Returns the panorama asked for with the panorama command, if there is one which has not
+    /// been rendered yet.
+    pub fn take_panorama_request(&mut self) -> Option<PanoramaRequest> {
+        self.panorama_request.take()
+    }
+
+    /// Returns true once after the screenshot control is pressed, meaning the frame being rendered
+    /// should be saved.
+    pub fn take_screenshot_request(&mut self) -> bool {
+        std::mem::replace(&mut self.screenshot_requested, false)
+    }
+
+    pub fn has_beauty_shot_request(&self) -> bool {
+        self.beauty_shot_request.is_some()
+    }
+
+    /// Takes a beauty shot as if the beauty_shot command was used.
+    pub fn request_beauty_shot(&mut self, request: BeautyShotRequest) {
+        self.beauty_shot_request = Some(request);
+    }
+
+    /// The renderer switches to this schedule at the start of the next frame.
+    pub fn borrow_denoise_schedule(&self) -> &DenoiseSchedule {
+        &self.denoise_schedule
+    }
+
+    pub fn borrow_comparison_schedule(&self) -> Option<&DenoiseSchedule> {
+        self.comparison_schedule.as_ref()
+    }
+
+    pub fn get_comparison_divider(&self) -> f32 {
+        self.comparison_divider
+    }
+
+    pub fn set_denoise_schedule(&mut self, schedule: DenoiseSchedule) {
+        self.denoise_schedule = schedule;
+    }
+
     pub fn borrow_controls(&self) -> &ControlSet {
         &self.controls
     }
@@ -121,6 +1185,50 @@ impl Game {
     }
 
     pub fn get_sun_angle(&self) -> f32 {
-        self.sun_angle
+        self.lighting.sun_angle
+    }
+
+    pub fn set_sun_angle(&mut self, sun_angle: f32) {
+        self.lighting.sun_angle = sun_angle;
+    }
+
+    pub fn get_game_time(&self) -> f32 {
+        self.game_time
+    }
+
+    pub fn borrow_lighting(&self) -> &Lighting {
+        &self.lighting
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time_of_day::Sky;
+
+    #[test]
+    fn session_round_trip() {
+        let mut camera = Camera::new();
+        camera.origin = Vector3::new(1.5, -2.0, 300.25);
+        camera.pitch.0 = -0.5;
+        let lighting = Lighting {
+            sun_angle: 0.75,
+            sun_intensity: 0.5,
+            fog_density: 2.0,
+            sky: Sky::Overcast {
+                intensity: 0.5,
+                color: [1.0, 1.0, 0.75],
+            },
+        };
+        let session = ConfigFile::parse(&format_session(&camera, &lighting));
+        assert_eq!(session.get("x", 0.0), 1.5);
+        assert_eq!(session.get("y", 0.0), -2.0);
+        assert_eq!(session.get("z", 0.0), 300.25);
+        assert_eq!(session.get("heading", 0.0), camera.heading.0);
+        assert_eq!(session.get("pitch", 0.0), -0.5);
+        assert_eq!(session.get("sun_angle", 0.0), 0.75);
+        assert_eq!(session.get("sun_intensity", 0.0), 0.5);
+        assert_eq!(session.get("fog_density", 0.0), 2.0);
+        assert_eq!(session.get("sky", Sky::Clear), lighting.sky);
     }
 }
@@ -0,0 +1,68 @@
+/// Which screen the game is on. The world is only simulated while Playing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GameState {
+    /// Shown while the terrain around the camera is generated on startup.
+    Loading,
+    MainMenu,
+    Playing,
+    Paused,
+    /// The game should close as soon as possible.
+    Quitting,
+}
+
+impl GameState {
+    /// The state to switch to when the back control (Escape) is pressed.
+    pub fn on_back(self) -> GameState {
+        match self {
+            GameState::Loading | GameState::MainMenu => GameState::Quitting,
+            GameState::Playing => GameState::Paused,
+            GameState::Paused => GameState::Playing,
+            GameState::Quitting => GameState::Quitting,
+        }
+    }
+
+    /// The state to switch to when the confirm control (Enter) is pressed.
+    pub fn on_confirm(self) -> GameState {
+        match self {
+            GameState::MainMenu | GameState::Paused => GameState::Playing,
+            other => other,
+        }
+    }
+
+    /// The state to switch to when the menu control (M) is pressed.
+    pub fn on_menu(self) -> GameState {
+        match self {
+            GameState::Paused => GameState::MainMenu,
+            other => other,
+        }
+    }
+
+    /// True if the mouse should be hidden and locked to the window.
+    pub fn captures_mouse(self) -> bool {
+        self == GameState::Playing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_pauses_and_resumes() {
+        let state = GameState::MainMenu.on_confirm();
+        assert_eq!(state, GameState::Playing);
+        assert_eq!(state.on_back(), GameState::Paused);
+        assert_eq!(state.on_back().on_back(), GameState::Playing);
+        assert_eq!(state.on_back().on_menu().on_back(), GameState::Quitting);
+        // Only the paused screen leads back to the main menu.
+        assert_eq!(state.on_menu(), GameState::Playing);
+    }
+
+    #[test]
+    fn loading_can_only_be_quit() {
+        assert_eq!(GameState::Loading.on_confirm(), GameState::Loading);
+        assert_eq!(GameState::Loading.on_menu(), GameState::Loading);
+        assert_eq!(GameState::Loading.on_back(), GameState::Quitting);
+        assert!(!GameState::Loading.captures_mouse());
+    }
+}
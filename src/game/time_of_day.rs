@@ -0,0 +1,212 @@
+use cgmath::Vector4;
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+
+/// What lights the world from above, written as `clear` or `overcast [intensity] [r g b]`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sky {
+    /// The sun and a sky which brightens around it.
+    Clear,
+    /// No sun, only a sky which is the same color in every direction. Useful for judging
+    /// materials without shadows or a tinted sky getting in the way.
+    Overcast { intensity: f32, color: [f32; 3] },
+}
+
+impl Sky {
+    /// For the raytrace uniform, the color of an overcast sky in RGB and whether the sky is
+    /// overcast in A. Must match is_overcast in raytrace_common.glsl.
+    pub fn to_uniform(&self) -> Vector4<f32> {
+        match self {
+            Sky::Clear => Vector4::new(0.0, 0.0, 0.0, 0.0),
+            Sky::Overcast { intensity, color } => Vector4::new(
+                color[0] * intensity,
+                color[1] * intensity,
+                color[2] * intensity,
+                1.0,
+            ),
+        }
+    }
+}
+
+impl FromStr for Sky {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Sky, String> {
+        let words: Vec<_> = text.split_whitespace().collect();
+        let values: Result<Vec<f32>, _> = words.iter().skip(1).map(|word| word.parse()).collect();
+        let values =
+            values.map_err(|_| format!("'{}' has a value which is not a number.", text))?;
+        match (words.first().cloned(), &values[..]) {
+            (Some("clear"), []) => Ok(Sky::Clear),
+            (Some("overcast"), []) => Ok(Sky::Overcast {
+                intensity: 1.0,
+                color: [1.0; 3],
+            }),
+            (Some("overcast"), &[intensity]) => Ok(Sky::Overcast {
+                intensity,
+                color: [1.0; 3],
+            }),
+            (Some("overcast"), &[intensity, r, g, b]) => Ok(Sky::Overcast {
+                intensity,
+                color: [r, g, b],
+            }),
+            _ => Err(format!("'{}' is not a sky.", text)),
+        }
+    }
+}
+
+impl Display for Sky {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Sky::Clear => write!(f, "clear"),
+            Sky::Overcast { intensity, color } => write!(
+                f,
+                "overcast {} {} {} {}",
+                intensity, color[0], color[1], color[2]
+            ),
+        }
+    }
+}
+
+/// The lighting a time of day track gives at one point in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lighting {
+    /// In the same units as the sun angle the sunup and sundown controls change.
+    pub sun_angle: f32,
+    /// Multiplies the brightness of the sun and the sky it lights up.
+    pub sun_intensity: f32,
+    /// Multiplies how quickly distant terrain fades into the fog.
+    pub fog_density: f32,
+    /// Not part of time of day tracks, which always leave it as it was. See the sky command.
+    pub sky: Sky,
+}
+
+impl Default for Lighting {
+    fn default() -> Self {
+        Self {
+            sun_angle: 0.0,
+            sun_intensity: 1.0,
+            fog_density: 1.0,
+            sky: Sky::Clear,
+        }
+    }
+}
+
+/// Keyframes of the lighting over game time, which is blended linearly between keyframes and held
+/// before the first and after the last one. Files have one keyframe per line, written as
+/// `time sun_angle sun_intensity fog_density` with the time in seconds. Blank lines and lines
+/// starting with # are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeOfDayTrack {
+    // Sorted by time, never empty.
+    keyframes: Vec<(f32, Lighting)>,
+}
+
+impl TimeOfDayTrack {
+    pub fn parse(text: &str) -> Result<TimeOfDayTrack, String> {
+        let mut keyframes = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with('#') {
+                continue;
+            }
+            let values: Result<Vec<f32>, _> = line.split_whitespace().map(str::parse).collect();
+            match values {
+                Ok(values) if values.len() == 4 => {
+                    let lighting = Lighting {
+                        sun_angle: values[1],
+                        sun_intensity: values[2],
+                        fog_density: values[3],
+                        sky: Sky::Clear,
+                    };
+                    keyframes.push((values[0], lighting));
+                }
+                _ => {
+                    return Err(format!(
+                        "Line {} should be 'time sun_angle sun_intensity fog_density'.",
+                        index + 1
+                    ))
+                }
+            }
+        }
+        if keyframes.len() == 0 {
+            return Err("The track has no keyframes.".to_owned());
+        }
+        keyframes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        Ok(TimeOfDayTrack { keyframes })
+    }
+
+    pub fn load(path: &Path) -> Result<TimeOfDayTrack, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Self::parse(&text)
+    }
+
+    /// The time of the last keyframe, after which the lighting stops changing.
+    pub fn get_length(&self) -> f32 {
+        self.keyframes[self.keyframes.len() - 1].0
+    }
+
+    /// The sky of the result is always clear.
+    pub fn sample(&self, time: f32) -> Lighting {
+        let next = self
+            .keyframes
+            .iter()
+            .position(|(key_time, _)| *key_time > time);
+        let (start, end) = match next {
+            Some(0) => return self.keyframes[0].1,
+            Some(next) => (self.keyframes[next - 1], self.keyframes[next]),
+            None => return self.keyframes[self.keyframes.len() - 1].1,
+        };
+        let amount = (time - start.0) / (end.0 - start.0);
+        let mix = |a: f32, b: f32| a + (b - a) * amount;
+        Lighting {
+            sun_angle: mix(start.1.sun_angle, end.1.sun_angle),
+            sun_intensity: mix(start.1.sun_intensity, end.1.sun_intensity),
+            fog_density: mix(start.1.fog_density, end.1.fog_density),
+            sky: Sky::Clear,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyframes_are_blended() {
+        let track = TimeOfDayTrack::parse("# Sunset\n10 1.0 0.5 2\n\n0 0.0 1.0 1\n").unwrap();
+        assert_eq!(track.get_length(), 10.0);
+        assert_eq!(track.sample(-1.0), Lighting::default());
+        let middle = track.sample(5.0);
+        assert_eq!(middle.sun_angle, 0.5);
+        assert_eq!(middle.sun_intensity, 0.75);
+        assert_eq!(middle.fog_density, 1.5);
+        assert_eq!(track.sample(20.0).fog_density, 2.0);
+    }
+
+    #[test]
+    fn skies_round_trip() {
+        for sky in &[
+            Sky::Clear,
+            Sky::Overcast {
+                intensity: 0.5,
+                color: [1.0, 0.9, 0.8],
+            },
+        ] {
+            assert_eq!(sky.to_string().parse::<Sky>(), Ok(*sky));
+        }
+        let overcast: Sky = "overcast 2".parse().unwrap();
+        assert_eq!(overcast.to_uniform(), Vector4::new(2.0, 2.0, 2.0, 1.0));
+        assert_eq!(Sky::Clear.to_uniform().w, 0.0);
+        assert!("overcast 1 2".parse::<Sky>().is_err());
+        assert!("cloudy".parse::<Sky>().is_err());
+    }
+
+    #[test]
+    fn rejects_bad_tracks() {
+        assert!(TimeOfDayTrack::parse("").is_err());
+        assert!(TimeOfDayTrack::parse("0 1 2").is_err());
+        assert!(TimeOfDayTrack::parse("0 1 2 fog").is_err());
+    }
+}
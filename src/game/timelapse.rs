@@ -0,0 +1,211 @@
+use super::edit_log::{EditLog, WorldEdit};
+use crate::render::Camera;
+use cgmath::{Rad, Vector3};
+use std::path::{Path, PathBuf};
+
+/// How many frames are saved for each second of the finished video.
+pub const TIMELAPSE_FPS: f32 = 30.0;
+/// The camera path only goes through where the camera was for one edit in each stretch of this
+/// many seconds of the recording, so that it glides past instead of following every small step.
+const CAMERA_KEY_INTERVAL: f32 = 4.0;
+
+/// Catmull-Rom spline through p[1] and p[2], u goes from 0 at p[1] to 1 at p[2].
+fn catmull_rom(p: [f64; 4], u: f64) -> f64 {
+    let (u2, u3) = (u * u, u * u * u);
+    0.5 * (2.0 * p[1]
+        + (p[2] - p[0]) * u
+        + (2.0 * p[0] - 5.0 * p[1] + 4.0 * p[2] - p[3]) * u2
+        + (3.0 * p[1] - p[0] - 3.0 * p[2] + p[3]) * u3)
+}
+
+/// Replays a recorded edit log at a faster speed, saving a beauty shot for every frame of the
+/// video while the camera moves along a smooth path through where the camera was while building.
+pub struct Timelapse {
+    edits: Vec<WorldEdit>,
+    // How many of the edits are in the world so far.
+    edits_applied: usize,
+    // How many of the edits were in the last frame which was saved.
+    edits_saved: usize,
+    // Times are relative to the first edit.
+    camera_keys: Vec<(f32, Camera)>,
+    directory: PathBuf,
+    // Seconds of the recording which pass in each second of the video.
+    speed: f32,
+    samples: u32,
+    frame: u32,
+    // True while the beauty shot of the current frame is being rendered.
+    capturing: bool,
+}
+
+impl Timelapse {
+    /// Returns None if the log is empty.
+    pub fn new(log: &EditLog, directory: &Path, speed: f32, samples: u32) -> Option<Timelapse> {
+        let edits = log.borrow_edits().to_vec();
+        let start = edits.first()?.time;
+        let mut camera_keys: Vec<(f32, Camera)> = Vec::new();
+        for edit in &edits {
+            let time = edit.time - start;
+            match camera_keys.last() {
+                Some((last, _)) if time - last < CAMERA_KEY_INTERVAL => (),
+                _ => camera_keys.push((time, edit.camera.clone())),
+            }
+        }
+        // Make sure the path ends where the last edit was made.
+        let last = edits.last()?;
+        if camera_keys.last()?.0 < last.time - start {
+            camera_keys.push((last.time - start, last.camera.clone()));
+        }
+        Some(Timelapse {
+            edits,
+            edits_applied: 0,
+            edits_saved: 0,
+            camera_keys,
+            directory: directory.to_owned(),
+            speed,
+            samples,
+            frame: 0,
+            capturing: false,
+        })
+    }
+
+    /// How many seconds of the recording have been replayed by the current frame.
+    pub fn get_replay_time(&self) -> f32 {
+        self.frame as f32 / TIMELAPSE_FPS * self.speed
+    }
+
+    /// True once every edit is back in the world and the last frame has been saved.
+    pub fn is_finished(&self) -> bool {
+        self.edits_saved == self.edits.len() && !self.capturing
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capturing
+    }
+
+    /// Returns the edits which should be in the world by the current frame and have not been
+    /// taken yet.
+    pub fn take_due_edits(&mut self) -> &[WorldEdit] {
+        let start = self.edits[0].time;
+        let time = self.get_replay_time();
+        let due = self.edits[self.edits_applied..]
+            .iter()
+            .take_while(|edit| edit.time - start <= time)
+            .count();
+        let first = self.edits_applied;
+        self.edits_applied += due;
+        &self.edits[first..self.edits_applied]
+    }
+
+    /// Returns every edit which has not been taken yet, for when the timelapse is stopped early.
+    pub fn take_remaining_edits(&mut self) -> &[WorldEdit] {
+        let first = self.edits_applied;
+        self.edits_applied = self.edits.len();
+        &self.edits[first..]
+    }
+
+    /// Where the camera is at a time relative to the first edit.
+    pub fn sample_camera(&self, time: f32) -> Camera {
+        let keys = &self.camera_keys;
+        let segment = keys
+            .iter()
+            .position(|(key_time, _)| *key_time > time)
+            .unwrap_or(keys.len());
+        if segment == 0 {
+            return keys[0].1.clone();
+        } else if segment == keys.len() {
+            return keys[keys.len() - 1].1.clone();
+        }
+        let index = |offset: isize| {
+            let index = segment as isize - 2 + offset;
+            &keys[index.max(0).min(keys.len() as isize - 1) as usize]
+        };
+        let (start, end) = (index(1).0, index(2).0);
+        let u = ((time - start) / (end - start)) as f64;
+        let spline = |value: &dyn Fn(&Camera) -> f64| {
+            let points = [
+                value(&index(0).1),
+                value(&index(1).1),
+                value(&index(2).1),
+                value(&index(3).1),
+            ];
+            catmull_rom(points, u)
+        };
+        Camera {
+            origin: Vector3::new(
+                spline(&|camera| camera.origin.x),
+                spline(&|camera| camera.origin.y),
+                spline(&|camera| camera.origin.z),
+            ),
+            heading: Rad(spline(&|camera| camera.heading.0 as f64) as f32),
+            pitch: Rad(spline(&|camera| camera.pitch.0 as f64) as f32),
+        }
+    }
+
+    /// Starts capturing the current frame, returning where to save it and how many frames to
+    /// blend together for it.
+    pub fn start_capture(&mut self) -> (PathBuf, u32) {
+        self.capturing = true;
+        let path = self.directory.join(format!("frame_{:05}.png", self.frame));
+        (path, self.samples)
+    }
+
+    /// Moves on to the next frame once the current one has been saved.
+    pub fn finish_capture(&mut self) {
+        self.capturing = false;
+        self.edits_saved = self.edits_applied;
+        self.frame += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_of(times: &[f32]) -> EditLog {
+        let mut log = EditLog::new();
+        for (index, time) in times.iter().enumerate() {
+            let mut camera = Camera::new();
+            camera.origin.x = index as f64 * 10.0;
+            log.record(WorldEdit {
+                time: *time,
+                block: (index as isize, 0, 0),
+                before: 0,
+                after: 1,
+                camera,
+            });
+        }
+        log
+    }
+
+    #[test]
+    fn camera_passes_through_keys() {
+        let log = log_of(&[10.0, 11.0, 20.0, 30.0]);
+        let timelapse = Timelapse::new(&log, Path::new("."), 1.0, 1).unwrap();
+        // The edit at 11 seconds is too close to the first one to be a key.
+        assert_eq!(timelapse.camera_keys.len(), 3);
+        assert_eq!(timelapse.sample_camera(-1.0).origin.x, 0.0);
+        assert!((timelapse.sample_camera(10.0).origin.x - 20.0).abs() < 1e-6);
+        assert_eq!(timelapse.sample_camera(25.0).origin.x, 30.0);
+        let middle = timelapse.sample_camera(5.0).origin.x;
+        assert!(middle > 0.0 && middle < 20.0);
+    }
+
+    #[test]
+    fn edits_are_replayed_over_frames() {
+        let log = log_of(&[0.0, 1.0, 2.0]);
+        let mut timelapse = Timelapse::new(&log, Path::new("."), TIMELAPSE_FPS, 1).unwrap();
+        assert_eq!(timelapse.take_due_edits().len(), 1);
+        let (path, _) = timelapse.start_capture();
+        assert_eq!(path, Path::new("./frame_00000.png"));
+        timelapse.finish_capture();
+        assert!(!timelapse.is_finished());
+        assert_eq!(timelapse.take_due_edits()[0].block, (1, 0, 0));
+        timelapse.start_capture();
+        timelapse.finish_capture();
+        assert_eq!(timelapse.take_due_edits().len(), 1);
+        assert!(!timelapse.is_finished());
+        timelapse.start_capture();
+        timelapse.finish_capture();
+        assert!(timelapse.is_finished());
+    }
+}
@@ -1,4 +1,8 @@
+pub mod config;
 pub mod game;
 pub mod render;
+pub mod report;
+pub mod shot_matrix;
+pub mod telemetry;
 pub mod util;
-pub mod world;
\ No newline at end of file
+pub mod world;
@@ -3,7 +3,12 @@
 pub struct Material {
     pub albedo: (u16, u16, u16),
     pub emission: (u16, u16, u16),
+    pub roughness: u16,
     pub solid: bool,
+    pub transparent: bool,
+    // 0 for materials which do not glow, otherwise one more than the index of their entry in
+    // EMITTERS.
+    pub emitter: u16,
 }
 
 impl Material {
@@ -11,7 +16,10 @@ impl Material {
         Self {
             albedo: (0, 0, 0),
             emission: (0, 0, 0),
+            roughness: 127,
             solid: false,
+            transparent: false,
+            emitter: 0,
         }
     }
 
@@ -19,7 +27,10 @@ impl Material {
         Self {
             albedo: (0, 0, 0),
             emission: (0, 0, 0),
+            roughness: 127,
             solid: true,
+            transparent: false,
+            emitter: 0,
         }
     }
 
@@ -30,6 +41,8 @@ impl Material {
 		self.emission.0 += other.emission.0;
 		self.emission.1 += other.emission.1;
         self.emission.2 += other.emission.2;
+        self.roughness += other.roughness;
+        self.emitter = self.emitter.max(other.emitter);
 	}
 
 	pub fn divide(&mut self, factor: u16) {
@@ -39,6 +52,7 @@ impl Material {
 		self.emission.0 /= factor;
 		self.emission.1 /= factor;
         self.emission.2 /= factor;
+        self.roughness /= factor;
     }
 
     pub fn pack(&self) -> u32 {
@@ -46,8 +60,11 @@ impl Material {
         let ag = (self.albedo.1) as u32;
         let ab = (self.albedo.2) as u32;
         let albedo = ar << 14 | ag << 7 | ab;
+        let roughness = (self.roughness as u32) << 21;
         let solid = if self.solid { 1 } else { 0 };
-        (solid << 15) | albedo
+        let transparent = if self.transparent { 1 } else { 0 };
+        let emitter = (self.emitter as u32) << 29;
+        emitter | (transparent << 28) | (solid << 15) | albedo | roughness
     }
 
     pub fn unpack(packed: u32) -> Self {
@@ -57,50 +74,169 @@ impl Material {
             (packed >> 0 & 0x7F) as u16,
         );
         let emission = (0, 0, 0);
+        let roughness = (packed >> 21 & 0x7F) as u16;
         let solid = packed >> 15 & 0b1 != 0;
+        let transparent = packed >> 28 & 0b1 != 0;
+        let emitter = (packed >> 29 & 0b111) as u16;
         Self {
             albedo,
             emission,
+            roughness,
             solid,
+            transparent,
+            emitter,
         }
     }
 }
 
 #[rustfmt::skip]
-pub const MATERIALS: [Material; 7] = [
+pub const MATERIALS: [Material; 10] = [
 	Material {
 		albedo:   (0, 0, 0),
 		emission: (0, 0, 0),
+		roughness: 127,
 		solid: false,
+		transparent: false,
+		emitter: 0,
 	},
 	Material {
 		albedo:   (127, 0, 127),
 		emission: (0, 0, 0),
+		roughness: 127,
 		solid: true,
+		transparent: false,
+		emitter: 0,
 	},
 	Material {
 		albedo:   (39, 110, 61),
 		emission: (0, 0, 0),
+		roughness: 127,
 		solid: true,
+		transparent: false,
+		emitter: 0,
 	},
 	Material {
 		albedo:   (51, 38, 25),
 		emission: (320, 154, 76),
+		roughness: 127,
 		solid: true,
+		transparent: false,
+		emitter: 1,
 	},
 	Material {
 		albedo:   (51, 51, 51),
 		emission: (0, 0, 0),
+		roughness: 127,
 		solid: true,
+		transparent: false,
+		emitter: 0,
 	},
 	Material {
 		albedo:   (62, 27, 22),
 		emission: (0, 0, 0),
+		roughness: 127,
 		solid: true,
+		transparent: false,
+		emitter: 0,
 	},
 	Material {
 		albedo:   (110, 116, 115),
 		emission: (0, 0, 0),
+		roughness: 24,
 		solid: true,
+		transparent: false,
+		emitter: 0,
+	},
+	Material {
+		albedo:   (98, 112, 116),
+		emission: (0, 0, 0),
+		roughness: 127,
+		solid: true,
+		transparent: true,
+		emitter: 0,
+	},
+	Material {
+		albedo:   (45, 20, 70),
+		emission: (210, 90, 382),
+		roughness: 127,
+		solid: true,
+		transparent: false,
+		emitter: 2,
+	},
+	Material {
+		albedo:   (32, 64, 110),
+		emission: (0, 0, 0),
+		roughness: 8,
+		solid: true,
+		transparent: true,
+		emitter: 0,
+	},
+];
+
+#[rustfmt::skip]
+pub const EMITTERS: [crate::render::emission::Emitter; 2] = [
+	crate::render::emission::Emitter {
+		emission: (2.509804, 1.2078432, 0.59607846),
+		waveform: crate::render::emission::Waveform::Flicker,
+		frequency: 1.5,
+	},
+	crate::render::emission::Emitter {
+		emission: (1.6470588, 0.7058824, 3.0),
+		waveform: crate::render::emission::Waveform::Pulse,
+		frequency: 0.5,
+	},
+];
+
+#[rustfmt::skip]
+pub const MATERIAL_INFO: [crate::render::material_info::MaterialInfo; 10] = [
+	crate::render::material_info::MaterialInfo {
+		footstep: crate::render::material_info::FootstepSound::None,
+		particles: (0.0, 0.0, 0.0),
+		hardness: 0.0,
+	},
+	crate::render::material_info::MaterialInfo {
+		footstep: crate::render::material_info::FootstepSound::Stone,
+		particles: (1.0, 0.0, 1.0),
+		hardness: 0.0,
+	},
+	crate::render::material_info::MaterialInfo {
+		footstep: crate::render::material_info::FootstepSound::Grass,
+		particles: (0.23529412, 0.6666667, 0.3529412),
+		hardness: 0.0,
+	},
+	crate::render::material_info::MaterialInfo {
+		footstep: crate::render::material_info::FootstepSound::Stone,
+		particles: (1.0, 0.54901963, 0.23529412),
+		hardness: 0.5,
+	},
+	crate::render::material_info::MaterialInfo {
+		footstep: crate::render::material_info::FootstepSound::Stone,
+		particles: (0.4, 0.4, 0.4),
+		hardness: 0.6,
+	},
+	crate::render::material_info::MaterialInfo {
+		footstep: crate::render::material_info::FootstepSound::Soil,
+		particles: (0.4862745, 0.21176471, 0.17254902),
+		hardness: 0.2,
+	},
+	crate::render::material_info::MaterialInfo {
+		footstep: crate::render::material_info::FootstepSound::Snow,
+		particles: (1.0, 1.0, 1.0),
+		hardness: 0.0,
+	},
+	crate::render::material_info::MaterialInfo {
+		footstep: crate::render::material_info::FootstepSound::Glass,
+		particles: (0.76862746, 0.8784314, 0.9098039),
+		hardness: 0.3,
+	},
+	crate::render::material_info::MaterialInfo {
+		footstep: crate::render::material_info::FootstepSound::Glass,
+		particles: (0.7058824, 0.39215687, 1.0),
+		hardness: 0.8,
+	},
+	crate::render::material_info::MaterialInfo {
+		footstep: crate::render::material_info::FootstepSound::Splash,
+		particles: (0.2509804, 0.5019608, 0.8627451),
+		hardness: 0.0,
 	},
 ];
@@ -0,0 +1,116 @@
+use crate::util::prelude::*;
+use crate::world::{self, Biome};
+
+/// How the air of a biome looks. Every value multiplies what the frame would otherwise have, so
+/// 1 leaves it unchanged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Atmosphere {
+    /// Multiplies the color of the sky that distant terrain fades into.
+    pub fog_color: [f32; 3],
+    pub fog_density: f32,
+    /// Multiplies the color of every pixel before tone mapping.
+    pub tint: [f32; 3],
+    /// 0 is greyscale, values above 1 make colors more vivid.
+    pub saturation: f32,
+}
+
+impl Atmosphere {
+    pub fn of(biome: Biome) -> Atmosphere {
+        match biome {
+            Biome::Plains => Atmosphere {
+                fog_color: [1.0, 1.0, 1.0],
+                fog_density: 1.0,
+                tint: [1.0, 1.0, 1.0],
+                saturation: 1.0,
+            },
+            Biome::Swamp => Atmosphere {
+                fog_color: [0.6, 0.8, 0.5],
+                fog_density: 3.0,
+                tint: [0.95, 1.0, 0.85],
+                saturation: 0.8,
+            },
+            Biome::Desert => Atmosphere {
+                fog_color: [1.4, 1.1, 0.7],
+                fog_density: 1.5,
+                tint: [1.1, 1.0, 0.85],
+                saturation: 1.1,
+            },
+            Biome::Snow => Atmosphere {
+                fog_color: [1.5, 1.6, 1.8],
+                fog_density: 2.0,
+                tint: [0.9, 0.95, 1.1],
+                saturation: 0.7,
+            },
+        }
+    }
+
+    /// Returns the texel of the fog and grading images. Colors are halved and the fog density
+    /// is quartered so that they fit in RGBA8 texels. Must match finalize.comp.
+    fn pack(&self) -> ([u8; 4], [u8; 4]) {
+        let unorm = |value: f32, max: f32| (value / max * 255.0).clamp(0.0, 255.0).round() as u8;
+        let fog = [
+            unorm(self.fog_color[0], 2.0),
+            unorm(self.fog_color[1], 2.0),
+            unorm(self.fog_color[2], 2.0),
+            unorm(self.fog_density, 4.0),
+        ];
+        let grading = [
+            unorm(self.tint[0], 2.0),
+            unorm(self.tint[1], 2.0),
+            unorm(self.tint[2], 2.0),
+            unorm(self.saturation, 2.0),
+        ];
+        (fog, grading)
+    }
+}
+
+/// A coarse grid of the atmosphere around the camera, which the finalize stage samples with
+/// linear filtering so that the look of the frame blends between biomes as the camera moves.
+pub struct AtmosphereVolume {
+    /// RGBA8 texels laid out with X changing the fastest, see Atmosphere::pack.
+    pub fog: Vec<u8>,
+    pub grading: Vec<u8>,
+}
+
+/// Builds a volume size cells wide along each axis centered on the given block, where each cell
+/// holds the atmosphere of the biome at its center.
+pub fn build_atmosphere_volume(
+    center: SignedCoord3D,
+    size: usize,
+    blocks_per_cell: usize,
+) -> AtmosphereVolume {
+    let step = blocks_per_cell as isize;
+    let corner = center.sub((size as isize / 2 * step).repeat());
+    let mut fog = Vec::with_capacity(size * size * size * 4);
+    let mut grading = Vec::with_capacity(size * size * size * 4);
+    for cell in crate::util::coord_iter_3d(size) {
+        let cell_center = corner
+            .add(cell.signed().scale(step))
+            .add((step / 2).repeat());
+        let biome = world::biome(cell_center.0, cell_center.1, cell_center.2);
+        let (fog_texel, grading_texel) = Atmosphere::of(biome).pack();
+        fog.extend_from_slice(&fog_texel);
+        grading.extend_from_slice(&grading_texel);
+    }
+    AtmosphereVolume { fog, grading }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plains_pack_to_neutral_texels() {
+        let (fog, grading) = Atmosphere::of(Biome::Plains).pack();
+        assert_eq!(fog, [128, 128, 128, 64]);
+        assert_eq!(grading, [128, 128, 128, 128]);
+    }
+
+    #[test]
+    fn volume_is_snowy_above_snow_line() {
+        let volume = build_atmosphere_volume((0, 0, 1000), 4, 16);
+        assert_eq!(volume.fog.len(), 4 * 4 * 4 * 4);
+        let (snow, _) = Atmosphere::of(Biome::Snow).pack();
+        assert!(volume.fog.chunks(4).all(|texel| texel == snow));
+    }
+}
@@ -3,14 +3,19 @@ use ash::vk_make_version;
 // Core constants.
 pub const APPLICATION_VERSION: u32 = vk_make_version!(1, 0, 0);
 pub const ENGINE_VERSION: u32 = vk_make_version!(1, 0, 0);
+// The oldest version the renderer can run on. Newer versions are used when the loader supports
+// them, see features::choose_api_version.
 pub const API_VERSION: u32 = vk_make_version!(1, 0, 92);
 
 pub const WINDOW_TITLE: &str = "Hello world";
-pub const WINDOW_WIDTH: u32 = 1024;
-pub const WINDOW_HEIGHT: u32 = 1024;
 pub const ENABLE_DEBUG: bool = cfg!(debug_assertions);
 pub const VALIDATION_LAYERS: &[&str] = &["VK_LAYER_KHRONOS_validation"];
 pub const DEVICE_EXTENSIONS: &[&str] = &["VK_KHR_swapchain"];
+// Enabled when the device supports them. These report where the GPU was when the device is lost.
+pub const OPTIONAL_DEVICE_EXTENSIONS: &[&str] = &[
+    "VK_NV_device_diagnostic_checkpoints",
+    "VK_AMD_buffer_marker",
+];
 
 // Pipeline constants.
 pub const BLUE_NOISE_WIDTH: usize = 512;
@@ -20,14 +25,64 @@ pub const BLUE_NOISE_SIZE: usize = BLUE_NOISE_WIDTH * BLUE_NOISE_HEIGHT * BLUE_N
 
 // The LOD that takes up an entire chunk.
 pub const MAX_CHUNK_LOD: usize = 6;
+// Chunks are stored on disk at this size, so unlike the sizes in RenderSettings it is fixed.
 pub const CHUNK_SIZE: usize = 1 << MAX_CHUNK_LOD; // 64
 pub const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
-// This should always be a multiple of 2
-pub const ROOT_CHUNK_SIZE: usize = 4;
-pub const ROOT_BLOCK_SIZE: usize = CHUNK_SIZE * ROOT_CHUNK_SIZE;
-pub const ROOT_BLOCK_VOLUME: usize = ROOT_BLOCK_SIZE * ROOT_BLOCK_SIZE * ROOT_BLOCK_SIZE;
 // Slices are used to upload new terrain data to the GPU.
 pub const SLICE_SIZE: usize = 16;
 pub const SLICES_PER_CHUNK: usize = CHUNK_SIZE / SLICE_SIZE;
 
 pub const SHADER_GROUP_SIZE: usize = 8; // Each compute shader works on 8x8 groups.
+
+// How many items each work group processes in stages dispatched from a work list. Must match the
+// shaders that use one.
+pub const WORK_LIST_GROUP_SIZE: usize = 64;
+// The most rays a ray queue can hold for each pixel, see push_ray in shade.comp.
+pub const RAYS_PER_PIXEL: usize = 3;
+// How many times rays are traced and shaded each frame. Paths longer than this are cut off.
+pub const RAY_QUEUE_PASSES: usize = 4;
+// How many u32s each ray in a ray queue takes up. Must match Ray in raytrace_common.glsl.
+pub const RAY_SIZE: usize = 12;
+// Must match ACCUMULATORS_PER_PIXEL in raytrace_common.glsl.
+pub const ACCUMULATORS_PER_PIXEL: usize = 6;
+// Step sizes of each pass of the reflection denoiser. There must be an even number of passes so
+// that the result ends up back in the reflection buffer.
+pub const REFLECTION_DENOISE_SCHEDULE: [i32; 2] = [1, 2];
+// Lighting from previous frames is thrown away if the sun moves at least this far (in radians)
+// in a single frame. Smaller movements only make it count for less.
+pub const SUN_MOTION_HISTORY_LIMIT: f32 = 0.02;
+// How many animated emission colors the raytrace uniform holds, including slot 0 which is for
+// materials that do not glow. Packed materials store their slot in 3 bits. Must match
+// raytrace_common.glsl.
+pub const EMITTER_SLOTS: usize = 8;
+
+// How many blocks wide each cell of the light volume is, and how many frames it takes
+// light_volume.comp to update every cell once. Must match the shaders.
+pub const LIGHT_VOLUME_CELL_SIZE: usize = 4;
+pub const LIGHT_VOLUME_UPDATE_INTERVAL: usize = 4;
+
+// How many materials can be shown in the hotbar at once. Must match overlay.comp.
+pub const MAX_HOTBAR_SLOTS: usize = 9;
+// How many pixels wide and tall the minimap is. Must match overlay.comp.
+pub const MINIMAP_SIZE: usize = 128;
+pub const MINIMAP_BLOCKS_PER_PIXEL: usize = 2;
+// How many times narrower and shorter than the screen the picture-in-picture view is.
+pub const PIP_SCALE: u32 = 4;
+// How many times narrower and shorter than its view the fog color buffer is, so that fog.comp only
+// runs for a quarter of the pixels. Must match finalize.comp.
+pub const FOG_SCALE: u32 = 2;
+// How many reflection probes can be placed at once, and how many texels wide each face of their
+// cubemaps is. Must match raytrace_common.glsl.
+pub const MAX_PROBES: usize = 8;
+pub const PROBE_SIZE: u32 = 32;
+// How many cells wide the atmosphere volume is along each axis, and how many blocks each cell
+// covers. It is rebuilt once the camera leaves the cell at its center.
+pub const ATMOSPHERE_VOLUME_SIZE: usize = 8;
+pub const ATMOSPHERE_BLOCKS_PER_CELL: usize = 64;
+// How many texels wide and tall the heightmap rays fall back to beyond the loaded region is, and
+// how many blocks each texel covers. Must match traverse.comp.
+pub const DISTANT_TERRAIN_SIZE: usize = 256;
+pub const DISTANT_TERRAIN_BLOCKS_PER_TEXEL: usize = 64;
+// The distant terrain is rebuilt around the camera once it gets this many blocks away from the
+// center, which leaves most of the heightmap in front of it whichever way it is going.
+pub const DISTANT_TERRAIN_RECENTER_DISTANCE: isize = 2048;
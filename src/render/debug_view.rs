@@ -0,0 +1,87 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// Replaces or tints parts of the image to show what the renderer is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugView {
+    Off,
+    /// Pixels whose history was rejected by the temporal stage are drawn in magenta.
+    Disocclusion,
+    /// Pixels whose lighting is NaN or infinite are drawn in cyan, and how many there are is
+    /// printed every frame. Only float lighting formats can hold these values.
+    InvalidLighting,
+    /// A grid is drawn along chunk boundaries where rays leave the region of the world streamed to
+    /// the GPU, showing how far it reaches.
+    StreamedBounds,
+    /// The minimap is replaced with a zoomed out view from above of the region streamed at full
+    /// detail, the distant terrain, the slices waiting to be uploaded and the edges of the view.
+    /// See streaming_map.rs.
+    StreamingVolumes,
+    /// The albedo is left out of the final image, showing the demodulated lighting which the
+    /// denoisers work on. Textures and block colors should not show up in it at all.
+    Lighting,
+}
+
+impl DebugView {
+    pub const ALL: [DebugView; 6] = [
+        DebugView::Off,
+        DebugView::Disocclusion,
+        DebugView::InvalidLighting,
+        DebugView::StreamedBounds,
+        DebugView::StreamingVolumes,
+        DebugView::Lighting,
+    ];
+
+    /// The value shaders compare against. Must match the DEBUG_VIEW constants in the shaders.
+    pub fn to_index(self) -> u32 {
+        self as u32
+    }
+
+    pub fn get_name(self) -> &'static str {
+        match self {
+            DebugView::Off => "off",
+            DebugView::Disocclusion => "disocclusion",
+            DebugView::InvalidLighting => "invalid_lighting",
+            DebugView::StreamedBounds => "streamed_bounds",
+            DebugView::StreamingVolumes => "streaming_volumes",
+            DebugView::Lighting => "lighting",
+        }
+    }
+}
+
+impl Default for DebugView {
+    fn default() -> Self {
+        DebugView::Off
+    }
+}
+
+impl FromStr for DebugView {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<DebugView, String> {
+        Self::ALL
+            .iter()
+            .cloned()
+            .find(|view| view.get_name() == text)
+            .ok_or_else(|| format!("'{}' is not a debug view.", text))
+    }
+}
+
+impl Display for DebugView {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for view in DebugView::ALL.iter() {
+            assert_eq!(view.to_string().parse(), Ok(*view));
+        }
+        assert!("wireframe".parse::<DebugView>().is_err());
+    }
+}
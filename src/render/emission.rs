@@ -0,0 +1,95 @@
+use crate::render::constants::*;
+use crate::render::EMITTERS;
+use cgmath::Vector4;
+use std::f32::consts::PI;
+
+/// How the brightness of a glowing material changes over time, set in the waveform column of
+/// materials.csv.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Steady,
+    /// Wavers unevenly like a flame, never dropping below half brightness.
+    Flicker,
+    /// Fades smoothly between a quarter and full brightness.
+    Pulse,
+}
+
+impl Waveform {
+    /// Returns a brightness between 0 and 1 for the given number of cycles since the game began.
+    pub fn brightness(self, cycles: f32) -> f32 {
+        let angle = cycles * 2.0 * PI;
+        match self {
+            Waveform::Steady => 1.0,
+            // Waves with unrelated frequencies add up to something which does not visibly repeat.
+            Waveform::Flicker => {
+                let wobble = angle.sin() * 0.5
+                    + (angle * 2.7 + 1.3).sin() * 0.3
+                    + (angle * 5.1 + 2.1).sin() * 0.2;
+                0.75 + wobble * 0.25
+            }
+            Waveform::Pulse => 0.625 + angle.sin() * 0.375,
+        }
+    }
+}
+
+/// The emission of a material which glows, see EMITTERS.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Emitter {
+    pub emission: (f32, f32, f32),
+    pub waveform: Waveform,
+    /// How many times a second the waveform repeats.
+    pub frequency: f32,
+}
+
+impl Emitter {
+    pub fn color_at(&self, time: f32) -> Vector4<f32> {
+        let brightness = self.waveform.brightness(time * self.frequency);
+        let (r, g, b) = self.emission;
+        [r * brightness, g * brightness, b * brightness, 0.0].into()
+    }
+}
+
+/// The emission each emitter slot has at the given game time, for the raytrace uniform. Slot 0 is
+/// used by materials which do not glow, so it is always black. This is all that changes from
+/// frame to frame, so animated materials never need their chunks to be uploaded again.
+pub fn emitter_colors(time: f32) -> [Vector4<f32>; EMITTER_SLOTS] {
+    let mut colors = [Vector4::new(0.0, 0.0, 0.0, 0.0); EMITTER_SLOTS];
+    for (index, emitter) in EMITTERS.iter().enumerate() {
+        colors[index + 1] = emitter.color_at(time);
+    }
+    colors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waveforms_stay_in_range() {
+        for step in 0..1000 {
+            let cycles = step as f32 * 0.013;
+            assert_eq!(Waveform::Steady.brightness(cycles), 1.0);
+            let flicker = Waveform::Flicker.brightness(cycles);
+            assert!(flicker >= 0.5 && flicker <= 1.0);
+            let pulse = Waveform::Pulse.brightness(cycles);
+            assert!(pulse >= 0.25 && pulse <= 1.0);
+        }
+        assert!((Waveform::Pulse.brightness(0.25) - 1.0).abs() < 1e-5);
+        assert!((Waveform::Pulse.brightness(0.75) - 0.25).abs() < 1e-5);
+    }
+
+    #[test]
+    fn emitter_slots_match_materials() {
+        let colors = emitter_colors(0.0);
+        assert_eq!(colors[0], Vector4::new(0.0, 0.0, 0.0, 0.0));
+        for material in crate::render::MATERIALS.iter() {
+            let slot = material.emitter as usize;
+            assert!(slot < EMITTER_SLOTS);
+            assert_eq!(slot == 0, material.emission == (0, 0, 0));
+            assert_eq!(
+                crate::render::Material::unpack(material.pack()).emitter,
+                material.emitter
+            );
+        }
+    }
+}
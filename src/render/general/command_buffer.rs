@@ -119,6 +119,12 @@ impl CommandBuffer {
         index: u32,
         descriptor_set: vk::DescriptorSet,
     ) {
+        if cfg!(debug_assertions) {
+            let registry = self.core.descriptor_registry.borrow();
+            if let Err(problem) = registry.check_binding(pipeline_layout, index, descriptor_set) {
+                panic!("{}", problem);
+            }
+        }
         unsafe {
             self.core.device.cmd_bind_descriptor_sets(
                 self.command_buffer,
@@ -172,7 +178,109 @@ impl CommandBuffer {
         }
     }
 
+    /// Reads the group counts from the buffer at the given offset when the command executes, so
+    /// that earlier commands can decide how much work there is.
+    pub fn dispatch_indirect(&self, buffer: &impl BufferWrapper, offset: u64) {
+        unsafe {
+            self.core.device.cmd_dispatch_indirect(
+                self.command_buffer,
+                buffer.get_vk_buffer(),
+                offset,
+            );
+        }
+    }
+
+    /// Writes a small amount of data (at most 65536 bytes) to the start of a buffer.
+    pub fn update_buffer<T: Sized>(&self, buffer: &impl BufferWrapper, value: &T) {
+        let val_ptr: *const T = value;
+        let bytes_ptr = val_ptr as *const u8;
+        unsafe {
+            let bytes_slice = std::slice::from_raw_parts(bytes_ptr, std::mem::size_of::<T>());
+            self.core.device.cmd_update_buffer(
+                self.command_buffer,
+                buffer.get_vk_buffer(),
+                0,
+                bytes_slice,
+            );
+        }
+    }
+
+    /// Sets every 4 byte word of the buffer to the given value.
+    pub fn fill_buffer(&self, buffer: &impl BufferWrapper, value: u32) {
+        unsafe {
+            self.core.device.cmd_fill_buffer(
+                self.command_buffer,
+                buffer.get_vk_buffer(),
+                0,
+                vk::WHOLE_SIZE,
+                value,
+            );
+        }
+    }
+
+    /// Sets every texel of the first mip level of every layer of an image to zero.
+    pub fn clear_image(&self, image: &impl ImageWrapper, layout: vk::ImageLayout) {
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: vk::REMAINING_ARRAY_LAYERS,
+        };
+        Self::check_layout(image, layout);
+        unsafe {
+            self.core.device.cmd_clear_color_image(
+                self.command_buffer,
+                image.get_vk_image(),
+                layout,
+                &vk::ClearColorValue { float32: [0.0; 4] },
+                &[range],
+            );
+        }
+    }
+
+    pub fn reset_query_pool(&self, query_pool: vk::QueryPool, first_query: u32, query_count: u32) {
+        unsafe {
+            self.core.device.cmd_reset_query_pool(
+                self.command_buffer,
+                query_pool,
+                first_query,
+                query_count,
+            );
+        }
+    }
+
+    /// Records the time at which all previously submitted commands have finished executing.
+    pub fn write_timestamp(&self, query_pool: vk::QueryPool, query: u32) {
+        unsafe {
+            self.core.device.cmd_write_timestamp(
+                self.command_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                query_pool,
+                query,
+            );
+        }
+    }
+
     // TODO: Allow for custom pipeline stage flag specification.
+    /// Transitions an image from whatever layout it is in, recording nothing if it is already in
+    /// the given layout. Only works for images whose layout is tracked, see ImageState.
+    pub fn transition_to(&self, image: &impl ImageWrapper, to: vk::ImageLayout) {
+        let state = image
+            .get_image_state()
+            .expect("Only images with tracked layouts can be transitioned automatically.");
+        let from = state.get_layout();
+        if from != to {
+            self.transition_layout(image, from, to);
+        }
+    }
+
+    fn check_layout(image: &impl ImageWrapper, layout: vk::ImageLayout) {
+        if let Some(state) = image.get_image_state() {
+            state.check_layout(layout);
+        }
+    }
+
     pub fn transition_layout(
         &self,
         image: &impl ImageWrapper,
@@ -188,6 +296,10 @@ impl CommandBuffer {
         to: vk::ImageLayout,
         mip_level_count: u32,
     ) {
+        let from = match image.get_image_state() {
+            Some(state) => state.begin_transition(from, to),
+            None => from,
+        };
         let image_barrier = vk::ImageMemoryBarrier {
             old_layout: from,
             new_layout: to,
@@ -199,7 +311,7 @@ impl CommandBuffer {
                 base_mip_level: 0,
                 level_count: mip_level_count,
                 base_array_layer: 0,
-                layer_count: 1,
+                layer_count: vk::REMAINING_ARRAY_LAYERS,
             },
             ..Default::default()
         };
@@ -248,6 +360,7 @@ impl CommandBuffer {
             ..Default::default()
         };
 
+        Self::check_layout(image, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
         unsafe {
             self.core.device.cmd_copy_buffer_to_image(
                 self.command_buffer,
@@ -259,6 +372,156 @@ impl CommandBuffer {
         }
     }
 
+    /// Makes the writes of commands in src_stage visible to commands in dst_stage.
+    pub fn memory_barrier(
+        &self,
+        src_stage: vk::PipelineStageFlags,
+        dst_stage: vk::PipelineStageFlags,
+    ) {
+        let barrier = vk::MemoryBarrier {
+            src_access_mask: vk::AccessFlags::MEMORY_WRITE,
+            dst_access_mask: vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE,
+            ..Default::default()
+        };
+        unsafe {
+            self.core.device.cmd_pipeline_barrier(
+                self.command_buffer,
+                src_stage,
+                dst_stage,
+                Default::default(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        }
+    }
+
+    /// Copies the entirety of one image to another of the same size, converting between their
+    /// formats. The source must be in the GENERAL layout and the destination in the
+    /// TRANSFER_DST_OPTIMAL layout.
+    pub fn blit_image(
+        &self,
+        source: &impl ImageWrapper,
+        destination: &impl ImageWrapper,
+        extent: &impl ExtentWrapper,
+    ) {
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let extent = extent.get_vk_extent();
+        let corners = [
+            vk::Offset3D { x: 0, y: 0, z: 0 },
+            vk::Offset3D {
+                x: extent.width as i32,
+                y: extent.height as i32,
+                z: extent.depth as i32,
+            },
+        ];
+        let blit_info = vk::ImageBlit {
+            src_subresource: subresource,
+            src_offsets: corners,
+            dst_subresource: subresource,
+            dst_offsets: corners,
+        };
+        Self::check_layout(source, vk::ImageLayout::GENERAL);
+        Self::check_layout(destination, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+        unsafe {
+            self.core.device.cmd_blit_image(
+                self.command_buffer,
+                source.get_vk_image(),
+                vk::ImageLayout::GENERAL,
+                destination.get_vk_image(),
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[blit_info],
+                vk::Filter::NEAREST,
+            );
+        }
+    }
+
+    /// Stretches the entirety of one image over the entirety of another, which can be a different
+    /// size and format. Both images must be in the GENERAL layout. Integer formats can only be
+    /// resampled with NEAREST filtering.
+    pub fn resample_image<Image: ImageWrapper + ExtentWrapper>(
+        &self,
+        source: &Image,
+        destination: &Image,
+        filter: vk::Filter,
+    ) {
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let corners = |image: &Image| {
+            let extent = image.get_vk_extent();
+            [
+                vk::Offset3D { x: 0, y: 0, z: 0 },
+                vk::Offset3D {
+                    x: extent.width as i32,
+                    y: extent.height as i32,
+                    z: extent.depth as i32,
+                },
+            ]
+        };
+        let blit_info = vk::ImageBlit {
+            src_subresource: subresource,
+            src_offsets: corners(source),
+            dst_subresource: subresource,
+            dst_offsets: corners(destination),
+        };
+        Self::check_layout(source, vk::ImageLayout::GENERAL);
+        Self::check_layout(destination, vk::ImageLayout::GENERAL);
+        unsafe {
+            self.core.device.cmd_blit_image(
+                self.command_buffer,
+                source.get_vk_image(),
+                vk::ImageLayout::GENERAL,
+                destination.get_vk_image(),
+                vk::ImageLayout::GENERAL,
+                &[blit_info],
+                filter,
+            );
+        }
+    }
+
+    /// Copies the entirety of one image to another of the same size. Both images must be in the
+    /// GENERAL layout.
+    pub fn copy_image(
+        &self,
+        source: &impl ImageWrapper,
+        destination: &impl ImageWrapper,
+        extent: &impl ExtentWrapper,
+    ) {
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let copy_info = vk::ImageCopy {
+            src_subresource: subresource,
+            dst_subresource: subresource,
+            extent: extent.get_vk_extent(),
+            ..Default::default()
+        };
+        Self::check_layout(source, vk::ImageLayout::GENERAL);
+        Self::check_layout(destination, vk::ImageLayout::GENERAL);
+        unsafe {
+            self.core.device.cmd_copy_image(
+                self.command_buffer,
+                source.get_vk_image(),
+                vk::ImageLayout::GENERAL,
+                destination.get_vk_image(),
+                vk::ImageLayout::GENERAL,
+                &[copy_info],
+            );
+        }
+    }
+
     pub fn copy_buffer_to_image_offset(
         &self,
         data_buffer: &impl BufferWrapper,
@@ -286,6 +549,7 @@ impl CommandBuffer {
             ..Default::default()
         };
 
+        Self::check_layout(image, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
         unsafe {
             self.core.device.cmd_copy_buffer_to_image(
                 self.command_buffer,
@@ -338,6 +602,7 @@ impl CommandBuffer {
             ..Default::default()
         };
 
+        Self::check_layout(image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
         unsafe {
             self.core.device.cmd_copy_image_to_buffer(
                 self.command_buffer,
@@ -386,6 +651,8 @@ impl CommandBuffer {
             ..Default::default()
         };
 
+        Self::check_layout(source, vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
+        Self::check_layout(dest, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
         unsafe {
             self.core.device.cmd_copy_image(
                 self.command_buffer,
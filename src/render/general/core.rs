@@ -2,11 +2,15 @@ use ash::extensions::{ext::DebugUtils, khr::Surface};
 use ash::version::DeviceV1_0;
 use ash::version::InstanceV1_0;
 use ash::vk::{self, Handle};
+use std::cell::{Ref, RefCell};
 use winit::window::Window;
 
-use crate::render::constants::*;
+use crate::render::OutputFormat;
 
 use super::debug;
+use super::descriptors::DescriptorRegistry;
+use super::features::DeviceFeatures;
+use super::lifetime::{DeferredObject, LifetimeTracker};
 
 pub struct Core {
     pub entry: ash::Entry,
@@ -19,13 +23,27 @@ pub struct Core {
     pub surface: vk::SurfaceKHR,
     pub debug_messenger: vk::DebugUtilsMessengerEXT,
     pub memory_properties: vk::PhysicalDeviceMemoryProperties,
-    pub swapchain: SwapChainInfo,
+    // Replaced whenever the window changes size, see recreate_swapchain.
+    pub(super) swapchain: RefCell<SwapChainInfo>,
+    // Whether the swapchain waits for vertical blank, so that it is recreated the same way.
+    pub(super) vsync: bool,
+    // The output format from the settings, which the swapchain may have fallen back from.
+    pub(super) output_format: OutputFormat,
     pub window: Box<Window>,
 
     pub queue_family_indices: QueueFamilyIndices,
     pub compute_queue: vk::Queue,
     pub present_queue: vk::Queue,
     pub command_pool: vk::CommandPool,
+    // Which of OPTIONAL_DEVICE_EXTENSIONS the device supports and were enabled.
+    pub optional_extensions: Vec<&'static str>,
+    pub features: DeviceFeatures,
+    // Whether validation layers and the debug messenger were enabled.
+    pub validation: bool,
+    // Objects which were dropped while frames using them might still be rendering.
+    pub(super) lifetimes: RefCell<LifetimeTracker<DeferredObject>>,
+    // Used to check that descriptor sets are bound to stages which expect them.
+    pub(super) descriptor_registry: RefCell<DescriptorRegistry>,
 }
 
 impl Core {
@@ -80,21 +98,143 @@ impl Core {
         panic!("Could not find appropriate memory type!");
     }
 
+    pub fn create_timestamp_query_pool(&self, query_count: u32, debug_name: &str) -> vk::QueryPool {
+        let create_info = vk::QueryPoolCreateInfo {
+            query_type: vk::QueryType::TIMESTAMP,
+            query_count,
+            ..Default::default()
+        };
+        let query_pool = unsafe {
+            self.device
+                .create_query_pool(&create_info, None)
+                .expect("Failed to create query pool.")
+        };
+        self.set_debug_name(query_pool, debug_name);
+        query_pool
+    }
+
+    pub fn get_physical_device_limits(&self) -> vk::PhysicalDeviceLimits {
+        unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+                .limits
+        }
+    }
+
+    /// The kind of GPU and how many bytes of device local memory it has, summed over every heap.
+    pub fn get_device_type_and_memory(&self) -> (vk::PhysicalDeviceType, u64) {
+        let (properties, memory) = unsafe {
+            (
+                self.instance
+                    .get_physical_device_properties(self.physical_device),
+                self.instance
+                    .get_physical_device_memory_properties(self.physical_device),
+            )
+        };
+        let heaps = &memory.memory_heaps[..memory.memory_heap_count as usize];
+        let device_local = heaps
+            .iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum();
+        (properties.device_type, device_local)
+    }
+
+    /// True if images of the given format can be used as storage images in compute shaders.
+    pub fn supports_storage_image_format(&self, format: vk::Format) -> bool {
+        self.supports_format_features(format, vk::FormatFeatureFlags::STORAGE_IMAGE)
+    }
+
+    /// True if optimally tiled images of the given format support all the given features.
+    pub fn supports_format_features(
+        &self,
+        format: vk::Format,
+        features: vk::FormatFeatureFlags,
+    ) -> bool {
+        let properties = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.physical_device, format)
+        };
+        properties.optimal_tiling_features.contains(features)
+    }
+
+    /// The swapchain can be replaced by recreate_swapchain, so the returned reference must be
+    /// dropped before that is called.
+    pub fn borrow_swapchain(&self) -> Ref<'_, SwapChainInfo> {
+        self.swapchain.borrow()
+    }
+
+    pub fn has_optional_extension(&self, name: &str) -> bool {
+        self.optional_extensions.contains(&name)
+    }
+
     pub fn set_debug_name<VkObject: Handle>(&self, object: VkObject, name: &str) {
         debug::set_debug_name(&self.device, &self.ext_debug_utils, object, name);
     }
+
+    /// Destroys the objects once every frame which was submitted before this call has finished
+    /// rendering, so that wrappers can be dropped while command buffers still refer to them.
+    pub fn destroy_deferred(&self, objects: &[DeferredObject]) {
+        let mut lifetimes = self.lifetimes.borrow_mut();
+        let mut registry = self.descriptor_registry.borrow_mut();
+        for object in objects {
+            // Nothing can be bound with them after this, and their handles may be reused.
+            match object {
+                DeferredObject::DescriptorPool(pool) => registry.forget_pool(*pool),
+                DeferredObject::DescriptorSetLayout(layout) => registry.forget_layout(*layout),
+                _ => (),
+            }
+            if let Some(object) = lifetimes.defer(*object) {
+                unsafe { object.destroy(&self.device) };
+            }
+        }
+    }
+
+    /// Lets command buffers check that descriptor sets bound with this pipeline layout match the
+    /// layouts it was created with. Must be forgotten before the pipeline layout is destroyed.
+    pub fn register_pipeline_layout(
+        &self,
+        pipeline_layout: vk::PipelineLayout,
+        name: &str,
+        set_layouts: &[vk::DescriptorSetLayout],
+    ) {
+        self.descriptor_registry
+            .borrow_mut()
+            .register_pipeline_layout(pipeline_layout, name, set_layouts);
+    }
+
+    pub fn forget_pipeline_layout(&self, pipeline_layout: vk::PipelineLayout) {
+        self.descriptor_registry
+            .borrow_mut()
+            .forget_pipeline_layout(pipeline_layout);
+    }
+
+    /// Must be called right after every frame is submitted to the GPU.
+    pub fn on_frame_submitted(&self) {
+        self.lifetimes.borrow_mut().on_frame_submitted();
+    }
+
+    /// Must be called once the fence of the oldest frame in flight has been waited on. Destroys
+    /// everything that frame was the last to use.
+    pub fn on_frame_completed(&self) {
+        let released = self.lifetimes.borrow_mut().on_frame_completed();
+        for object in released {
+            unsafe { object.destroy(&self.device) };
+        }
+    }
 }
 
 impl Drop for Core {
     fn drop(&mut self) {
         unsafe {
-            for view in &self.swapchain.swapchain_image_views {
-                self.device.destroy_image_view(*view, None);
+            self.device
+                .device_wait_idle()
+                .expect("Failed to wait for device to finish rendering.");
+            for object in self.lifetimes.get_mut().take_all() {
+                object.destroy(&self.device);
             }
 
-            self.swapchain
-                .swapchain_loader
-                .destroy_swapchain(self.swapchain.swapchain, None);
+            self.swapchain.get_mut().destroy(&self.device);
 
             self.device.destroy_command_pool(self.command_pool, None);
 
@@ -102,7 +242,7 @@ impl Drop for Core {
 
             self.ext_surface.destroy_surface(self.surface, None);
 
-            if ENABLE_DEBUG {
+            if self.validation {
                 self.ext_debug_utils
                     .destroy_debug_utils_messenger(self.debug_messenger, None);
             }
@@ -129,5 +269,25 @@ pub struct SwapChainInfo {
     pub swapchain_images: Vec<vk::Image>,
     pub swapchain_format: vk::Format,
     pub swapchain_extent: vk::Extent2D,
+    pub swapchain_image_usage: vk::ImageUsageFlags,
     pub swapchain_image_views: Vec<vk::ImageView>,
+    // What the swapchain images actually hold, which is Sdr if the requested format is missing.
+    pub output_format: OutputFormat,
+}
+
+impl SwapChainInfo {
+    /// False if frames have to be rendered to a separate image and blitted to the swapchain.
+    pub fn is_storage(&self) -> bool {
+        self.swapchain_image_usage
+            .contains(vk::ImageUsageFlags::STORAGE)
+    }
+
+    /// Nothing may be using the swapchain images anymore.
+    pub(super) unsafe fn destroy(&self, device: &ash::Device) {
+        for view in &self.swapchain_image_views {
+            device.destroy_image_view(*view, None);
+        }
+        self.swapchain_loader
+            .destroy_swapchain(self.swapchain, None);
+    }
 }
@@ -3,6 +3,7 @@ use ash::version::DeviceV1_0;
 use ash::version::EntryV1_0;
 use ash::version::InstanceV1_0;
 use ash::vk;
+use std::cell::RefCell;
 use std::ffi::CString;
 use std::os::raw::{c_char, c_void};
 use std::ptr;
@@ -12,19 +13,27 @@ use winit::dpi::PhysicalSize;
 
 use crate::render::constants::*;
 use crate::render::util;
+use crate::render::{OutputFormat, RenderSettings};
 
 use super::core::{Core, QueueFamilyIndices, SwapChainInfo};
 use super::debug;
+use super::descriptors::DescriptorRegistry;
+use super::features::{self, DeviceFeatures};
+use super::lifetime::LifetimeTracker;
 use super::platform_specific;
 
 impl Core {
-    pub fn new(event_loop: &EventLoop<()>) -> Core {
+    pub fn new(event_loop: &EventLoop<()>, settings: &RenderSettings) -> Core {
         let entry = ash::Entry::new().unwrap();
-        let instance = create_instance(&entry, WINDOW_TITLE);
-        let (ext_debug_utils, debug_messenger) = debug::setup_debug_utils(&entry, &instance);
+        let validation = settings.validation;
+        let (instance, api_version) = create_instance(&entry, WINDOW_TITLE, validation);
+        let (ext_debug_utils, debug_messenger) =
+            debug::setup_debug_utils(&entry, &instance, validation);
+        let size = choose_window_size(event_loop, settings);
         let window = WindowBuilder::new()
             .with_title(WINDOW_TITLE)
-            .with_inner_size(PhysicalSize::new(WINDOW_WIDTH, WINDOW_HEIGHT))
+            .with_visible(!settings.headless)
+            .with_inner_size(size)
             .build(event_loop)
             .expect("Failed to create window.");
         let window = Box::new(window);
@@ -32,8 +41,13 @@ impl Core {
         let physical_device = pick_physical_device(&instance, &surface_info);
         let memory_properties =
             unsafe { instance.get_physical_device_memory_properties(physical_device) };
-        let (device, queue_family_indices) =
-            create_logical_device(&instance, physical_device, &surface_info);
+        let (device, queue_family_indices, optional_extensions, features) = create_logical_device(
+            &instance,
+            api_version,
+            physical_device,
+            &surface_info,
+            validation,
+        );
         let command_pool = create_command_pool(
             &device,
             &ext_debug_utils,
@@ -47,6 +61,9 @@ impl Core {
             &window,
             &surface_info,
             &queue_family_indices,
+            settings.vsync,
+            settings.output_format,
+            vk::SwapchainKHR::null(),
         );
         let compute_queue =
             unsafe { device.get_device_queue(queue_family_indices.compute.unwrap(), 0) };
@@ -64,13 +81,78 @@ impl Core {
             physical_device,
             memory_properties,
             device,
-            swapchain,
+            swapchain: RefCell::new(swapchain),
+            vsync: settings.vsync,
+            output_format: settings.output_format,
             compute_queue,
             present_queue,
             command_pool,
             window,
+            optional_extensions,
+            features,
+            validation,
+            lifetimes: RefCell::new(LifetimeTracker::new()),
+            descriptor_registry: RefCell::new(DescriptorRegistry::new()),
         }
     }
+
+    /// Replaces the swapchain with one that matches the current size of the window, waiting for
+    /// the device to finish with the old one first. Everything created from the old swapchain
+    /// images has to be recreated afterwards. Returns false without doing anything if the window
+    /// has no area, such as while it is minimized, since a swapchain can't be created for it.
+    pub fn recreate_swapchain(&self) -> bool {
+        let surface_info = SurfaceInfo {
+            ext_surface: self.ext_surface.clone(),
+            surface: self.surface,
+        };
+        let support = query_swapchain_support(self.physical_device, &surface_info);
+        let extent = choose_swapchain_extent(&support.capabilities, &self.window);
+        if extent.width == 0 || extent.height == 0 {
+            return false;
+        }
+        unsafe {
+            self.device
+                .device_wait_idle()
+                .expect("Failed to wait for device to finish rendering.");
+        }
+        let mut swapchain = self.swapchain.borrow_mut();
+        let new_swapchain = create_swapchain(
+            &self.instance,
+            &self.device,
+            &self.ext_debug_utils,
+            self.physical_device,
+            &self.window,
+            &surface_info,
+            &self.queue_family_indices,
+            self.vsync,
+            self.output_format,
+            swapchain.swapchain,
+        );
+        let old_swapchain = std::mem::replace(&mut *swapchain, new_swapchain);
+        unsafe { old_swapchain.destroy(&self.device) };
+        true
+    }
+}
+
+/// Returns the requested window size, shrunk to fit on the monitor if it is too large. Hidden
+/// windows can be any size. The swapchain may still end up a different size than this.
+fn choose_window_size(event_loop: &EventLoop<()>, settings: &RenderSettings) -> PhysicalSize<u32> {
+    let requested = PhysicalSize::new(settings.window_width, settings.window_height);
+    if settings.headless {
+        return requested;
+    }
+    let monitor = event_loop.primary_monitor().size();
+    let size = PhysicalSize::new(
+        requested.width.min(monitor.width),
+        requested.height.min(monitor.height),
+    );
+    if size != requested {
+        println!(
+            "WARNING: The window ({}x{}) does not fit on the monitor ({}x{}), shrinking it.",
+            requested.width, requested.height, monitor.width, monitor.height
+        );
+    }
+    size
 }
 
 pub struct SurfaceInfo {
@@ -84,11 +166,20 @@ pub struct SwapChainSupportInfo {
     pub present_modes: Vec<vk::PresentModeKHR>,
 }
 
-pub fn create_instance(entry: &ash::Entry, window_title: &str) -> ash::Instance {
-    if ENABLE_DEBUG && !check_validation_layer_support(entry) {
+/// Returns the instance along with the API version it was created with.
+pub fn create_instance(
+    entry: &ash::Entry,
+    window_title: &str,
+    validation: bool,
+) -> (ash::Instance, u32) {
+    if validation && !check_validation_layer_support(entry) {
         panic!("Validation layers requested, but not available!");
     }
 
+    let loader_version = entry
+        .try_enumerate_instance_version()
+        .expect("Failed to get the Vulkan loader version.");
+    let api_version = features::choose_api_version(loader_version);
     let app_name = CString::new(window_title).unwrap();
     let engine_name = CString::new("Vulkan Engine").unwrap();
     let app_info = vk::ApplicationInfo {
@@ -98,13 +189,24 @@ pub fn create_instance(entry: &ash::Entry, window_title: &str) -> ash::Instance
         application_version: APPLICATION_VERSION,
         p_engine_name: engine_name.as_ptr(),
         engine_version: ENGINE_VERSION,
-        api_version: API_VERSION,
+        api_version,
     };
 
     // This create info used to debug issues in vk::createInstance and vk::destroyInstance.
     let debug_utils_create_info = debug::build_debug_utils_create_info();
 
-    let extension_names = platform_specific::required_extension_names();
+    let mut extension_names = platform_specific::required_extension_names();
+    // Surfaces only report the color spaces of HDR output formats when this is enabled.
+    let colorspace_name = vk::ExtSwapchainColorspaceFn::name();
+    let available_extensions = entry
+        .enumerate_instance_extension_properties()
+        .expect("Failed to enumerate instance extensions.");
+    let supports_colorspace = available_extensions.iter().any(|extension| {
+        util::convert_raw_cstring(&extension.extension_name) == colorspace_name.to_str().unwrap()
+    });
+    if supports_colorspace {
+        extension_names.push(colorspace_name.as_ptr());
+    }
 
     let validation_layer_names: Vec<CString> = VALIDATION_LAYERS
         .iter()
@@ -117,19 +219,19 @@ pub fn create_instance(entry: &ash::Entry, window_title: &str) -> ash::Instance
 
     let create_info = vk::InstanceCreateInfo {
         s_type: vk::StructureType::INSTANCE_CREATE_INFO,
-        p_next: if ENABLE_DEBUG {
+        p_next: if validation {
             &debug_utils_create_info as *const vk::DebugUtilsMessengerCreateInfoEXT as *const c_void
         } else {
             ptr::null()
         },
         flags: vk::InstanceCreateFlags::empty(),
         p_application_info: &app_info,
-        pp_enabled_layer_names: if ENABLE_DEBUG {
+        pp_enabled_layer_names: if validation {
             validation_layer_name_pointers.as_ptr()
         } else {
             ptr::null()
         },
-        enabled_layer_count: if ENABLE_DEBUG {
+        enabled_layer_count: if validation {
             validation_layer_name_pointers.len()
         } else {
             0
@@ -144,7 +246,7 @@ pub fn create_instance(entry: &ash::Entry, window_title: &str) -> ash::Instance
             .expect("Failed to create Vulkan instance!")
     };
 
-    instance
+    (instance, api_version)
 }
 
 pub fn check_validation_layer_support(entry: &ash::Entry) -> bool {
@@ -246,9 +348,16 @@ pub fn is_physical_device_suitable(
 
 pub fn create_logical_device(
     instance: &ash::Instance,
+    instance_version: u32,
     physical_device: vk::PhysicalDevice,
     surface_info: &SurfaceInfo,
-) -> (ash::Device, QueueFamilyIndices) {
+    validation: bool,
+) -> (
+    ash::Device,
+    QueueFamilyIndices,
+    Vec<&'static str>,
+    DeviceFeatures,
+) {
     let indices = find_queue_family(instance, physical_device, surface_info);
 
     use std::collections::HashSet;
@@ -281,8 +390,22 @@ pub fn create_logical_device(
         .map(|layer_name| layer_name.as_ptr())
         .collect();
 
+    let available_extensions = get_device_extensions(instance, physical_device);
+    let optional_extensions: Vec<&'static str> = OPTIONAL_DEVICE_EXTENSIONS
+        .iter()
+        .cloned()
+        .filter(|extension| available_extensions.iter().any(|name| name == extension))
+        .collect();
+    let (features, feature_extensions, feature_chain) = features::query_features(
+        instance,
+        instance_version,
+        physical_device,
+        &available_extensions,
+    );
     let device_extension_cstrings: Vec<CString> = DEVICE_EXTENSIONS
         .iter()
+        .chain(optional_extensions.iter())
+        .chain(feature_extensions.iter())
         .map(|extension_name| CString::new(*extension_name).unwrap())
         .collect();
     let device_extension_cstring_pointers: Vec<*const c_char> = device_extension_cstrings
@@ -290,25 +413,30 @@ pub fn create_logical_device(
         .map(|extension_name_cstring| extension_name_cstring.as_ptr())
         .collect();
 
+    // When features are enabled through the chain, the basic features are part of it too.
+    let (p_next, p_enabled_features) = match &feature_chain {
+        Some(chain) => (chain.as_next_pointer(), ptr::null()),
+        None => (ptr::null(), &physical_device_features as *const _),
+    };
     let device_create_info = vk::DeviceCreateInfo {
         s_type: vk::StructureType::DEVICE_CREATE_INFO,
-        p_next: ptr::null(),
+        p_next,
         flags: vk::DeviceCreateFlags::empty(),
         queue_create_info_count: queue_create_infos.len() as u32,
         p_queue_create_infos: queue_create_infos.as_ptr(),
-        enabled_layer_count: if ENABLE_DEBUG {
+        enabled_layer_count: if validation {
             enable_layer_names.len()
         } else {
             0
         } as u32,
-        pp_enabled_layer_names: if ENABLE_DEBUG {
+        pp_enabled_layer_names: if validation {
             enable_layer_names.as_ptr()
         } else {
             ptr::null()
         },
         enabled_extension_count: device_extension_cstring_pointers.len() as u32,
         pp_enabled_extension_names: device_extension_cstring_pointers.as_ptr(),
-        p_enabled_features: &physical_device_features,
+        p_enabled_features,
     };
 
     let device: ash::Device = unsafe {
@@ -317,11 +445,15 @@ pub fn create_logical_device(
             .expect("Failed to create logical Device!")
     };
 
-    if ENABLE_DEBUG {
+    if validation {
         println!("Validation layers enabled!");
     }
+    let enabled_features = features.get_enabled_names();
+    if enabled_features.len() > 0 {
+        println!("Optional features enabled: {}", enabled_features.join(", "));
+    }
 
-    (device, indices)
+    (device, indices, optional_extensions, features)
 }
 
 pub fn find_queue_family(
@@ -331,45 +463,63 @@ pub fn find_queue_family(
 ) -> QueueFamilyIndices {
     let queue_families =
         unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+    let capabilities: Vec<_> = queue_families
+        .iter()
+        .enumerate()
+        .map(|(index, queue_family)| {
+            let is_present_support = unsafe {
+                surface_info
+                    .ext_surface
+                    .get_physical_device_surface_support(
+                        physical_device,
+                        index as u32,
+                        surface_info.surface,
+                    )
+            };
+            (*queue_family, is_present_support)
+        })
+        .collect();
+    choose_queue_families(&capabilities)
+}
 
-    let mut queue_family_indices = QueueFamilyIndices::default();
-
-    let mut index = 0;
-    for queue_family in queue_families.iter() {
-        if queue_family.queue_count > 0
-            && queue_family.queue_flags.contains(vk::QueueFlags::COMPUTE)
-            && queue_family.queue_flags.contains(vk::QueueFlags::TRANSFER)
-        {
-            queue_family_indices.compute = Some(index);
-        }
+/// Takes the properties of each queue family and whether it can present to the surface. A single
+/// family which can do both is preferred, since then every image can be used by both queues
+/// without any extra work. Otherwise the first family which can do each is used, and the swapchain
+/// is shared between them, see create_swapchain.
+fn choose_queue_families(
+    queue_families: &[(vk::QueueFamilyProperties, bool)],
+) -> QueueFamilyIndices {
+    let can_compute = |properties: &vk::QueueFamilyProperties| {
+        properties.queue_count > 0
+            && properties.queue_flags.contains(vk::QueueFlags::COMPUTE)
+            && properties.queue_flags.contains(vk::QueueFlags::TRANSFER)
+    };
+    let can_present = |(properties, is_present_support): &(vk::QueueFamilyProperties, bool)| {
+        properties.queue_count > 0 && *is_present_support
+    };
+    let find = |predicate: &dyn Fn(&(vk::QueueFamilyProperties, bool)) -> bool| {
+        queue_families
+            .iter()
+            .position(|family| predicate(family))
+            .map(|index| index as u32)
+    };
 
-        let is_present_support = unsafe {
-            surface_info
-                .ext_surface
-                .get_physical_device_surface_support(
-                    physical_device,
-                    index as u32,
-                    surface_info.surface,
-                )
+    if let Some(index) = find(&|family| can_compute(&family.0) && can_present(family)) {
+        return QueueFamilyIndices {
+            compute: Some(index),
+            present: Some(index),
         };
-        if queue_family.queue_count > 0 && is_present_support {
-            queue_family_indices.present = Some(index);
-        }
-
-        if queue_family_indices.is_complete() {
-            break;
-        }
-
-        index += 1;
     }
-
-    queue_family_indices
+    QueueFamilyIndices {
+        compute: find(&|family| can_compute(&family.0)),
+        present: find(&can_present),
+    }
 }
 
-pub fn check_device_extension_support(
+fn get_device_extensions(
     instance: &ash::Instance,
     physical_device: vk::PhysicalDevice,
-) -> bool {
+) -> Vec<String> {
     let available_extensions = unsafe {
         instance
             .enumerate_device_extension_properties(physical_device)
@@ -383,6 +533,14 @@ pub fn check_device_extension_support(
 
         available_extension_names.push(extension_name);
     }
+    available_extension_names
+}
+
+pub fn check_device_extension_support(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> bool {
+    let available_extension_names = get_device_extensions(instance, physical_device);
 
     use std::collections::HashSet;
     let mut required_extensions = HashSet::new();
@@ -429,6 +587,8 @@ fn create_command_pool(
     queue_family_index: u32,
 ) -> vk::CommandPool {
     let create_info = vk::CommandPoolCreateInfo {
+        // Lets the pipeline re-record its command buffers when its settings change.
+        flags: vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
         queue_family_index,
         ..Default::default()
     };
@@ -449,12 +609,65 @@ pub fn create_swapchain(
     window: &winit::window::Window,
     surface_info: &SurfaceInfo,
     queue_family: &QueueFamilyIndices,
+    vsync: bool,
+    output_format: OutputFormat,
+    // The swapchain being replaced, or null. Passing it lets the driver reuse its resources.
+    old_swapchain: vk::SwapchainKHR,
 ) -> SwapChainInfo {
     let swapchain_support = query_swapchain_support(physical_device, surface_info);
 
-    let surface_format = choose_swapchain_format(&swapchain_support.formats);
-    let present_mode = choose_swapchain_present_mode(&swapchain_support.present_modes);
+    // Copying from the swapchain images allows screenshots to be taken, but is not required.
+    let supported_usage = swapchain_support.capabilities.supported_usage_flags;
+    let get_format_features = |format| unsafe {
+        instance
+            .get_physical_device_format_properties(physical_device, format)
+            .optimal_tiling_features
+    };
+    let supports_storage = |format| {
+        supported_usage.contains(vk::ImageUsageFlags::STORAGE)
+            && get_format_features(format).contains(vk::FormatFeatureFlags::STORAGE_IMAGE)
+    };
+    let supports_blit = |format| {
+        supported_usage.contains(vk::ImageUsageFlags::TRANSFER_DST)
+            && get_format_features(format).contains(vk::FormatFeatureFlags::BLIT_DST)
+    };
+    let (surface_format, chosen_output_format) = choose_swapchain_format(
+        &swapchain_support.formats,
+        output_format,
+        supports_storage,
+        supports_blit,
+    );
+    if chosen_output_format != output_format {
+        println!(
+            "WARNING: The display does not support the {} output format, using {} instead.",
+            output_format, chosen_output_format
+        );
+    }
+    let output_format = chosen_output_format;
+    let present_mode = choose_swapchain_present_mode(&swapchain_support.present_modes, vsync);
     let extent = choose_swapchain_extent(&swapchain_support.capabilities, window);
+    let storage = !output_format.uses_float_output() && supports_storage(surface_format.format);
+    let main_usage = if storage {
+        vk::ImageUsageFlags::STORAGE
+    } else {
+        // Frames are rendered to a separate image which is blitted to the swapchain instead.
+        if !supports_blit(surface_format.format) {
+            panic!(
+                "The swapchain images ({:?}) can neither be written to by compute shaders nor \
+                blitted to.",
+                surface_format.format
+            );
+        }
+        if !output_format.uses_float_output() {
+            println!(
+                "WARNING: The swapchain images ({:?}) can't be written to by compute shaders, so \
+                frames are rendered to a separate image and copied over. This is a little slower.",
+                surface_format.format
+            );
+        }
+        vk::ImageUsageFlags::TRANSFER_DST
+    };
+    let image_usage = main_usage | (supported_usage & vk::ImageUsageFlags::TRANSFER_SRC);
 
     let image_count = swapchain_support.capabilities.min_image_count + 1;
     let image_count = if swapchain_support.capabilities.max_image_count > 0 {
@@ -463,6 +676,10 @@ pub fn create_swapchain(
         image_count
     };
 
+    // Swapchain images are written on the compute queue and presented on the present queue.
+    // Sharing them concurrently lets both use them without transferring ownership back and forth,
+    // which is all that is needed since the present queue waits on the frame_complete semaphore
+    // and every other image is only ever used on the compute queue.
     let (image_sharing_mode, queue_family_index_count, queue_family_indices) =
         if queue_family.compute != queue_family.present {
             (
@@ -483,7 +700,7 @@ pub fn create_swapchain(
         image_color_space: surface_format.color_space,
         image_format: surface_format.format,
         image_extent: extent,
-        image_usage: vk::ImageUsageFlags::STORAGE,
+        image_usage,
         image_sharing_mode,
         p_queue_family_indices: queue_family_indices.as_ptr(),
         queue_family_index_count,
@@ -491,7 +708,7 @@ pub fn create_swapchain(
         composite_alpha: vk::CompositeAlphaFlagsKHR::OPAQUE,
         present_mode,
         clipped: vk::TRUE,
-        old_swapchain: vk::SwapchainKHR::null(),
+        old_swapchain,
         image_array_layers: 1,
     };
 
@@ -549,28 +766,64 @@ pub fn create_swapchain(
         swapchain,
         swapchain_format: surface_format.format,
         swapchain_extent: extent,
+        swapchain_image_usage: image_usage,
         swapchain_images,
         swapchain_image_views,
+        output_format,
     }
 }
 
+/// Returns a surface format for the requested output format along with the output format it is
+/// for, which is Sdr if none of the formats of the requested one can be blitted to in its color
+/// space. For Sdr, 8 bit UNORM formats are preferred since the shaders apply gamma themselves, and
+/// among those the ones which compute shaders can write to directly so that frames don't have to
+/// be blitted.
 pub fn choose_swapchain_format(
     available_formats: &Vec<vk::SurfaceFormatKHR>,
-) -> vk::SurfaceFormatKHR {
-    for available_format in available_formats {
-        if available_format.format == vk::Format::B8G8R8A8_UNORM
-            && available_format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
-        {
-            return available_format.clone();
+    requested: OutputFormat,
+    supports_storage: impl Fn(vk::Format) -> bool,
+    supports_blit: impl Fn(vk::Format) -> bool,
+) -> (vk::SurfaceFormatKHR, OutputFormat) {
+    if requested != OutputFormat::Sdr {
+        let color_space = requested.get_color_space();
+        for &format in requested.get_vk_formats() {
+            let found = available_formats.iter().find(|available_format| {
+                available_format.format == format && available_format.color_space == color_space
+            });
+            match found {
+                Some(available_format) if supports_blit(format) => {
+                    return (*available_format, requested);
+                }
+                _ => (),
+            }
         }
     }
-
-    return available_formats.first().unwrap().clone();
+    let is_unorm = |available_format: &&vk::SurfaceFormatKHR| {
+        OutputFormat::Sdr
+            .get_vk_formats()
+            .contains(&available_format.format)
+            && available_format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+    };
+    let mut unorm_formats = available_formats.iter().filter(is_unorm);
+    let storage_format = unorm_formats
+        .clone()
+        .find(|available_format| supports_storage(available_format.format));
+    let format = storage_format
+        .or_else(|| unorm_formats.next())
+        .unwrap_or_else(|| available_formats.first().unwrap())
+        .clone();
+    (format, OutputFormat::Sdr)
 }
 
 pub fn choose_swapchain_present_mode(
     available_present_modes: &Vec<vk::PresentModeKHR>,
+    vsync: bool,
 ) -> vk::PresentModeKHR {
+    // FIFO is the only mode that is guaranteed to be available, and it blocks when presenting
+    // faster than the display refreshes.
+    if vsync {
+        return vk::PresentModeKHR::FIFO;
+    }
     for &available_present_mode in available_present_modes.iter() {
         if available_present_mode == vk::PresentModeKHR::MAILBOX {
             return available_present_mode;
@@ -605,3 +858,80 @@ pub fn choose_swapchain_extent(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swapchain_format_prefers_storage() {
+        let make_format = |format| vk::SurfaceFormatKHR {
+            format,
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+        };
+        let formats = vec![
+            make_format(vk::Format::B8G8R8A8_SRGB),
+            make_format(vk::Format::B8G8R8A8_UNORM),
+            make_format(vk::Format::R8G8B8A8_UNORM),
+        ];
+        let choose = |formats: &Vec<_>, storage: fn(vk::Format) -> bool| {
+            let sdr = OutputFormat::Sdr;
+            let (chosen, output) = choose_swapchain_format(formats, sdr, storage, |_| true);
+            assert_eq!(output, sdr);
+            chosen.format
+        };
+        let storage = |format| format == vk::Format::R8G8B8A8_UNORM;
+        assert_eq!(choose(&formats, storage), vk::Format::R8G8B8A8_UNORM);
+        assert_eq!(choose(&formats, |_| false), vk::Format::B8G8R8A8_UNORM);
+        let srgb_only = formats[..1].to_vec();
+        assert_eq!(choose(&srgb_only, |_| false), vk::Format::B8G8R8A8_SRGB);
+    }
+
+    #[test]
+    fn swapchain_format_falls_back_to_sdr() {
+        let formats = vec![
+            vk::SurfaceFormatKHR {
+                format: vk::Format::B8G8R8A8_UNORM,
+                color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            },
+            vk::SurfaceFormatKHR {
+                format: vk::Format::A2B10G10R10_UNORM_PACK32,
+                color_space: vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+            },
+        ];
+        let choose = |requested, blit: fn(vk::Format) -> bool| {
+            let (chosen, output) = choose_swapchain_format(&formats, requested, |_| true, blit);
+            (chosen.format, output)
+        };
+        let hdr10 = (vk::Format::A2B10G10R10_UNORM_PACK32, OutputFormat::Hdr10);
+        let sdr = (vk::Format::B8G8R8A8_UNORM, OutputFormat::Sdr);
+        assert_eq!(choose(OutputFormat::Hdr10, |_| true), hdr10);
+        // The 10 bit format is only there in the HDR10 color space.
+        assert_eq!(choose(OutputFormat::Sdr10, |_| true), sdr);
+        assert_eq!(choose(OutputFormat::Hdr10, |_| false), sdr);
+    }
+
+    #[test]
+    fn queue_families_are_shared_when_possible() {
+        let make_family = |queue_flags, is_present_support| {
+            let properties = vk::QueueFamilyProperties {
+                queue_flags,
+                queue_count: 1,
+                ..Default::default()
+            };
+            (properties, is_present_support)
+        };
+        let compute = vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER;
+        let families = vec![
+            make_family(compute, false),
+            make_family(vk::QueueFlags::TRANSFER, true),
+            make_family(compute | vk::QueueFlags::GRAPHICS, true),
+        ];
+        let chosen = choose_queue_families(&families);
+        assert_eq!((chosen.compute, chosen.present), (Some(2), Some(2)));
+        let chosen = choose_queue_families(&families[..2]);
+        assert_eq!((chosen.compute, chosen.present), (Some(0), Some(1)));
+        let chosen = choose_queue_families(&families[..1]);
+        assert!(!chosen.is_complete());
+    }
+}
@@ -1,10 +1,18 @@
 use ash::extensions::ext::DebugUtils;
 use ash::vk::{self, Handle};
 use colored::*;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
 use std::ffi::{c_void, CStr, CString};
+use std::os::raw::c_char;
 use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use crate::render::constants::*;
+/// At most this many messages are printed in full each second, the rest are counted and
+/// mentioned once the second is over.
+const MAX_MESSAGES_PER_SECOND: usize = 20;
 
 fn name_of_type(typ: vk::ObjectType) -> &'static str {
     match typ {
@@ -49,6 +57,114 @@ fn name_of_type(typ: vk::ObjectType) -> &'static str {
     }
 }
 
+static ERROR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// What to do with a message from the validation layers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Verdict {
+    /// Print the whole message, after mentioning how many were suppressed by the rate limit
+    /// since the last one was printed.
+    Print {
+        suppressed: usize,
+    },
+    /// Print a single line saying the message has now been seen this many times.
+    Repeated {
+        count: usize,
+    },
+    Skip,
+}
+
+/// Keeps messages which repeat every frame from flooding the console. Each distinct message is
+/// printed in full the first time it is seen, after that only a line saying how many times it
+/// has been seen is printed when that reaches a power of ten.
+struct MessageFilter {
+    counts: HashMap<String, usize>,
+    window_start: Option<Instant>,
+    printed_in_window: usize,
+    suppressed: usize,
+}
+
+impl MessageFilter {
+    fn new() -> Self {
+        Self {
+            counts: HashMap::new(),
+            window_start: None,
+            printed_in_window: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Key should identify the message, such as its message ID name.
+    fn on_message(&mut self, key: &str, now: Instant) -> Verdict {
+        let count = self.counts.entry(key.to_owned()).or_insert(0);
+        *count += 1;
+        if *count > 1 {
+            let count = *count;
+            let mut power = 10;
+            while power < count {
+                power *= 10;
+            }
+            return if count == power {
+                Verdict::Repeated { count }
+            } else {
+                Verdict::Skip
+            };
+        }
+        let window_over = match self.window_start {
+            Some(start) => now.duration_since(start) >= Duration::from_secs(1),
+            None => true,
+        };
+        if window_over {
+            self.window_start = Some(now);
+            self.printed_in_window = 0;
+        }
+        if self.printed_in_window >= MAX_MESSAGES_PER_SECOND {
+            self.suppressed += 1;
+            // Otherwise the message would be treated as a repeat the next time it is seen, and
+            // never printed.
+            self.counts.remove(key);
+            return Verdict::Skip;
+        }
+        self.printed_in_window += 1;
+        let suppressed = self.suppressed;
+        self.suppressed = 0;
+        Verdict::Print { suppressed }
+    }
+}
+
+lazy_static! {
+    static ref MESSAGE_FILTER: Mutex<MessageFilter> = Mutex::new(MessageFilter::new());
+}
+
+/// Copies a string from the validation layers, which may be null.
+unsafe fn c_str_or(pointer: *const c_char, default: &str) -> String {
+    if pointer.is_null() {
+        default.to_owned()
+    } else {
+        CStr::from_ptr(pointer).to_string_lossy().into_owned()
+    }
+}
+
+/// One line for each object a message is about, with the names given by set_debug_name.
+unsafe fn format_objects(objects: &[vk::DebugUtilsObjectNameInfoEXT]) -> String {
+    let mut formatted = String::new();
+    for object in objects {
+        formatted.push_str(&format!(
+            "\n > {} {:#x} \"{}\"",
+            name_of_type(object.object_type),
+            object.object_handle,
+            c_str_or(object.p_object_name, "unnamed")
+        ));
+    }
+    formatted
+}
+
+/// How many errors the validation layers have reported. Always zero in release builds, which do
+/// not enable the validation layers.
+pub fn get_error_count() -> usize {
+    ERROR_COUNT.load(Ordering::Relaxed)
+}
+
 unsafe extern "system" fn vulkan_debug_utils_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     message_type: vk::DebugUtilsMessageTypeFlagsEXT,
@@ -68,9 +184,34 @@ unsafe extern "system" fn vulkan_debug_utils_callback(
         vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION => "[Validation]",
         _ => "[Unknown]",
     };
-    let message_cstring = CStr::from_ptr((*p_callback_data).p_message).to_owned();
+    let callback_data = &*p_callback_data;
+    let message_cstring = CStr::from_ptr(callback_data.p_message).to_owned();
     let message = message_cstring.to_string_lossy().to_owned();
 
+    if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    // General messages often have no ID, in which case the message itself is used.
+    let id_name = c_str_or(callback_data.p_message_id_name, &message);
+    let verdict = match MESSAGE_FILTER.lock() {
+        Ok(mut filter) => filter.on_message(&id_name, Instant::now()),
+        Err(..) => Verdict::Print { suppressed: 0 },
+    };
+    let suppressed = match verdict {
+        Verdict::Print { suppressed } => suppressed,
+        Verdict::Repeated { count } => {
+            println!("[Debug] {} has now been reported {} times.", id_name, count);
+            return vk::FALSE;
+        }
+        Verdict::Skip => return vk::FALSE,
+    };
+    if suppressed > 0 {
+        println!(
+            "[Debug] {} more messages were not shown to avoid flooding the console.",
+            suppressed
+        );
+    }
+
     let mut formatted_error =
         if message_type.contains(vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION) {
             if message.contains("The Vulkan spec states:") {
@@ -91,6 +232,12 @@ unsafe extern "system" fn vulkan_debug_utils_callback(
         } else {
             message.into()
         };
+    let objects = if callback_data.object_count == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(callback_data.p_objects, callback_data.object_count as usize)
+    };
+    formatted_error.push_str(&format_objects(objects));
 
     let header = format!("[Debug]{}{}", severity, types);
     let header = if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
@@ -105,6 +252,9 @@ unsafe extern "system" fn vulkan_debug_utils_callback(
         header.white()
     };
     println!("{}\n{}", header, formatted_error);
+    crate::telemetry::record(|telemetry| {
+        telemetry.record_debug_message(format!("[Debug]{}{}\n{}", severity, types, formatted_error))
+    });
 
     vk::FALSE
 }
@@ -133,10 +283,11 @@ pub fn set_debug_name<VkObject: Handle>(
 pub fn setup_debug_utils(
     entry: &ash::Entry,
     instance: &ash::Instance,
+    validation: bool,
 ) -> (ash::extensions::ext::DebugUtils, vk::DebugUtilsMessengerEXT) {
     let debug_utils_loader = ash::extensions::ext::DebugUtils::new(entry, instance);
 
-    if ENABLE_DEBUG {
+    if validation {
         let messenger_create_info = build_debug_utils_create_info();
 
         let utils_messenger = unsafe {
@@ -168,3 +319,45 @@ pub fn build_debug_utils_create_info() -> vk::DebugUtilsMessengerCreateInfoEXT {
         p_user_data: ptr::null_mut(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_are_only_counted() {
+        let mut filter = MessageFilter::new();
+        let now = Instant::now();
+        assert_eq!(
+            filter.on_message("a", now),
+            Verdict::Print { suppressed: 0 }
+        );
+        let verdicts: Vec<_> = (2..=100).map(|_| filter.on_message("a", now)).collect();
+        assert_eq!(verdicts[10 - 2], Verdict::Repeated { count: 10 });
+        assert_eq!(verdicts[100 - 2], Verdict::Repeated { count: 100 });
+        let num_skipped = verdicts.iter().filter(|v| **v == Verdict::Skip).count();
+        assert_eq!(num_skipped, verdicts.len() - 2);
+        assert_eq!(
+            filter.on_message("b", now),
+            Verdict::Print { suppressed: 0 }
+        );
+    }
+
+    #[test]
+    fn too_many_messages_are_suppressed() {
+        let mut filter = MessageFilter::new();
+        let now = Instant::now();
+        for index in 0..MAX_MESSAGES_PER_SECOND {
+            let verdict = filter.on_message(&index.to_string(), now);
+            assert_eq!(verdict, Verdict::Print { suppressed: 0 });
+        }
+        assert_eq!(filter.on_message("x", now), Verdict::Skip);
+        assert_eq!(filter.on_message("y", now), Verdict::Skip);
+        let later = now + Duration::from_secs(1);
+        // Suppressed messages are printed the next time they are seen.
+        assert_eq!(
+            filter.on_message("x", later),
+            Verdict::Print { suppressed: 2 }
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use ash::version::DeviceV1_0;
 use ash::vk;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 use super::core::Core;
@@ -9,6 +10,7 @@ pub enum DescriptorPrototype {
     StorageImage(vk::ImageView, vk::ImageLayout),
     CombinedImageSampler(vk::ImageView, vk::ImageLayout, vk::Sampler),
     UniformBuffer(vk::Buffer, u64, u64),
+    StorageBuffer(vk::Buffer, u64, u64),
 }
 
 impl DescriptorPrototype {
@@ -35,6 +37,13 @@ impl DescriptorPrototype {
                     false
                 }
             }
+            Self::StorageBuffer(..) => {
+                if let Self::StorageBuffer(..) = other {
+                    true
+                } else {
+                    false
+                }
+            }
         }
     }
 
@@ -43,6 +52,7 @@ impl DescriptorPrototype {
             Self::StorageImage(..) => vk::DescriptorType::STORAGE_IMAGE,
             Self::CombinedImageSampler(..) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
             Self::UniformBuffer(..) => vk::DescriptorType::UNIFORM_BUFFER,
+            Self::StorageBuffer(..) => vk::DescriptorType::STORAGE_BUFFER,
         }
     }
 
@@ -73,7 +83,8 @@ impl DescriptorPrototype {
                     ..Default::default()
                 })
             }
-            Self::UniformBuffer(buffer, offset, range) => {
+            Self::UniformBuffer(buffer, offset, range)
+            | Self::StorageBuffer(buffer, offset, range) => {
                 DescriptorPayload::BufferInfo(vk::DescriptorBufferInfo {
                     buffer,
                     offset,
@@ -100,6 +111,123 @@ impl DescriptorPayload {
     }
 }
 
+/// Remembers which layout every descriptor set was allocated from and which layouts every pipeline
+/// layout expects, so that binding a descriptor set to the wrong stage can be caught while
+/// recording, naming both of them, instead of making shaders silently read the wrong resources.
+#[derive(Default)]
+pub struct DescriptorRegistry {
+    layouts: HashMap<vk::DescriptorSetLayout, (String, Vec<vk::DescriptorType>)>,
+    // The pool each set was allocated from, its layout and its name.
+    sets: HashMap<vk::DescriptorSet, (vk::DescriptorPool, vk::DescriptorSetLayout, String)>,
+    pipeline_layouts: HashMap<vk::PipelineLayout, (String, Vec<vk::DescriptorSetLayout>)>,
+}
+
+impl DescriptorRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register_layout(
+        &mut self,
+        layout: vk::DescriptorSetLayout,
+        name: &str,
+        types: Vec<vk::DescriptorType>,
+    ) {
+        self.layouts.insert(layout, (name.to_owned(), types));
+    }
+
+    pub fn register_set(
+        &mut self,
+        set: vk::DescriptorSet,
+        pool: vk::DescriptorPool,
+        layout: vk::DescriptorSetLayout,
+        name: &str,
+    ) {
+        self.sets.insert(set, (pool, layout, name.to_owned()));
+    }
+
+    pub fn register_pipeline_layout(
+        &mut self,
+        pipeline_layout: vk::PipelineLayout,
+        name: &str,
+        set_layouts: &[vk::DescriptorSetLayout],
+    ) {
+        self.pipeline_layouts
+            .insert(pipeline_layout, (name.to_owned(), set_layouts.to_owned()));
+    }
+
+    pub fn forget_layout(&mut self, layout: vk::DescriptorSetLayout) {
+        self.layouts.remove(&layout);
+    }
+
+    /// Forgets every set allocated from the pool.
+    pub fn forget_pool(&mut self, pool: vk::DescriptorPool) {
+        self.sets.retain(|_, (set_pool, _, _)| *set_pool != pool);
+    }
+
+    pub fn forget_pipeline_layout(&mut self, pipeline_layout: vk::PipelineLayout) {
+        self.pipeline_layouts.remove(&pipeline_layout);
+    }
+
+    fn get_layout_name(&self, layout: vk::DescriptorSetLayout) -> &str {
+        match self.layouts.get(&layout) {
+            Some((name, _)) => name,
+            None => "an unknown layout",
+        }
+    }
+
+    /// Returns an error describing the problem if the set can't be bound at the given index of
+    /// the pipeline layout. Sets and pipeline layouts which were never registered are assumed to
+    /// be fine.
+    pub fn check_binding(
+        &self,
+        pipeline_layout: vk::PipelineLayout,
+        index: u32,
+        set: vk::DescriptorSet,
+    ) -> Result<(), String> {
+        let (pipeline_name, expected_layouts) = match self.pipeline_layouts.get(&pipeline_layout) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        let (_, set_layout, set_name) = match self.sets.get(&set) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        let expected_layout = match expected_layouts.get(index as usize) {
+            Some(layout) => *layout,
+            None => {
+                return Err(format!(
+                    "Bound {} to set {} of {}, which only has {} sets.",
+                    set_name,
+                    index,
+                    pipeline_name,
+                    expected_layouts.len()
+                ))
+            }
+        };
+        if expected_layout == *set_layout {
+            return Ok(());
+        }
+        // Layouts which are defined the same way are compatible.
+        let expected_types = self.layouts.get(&expected_layout).map(|(_, types)| types);
+        let actual_types = self.layouts.get(set_layout).map(|(_, types)| types);
+        if expected_types.is_some() && expected_types == actual_types {
+            return Ok(());
+        }
+        Err(format!(
+            "Bound {} (from {}) to set {} of {}, which expects {}. Expected bindings {:?}, got \
+            {:?}.",
+            set_name,
+            self.get_layout_name(*set_layout),
+            index,
+            pipeline_name,
+            self.get_layout_name(expected_layout),
+            expected_types,
+            actual_types,
+        ))
+    }
+}
+
 pub struct DescriptorData {
     pub layout: vk::DescriptorSetLayout,
     pub variants: Vec<vk::DescriptorSet>,
@@ -177,11 +305,14 @@ impl DescriptorTypeAccumulator {
 
 pub type PrototypeGenerator<Data> = Box<dyn Fn(Rc<Core>, &Data) -> Vec<Vec<DescriptorPrototype>>>;
 
+/// Creates a layout for each generator unless existing_layouts holds one for each already, which
+/// must match what the generators produce.
 pub fn generate_descriptor_pool<Data>(
     prototype_generators: &[PrototypeGenerator<Data>],
     names: &[&str],
     core: Rc<Core>,
     data: &Data,
+    existing_layouts: Option<&[vk::DescriptorSetLayout]>,
 ) -> (vk::DescriptorPool, Vec<DescriptorData>) {
     let prototypes: Vec<_> = prototype_generators
         .into_iter()
@@ -192,6 +323,7 @@ pub fn generate_descriptor_pool<Data>(
         })
         .collect();
 
+    let mut registry = core.descriptor_registry.borrow_mut();
     let empty_variant = vec![];
     let mut counter = DescriptorTypeAccumulator::new();
     let mut total_descriptor_sets = 0;
@@ -211,6 +343,9 @@ pub fn generate_descriptor_pool<Data>(
             for item in arbitrary_variant {
                 counter.increment(item.get_descriptor_type(), variants.len() as u32);
             }
+            if let Some(layouts) = existing_layouts {
+                return (layouts[index], variants.len());
+            }
             let bindings: Vec<_> = arbitrary_variant
                 .iter()
                 .enumerate()
@@ -226,7 +361,13 @@ pub fn generate_descriptor_pool<Data>(
                     .create_descriptor_set_layout(&create_info, None)
                     .expect("Failed to create descriptor set layout.")
             };
-            core.set_debug_name(layout, &format!("{}_ds_layout", names[index]));
+            let layout_name = format!("{}_ds_layout", names[index]);
+            core.set_debug_name(layout, &layout_name);
+            let types = bindings
+                .iter()
+                .map(|binding| binding.descriptor_type)
+                .collect();
+            registry.register_layout(layout, &layout_name, types);
             (layout, variants.len())
         })
         .collect();
@@ -300,10 +441,9 @@ pub fn generate_descriptor_pool<Data>(
     for (layout_index, (layout, quantity)) in layout_info.into_iter().enumerate() {
         let variants: Vec<_> = descriptor_sets.drain(0..quantity).collect();
         for (variant_index, variant) in variants.iter().enumerate() {
-            core.set_debug_name(
-                *variant,
-                &format!("{}_ds_variant_{}", names[layout_index], variant_index),
-            );
+            let name = format!("{}_ds_variant_{}", names[layout_index], variant_index);
+            core.set_debug_name(*variant, &name);
+            registry.register_set(*variant, descriptor_pool, layout, &name);
         }
         descriptor_datas.push(DescriptorData { layout, variants });
     }
@@ -328,7 +468,11 @@ pub fn generate_descriptor_pool<Data>(
 /// collection.world_data.layout; // Layout of world data descriptor sets.
 /// // The first descriptor set from the first prototype generated by generate_world_data_ds_protos
 /// collection.world_data.variants[0];
-/// collection.destroy(reference_to_core); // Cleans up descriptor pool and all descriptor layouts.
+/// // Regenerating the sets after the objects they describe were replaced keeps the layouts.
+/// collection.recreate_sets(reference_to_aux_data);
+/// // Dropping the collection cleans up the descriptor pool and all descriptor layouts once no
+/// // frame in flight uses them anymore.
+/// drop(collection);
 #[macro_export]
 macro_rules! create_descriptor_collection_struct {
     {
@@ -350,14 +494,7 @@ macro_rules! create_descriptor_collection_struct {
                 core: std::rc::Rc<crate::render::general::core::Core>,
                 aux_data: &$aux_data_type
             ) -> Self {
-                let generators = [$(
-                    Box::new($generator_name)
-                    as crate::render::general::descriptors::PrototypeGenerator<$aux_data_type>
-                ),*];
-                let names = [$(stringify!($field_name)),*];
-                let (pool, datas) = crate::render::general::descriptors::generate_descriptor_pool(
-                    &generators, &names, core.clone(), aux_data
-                );
+                let (pool, datas) = Self::generate(core.clone(), aux_data, None);
                 let mut datas_consumer = datas.into_iter();
                 $struct_name {
                     core,
@@ -365,18 +502,102 @@ macro_rules! create_descriptor_collection_struct {
                     $($field_name : datas_consumer.next().unwrap()),*
                 }
             }
+
+            /// Replaces every descriptor set with one generated from aux_data again, for when the
+            /// objects they describe were recreated. The layouts are kept, so anything created
+            /// with them stays valid.
+            pub fn recreate_sets(&mut self, aux_data: &$aux_data_type) {
+                use crate::render::general::lifetime::DeferredObject;
+                let layouts = [$(self.$field_name.layout),*];
+                let (pool, datas) = Self::generate(self.core.clone(), aux_data, Some(&layouts));
+                self.core.destroy_deferred(&[DeferredObject::DescriptorPool(self.pool)]);
+                self.pool = pool;
+                let mut datas_consumer = datas.into_iter();
+                $(self.$field_name = datas_consumer.next().unwrap();)*
+            }
+
+            fn generate(
+                core: std::rc::Rc<crate::render::general::core::Core>,
+                aux_data: &$aux_data_type,
+                existing_layouts: Option<&[vk::DescriptorSetLayout]>,
+            ) -> (vk::DescriptorPool, Vec<crate::render::general::descriptors::DescriptorData>) {
+                let generators = [$(
+                    Box::new($generator_name)
+                    as crate::render::general::descriptors::PrototypeGenerator<$aux_data_type>
+                ),*];
+                let names = [$(stringify!($field_name)),*];
+                crate::render::general::descriptors::generate_descriptor_pool(
+                    &generators, &names, core, aux_data, existing_layouts
+                )
+            }
         }
 
         impl Drop for $struct_name {
             fn drop(&mut self) {
-                unsafe {
-                    $(self.core.device.destroy_descriptor_set_layout(
-                        self.$field_name.layout,
-                        None
-                    );)*
-                    self.core.device.destroy_descriptor_pool(self.pool, None);
-                }
+                use crate::render::general::lifetime::DeferredObject;
+                self.core.destroy_deferred(&[
+                    $(DeferredObject::DescriptorSetLayout(self.$field_name.layout),)*
+                    DeferredObject::DescriptorPool(self.pool),
+                ]);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    fn make_registry() -> DescriptorRegistry {
+        let mut registry = DescriptorRegistry::new();
+        let image = vec![vk::DescriptorType::STORAGE_IMAGE];
+        let pool = vk::DescriptorPool::from_raw(1);
+        for (index, name) in ["shade", "finalize", "text"].iter().enumerate() {
+            let layout = vk::DescriptorSetLayout::from_raw(index as u64 + 1);
+            let types = if *name == "text" {
+                vec![vk::DescriptorType::STORAGE_BUFFER]
+            } else {
+                image.clone()
+            };
+            registry.register_layout(layout, &format!("{}_ds_layout", name), types);
+            let set = vk::DescriptorSet::from_raw(index as u64 + 1);
+            registry.register_set(set, pool, layout, &format!("{}_ds_variant_0", name));
+        }
+        let shade_layout = [vk::DescriptorSetLayout::from_raw(1)];
+        registry.register_pipeline_layout(vk::PipelineLayout::from_raw(1), "shade", &shade_layout);
+        registry
+    }
+
+    #[test]
+    fn compatible_sets_can_be_bound() {
+        let registry = make_registry();
+        let pipeline_layout = vk::PipelineLayout::from_raw(1);
+        let check =
+            |set| registry.check_binding(pipeline_layout, 0, vk::DescriptorSet::from_raw(set));
+        assert_eq!(check(1), Ok(()));
+        // Defined the same way as the shade layout.
+        assert_eq!(check(2), Ok(()));
+        // Never registered.
+        assert_eq!(check(10), Ok(()));
+    }
+
+    #[test]
+    fn incompatible_sets_are_named() {
+        let mut registry = make_registry();
+        let pipeline_layout = vk::PipelineLayout::from_raw(1);
+        let text_set = vk::DescriptorSet::from_raw(3);
+        let problem = registry
+            .check_binding(pipeline_layout, 0, text_set)
+            .unwrap_err();
+        assert!(problem.contains("text_ds_variant_0"));
+        assert!(problem.contains("shade_ds_layout"));
+        let shade_set = vk::DescriptorSet::from_raw(1);
+        assert!(registry
+            .check_binding(pipeline_layout, 1, shade_set)
+            .is_err());
+
+        registry.forget_pool(vk::DescriptorPool::from_raw(1));
+        assert_eq!(registry.check_binding(pipeline_layout, 0, text_set), Ok(()));
+    }
+}
@@ -0,0 +1,206 @@
+use ash::version::{InstanceV1_0, InstanceV1_1};
+use ash::vk;
+use ash::vk_make_version;
+use std::os::raw::c_void;
+use std::ptr;
+
+use crate::render::constants::*;
+
+const VERSION_1_1: u32 = vk_make_version!(1, 1, 0);
+const VERSION_1_2: u32 = vk_make_version!(1, 2, 0);
+
+// This version of ash predates Vulkan 1.2, so these are written out here. They must match the
+// values and layouts in the Vulkan headers.
+const PHYSICAL_DEVICE_TIMELINE_SEMAPHORE_FEATURES: i32 = 1000207000;
+const PHYSICAL_DEVICE_BUFFER_DEVICE_ADDRESS_FEATURES: i32 = 1000257000;
+
+#[repr(C)]
+struct PhysicalDeviceTimelineSemaphoreFeatures {
+    s_type: vk::StructureType,
+    p_next: *mut c_void,
+    timeline_semaphore: vk::Bool32,
+}
+
+/// Features which were promoted to core in Vulkan 1.2, which are enabled when the device supports
+/// them either through 1.2 or through the extensions they started out as. The rest of the
+/// renderer can branch on these, and must work when all of them are false.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceFeatures {
+    pub timeline_semaphores: bool,
+    /// Runtime sized descriptor arrays which can be partially bound and indexed non-uniformly.
+    pub descriptor_indexing: bool,
+    pub buffer_device_address: bool,
+    pub scalar_block_layout: bool,
+}
+
+impl DeviceFeatures {
+    pub fn get_enabled_names(&self) -> Vec<&'static str> {
+        let features = [
+            ("timeline semaphores", self.timeline_semaphores),
+            ("descriptor indexing", self.descriptor_indexing),
+            ("buffer device address", self.buffer_device_address),
+            ("scalar block layout", self.scalar_block_layout),
+        ];
+        features
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+/// The API version to create the instance with, which is the newest version the loader supports
+/// up to Vulkan 1.2. None means the loader only supports Vulkan 1.0.
+pub fn choose_api_version(loader_version: Option<u32>) -> u32 {
+    match loader_version {
+        Some(version) => version.max(API_VERSION).min(VERSION_1_2),
+        None => API_VERSION,
+    }
+}
+
+/// Returns the extensions which need to be enabled to use each optional feature on a device
+/// with the given API version and extensions, or None for features the device can't support.
+/// Features which are core in the device's version don't need any extensions.
+fn get_feature_extensions(
+    api_version: u32,
+    available: &[String],
+) -> [Option<Vec<&'static str>>; 4] {
+    let find = |extensions: &[&'static str]| {
+        if api_version >= VERSION_1_2 {
+            Some(vec![])
+        } else if extensions
+            .iter()
+            .all(|extension| available.iter().any(|name| name == extension))
+        {
+            Some(extensions.to_vec())
+        } else {
+            None
+        }
+    };
+    [
+        find(&["VK_KHR_timeline_semaphore"]),
+        find(&["VK_KHR_maintenance3", "VK_EXT_descriptor_indexing"]),
+        find(&["VK_KHR_buffer_device_address"]),
+        find(&["VK_EXT_scalar_block_layout"]),
+    ]
+}
+
+/// The structures queried from the device and then passed on to device creation to enable the
+/// features which were found. Boxed since the structures point to each other.
+pub struct FeatureChain {
+    features2: vk::PhysicalDeviceFeatures2,
+    timeline: PhysicalDeviceTimelineSemaphoreFeatures,
+    indexing: vk::PhysicalDeviceDescriptorIndexingFeaturesEXT,
+    // Has the same layout as the structure from VK_KHR_buffer_device_address.
+    address: vk::PhysicalDeviceBufferAddressFeaturesEXT,
+    scalar: vk::PhysicalDeviceScalarBlockLayoutFeaturesEXT,
+}
+
+impl FeatureChain {
+    /// Should be used as the next pointer of the device create info, in which case the enabled
+    /// features pointer must be null.
+    pub fn as_next_pointer(&self) -> *const c_void {
+        &self.features2 as *const vk::PhysicalDeviceFeatures2 as *const c_void
+    }
+}
+
+/// Finds which optional features the device supports. Returns them along with the extensions
+/// which must be enabled and the chain which enables them, or None for the chain if the
+/// instance or device are too old to query features this way.
+pub fn query_features(
+    instance: &ash::Instance,
+    instance_version: u32,
+    physical_device: vk::PhysicalDevice,
+    available_extensions: &[String],
+) -> (DeviceFeatures, Vec<&'static str>, Option<Box<FeatureChain>>) {
+    let device_version =
+        unsafe { instance.get_physical_device_properties(physical_device) }.api_version;
+    if instance_version < VERSION_1_1 || device_version < VERSION_1_1 {
+        return (DeviceFeatures::default(), vec![], None);
+    }
+    let extensions = get_feature_extensions(device_version, available_extensions);
+    let mut chain = Box::new(FeatureChain {
+        features2: vk::PhysicalDeviceFeatures2::default(),
+        timeline: PhysicalDeviceTimelineSemaphoreFeatures {
+            s_type: vk::StructureType::from_raw(PHYSICAL_DEVICE_TIMELINE_SEMAPHORE_FEATURES),
+            p_next: ptr::null_mut(),
+            timeline_semaphore: vk::FALSE,
+        },
+        indexing: vk::PhysicalDeviceDescriptorIndexingFeaturesEXT::default(),
+        address: vk::PhysicalDeviceBufferAddressFeaturesEXT {
+            s_type: vk::StructureType::from_raw(PHYSICAL_DEVICE_BUFFER_DEVICE_ADDRESS_FEATURES),
+            ..Default::default()
+        },
+        scalar: vk::PhysicalDeviceScalarBlockLayoutFeaturesEXT::default(),
+    });
+    // Only structures for features the device has some way of supporting may be chained.
+    let mut next: *mut c_void = ptr::null_mut();
+    if extensions[3].is_some() {
+        chain.scalar.p_next = next;
+        next = &mut chain.scalar as *mut _ as *mut c_void;
+    }
+    if extensions[2].is_some() {
+        chain.address.p_next = next;
+        next = &mut chain.address as *mut _ as *mut c_void;
+    }
+    if extensions[1].is_some() {
+        chain.indexing.p_next = next;
+        next = &mut chain.indexing as *mut _ as *mut c_void;
+    }
+    if extensions[0].is_some() {
+        chain.timeline.p_next = next;
+        next = &mut chain.timeline as *mut _ as *mut c_void;
+    }
+    chain.features2.p_next = next;
+    // This version of ash has no wrapper for it, only the function pointer.
+    unsafe {
+        instance
+            .fp_v1_1()
+            .get_physical_device_features2(physical_device, &mut chain.features2)
+    };
+
+    // Everything else the device supports is left on when the chain is passed to device
+    // creation, apart from these which are only useful on multi-GPU and capture setups.
+    chain.address.buffer_device_address_capture_replay = vk::FALSE;
+    chain.address.buffer_device_address_multi_device = vk::FALSE;
+    // Fields of PhysicalDeviceFeatures which this renderer does not use are left off.
+    chain.features2.features = vk::PhysicalDeviceFeatures::default();
+
+    let indexing = &chain.indexing;
+    let features = DeviceFeatures {
+        timeline_semaphores: extensions[0].is_some() && chain.timeline.timeline_semaphore != 0,
+        descriptor_indexing: extensions[1].is_some()
+            && indexing.runtime_descriptor_array != 0
+            && indexing.descriptor_binding_partially_bound != 0
+            && indexing.shader_storage_image_array_non_uniform_indexing != 0,
+        buffer_device_address: extensions[2].is_some() && chain.address.buffer_device_address != 0,
+        scalar_block_layout: extensions[3].is_some() && chain.scalar.scalar_block_layout != 0,
+    };
+    // The extensions for every chained structure are enabled, even where the feature itself
+    // turned out to be unsupported, since the structures are passed on to device creation.
+    let needed_extensions = extensions.iter().flatten().flatten().cloned().collect();
+    (features, needed_extensions, Some(chain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_version_is_clamped() {
+        assert_eq!(choose_api_version(None), API_VERSION);
+        assert_eq!(choose_api_version(Some(VERSION_1_1)), VERSION_1_1);
+        let newer = vk_make_version!(1, 3, 0);
+        assert_eq!(choose_api_version(Some(newer)), VERSION_1_2);
+    }
+
+    #[test]
+    fn features_need_extensions_before_1_2() {
+        let available = vec!["VK_EXT_scalar_block_layout".to_owned()];
+        let extensions = get_feature_extensions(VERSION_1_1, &available);
+        assert_eq!(extensions[0], None);
+        assert_eq!(extensions[3], Some(vec!["VK_EXT_scalar_block_layout"]));
+        let extensions = get_feature_extensions(VERSION_1_2, &[]);
+        assert!(extensions.iter().all(|needed| needed == &Some(vec![])));
+    }
+}
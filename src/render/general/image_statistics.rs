@@ -0,0 +1,155 @@
+use ash::vk;
+
+use super::command_buffer::CommandBuffer;
+use super::structures::Buffer;
+
+/// How many luminance bins the histogram has, each covering half a stop from 2^-8 to 2^8. Must
+/// match image_statistics.comp.
+pub const HISTOGRAM_BINS: usize = 32;
+/// Each bin holds how many pixels landed in it, then the sums of their red, green and blue
+/// chromaticities in fixed point. Must match image_statistics.comp.
+pub const HISTOGRAM_VALUES_PER_BIN: usize = 4;
+/// The buffer starts with how many pixels were measured, the bits of the smallest luminance
+/// inverted so that it can be found with atomicMax, the bits of the largest luminance and the sum
+/// of the log luminances in fixed point. The histogram comes after. Must match
+/// image_statistics.comp.
+const HEADER_VALUES: usize = 4;
+/// How many values the buffer read back from image_statistics.comp holds.
+pub const STATISTICS_BUFFER_LEN: usize = HEADER_VALUES + HISTOGRAM_BINS * HISTOGRAM_VALUES_PER_BIN;
+/// Log luminances are offset by this so that they are never negative, then multiplied by
+/// LOG_SCALE. Must match image_statistics.comp.
+const LOG_OFFSET: f32 = 16.0;
+const LOG_SCALE: f32 = 16.0;
+/// Only one pixel in every STRIDE x STRIDE block is measured. Must match image_statistics.comp.
+const STRIDE: u32 = 4;
+/// The size of the work groups of image_statistics.comp.
+const GROUP_SIZE: u32 = 8;
+
+/// Records measuring an image with image_statistics.comp, which the CPU can read back from the
+/// statistics buffer with ImageStatistics::from_raw once the commands have finished. The
+/// descriptor set must hold the image in GENERAL layout and the statistics buffer, which must be
+/// STATISTICS_BUFFER_LEN long and usable as a storage buffer and transfer destination. Anything
+/// which wrote to the image must already have finished or been waited on with a barrier.
+pub fn record_image_statistics(
+    buffer: &CommandBuffer,
+    statistics: &Buffer<u32>,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    set: vk::DescriptorSet,
+    extent: vk::Extent3D,
+) {
+    let compute = vk::PipelineStageFlags::COMPUTE_SHADER;
+    let transfer = vk::PipelineStageFlags::TRANSFER;
+    buffer.fill_buffer(statistics, 0);
+    buffer.memory_barrier(compute | transfer, compute);
+    buffer.bind_descriptor_set(pipeline_layout, 0, set);
+    buffer.bind_pipeline(pipeline);
+    let pixels_per_group = GROUP_SIZE * STRIDE;
+    buffer.dispatch(
+        extent.width.div_ceil(pixels_per_group),
+        extent.height.div_ceil(pixels_per_group),
+        1,
+    );
+}
+
+/// What image_statistics.comp measured about the light in an image. Luminances are scaled the same
+/// way as the light the shade stage computes, with pixels that were black or invalid left out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageStatistics {
+    values: Vec<u32>,
+}
+
+impl ImageStatistics {
+    /// Returns None if no pixels were measured, such as when the image was completely black.
+    pub fn from_raw(values: &[u32]) -> Option<Self> {
+        assert_eq!(values.len(), STATISTICS_BUFFER_LEN);
+        if values[0] == 0 {
+            return None;
+        }
+        Some(Self {
+            values: values.to_owned(),
+        })
+    }
+
+    pub fn get_num_pixels(&self) -> u32 {
+        self.values[0]
+    }
+
+    pub fn get_min_luminance(&self) -> f32 {
+        f32::from_bits(!self.values[1])
+    }
+
+    pub fn get_max_luminance(&self) -> f32 {
+        f32::from_bits(self.values[2])
+    }
+
+    /// The geometric mean, which is what auto exposure should aim for since a few very bright
+    /// pixels barely move it.
+    pub fn get_average_luminance(&self) -> f32 {
+        let log_sum = self.values[3] as f32 / LOG_SCALE;
+        let average_log = log_sum / self.get_num_pixels() as f32 - LOG_OFFSET;
+        average_log.exp2()
+    }
+
+    /// The luminance which the given fraction of the pixels are darker than, accurate to the
+    /// half stop width of the bins.
+    pub fn get_luminance_percentile(&self, fraction: f32) -> f32 {
+        let target = self.get_num_pixels() as f32 * fraction.clamp(0.0, 1.0);
+        let mut below = 0.0;
+        let bins = self.get_histogram().chunks(HISTOGRAM_VALUES_PER_BIN);
+        for (index, bin) in bins.enumerate() {
+            let count = bin[0] as f32;
+            if count > 0.0 && below + count >= target {
+                let within = (target - below) / count;
+                let log = (index as f32 + within) / 2.0 - 8.0;
+                let luminance = log.exp2();
+                return luminance.clamp(self.get_min_luminance(), self.get_max_luminance());
+            }
+            below += count;
+        }
+        self.get_max_luminance()
+    }
+
+    /// HISTOGRAM_BINS bins of HISTOGRAM_VALUES_PER_BIN values each, see estimate_cast in
+    /// white_balance.rs.
+    pub fn get_histogram(&self) -> &[u32] {
+        &self.values[HEADER_VALUES..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_statistics_of(luminances: &[f32]) -> Vec<u32> {
+        let mut values = vec![0; STATISTICS_BUFFER_LEN];
+        for luminance in luminances {
+            values[0] += 1;
+            values[1] = values[1].max(!luminance.to_bits());
+            values[2] = values[2].max(luminance.to_bits());
+            values[3] += ((luminance.log2() + LOG_OFFSET) * LOG_SCALE) as u32;
+            let bin = ((luminance.log2() + 8.0) * 2.0).floor() as usize;
+            values[HEADER_VALUES + bin.min(HISTOGRAM_BINS - 1) * HISTOGRAM_VALUES_PER_BIN] += 1;
+        }
+        values
+    }
+
+    #[test]
+    fn black_images_have_no_statistics() {
+        assert_eq!(ImageStatistics::from_raw(&raw_statistics_of(&[])), None);
+    }
+
+    #[test]
+    fn statistics_describe_luminance() {
+        let raw = raw_statistics_of(&[0.25, 1.0, 1.0, 4.0]);
+        let statistics = ImageStatistics::from_raw(&raw).unwrap();
+        assert_eq!(statistics.get_num_pixels(), 4);
+        assert_eq!(statistics.get_min_luminance(), 0.25);
+        assert_eq!(statistics.get_max_luminance(), 4.0);
+        assert!((statistics.get_average_luminance() - 1.0).abs() < 0.05);
+        assert_eq!(statistics.get_luminance_percentile(0.0), 0.25);
+        let median = statistics.get_luminance_percentile(0.5);
+        assert!(median >= 1.0 && median < 1.5);
+        assert_eq!(statistics.get_luminance_percentile(1.0), 4.0);
+    }
+}
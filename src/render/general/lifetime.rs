@@ -0,0 +1,118 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+/// A Vulkan object whose destruction has been put off until the GPU is done with it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeferredObject {
+    Buffer(vk::Buffer),
+    Image(vk::Image),
+    ImageView(vk::ImageView),
+    Sampler(vk::Sampler),
+    Memory(vk::DeviceMemory),
+    DescriptorPool(vk::DescriptorPool),
+    DescriptorSetLayout(vk::DescriptorSetLayout),
+}
+
+impl DeferredObject {
+    pub unsafe fn destroy(self, device: &ash::Device) {
+        match self {
+            Self::Buffer(buffer) => device.destroy_buffer(buffer, None),
+            Self::Image(image) => device.destroy_image(image, None),
+            Self::ImageView(view) => device.destroy_image_view(view, None),
+            Self::Sampler(sampler) => device.destroy_sampler(sampler, None),
+            Self::Memory(memory) => device.free_memory(memory, None),
+            Self::DescriptorPool(pool) => device.destroy_descriptor_pool(pool, None),
+            Self::DescriptorSetLayout(layout) => device.destroy_descriptor_set_layout(layout, None),
+        }
+    }
+}
+
+/// Keeps track of which frames the GPU might still be working on so that objects dropped on the
+/// CPU are only destroyed once every frame which could have used them has finished. Frames are
+/// numbered in the order they are submitted, and an object dropped after frame N was submitted is
+/// kept until the fence for frame N has been waited on.
+pub struct LifetimeTracker<T> {
+    num_submitted: u64,
+    num_completed: u64,
+    // Each object along with the number of frames which had been submitted when it was dropped,
+    // oldest first.
+    pending: Vec<(u64, T)>,
+}
+
+impl<T> LifetimeTracker<T> {
+    pub fn new() -> Self {
+        Self {
+            num_submitted: 0,
+            num_completed: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Returns the object right away if no frames are in flight, otherwise holds on to it until
+    /// they complete.
+    pub fn defer(&mut self, object: T) -> Option<T> {
+        if self.num_completed == self.num_submitted {
+            Some(object)
+        } else {
+            self.pending.push((self.num_submitted, object));
+            None
+        }
+    }
+
+    pub fn on_frame_submitted(&mut self) {
+        self.num_submitted += 1;
+    }
+
+    /// Called once the fence of the oldest frame in flight has signaled. Returns every object
+    /// which no frame in flight can be using anymore.
+    pub fn on_frame_completed(&mut self) -> Vec<T> {
+        self.num_completed = (self.num_completed + 1).min(self.num_submitted);
+        let num_completed = self.num_completed;
+        let num_ready = self
+            .pending
+            .iter()
+            .take_while(|(frame, _)| *frame <= num_completed)
+            .count();
+        self.pending
+            .drain(..num_ready)
+            .map(|(_, object)| object)
+            .collect()
+    }
+
+    /// Returns every object no matter which frames are in flight, for when the device is idle.
+    pub fn take_all(&mut self) -> Vec<T> {
+        self.num_completed = self.num_submitted;
+        self.pending.drain(..).map(|(_, object)| object).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn objects_wait_for_frames_in_flight() {
+        let mut tracker = LifetimeTracker::new();
+        assert_eq!(tracker.defer(1), Some(1));
+        tracker.on_frame_submitted();
+        assert_eq!(tracker.defer(2), None);
+        tracker.on_frame_submitted();
+        assert_eq!(tracker.defer(3), None);
+        assert_eq!(tracker.pending.len(), 2);
+        assert_eq!(tracker.on_frame_completed(), vec![2]);
+        assert_eq!(tracker.on_frame_completed(), vec![3]);
+        // Nothing is in flight anymore.
+        assert_eq!(tracker.defer(4), Some(4));
+        assert_eq!(tracker.on_frame_completed(), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn take_all_releases_everything() {
+        let mut tracker = LifetimeTracker::new();
+        tracker.on_frame_submitted();
+        tracker.on_frame_submitted();
+        assert_eq!(tracker.defer(1), None);
+        assert_eq!(tracker.take_all(), vec![1]);
+        assert_eq!(tracker.defer(2), Some(2));
+    }
+}
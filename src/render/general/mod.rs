@@ -3,5 +3,9 @@ pub(super) mod core;
 pub(super) mod core_builder;
 pub(super) mod debug;
 pub(super) mod descriptors;
+pub(super) mod features;
+pub(super) mod image_statistics;
+pub(super) mod lifetime;
 pub(super) mod platform_specific;
+pub(super) mod recording;
 pub(super) mod structures;
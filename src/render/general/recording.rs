@@ -0,0 +1,193 @@
+use ash::vk;
+
+use super::command_buffer::CommandBuffer;
+use super::structures::{Buffer, BufferWrapper, ExtentWrapper, ImageWrapper};
+
+/// The transfer commands the CPU side of the pipeline records while uploading data. Implemented
+/// by CommandBuffer, and by mock::MockCommands so that the logic deciding what to record can be
+/// tested without a device.
+pub trait TransferCommands {
+    fn transition_layout(
+        &mut self,
+        image: &impl ImageWrapper,
+        from: vk::ImageLayout,
+        to: vk::ImageLayout,
+    );
+
+    fn copy_buffer_to_image_offset(
+        &mut self,
+        data_buffer: &impl BufferWrapper,
+        buffer_offset: u64,
+        buffer_width: u32,
+        buffer_height: u32,
+        image: &impl ImageWrapper,
+        offset: vk::Offset3D,
+        extent: &impl ExtentWrapper,
+    );
+
+    fn memory_barrier(
+        &mut self,
+        src_stage: vk::PipelineStageFlags,
+        dst_stage: vk::PipelineStageFlags,
+    );
+}
+
+impl TransferCommands for CommandBuffer {
+    fn transition_layout(
+        &mut self,
+        image: &impl ImageWrapper,
+        from: vk::ImageLayout,
+        to: vk::ImageLayout,
+    ) {
+        CommandBuffer::transition_layout(self, image, from, to)
+    }
+
+    fn copy_buffer_to_image_offset(
+        &mut self,
+        data_buffer: &impl BufferWrapper,
+        buffer_offset: u64,
+        buffer_width: u32,
+        buffer_height: u32,
+        image: &impl ImageWrapper,
+        offset: vk::Offset3D,
+        extent: &impl ExtentWrapper,
+    ) {
+        CommandBuffer::copy_buffer_to_image_offset(
+            self,
+            data_buffer,
+            buffer_offset,
+            buffer_width,
+            buffer_height,
+            image,
+            offset,
+            extent,
+        )
+    }
+
+    fn memory_barrier(
+        &mut self,
+        src_stage: vk::PipelineStageFlags,
+        dst_stage: vk::PipelineStageFlags,
+    ) {
+        CommandBuffer::memory_barrier(self, src_stage, dst_stage)
+    }
+}
+
+/// A buffer which the CPU can write to. The contents are only mapped for the duration of the
+/// closure.
+pub trait HostBuffer<ItemType>: BufferWrapper {
+    fn with_mapped<R>(&mut self, f: impl FnOnce(&mut [ItemType]) -> R) -> R;
+}
+
+impl<ItemType> HostBuffer<ItemType> for Buffer<ItemType> {
+    fn with_mapped<R>(&mut self, f: impl FnOnce(&mut [ItemType]) -> R) -> R {
+        let mut view = self.bind_all();
+        f(view.as_slice_mut())
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use ash::vk::Handle;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum RecordedCommand {
+        TransitionLayout {
+            image: vk::Image,
+            from: vk::ImageLayout,
+            to: vk::ImageLayout,
+        },
+        CopyBufferToImage {
+            buffer: vk::Buffer,
+            buffer_offset: u64,
+            buffer_size: (u32, u32),
+            image: vk::Image,
+            offset: (i32, i32, i32),
+            extent: vk::Extent3D,
+        },
+        MemoryBarrier {
+            src_stage: vk::PipelineStageFlags,
+            dst_stage: vk::PipelineStageFlags,
+        },
+    }
+
+    /// Keeps a list of every command recorded into it instead of sending them to a device.
+    #[derive(Default)]
+    pub struct MockCommands {
+        pub commands: Vec<RecordedCommand>,
+    }
+
+    impl TransferCommands for MockCommands {
+        fn transition_layout(
+            &mut self,
+            image: &impl ImageWrapper,
+            from: vk::ImageLayout,
+            to: vk::ImageLayout,
+        ) {
+            self.commands.push(RecordedCommand::TransitionLayout {
+                image: image.get_vk_image(),
+                from,
+                to,
+            });
+        }
+
+        fn copy_buffer_to_image_offset(
+            &mut self,
+            data_buffer: &impl BufferWrapper,
+            buffer_offset: u64,
+            buffer_width: u32,
+            buffer_height: u32,
+            image: &impl ImageWrapper,
+            offset: vk::Offset3D,
+            extent: &impl ExtentWrapper,
+        ) {
+            self.commands.push(RecordedCommand::CopyBufferToImage {
+                buffer: data_buffer.get_vk_buffer(),
+                buffer_offset,
+                buffer_size: (buffer_width, buffer_height),
+                image: image.get_vk_image(),
+                offset: (offset.x, offset.y, offset.z),
+                extent: extent.get_vk_extent(),
+            });
+        }
+
+        fn memory_barrier(
+            &mut self,
+            src_stage: vk::PipelineStageFlags,
+            dst_stage: vk::PipelineStageFlags,
+        ) {
+            self.commands.push(RecordedCommand::MemoryBarrier {
+                src_stage,
+                dst_stage,
+            });
+        }
+    }
+
+    /// A host buffer backed by a plain vector. The handle only identifies it in recorded commands.
+    pub struct MockBuffer<ItemType> {
+        pub handle: vk::Buffer,
+        pub data: Vec<ItemType>,
+    }
+
+    impl<ItemType: Clone + Default> MockBuffer<ItemType> {
+        pub fn new(handle: u64, num_items: usize) -> Self {
+            Self {
+                handle: vk::Buffer::from_raw(handle),
+                data: vec![Default::default(); num_items],
+            }
+        }
+    }
+
+    impl<ItemType> BufferWrapper for MockBuffer<ItemType> {
+        fn get_vk_buffer(&self) -> vk::Buffer {
+            self.handle
+        }
+    }
+
+    impl<ItemType> HostBuffer<ItemType> for MockBuffer<ItemType> {
+        fn with_mapped<R>(&mut self, f: impl FnOnce(&mut [ItemType]) -> R) -> R {
+            f(&mut self.data[..])
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use ash::version::DeviceV1_0;
 use ash::vk;
 use image::GenericImageView;
+use std::cell::Cell;
 use std::marker::PhantomData;
 use std::ops::{Index, IndexMut};
 use std::rc::Rc;
@@ -8,6 +9,7 @@ use std::rc::Rc;
 use super::command_buffer::CommandBuffer;
 use super::core::Core;
 use super::descriptors::DescriptorPrototype;
+use super::lifetime::DeferredObject;
 
 pub trait BufferWrapper {
     fn get_vk_buffer(&self) -> vk::Buffer;
@@ -21,6 +23,11 @@ impl BufferWrapper for vk::Buffer {
 
 pub trait ImageWrapper {
     fn get_vk_image(&self) -> vk::Image;
+
+    /// None for images whose layout is not tracked, such as swapchain images.
+    fn get_image_state(&self) -> Option<&ImageState> {
+        None
+    }
 }
 
 impl ImageWrapper for vk::Image {
@@ -29,6 +36,56 @@ impl ImageWrapper for vk::Image {
     }
 }
 
+/// Which layout an image will be in once every command recorded so far has run. CommandBuffer
+/// updates it whenever it records a transition and checks it whenever it records a command which
+/// needs the image to be in a particular layout, so that transitions from a layout the image is
+/// not actually in are caught while recording instead of corrupting the image on the GPU. Command
+/// buffers which are recorded ahead of time must leave their images in the same layouts they
+/// found them in for this to stay accurate.
+pub struct ImageState {
+    name: String,
+    layout: Cell<vk::ImageLayout>,
+}
+
+impl ImageState {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            layout: Cell::new(vk::ImageLayout::UNDEFINED),
+        }
+    }
+
+    pub fn get_layout(&self) -> vk::ImageLayout {
+        self.layout.get()
+    }
+
+    /// Returns the layout a transition which was written as starting from the given one should
+    /// really start from, warning if they are different. Transitions from UNDEFINED throw away
+    /// the contents of the image, so they are correct from any layout.
+    pub fn begin_transition(&self, from: vk::ImageLayout, to: vk::ImageLayout) -> vk::ImageLayout {
+        let current = self.layout.replace(to);
+        if from == vk::ImageLayout::UNDEFINED || from == current {
+            return from;
+        }
+        println!(
+            "WARNING: {} was transitioned from {:?} to {:?}, but it was in {:?}.",
+            self.name, from, to, current
+        );
+        current
+    }
+
+    /// Warns if the image is not in the layout a command is about to use it in.
+    pub fn check_layout(&self, expected: vk::ImageLayout) {
+        let current = self.layout.get();
+        if current != expected {
+            println!(
+                "WARNING: {} was used as if it was in {:?}, but it was in {:?}.",
+                self.name, expected, current
+            );
+        }
+    }
+}
+
 pub trait ImageViewWrapper {
     fn get_vk_image_view(&self) -> vk::ImageView;
 }
@@ -74,6 +131,12 @@ macro_rules! wrap_field {
             fn get_vk_image(&self) -> vk::Image { self.image }
         }
     };
+    {$struct_name:ty, [$($type_parameter:ident),*], tracked_image} => {
+        impl<$($type_parameter),*> ImageWrapper for $struct_name {
+            fn get_vk_image(&self) -> vk::Image { self.image }
+            fn get_image_state(&self) -> Option<&ImageState> { Some(&self.state) }
+        }
+    };
     {$struct_name:ty, [$($type_parameter:ident),*], image_view} => {
         impl<$($type_parameter),*> ImageViewWrapper for $struct_name {
             fn get_vk_image_view(&self) -> vk::ImageView { self.image_view }
@@ -162,6 +225,29 @@ derive_wrappers!(Buffer<ItemType>, [ItemType], [core, buffer]);
 
 impl<ItemType> Buffer<ItemType> {
     pub fn create(core: Rc<Core>, name: &str, num_items: u64, usage: vk::BufferUsageFlags) -> Self {
+        let memory_properties =
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        Self::create_with_properties(core, name, num_items, usage, memory_properties)
+    }
+
+    /// Creates a buffer which only the GPU can access, so bind_all must never be called on it.
+    pub fn create_device_local(
+        core: Rc<Core>,
+        name: &str,
+        num_items: u64,
+        usage: vk::BufferUsageFlags,
+    ) -> Self {
+        let memory_properties = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        Self::create_with_properties(core, name, num_items, usage, memory_properties)
+    }
+
+    fn create_with_properties(
+        core: Rc<Core>,
+        name: &str,
+        num_items: u64,
+        usage: vk::BufferUsageFlags,
+        memory_properties: vk::MemoryPropertyFlags,
+    ) -> Self {
         let size = num_items * std::mem::size_of::<ItemType>() as u64;
         let create_info = vk::BufferCreateInfo {
             size,
@@ -181,7 +267,7 @@ impl<ItemType> Buffer<ItemType> {
             allocation_size: memory_requirements.size,
             memory_type_index: core.find_compatible_memory_type(
                 memory_requirements.memory_type_bits,
-                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                memory_properties,
             ),
             ..Default::default()
         };
@@ -211,6 +297,11 @@ impl<ItemType> Buffer<ItemType> {
         DescriptorPrototype::UniformBuffer(self.buffer, 0, self.size)
     }
 
+    /// For buffers that shaders write to, which must be created with STORAGE_BUFFER usage.
+    pub fn create_storage_dp(&self) -> DescriptorPrototype {
+        DescriptorPrototype::StorageBuffer(self.buffer, 0, self.size)
+    }
+
     pub fn bind_all(&mut self) -> BufferView<ItemType> {
         let slice = unsafe {
             let ptr = self
@@ -243,10 +334,10 @@ impl<ItemType> Buffer<ItemType> {
 
 impl<ItemType> Drop for Buffer<ItemType> {
     fn drop(&mut self) {
-        unsafe {
-            self.core.device.destroy_buffer(self.buffer, None);
-            self.core.device.free_memory(self.memory, None);
-        }
+        self.core.destroy_deferred(&[
+            DeferredObject::Buffer(self.buffer),
+            DeferredObject::Memory(self.memory),
+        ]);
     }
 }
 
@@ -256,6 +347,8 @@ pub struct ImageOptions {
     pub format: vk::Format,
     pub usage: vk::ImageUsageFlags,
     pub mip_levels: u32,
+    /// 2D images with more than one layer are viewed as arrays.
+    pub array_layers: u32,
 }
 
 impl Default for ImageOptions {
@@ -266,6 +359,7 @@ impl Default for ImageOptions {
             format: Default::default(),
             usage: Default::default(),
             mip_levels: 1,
+            array_layers: 1,
         }
     }
 }
@@ -281,7 +375,7 @@ fn create_image_resources(
         format: options.format,
         samples: vk::SampleCountFlags::TYPE_1,
         mip_levels: options.mip_levels,
-        array_layers: 1,
+        array_layers: options.array_layers,
         usage: options.usage,
         tiling: vk::ImageTiling::OPTIMAL,
         sharing_mode: vk::SharingMode::EXCLUSIVE,
@@ -319,6 +413,7 @@ fn create_image_resources(
         image,
         view_type: match options.typ {
             vk::ImageType::TYPE_1D => vk::ImageViewType::TYPE_1D,
+            vk::ImageType::TYPE_2D if options.array_layers > 1 => vk::ImageViewType::TYPE_2D_ARRAY,
             vk::ImageType::TYPE_2D => vk::ImageViewType::TYPE_2D,
             vk::ImageType::TYPE_3D => vk::ImageViewType::TYPE_3D,
             _ => unreachable!("Encountered invalid ImageType."),
@@ -329,7 +424,7 @@ fn create_image_resources(
             base_mip_level: 0,
             level_count: options.mip_levels,
             base_array_layer: 0,
-            layer_count: 1,
+            layer_count: options.array_layers,
         },
         ..Default::default()
     };
@@ -349,8 +444,9 @@ pub struct StorageImage {
     pub image_view: vk::ImageView,
     pub extent: vk::Extent3D,
     memory: vk::DeviceMemory,
+    state: ImageState,
 }
-derive_wrappers!(StorageImage, [core, image, image_view, extent]);
+derive_wrappers!(StorageImage, [core, tracked_image, image_view, extent]);
 
 impl StorageImage {
     pub fn create(core: Rc<Core>, name: &str, options: &ImageOptions) -> Self {
@@ -361,6 +457,7 @@ impl StorageImage {
             image_view,
             extent: options.extent,
             memory,
+            state: ImageState::new(name),
         }
     }
 
@@ -371,11 +468,11 @@ impl StorageImage {
 
 impl Drop for StorageImage {
     fn drop(&mut self) {
-        unsafe {
-            self.core.device.destroy_image(self.image, None);
-            self.core.device.destroy_image_view(self.image_view, None);
-            self.core.device.free_memory(self.memory, None);
-        }
+        self.core.destroy_deferred(&[
+            DeferredObject::ImageView(self.image_view),
+            DeferredObject::Image(self.image),
+            DeferredObject::Memory(self.memory),
+        ]);
     }
 }
 
@@ -408,8 +505,12 @@ pub struct SampledImage {
     pub sampler: vk::Sampler,
     pub extent: vk::Extent3D,
     memory: vk::DeviceMemory,
+    state: ImageState,
 }
-derive_wrappers!(SampledImage, [core, image, image_view, sampler, extent]);
+derive_wrappers!(
+    SampledImage,
+    [core, tracked_image, image_view, sampler, extent]
+);
 
 impl SampledImage {
     pub fn create(
@@ -457,22 +558,28 @@ impl SampledImage {
             sampler,
             memory,
             extent: image_options.extent,
+            state: ImageState::new(name),
         }
     }
 
     pub fn create_dp(&self, layout: vk::ImageLayout) -> DescriptorPrototype {
         DescriptorPrototype::CombinedImageSampler(self.image_view, layout, self.sampler)
     }
+
+    /// For images which are also written to by shaders, which needs the STORAGE usage.
+    pub fn create_storage_dp(&self, layout: vk::ImageLayout) -> DescriptorPrototype {
+        DescriptorPrototype::StorageImage(self.image_view, layout)
+    }
 }
 
 impl Drop for SampledImage {
     fn drop(&mut self) {
-        unsafe {
-            self.core.device.destroy_sampler(self.sampler, None);
-            self.core.device.destroy_image_view(self.image_view, None);
-            self.core.device.destroy_image(self.image, None);
-            self.core.device.free_memory(self.memory, None);
-        }
+        self.core.destroy_deferred(&[
+            DeferredObject::Sampler(self.sampler),
+            DeferredObject::ImageView(self.image_view),
+            DeferredObject::Image(self.image),
+            DeferredObject::Memory(self.memory),
+        ]);
     }
 }
 
@@ -537,3 +644,24 @@ where
         load_commands.blocking_execute_and_destroy();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions_start_from_the_tracked_layout() {
+        let state = ImageState::new("test_image");
+        let general = vk::ImageLayout::GENERAL;
+        let transfer_dst = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+        let read_only = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        assert_eq!(
+            state.begin_transition(vk::ImageLayout::UNDEFINED, transfer_dst),
+            vk::ImageLayout::UNDEFINED
+        );
+        assert_eq!(state.begin_transition(transfer_dst, general), transfer_dst);
+        // The image is really in GENERAL, so that is where the transition has to start.
+        assert_eq!(state.begin_transition(transfer_dst, read_only), general);
+        assert_eq!(state.get_layout(), read_only);
+    }
+}
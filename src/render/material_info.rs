@@ -0,0 +1,69 @@
+use crate::render::{Material, MATERIALS, MATERIAL_INFO};
+
+/// Which sound is played when walking on a material, set in the footstep column of
+/// materials.csv. See footstep_for in audio.rs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FootstepSound {
+    /// Nothing is played, like for air.
+    None,
+    Grass,
+    Soil,
+    Stone,
+    Snow,
+    Glass,
+    Splash,
+}
+
+/// Everything about a material which gameplay cares about but rendering does not, see
+/// MATERIAL_INFO.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialInfo {
+    pub footstep: FootstepSound,
+    /// The color of the bits which fly off when the material is broken, from 0 to 1.
+    pub particles: (f32, f32, f32),
+    /// How many seconds the break control has to be held to break the material. Zero breaks it
+    /// as soon as it is pressed.
+    pub hardness: f32,
+}
+
+/// Used for materials which are not in MATERIALS, which can't be told apart any further since
+/// blocks only store what is needed to render them.
+const UNKNOWN: MaterialInfo = MaterialInfo {
+    footstep: FootstepSound::Stone,
+    particles: (0.5, 0.5, 0.5),
+    hardness: 0.0,
+};
+
+impl MaterialInfo {
+    /// Looks up the entry of MATERIAL_INFO for whichever material in MATERIALS is stored the same
+    /// way as the given one.
+    pub fn of(material: &Material) -> &'static MaterialInfo {
+        let packed = material.pack();
+        MATERIALS
+            .iter()
+            .position(|candidate| candidate.pack() == packed)
+            .map(|index| &MATERIAL_INFO[index])
+            .unwrap_or(&UNKNOWN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_is_found_from_blocks() {
+        assert_eq!(MATERIAL_INFO.len(), MATERIALS.len());
+        let stored = Material::unpack(MATERIALS[6].pack());
+        assert_eq!(MaterialInfo::of(&stored).footstep, FootstepSound::Snow);
+        assert_eq!(
+            MaterialInfo::of(&Material::air()).footstep,
+            FootstepSound::None
+        );
+        let unknown = Material {
+            albedo: (1, 2, 3),
+            ..MATERIALS[4].clone()
+        };
+        assert_eq!(MaterialInfo::of(&unknown), &UNKNOWN);
+    }
+}
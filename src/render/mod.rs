@@ -2,13 +2,39 @@ use std::rc::Rc;
 use winit::event_loop::EventLoop;
 
 mod GEN_MATERIALS;
+pub mod atmosphere;
 pub mod constants;
+pub mod debug_view;
+pub mod emission;
+pub mod material_info;
 pub(self) mod general;
+pub mod palette;
+pub mod pip_camera;
 pub(self) mod pipeline;
+pub mod settings;
+pub mod stage_toggles;
+pub mod streaming_map;
+pub mod text;
+pub mod tonemap;
 pub(self) mod util;
+pub mod white_balance;
+pub mod world_mapping;
 
+pub use debug_view::DebugView;
 pub use general::core::Core;
-pub use pipeline::Pipeline;
+pub use general::debug::get_error_count as get_validation_error_count;
+pub use general::image_statistics::ImageStatistics;
+pub use palette::Palette;
+pub use pip_camera::PipCamera;
+pub use pipeline::{
+    BeautyShotRequest, PanoramaLayout, PanoramaRequest, Pipeline, RayStatistics,
+    DEFAULT_BEAUTY_SHOT_FRAMES,
+};
+pub use settings::{
+    DenoiseSchedule, LightingFormat, OutputFormat, QualityPreset, RenderSettings, TemporalSettings,
+};
+pub use stage_toggles::StageToggles;
+pub use tonemap::Tonemapper;
 pub use GEN_MATERIALS::*;
 
 // Positive Y (angle PI / 2) is forward
@@ -16,9 +42,11 @@ pub use GEN_MATERIALS::*;
 // Positive Z is up
 // Heading starts at Positive X and goes clockwise (towards Positive Y).
 // Pitch starts at zero and positive pitch looks up at Positive Z.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Camera {
-    pub origin: cgmath::Vector3<f32>,
+    // Stored at double precision so that it stays accurate far away from the origin of the world.
+    // Use util::world_to_local to get a position that can be sent to the GPU.
+    pub origin: cgmath::Vector3<f64>,
     pub heading: cgmath::Rad<f32>,
     pub pitch: cgmath::Rad<f32>,
 }
@@ -31,13 +59,108 @@ impl Camera {
             pitch: cgmath::Rad(0.0),
         }
     }
+
+    /// Blends between this camera and another, alpha = 0 returns this camera and alpha = 1
+    /// returns the other one.
+    pub fn interpolate(&self, other: &Camera, alpha: f32) -> Camera {
+        Camera {
+            origin: self.origin + (other.origin - self.origin) * alpha as f64,
+            heading: self.heading + (other.heading - self.heading) * alpha,
+            pitch: self.pitch + (other.pitch - self.pitch) * alpha,
+        }
+    }
 }
 
-pub fn create_instance(
-    event_loop: &EventLoop<()>,
+/// Only the window, vsync, output format and validation settings are used, so the quality preset
+/// can be detected from the device before the rest of the settings are decided. See
+/// create_pipeline. Settings which are invalid on any device are rejected before the window is
+/// opened.
+pub fn create_core(event_loop: &EventLoop<()>, settings: &RenderSettings) -> Rc<Core> {
+    if let Err(problems) = settings.validate_without_device() {
+        panic!("Invalid render settings:\n{}", problems);
+    }
+    Rc::new(Core::new(event_loop, settings))
+}
+
+pub fn detect_quality(core: &Core) -> QualityPreset {
+    let (device_type, memory) = core.get_device_type_and_memory();
+    QualityPreset::detect(device_type, memory)
+}
+
+pub fn create_pipeline(
+    core: Rc<Core>,
     game: &mut crate::game::Game,
-) -> (Rc<Core>, Pipeline) {
-    let core = Rc::new(Core::new(event_loop));
-    let pipeline = Pipeline::new(core.clone(), game);
-    (core, pipeline)
+    settings: &mut RenderSettings,
+) -> Pipeline {
+    let limits = core.get_physical_device_limits();
+    for downgrade in settings.fit_to_limits(&limits) {
+        println!("WARNING: {}", downgrade);
+    }
+    if let Err(problems) = settings.validate(&limits) {
+        panic!("Invalid render settings:\n{}", problems);
+    }
+    Pipeline::new(core, game, settings)
+}
+
+/// Applies render settings which were changed while the game is running and returns the settings
+/// now in use. The window is only created once and the swapchain is only recreated to follow its
+/// size, so changes to their settings are ignored until the game is restarted. Changing the size
+/// of the world recreates the pipeline, which carries over the temporal history of the old one.
+pub fn reload_settings(
+    core: &Rc<Core>,
+    pipeline: &mut Pipeline,
+    game: &mut crate::game::Game,
+    current: &RenderSettings,
+    new: &RenderSettings,
+) -> RenderSettings {
+    let mut applied = new.clone();
+    if (new.window_width, new.window_height) != (current.window_width, current.window_height) {
+        println!("WARNING: The window will not be resized until the game is restarted.");
+        applied.window_width = current.window_width;
+        applied.window_height = current.window_height;
+    }
+    if new.vsync != current.vsync {
+        println!("WARNING: Changes to vsync will not apply until the game is restarted.");
+        applied.vsync = current.vsync;
+    }
+    if new.output_format != current.output_format {
+        println!("WARNING: Changes to output_format will not apply until the game is restarted.");
+        applied.output_format = current.output_format;
+    }
+    if new.validation != current.validation {
+        println!("WARNING: Changes to validation will not apply until the game is restarted.");
+        applied.validation = current.validation;
+    }
+    let limits = core.get_physical_device_limits();
+    for downgrade in applied.fit_to_limits(&limits) {
+        println!("WARNING: {}", downgrade);
+    }
+    let valid = applied.validate_without_device();
+    if let Err(problems) = valid.and_then(|_| applied.validate(&limits)) {
+        println!("WARNING: Invalid render settings, keeping the old ones.");
+        println!("Caused by: {}", problems);
+        return current.clone();
+    }
+    game.set_max_fps(applied.max_fps);
+    game.set_denoise_schedule(applied.denoise_schedule.clone());
+    pipeline.set_temporal_settings(&applied.temporal);
+    pipeline.set_sun_angular_radius(applied.sun_angular_radius);
+    pipeline.set_denoise_gradient_weight(applied.denoise_gradient_weight);
+    pipeline.set_denoise_variance_weight(applied.denoise_variance_weight);
+    pipeline.set_render_scale(applied.render_scale);
+    pipeline.set_distant_terrain(applied.distant_terrain);
+    pipeline.set_auto_white_balance(applied.auto_white_balance);
+    let shading_changed = applied.sun_heightmap != current.sun_heightmap
+        || applied.smooth_normals != current.smooth_normals
+        || applied.world_wrap != current.world_wrap
+        || applied.light_volume != current.light_volume;
+    if applied.root_chunk_size != current.root_chunk_size || shading_changed {
+        println!("Recreating renderer (and world.)");
+        let old_pipeline = std::mem::replace(pipeline, Pipeline::new(core.clone(), game, &applied));
+        pipeline.resample_history_from(&old_pipeline);
+    } else {
+        // Only needs new images and stages, which are created by shaders::create_stages.
+        pipeline.set_lighting_format(applied.lighting_format);
+    }
+    applied
 }
@@ -0,0 +1,109 @@
+use cgmath::Vector4;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// Restyles the colors of every material as they are unpacked on the GPU, without changing the
+/// blocks of the world. Useful for stylized screenshots.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Palette {
+    Default,
+    /// Greens turn orange and red, and blues fade.
+    Autumn,
+    Greyscale,
+    /// Every surface is the same matte light grey, so that only the shapes and lighting show.
+    Clay,
+}
+
+impl Palette {
+    pub const ALL: [Palette; 4] = [
+        Palette::Default,
+        Palette::Autumn,
+        Palette::Greyscale,
+        Palette::Clay,
+    ];
+
+    pub fn get_name(self) -> &'static str {
+        match self {
+            Palette::Default => "default",
+            Palette::Autumn => "autumn",
+            Palette::Greyscale => "greyscale",
+            Palette::Clay => "clay",
+        }
+    }
+
+    /// The first three values are the columns of a matrix the albedo is multiplied by, then the
+    /// RGB of the last one is added to it. The alpha of the last one replaces the roughness
+    /// unless it is negative. See unpack_material in raytrace_common.glsl.
+    pub fn to_uniform(self) -> [Vector4<f32>; 4] {
+        let columns = |r: [f32; 3], g: [f32; 3], b: [f32; 3], offset: f32, roughness: f32| {
+            [
+                Vector4::new(r[0], r[1], r[2], 0.0),
+                Vector4::new(g[0], g[1], g[2], 0.0),
+                Vector4::new(b[0], b[1], b[2], 0.0),
+                Vector4::new(offset, offset, offset, roughness),
+            ]
+        };
+        // How much red, green and blue each contribute to brightness.
+        let luma = [0.2126, 0.7152, 0.0722];
+        match self {
+            Palette::Default => {
+                columns([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], 0.0, -1.0)
+            }
+            Palette::Autumn => columns(
+                [0.9, 0.2, 0.0],
+                [0.6, 0.55, 0.0],
+                [0.0, 0.0, 0.4],
+                0.0,
+                -1.0,
+            ),
+            Palette::Greyscale => columns([luma[0]; 3], [luma[1]; 3], [luma[2]; 3], 0.0, -1.0),
+            Palette::Clay => columns([0.0; 3], [0.0; 3], [0.0; 3], 0.75, 1.0),
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::Default
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Palette, String> {
+        Self::ALL
+            .iter()
+            .cloned()
+            .find(|palette| palette.get_name() == text)
+            .ok_or_else(|| format!("'{}' is not a palette.", text))
+    }
+}
+
+impl Display for Palette {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for palette in Palette::ALL.iter() {
+            assert_eq!(palette.to_string().parse(), Ok(*palette));
+        }
+        assert!("sepia".parse::<Palette>().is_err());
+    }
+
+    #[test]
+    fn greyscale_keeps_brightness() {
+        let uniform = Palette::Greyscale.to_uniform();
+        // White should stay white.
+        let sum = uniform[0] + uniform[1] + uniform[2];
+        assert!((sum.x - 1.0).abs() < 1e-5 && (sum.y - sum.z).abs() < 1e-5);
+        assert_eq!(Palette::Default.to_uniform()[3].w, -1.0);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::util::{self, TripleEulerVector};
+use cgmath::{Rad, Vector3};
+use std::f32::consts::FRAC_PI_2;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+// How far above the camera the overhead view is.
+const OVERHEAD_HEIGHT: f64 = 48.0;
+// How far back from and above the camera the behind view is.
+const BEHIND_DISTANCE: f64 = 12.0;
+const BEHIND_HEIGHT: f64 = 6.0;
+
+/// Where the picture-in-picture view in the corner of the screen is rendered from, relative to
+/// the main camera.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PipCamera {
+    /// Looking straight down at the camera, with the direction it faces at the top.
+    Overhead,
+    /// Following behind the camera and looking down at where it is.
+    Behind,
+}
+
+impl PipCamera {
+    pub const ALL: [PipCamera; 2] = [PipCamera::Overhead, PipCamera::Behind];
+
+    pub fn get_name(self) -> &'static str {
+        match self {
+            PipCamera::Overhead => "overhead",
+            PipCamera::Behind => "behind",
+        }
+    }
+
+    /// Returns the origin and directions of the view given the origin and heading of the main
+    /// camera.
+    pub fn get_view(
+        self,
+        origin: Vector3<f64>,
+        heading: Rad<f32>,
+    ) -> (Vector3<f64>, TripleEulerVector) {
+        match self {
+            PipCamera::Overhead => {
+                let origin = origin + Vector3::new(0.0, 0.0, OVERHEAD_HEIGHT);
+                let vectors = util::compute_triple_euler_vector(heading, Rad(-FRAC_PI_2));
+                (origin, vectors)
+            }
+            PipCamera::Behind => {
+                let back = Vector3::new(-heading.0.cos() as f64, -heading.0.sin() as f64, 0.0);
+                let offset = back * BEHIND_DISTANCE + Vector3::new(0.0, 0.0, BEHIND_HEIGHT);
+                let pitch = -(BEHIND_HEIGHT / BEHIND_DISTANCE).atan() as f32;
+                let vectors = util::compute_triple_euler_vector(heading, Rad(pitch));
+                (origin + offset, vectors)
+            }
+        }
+    }
+}
+
+impl FromStr for PipCamera {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<PipCamera, String> {
+        Self::ALL
+            .iter()
+            .cloned()
+            .find(|camera| camera.get_name() == text)
+            .ok_or_else(|| format!("'{}' is not a picture-in-picture camera.", text))
+    }
+}
+
+impl Display for PipCamera {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+
+    #[test]
+    fn views_look_at_the_camera() {
+        let origin = Vector3::new(10.0, -4.0, 30.0);
+        for camera in PipCamera::ALL.iter() {
+            let (view_origin, vectors) = camera.get_view(origin, Rad(1.0));
+            let to_camera = (origin - view_origin).normalize();
+            let to_camera =
+                Vector3::new(to_camera.x as f32, to_camera.y as f32, to_camera.z as f32);
+            assert!(vectors.forward.dot(to_camera) > 0.999);
+        }
+    }
+
+    #[test]
+    fn pip_camera_names_round_trip() {
+        for camera in PipCamera::ALL.iter() {
+            assert_eq!(camera.to_string().parse(), Ok(*camera));
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::render::Camera;
+
+/// Keeps track of how long the camera has been still, so that once it has been still for long
+/// enough the temporal stage can average every frame evenly instead of blending them, which
+/// converges to a noiseless image. See accumulation_buffer in temporal.comp.
+pub struct Accumulation {
+    // How many frames the camera has to be still for before accumulation starts, zero when
+    // accumulation is turned off.
+    delay: u32,
+    last_camera: Option<Camera>,
+    // How many frames in a row were rendered with last_camera, including the last one.
+    still_frames: u32,
+}
+
+impl Accumulation {
+    pub fn new(delay: u32) -> Self {
+        Self {
+            delay,
+            last_camera: None,
+            still_frames: 0,
+        }
+    }
+
+    pub fn set_delay(&mut self, delay: u32) {
+        if delay != self.delay {
+            self.delay = delay;
+            self.reset();
+        }
+    }
+
+    /// Starts accumulating over, for when something other than the camera changed what the frame
+    /// looks like.
+    pub fn reset(&mut self) {
+        self.last_camera = None;
+        self.still_frames = 0;
+    }
+
+    /// Should be called once for each frame with the camera it is rendered with. Returns how many
+    /// frames the accumulation buffer averages including this one, or zero if the frame should
+    /// not be accumulated.
+    pub fn advance(&mut self, camera: &Camera) -> u32 {
+        if self.last_camera.as_ref() == Some(camera) {
+            self.still_frames = self.still_frames.saturating_add(1);
+        } else {
+            self.last_camera = Some(camera.clone());
+            self.still_frames = 1;
+        }
+        if self.delay == 0 || self.still_frames < self.delay {
+            0
+        } else {
+            self.still_frames - self.delay + 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulates_after_delay() {
+        let mut accumulation = Accumulation::new(3);
+        let camera = Camera::new();
+        let counts: Vec<_> = (0..5).map(|_| accumulation.advance(&camera)).collect();
+        assert_eq!(counts, vec![0, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn moving_starts_over() {
+        let mut accumulation = Accumulation::new(1);
+        let mut camera = Camera::new();
+        assert_eq!(accumulation.advance(&camera), 1);
+        assert_eq!(accumulation.advance(&camera), 2);
+        camera.heading.0 += 0.1;
+        assert_eq!(accumulation.advance(&camera), 1);
+        accumulation.reset();
+        assert_eq!(accumulation.advance(&camera), 1);
+    }
+
+    #[test]
+    fn zero_delay_turns_it_off() {
+        let mut accumulation = Accumulation::new(0);
+        let camera = Camera::new();
+        for _ in 0..4 {
+            assert_eq!(accumulation.advance(&camera), 0);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::render::general::command_buffer::CommandBuffer;
+use crate::render::general::core::Core;
+use crate::render::general::structures::Buffer;
+use ash::vk;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use time::PrimitiveDateTime;
+
+/// How many frames are blended together for a beauty shot if the command does not say.
+pub const DEFAULT_BEAUTY_SHOT_FRAMES: u32 = 64;
+
+/// Asks the pipeline to render a still image of the current view without the HUD and save it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BeautyShotRequest {
+    pub path: PathBuf,
+    /// How many frames are blended together before the image is saved.
+    pub frames: u32,
+    /// The saved image is this many times smaller along each axis than what was rendered, which
+    /// smooths out edges when rendering at a higher resolution than the final image.
+    pub downsample: u32,
+}
+
+/// A beauty shot which is being rendered. While one is in progress, every frame is blended evenly
+/// into the temporal history instead of using the interactive history weight, so the noise keeps
+/// going down until the image is saved. The camera should stay still in the meantime.
+pub struct BeautyShot {
+    request: BeautyShotRequest,
+    frames_rendered: u32,
+}
+
+impl BeautyShot {
+    pub fn new(request: BeautyShotRequest) -> BeautyShot {
+        BeautyShot {
+            request,
+            frames_rendered: 0,
+        }
+    }
+
+    pub fn borrow_request(&self) -> &BeautyShotRequest {
+        &self.request
+    }
+
+    /// How much of the history to keep in the next frame so that every frame rendered for the shot
+    /// contributes equally.
+    pub fn get_history_weight(&self) -> f32 {
+        let frames = self.frames_rendered as f32;
+        frames / (frames + 1.0)
+    }
+
+    /// Should be called after each frame is submitted. Returns true if that frame was the last
+    /// one, meaning it should be saved.
+    pub fn advance(&mut self) -> bool {
+        self.frames_rendered += 1;
+        self.frames_rendered >= self.request.frames
+    }
+}
+
+/// Rearranges pixels in the given swapchain format into RGBA. The alpha channel is made opaque,
+/// since the swapchain is composited as if it were.
+fn convert_to_rgba(pixels: &mut [u8], format: vk::Format) {
+    let blue_first = format == vk::Format::B8G8R8A8_UNORM || format == vk::Format::B8G8R8A8_SRGB;
+    for pixel in pixels.chunks_mut(4) {
+        if blue_first {
+            pixel.swap(0, 2);
+        }
+        pixel[3] = 255;
+    }
+}
+
+/// Averages each square of factor by factor RGBA pixels into one pixel. Pixels past the last full
+/// square on the right and bottom edges are dropped.
+fn downsample(pixels: &[u8], width: u32, height: u32, factor: u32) -> (Vec<u8>, u32, u32) {
+    let (new_width, new_height) = (width / factor, height / factor);
+    let mut result = Vec::with_capacity((new_width * new_height * 4) as usize);
+    for y in 0..new_height {
+        for x in 0..new_width {
+            let mut sum = [0u32; 4];
+            for sample_y in y * factor..(y + 1) * factor {
+                for sample_x in x * factor..(x + 1) * factor {
+                    let index = ((sample_y * width + sample_x) * 4) as usize;
+                    for channel in 0..4 {
+                        sum[channel] += pixels[index + channel] as u32;
+                    }
+                }
+            }
+            let count = factor * factor;
+            result.extend(sum.iter().map(|total| ((total + count / 2) / count) as u8));
+        }
+    }
+    (result, new_width, new_height)
+}
+
+/// Copies a swapchain image into memory as RGBA pixels. The image must be finished rendering and
+/// not presented yet, and the swapchain must have been created with TRANSFER_SRC usage.
+pub fn read_swapchain_image(core: &Rc<Core>, image_index: u32) -> Vec<u8> {
+    let swapchain = core.borrow_swapchain();
+    let image = swapchain.swapchain_images[image_index as usize];
+    let extent = vk::Extent3D {
+        width: swapchain.swapchain_extent.width,
+        height: swapchain.swapchain_extent.height,
+        depth: 1,
+    };
+    let size = extent.width as u64 * extent.height as u64 * 4;
+    let mut buffer = Buffer::<u8>::create(
+        core.clone(),
+        "beauty_shot",
+        size,
+        vk::BufferUsageFlags::TRANSFER_DST,
+    );
+    let commands = CommandBuffer::create_single(core.clone());
+    commands.begin_one_time_submit();
+    let present = vk::ImageLayout::PRESENT_SRC_KHR;
+    let transfer = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
+    commands.transition_layout(&image, present, transfer);
+    commands.copy_image_to_buffer(&image, &extent, &buffer);
+    commands.transition_layout(&image, transfer, present);
+    commands.end();
+    commands.blocking_execute_and_destroy();
+
+    let mut pixels = buffer.bind_all().as_slice_mut().to_vec();
+    convert_to_rgba(&mut pixels, swapchain.swapchain_format);
+    pixels
+}
+
+/// Writes RGBA pixels to an image file, warning instead of failing if it can't be saved. The
+/// format is picked from the extension of the path.
+fn save_pixels(pixels: &[u8], width: u32, height: u32, path: &Path, description: &str) {
+    let color_type = image::ColorType::RGBA(8);
+    match image::save_buffer(path, pixels, width, height, color_type) {
+        Ok(()) => println!(
+            "Saved a {}x{} {} to {:?}.",
+            width, height, description, path
+        ),
+        Err(err) => {
+            println!("WARNING: Failed to save {} to {:?}.", description, path);
+            println!("Caused by: {}", err);
+        }
+    }
+}
+
+/// Saves a swapchain image as the image file a beauty shot asked for. See read_swapchain_image
+/// for when this can be called.
+pub fn save_swapchain_image(core: &Rc<Core>, image_index: u32, request: &BeautyShotRequest) {
+    let pixels = read_swapchain_image(core, image_index);
+    let extent = core.borrow_swapchain().swapchain_extent;
+    let (pixels, width, height) = if request.downsample > 1 {
+        downsample(&pixels, extent.width, extent.height, request.downsample)
+    } else {
+        (pixels, extent.width, extent.height)
+    };
+    save_pixels(&pixels, width, height, &request.path, "beauty shot");
+}
+
+/// Where a screenshot taken at the given time is saved. Times are in UTC, since that is all the
+/// time crate can tell on every platform.
+pub fn get_screenshot_path(taken_at: PrimitiveDateTime) -> PathBuf {
+    let name = format!("screenshot_{}.png", taken_at.format("%Y-%m-%d_%H-%M-%S"));
+    dirs::config_dir()
+        .expect("System somehow doesn't have a config dir?")
+        .join("raytrace")
+        .join("screenshots")
+        .join(name)
+}
+
+/// Saves a swapchain image exactly as it is shown, HUD included, to a new file named after the
+/// current time. See read_swapchain_image for when this can be called.
+pub fn save_screenshot(core: &Rc<Core>, image_index: u32) {
+    let pixels = read_swapchain_image(core, image_index);
+    let extent = core.borrow_swapchain().swapchain_extent;
+    let path = get_screenshot_path(PrimitiveDateTime::now());
+    if let Err(err) = std::fs::create_dir_all(path.parent().unwrap()) {
+        println!("WARNING: Failed to create the screenshot directory.");
+        println!("Caused by: {}", err);
+        return;
+    }
+    save_pixels(&pixels, extent.width, extent.height, &path, "screenshot");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_weighted_evenly() {
+        let request = BeautyShotRequest {
+            path: PathBuf::from("shot.png"),
+            frames: 3,
+            downsample: 1,
+        };
+        let mut shot = BeautyShot::new(request);
+        // The first frame has no history to blend with.
+        assert_eq!(shot.get_history_weight(), 0.0);
+        assert!(!shot.advance());
+        assert_eq!(shot.get_history_weight(), 0.5);
+        assert!(!shot.advance());
+        assert!(shot.advance());
+    }
+
+    #[test]
+    fn downsample_averages_squares() {
+        #[rustfmt::skip]
+        let pixels = vec![
+            0, 0, 0, 255,   4, 8, 0, 255,   9, 9, 9, 9,
+            0, 0, 0, 255,   4, 8, 2, 255,   9, 9, 9, 9,
+        ];
+        let (result, width, height) = downsample(&pixels, 3, 2, 2);
+        assert_eq!((width, height), (1, 1));
+        assert_eq!(result, vec![2, 4, 1, 255]);
+    }
+
+    #[test]
+    fn screenshots_are_named_after_time() {
+        let taken_at = time::Date::try_from_ymd(2020, 3, 14)
+            .unwrap()
+            .try_with_hms(9, 5, 26)
+            .unwrap();
+        let path = get_screenshot_path(taken_at);
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(name, "screenshot_2020-03-14_09-05-26.png");
+        assert!(path.parent().unwrap().ends_with("raytrace/screenshots"));
+    }
+
+    #[test]
+    fn swaps_blue_first_pixels() {
+        let mut pixels = vec![1, 2, 3, 0, 4, 5, 6, 7];
+        convert_to_rgba(&mut pixels, vk::Format::B8G8R8A8_UNORM);
+        assert_eq!(pixels, vec![3, 2, 1, 255, 6, 5, 4, 255]);
+        convert_to_rgba(&mut pixels, vk::Format::R8G8B8A8_UNORM);
+        assert_eq!(pixels, vec![3, 2, 1, 255, 6, 5, 4, 255]);
+    }
+}
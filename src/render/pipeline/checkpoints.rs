@@ -0,0 +1,121 @@
+use super::gpu_timer::STAGE_NAMES;
+use crate::render::general::command_buffer::CommandBuffer;
+use crate::render::general::core::Core;
+use crate::render::general::structures::{Buffer, BufferWrapper};
+use ash::version::InstanceV1_0;
+use ash::vk;
+use std::os::raw::c_void;
+use std::rc::Rc;
+
+enum Markers {
+    // Each checkpoint's marker is the index of the stage which starts after it.
+    Nv(vk::NvDeviceDiagnosticCheckpointsFn),
+    // The first item holds one more than the index of the last stage which started, the second
+    // one more than the index of the last stage which finished.
+    Amd(vk::AmdBufferMarkerFn, Buffer<u32>),
+}
+
+/// Marks the start and end of each stage in the command buffers so that the stage which was
+/// running when the device was lost can be reported. This needs one of the extensions in
+/// OPTIONAL_DEVICE_EXTENSIONS.
+pub struct Checkpoints {
+    core: Rc<Core>,
+    markers: Markers,
+}
+
+impl Checkpoints {
+    /// Returns None if the device supports neither kind of marker.
+    pub fn new(core: Rc<Core>) -> Option<Checkpoints> {
+        let load = |name: &std::ffi::CStr| unsafe {
+            std::mem::transmute(
+                core.instance
+                    .get_device_proc_addr(core.device.handle(), name.as_ptr()),
+            )
+        };
+        let markers = if core.has_optional_extension("VK_NV_device_diagnostic_checkpoints") {
+            Markers::Nv(vk::NvDeviceDiagnosticCheckpointsFn::load(load))
+        } else if core.has_optional_extension("VK_AMD_buffer_marker") {
+            let mut buffer = Buffer::create(
+                core.clone(),
+                "stage_markers",
+                2,
+                vk::BufferUsageFlags::TRANSFER_DST,
+            );
+            buffer.bind_all().as_slice_mut().copy_from_slice(&[0, 0]);
+            Markers::Amd(vk::AmdBufferMarkerFn::load(load), buffer)
+        } else {
+            return None;
+        };
+        Some(Checkpoints { core, markers })
+    }
+
+    /// Should be recorded before the commands for each stage, in the order of STAGE_NAMES.
+    pub fn record_stage_start(&self, buffer: &CommandBuffer, stage: usize) {
+        let command_buffer = buffer.get_vk_command_buffer();
+        match &self.markers {
+            Markers::Nv(nv) => unsafe {
+                nv.cmd_set_checkpoint_nv(command_buffer, stage as *const c_void);
+            },
+            Markers::Amd(amd, markers) => unsafe {
+                amd.cmd_write_buffer_marker_amd(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    markers.get_vk_buffer(),
+                    0,
+                    stage as u32 + 1,
+                );
+            },
+        }
+    }
+
+    /// Should be recorded after the commands for each stage, in the order of STAGE_NAMES.
+    pub fn record_stage_end(&self, buffer: &CommandBuffer, stage: usize) {
+        // The checkpoint before the next stage already shows when this one finishes.
+        if let Markers::Amd(amd, markers) = &self.markers {
+            unsafe {
+                amd.cmd_write_buffer_marker_amd(
+                    buffer.get_vk_command_buffer(),
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    markers.get_vk_buffer(),
+                    4,
+                    stage as u32 + 1,
+                );
+            }
+        }
+    }
+
+    /// Prints which stage the GPU was running. Should be called after the device is lost.
+    pub fn report(&mut self) {
+        match &mut self.markers {
+            Markers::Nv(nv) => {
+                let queue = self.core.compute_queue;
+                let mut count = 0;
+                unsafe {
+                    nv.get_queue_checkpoint_data_nv(queue, &mut count, std::ptr::null_mut());
+                }
+                let mut data = vec![vk::CheckpointDataNV::default(); count as usize];
+                unsafe {
+                    nv.get_queue_checkpoint_data_nv(queue, &mut count, data.as_mut_ptr());
+                }
+                for checkpoint in data.iter().take(count as usize) {
+                    let stage = checkpoint.p_checkpoint_marker as usize;
+                    let name = STAGE_NAMES.get(stage).unwrap_or(&"unknown");
+                    println!(
+                        "The last checkpoint reached at {:?} was before the {} stage.",
+                        checkpoint.stage, name
+                    );
+                }
+            }
+            Markers::Amd(_, markers) => {
+                let values = markers.bind_all();
+                let (started, finished) = (values[0] as usize, values[1] as usize);
+                if started == 0 || started == finished {
+                    println!("The GPU was not running any stage.");
+                } else {
+                    let name = STAGE_NAMES.get(started - 1).unwrap_or(&"unknown");
+                    println!("The GPU was running the {} stage.", name);
+                }
+            }
+        }
+    }
+}
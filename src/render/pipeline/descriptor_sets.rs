@@ -1,4 +1,3 @@
-use ash::version::DeviceV1_0;
 use ash::vk;
 use std::rc::Rc;
 
@@ -13,81 +12,424 @@ create_descriptor_collection_struct! {
     name: DescriptorCollection,
     aux_data_type: RenderData,
     items: {
+        compact_reflections = generate_compact_reflections_ds_prototypes,
         denoise = generate_denoise_ds_prototypes,
+        estimate_variance = generate_estimate_variance_ds_prototypes,
         finalize = generate_finalize_ds_prototypes,
-        raytrace = generate_raytrace_ds_prototypes,
+        fog = generate_fog_ds_prototypes,
+        image_statistics = generate_image_statistics_ds_prototypes,
+        light_volume = generate_light_volume_ds_prototypes,
+        overlay = generate_overlay_ds_prototypes,
+        pip_output = generate_pip_output_ds_prototypes,
+        probe_capture = generate_probe_capture_ds_prototypes,
+        raygen = generate_raygen_ds_prototypes,
+        reflection_denoise = generate_reflection_denoise_ds_prototypes,
+        resolve = generate_resolve_ds_prototypes,
+        scene = generate_scene_ds_prototypes,
+        shade = generate_shade_ds_prototypes,
+        sun_heightmap = generate_sun_heightmap_ds_prototypes,
         swapchain = generate_swapchain_ds_prototypes,
+        temporal = generate_temporal_ds_prototypes,
+        terrain_fill = generate_terrain_fill_ds_prototypes,
+        text = generate_text_ds_prototypes,
+        traverse = generate_traverse_ds_prototypes,
+        validate_lighting = generate_validate_lighting_ds_prototypes,
     }
 }
 
+#[rustfmt::skip]
+fn generate_compact_reflections_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
+        render_data.normal_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.emission_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.reflection_work_list.create_storage_dp(),
+    ]]
+}
+
 #[rustfmt::skip] // It keeps trying to spread my beautiful descriptors over 3 lines :(
 fn generate_denoise_ds_prototypes(
     _core: Rc<Core>,
     render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    let pip = &render_data.pip;
+    // The main view ping-pongs between the first two, the picture-in-picture view the last two.
+    // The variance is filtered alongside the lighting, so it ping-pongs the same way.
+    let passes = [
+        (&render_data.lighting_buffer, &render_data.lighting_pong_buffer, &render_data.depth_buffer,
+            &render_data.normal_buffer, &render_data.smooth_normal_buffer,
+            &render_data.variance_buffer, &render_data.variance_pong_buffer),
+        (&render_data.lighting_pong_buffer, &render_data.lighting_buffer, &render_data.depth_buffer,
+            &render_data.normal_buffer, &render_data.smooth_normal_buffer,
+            &render_data.variance_pong_buffer, &render_data.variance_buffer),
+        (&pip.lighting_buffer, &pip.lighting_pong_buffer, &pip.depth_buffer, &pip.normal_buffer,
+            &pip.smooth_normal_buffer, &pip.variance_buffer, &pip.variance_pong_buffer),
+        (&pip.lighting_pong_buffer, &pip.lighting_buffer, &pip.depth_buffer, &pip.normal_buffer,
+            &pip.smooth_normal_buffer, &pip.variance_pong_buffer, &pip.variance_buffer),
+    ];
+    passes.iter().map(|(source, destination, depth, normal, smooth_normal, variance_source,
+            variance_destination)| vec![
+        source.create_dp(vk::ImageLayout::GENERAL),
+        depth.create_dp(vk::ImageLayout::GENERAL),
+        normal.create_dp(vk::ImageLayout::GENERAL),
+        //
+        destination.create_dp(vk::ImageLayout::GENERAL),
+        smooth_normal.create_dp(vk::ImageLayout::GENERAL),
+        variance_source.create_dp(vk::ImageLayout::GENERAL),
+        //
+        variance_destination.create_dp(vk::ImageLayout::GENERAL),
+    ]).collect()
+}
+
+/// The second variant is for the picture-in-picture view, which never reads the moments.
+#[rustfmt::skip]
+fn generate_estimate_variance_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    let pip = &render_data.pip;
+    let views = [
+        (&render_data.lighting_buffer, &render_data.depth_buffer, &render_data.normal_buffer,
+            &render_data.variance_buffer),
+        (&pip.lighting_buffer, &pip.depth_buffer, &pip.normal_buffer, &pip.variance_buffer),
+    ];
+    views.iter().map(|(lighting, depth, normal, variance)| vec![
+        lighting.create_dp(vk::ImageLayout::GENERAL),
+        depth.create_dp(vk::ImageLayout::GENERAL),
+        normal.create_dp(vk::ImageLayout::GENERAL),
+        render_data.moments_buffer.create_dp(vk::ImageLayout::GENERAL),
+        variance.create_dp(vk::ImageLayout::GENERAL),
+    ]).collect()
+}
+
+#[rustfmt::skip]
+fn generate_finalize_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    // The denoiser ping-pongs between the two lighting buffers, so which one holds the final
+    // result depends on whether it runs an even or odd number of passes. The last two variants
+    // are for the picture-in-picture view, which shows its own lighting on both sides of the
+    // comparison divider.
+    let pip = &render_data.pip;
+    let views = [
+        (&render_data.lighting_buffer, &render_data.albedo_buffer, &render_data.emission_buffer,
+            &render_data.fog_color_buffer, &render_data.depth_buffer,
+            &render_data.reflection_buffer, &render_data.comparison_buffer),
+        (&render_data.lighting_pong_buffer, &render_data.albedo_buffer,
+            &render_data.emission_buffer, &render_data.fog_color_buffer, &render_data.depth_buffer,
+            &render_data.reflection_buffer, &render_data.comparison_buffer),
+        (&pip.lighting_buffer, &pip.albedo_buffer, &pip.emission_buffer, &pip.fog_color_buffer,
+            &pip.depth_buffer, &pip.reflection_buffer, &pip.lighting_buffer),
+        (&pip.lighting_pong_buffer, &pip.albedo_buffer, &pip.emission_buffer,
+            &pip.fog_color_buffer, &pip.depth_buffer, &pip.reflection_buffer,
+            &pip.lighting_pong_buffer),
+    ];
+    views.iter().map(|(lighting, albedo, emission, fog_color, depth, reflection, comparison)| vec![
+        albedo.create_dp(vk::ImageLayout::GENERAL),
+        emission.create_dp(vk::ImageLayout::GENERAL),
+        fog_color.create_dp(vk::ImageLayout::GENERAL),
+        //
+        lighting.create_dp(vk::ImageLayout::GENERAL),
+        depth.create_dp(vk::ImageLayout::GENERAL),
+        //
+        render_data.blue_noise.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        reflection.create_dp(vk::ImageLayout::GENERAL),
+        //
+        comparison.create_dp(vk::ImageLayout::GENERAL),
+        render_data.temporal_uniform_data_buffer.create_dp(),
+        render_data.atmosphere_fog.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        render_data.atmosphere_grading.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+    ]).collect()
+}
+
+/// The second variant is for the picture-in-picture view, which never reads the history.
+#[rustfmt::skip]
+fn generate_fog_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    let outputs = [&render_data.fog_color_buffer, &render_data.pip.fog_color_buffer];
+    outputs.iter().map(|output| vec![
+        output.create_dp(vk::ImageLayout::GENERAL),
+        render_data.history_fog_color_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.temporal_uniform_data_buffer.create_dp(),
+    ]).collect()
+}
+
+#[rustfmt::skip]
+fn generate_image_statistics_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
+        render_data.lighting_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.image_statistics.create_storage_dp(),
+    ]]
+}
+
+#[rustfmt::skip]
+fn generate_light_volume_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
+        render_data.light_volume.create_dp(vk::ImageLayout::GENERAL),
+    ]]
+}
+
+#[rustfmt::skip]
+fn generate_probe_capture_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
+        render_data.reflection_probes.create_dp(vk::ImageLayout::GENERAL),
+        render_data.light_volume.create_dp(vk::ImageLayout::GENERAL),
+    ]]
+}
+
+#[rustfmt::skip]
+fn generate_sun_heightmap_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
+        render_data.sun_heightmap.create_dp(vk::ImageLayout::GENERAL),
+    ]]
+}
+
+#[rustfmt::skip]
+fn generate_overlay_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
+        render_data.overlay_uniform_data_buffer.create_dp(),
+        render_data.minimap.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        render_data.pip.output.create_dp(vk::ImageLayout::GENERAL),
+    ]]
+}
+
+/// Bound in place of the swapchain when finalizing the picture-in-picture view.
+#[rustfmt::skip]
+fn generate_pip_output_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
+        render_data.pip.output.create_dp(vk::ImageLayout::GENERAL),
+    ]]
+}
+
+#[rustfmt::skip]
+fn generate_raygen_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
 ) -> Vec<Vec<DescriptorPrototype>> {
     vec![
         vec![
-            render_data.lighting_buffer.create_dp(vk::ImageLayout::GENERAL),
+            render_data.ray_queue.create_storage_dp(),
             render_data.depth_buffer.create_dp(vk::ImageLayout::GENERAL),
-            render_data.normal_buffer.create_dp(vk::ImageLayout::GENERAL),
-            //
-            render_data.lighting_pong_buffer.create_dp(vk::ImageLayout::GENERAL),
         ],
         vec![
-            render_data.lighting_pong_buffer.create_dp(vk::ImageLayout::GENERAL),
-            render_data.normal_buffer.create_dp(vk::ImageLayout::GENERAL),
-            render_data.depth_buffer.create_dp(vk::ImageLayout::GENERAL),
-            //
-            render_data.lighting_buffer.create_dp(vk::ImageLayout::GENERAL),
+            render_data.ray_queue.create_storage_dp(),
+            render_data.pip.depth_buffer.create_dp(vk::ImageLayout::GENERAL),
         ],
     ]
 }
 
 #[rustfmt::skip]
-fn generate_finalize_ds_prototypes(
+fn generate_reflection_denoise_ds_prototypes(
     _core: Rc<Core>,
     render_data: &RenderData,
 ) -> Vec<Vec<DescriptorPrototype>> {
-    vec![vec![
-        render_data.albedo_buffer.create_dp(vk::ImageLayout::GENERAL),
-        render_data.emission_buffer.create_dp(vk::ImageLayout::GENERAL),
-        render_data.fog_color_buffer.create_dp(vk::ImageLayout::GENERAL),
-        //
-        render_data.lighting_buffer.create_dp(vk::ImageLayout::GENERAL),
+    let pairs = [
+        (&render_data.reflection_buffer, &render_data.reflection_pong_buffer),
+        (&render_data.reflection_pong_buffer, &render_data.reflection_buffer),
+    ];
+    pairs.iter().map(|(source, destination)| vec![
+        source.create_dp(vk::ImageLayout::GENERAL),
         render_data.depth_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.normal_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.emission_buffer.create_dp(vk::ImageLayout::GENERAL),
         //
-        render_data.blue_noise.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
-    ]]
+        destination.create_dp(vk::ImageLayout::GENERAL),
+        render_data.reflection_work_list.create_storage_dp(),
+    ]).collect()
 }
 
 #[rustfmt::skip]
-fn generate_raytrace_ds_prototypes(
+fn generate_resolve_ds_prototypes(
     _core: Rc<Core>,
     render_data: &RenderData,
 ) -> Vec<Vec<DescriptorPrototype>> {
-    vec![vec![
+    vec![
+        vec![
+            render_data.light_accumulators.create_storage_dp(),
+            render_data.lighting_buffer.create_dp(vk::ImageLayout::GENERAL),
+            render_data.reflection_buffer.create_dp(vk::ImageLayout::GENERAL),
+        ],
+        vec![
+            render_data.light_accumulators.create_storage_dp(),
+            render_data.pip.lighting_buffer.create_dp(vk::ImageLayout::GENERAL),
+            render_data.pip.reflection_buffer.create_dp(vk::ImageLayout::GENERAL),
+        ],
+    ]
+}
+
+/// Shared by every kernel of the raytrace stage. The second variant has the camera of the
+/// picture-in-picture view.
+#[rustfmt::skip]
+fn generate_scene_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    let uniform_buffers = [
+        &render_data.raytrace_uniform_data_buffer,
+        &render_data.pip.uniform_data_buffer,
+    ];
+    uniform_buffers.iter().map(|uniform_buffer| vec![
         render_data.material_image.create_dp(vk::ImageLayout::GENERAL),
         render_data.minefield_image.create_dp(vk::ImageLayout::GENERAL),
+        render_data.blue_noise.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        uniform_buffer.create_dp(),
+        render_data.chunk_occupancy.create_storage_dp(),
+    ]).collect()
+}
+
+#[rustfmt::skip]
+fn generate_shade_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    let queues = [
+        (&render_data.ray_queue, &render_data.ray_pong_queue),
+        (&render_data.ray_pong_queue, &render_data.ray_queue),
+    ];
+    let pip = &render_data.pip;
+    // The first two variants are for the main view, the last two for the picture-in-picture view.
+    let views = [
+        [&render_data.albedo_buffer, &render_data.emission_buffer, &render_data.normal_buffer,
+            &render_data.depth_buffer, &render_data.motion_buffer, &render_data.reflection_buffer,
+            &render_data.smooth_normal_buffer],
+        [&pip.albedo_buffer, &pip.emission_buffer, &pip.normal_buffer, &pip.depth_buffer,
+            &pip.motion_buffer, &pip.reflection_buffer, &pip.smooth_normal_buffer],
+    ];
+    let variants = views.iter().flat_map(|view| queues.iter().map(move |queue| (view, queue)));
+    variants.map(|([albedo, emission, normal, depth, motion, reflection, smooth_normal],
+            (input, output))| vec![
+        input.create_storage_dp(),
+        output.create_storage_dp(),
+        render_data.light_accumulators.create_storage_dp(),
         //
-        render_data.albedo_buffer.create_dp(vk::ImageLayout::GENERAL),
-        render_data.emission_buffer.create_dp(vk::ImageLayout::GENERAL),
-        render_data.fog_color_buffer.create_dp(vk::ImageLayout::GENERAL),
-        //
+        albedo.create_dp(vk::ImageLayout::GENERAL),
+        emission.create_dp(vk::ImageLayout::GENERAL),
+        normal.create_dp(vk::ImageLayout::GENERAL),
+        depth.create_dp(vk::ImageLayout::GENERAL),
+        motion.create_dp(vk::ImageLayout::GENERAL),
+        reflection.create_dp(vk::ImageLayout::GENERAL),
+        render_data.sun_heightmap.create_dp(vk::ImageLayout::GENERAL),
+        smooth_normal.create_dp(vk::ImageLayout::GENERAL),
+        render_data.light_volume.create_dp(vk::ImageLayout::GENERAL),
+        render_data.reflection_probes.create_dp(vk::ImageLayout::GENERAL),
+    ]).collect()
+}
+
+#[rustfmt::skip]
+fn generate_temporal_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
         render_data.lighting_buffer.create_dp(vk::ImageLayout::GENERAL),
-        render_data.completed_buffer.create_dp(vk::ImageLayout::GENERAL),
         render_data.normal_buffer.create_dp(vk::ImageLayout::GENERAL),
-        render_data.depth_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.motion_buffer.create_dp(vk::ImageLayout::GENERAL),
         //
-        render_data.blue_noise.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
-        render_data.raytrace_uniform_data_buffer.create_dp(),
+        render_data.history_lighting_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.history_depth_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.history_normal_buffer.create_dp(vk::ImageLayout::GENERAL),
+        //
+        render_data.completed_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.temporal_uniform_data_buffer.create_dp(),
+        render_data.ray_statistics.create_storage_dp(),
+        //
+        render_data.moments_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.history_moments_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.accumulation_buffer.create_dp(vk::ImageLayout::GENERAL),
+    ]]
+}
+
+#[rustfmt::skip]
+fn generate_terrain_fill_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
+        render_data.material_image.create_storage_dp(vk::ImageLayout::GENERAL),
+        render_data.minefield_image.create_storage_dp(vk::ImageLayout::GENERAL),
+        render_data.terrain_fills.create_storage_dp(),
+    ]]
+}
+
+#[rustfmt::skip]
+fn generate_text_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
+        render_data.font_atlas.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        render_data.text_uniform_data_buffer.create_dp(),
+    ]]
+}
+
+#[rustfmt::skip]
+fn generate_traverse_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![
+        vec![
+            render_data.ray_queue.create_storage_dp(),
+            render_data.chunk_access_mask.create_storage_dp(),
+            render_data.distant_terrain.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            render_data.ray_statistics.create_storage_dp(),
+        ],
+        vec![
+            render_data.ray_pong_queue.create_storage_dp(),
+            render_data.chunk_access_mask.create_storage_dp(),
+            render_data.distant_terrain.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            render_data.ray_statistics.create_storage_dp(),
+        ],
+    ]
+}
+
+#[rustfmt::skip]
+fn generate_validate_lighting_ds_prototypes(
+    _core: Rc<Core>,
+    render_data: &RenderData,
+) -> Vec<Vec<DescriptorPrototype>> {
+    vec![vec![
+        render_data.lighting_buffer.create_dp(vk::ImageLayout::GENERAL),
+        render_data.invalid_lighting_count.create_storage_dp(),
+        render_data.temporal_uniform_data_buffer.create_dp(),
     ]]
 }
 
 fn generate_swapchain_ds_prototypes(
     core: Rc<Core>,
-    _render_data: &RenderData,
+    render_data: &RenderData,
 ) -> Vec<Vec<DescriptorPrototype>> {
-    let views = &core.swapchain.swapchain_image_views;
+    let swapchain = core.borrow_swapchain();
+    let views = &swapchain.swapchain_image_views;
+    if let Some(image) = &render_data.output_image {
+        return views
+            .iter()
+            .map(|_| vec![image.create_dp(vk::ImageLayout::GENERAL)])
+            .collect();
+    }
     views
         .iter()
         .map(|image_view| {
@@ -0,0 +1,329 @@
+use ash::vk;
+use std::collections::HashMap;
+
+use crate::render::general::command_buffer::CommandBuffer;
+use crate::render::general::structures::{BufferWrapper, ImageWrapper};
+
+/// Something a pass reads or writes, which the frame graph tracks to place barriers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Resource {
+    Image(vk::Image),
+    Buffer(vk::Buffer),
+}
+
+pub fn image(image: &impl ImageWrapper) -> Resource {
+    Resource::Image(image.get_vk_image())
+}
+
+pub fn buffer(buffer: &impl BufferWrapper) -> Resource {
+    Resource::Buffer(buffer.get_vk_buffer())
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Access {
+    resource: Resource,
+    stage: vk::PipelineStageFlags,
+    write: bool,
+}
+
+/// Where a pass goes relative to the passes added before it.
+#[derive(Clone, Debug)]
+enum Anchor {
+    // Right after the passes added before it.
+    End,
+    Before(String),
+    After(String),
+}
+
+/// A group of commands which runs as a unit, along with everything it reads and writes. Barriers
+/// inside a pass are up to the pass itself, the frame graph only places them between passes.
+pub struct Pass<'a> {
+    name: String,
+    stage: vk::PipelineStageFlags,
+    accesses: Vec<Access>,
+    anchor: Anchor,
+    record: Box<dyn Fn(&CommandBuffer) + 'a>,
+}
+
+impl<'a> Pass<'a> {
+    /// Resources the pass reads in the stage it was added with.
+    pub fn reads(&mut self, resources: &[Resource]) -> &mut Self {
+        self.access(resources, self.stage, false)
+    }
+
+    /// Resources the pass writes in the stage it was added with. Resources which are read and
+    /// written only need to be given here.
+    pub fn writes(&mut self, resources: &[Resource]) -> &mut Self {
+        self.access(resources, self.stage, true)
+    }
+
+    /// A buffer the pass reads dispatch sizes from, see CommandBuffer::dispatch_indirect.
+    pub fn reads_indirect(&mut self, resource: Resource) -> &mut Self {
+        self.access(&[resource], vk::PipelineStageFlags::DRAW_INDIRECT, false)
+    }
+
+    fn access(
+        &mut self,
+        resources: &[Resource],
+        stage: vk::PipelineStageFlags,
+        write: bool,
+    ) -> &mut Self {
+        for &resource in resources {
+            self.accesses.push(Access {
+                resource,
+                stage,
+                write,
+            });
+        }
+        self
+    }
+
+    /// Runs the pass right before the one with the given name, which must already have been
+    /// added. Optional passes use this so that they can be added after the rest of the frame.
+    pub fn before(&mut self, name: &str) -> &mut Self {
+        self.anchor = Anchor::Before(name.to_owned());
+        self
+    }
+
+    /// Runs the pass right after the one with the given name and any passes which were already
+    /// placed after it, which must already have been added.
+    pub fn after(&mut self, name: &str) -> &mut Self {
+        self.anchor = Anchor::After(name.to_owned());
+        self
+    }
+
+    pub fn record(&mut self, record: impl Fn(&CommandBuffer) + 'a) -> &mut Self {
+        self.record = Box::new(record);
+        self
+    }
+}
+
+/// The source and destination stages of a memory barrier, see CommandBuffer::memory_barrier.
+pub type Barrier = (vk::PipelineStageFlags, vk::PipelineStageFlags);
+
+/// A pass along with the barrier which has to be recorded before it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScheduledPass {
+    pub index: usize,
+    pub barrier: Option<Barrier>,
+}
+
+// What has happened to a resource so far while scheduling.
+#[derive(Default)]
+struct ResourceState {
+    // The stages of the last write, and the stages a barrier has made it visible to since.
+    write: Option<(vk::PipelineStageFlags, vk::PipelineStageFlags)>,
+    // The stages of each read since the last write, and the stages a barrier has made wait for
+    // it since.
+    reads: Vec<(vk::PipelineStageFlags, vk::PipelineStageFlags)>,
+}
+
+impl ResourceState {
+    // The stages which have to finish before an access in the given stage can happen.
+    fn get_hazard(&self, stage: vk::PipelineStageFlags, write: bool) -> vk::PipelineStageFlags {
+        let mut wait_for = vk::PipelineStageFlags::empty();
+        if let Some((write_stage, visible_to)) = self.write {
+            if !visible_to.contains(stage) {
+                wait_for |= write_stage;
+            }
+        }
+        if write {
+            for (read_stage, waited_by) in &self.reads {
+                if !waited_by.contains(stage) {
+                    wait_for |= *read_stage;
+                }
+            }
+        }
+        wait_for
+    }
+
+    fn apply_barrier(&mut self, src: vk::PipelineStageFlags, dst: vk::PipelineStageFlags) {
+        if let Some((write_stage, visible_to)) = &mut self.write {
+            if src.contains(*write_stage) {
+                *visible_to |= dst;
+            }
+        }
+        for (read_stage, waited_by) in &mut self.reads {
+            if src.contains(*read_stage) {
+                *waited_by |= dst;
+            }
+        }
+    }
+
+    fn apply_access(&mut self, stage: vk::PipelineStageFlags, write: bool) {
+        if write {
+            self.write = Some((stage, vk::PipelineStageFlags::empty()));
+            self.reads.clear();
+        } else {
+            self.reads.push((stage, vk::PipelineStageFlags::empty()));
+        }
+    }
+}
+
+/// The passes of a frame, which are recorded in the order they were added apart from those
+/// placed before or after a particular pass. Each pass declares what it reads and writes so that
+/// the graph can work out which barriers are needed between passes, instead of every stage
+/// having to know what ran before it.
+pub struct FrameGraph<'a> {
+    passes: Vec<Pass<'a>>,
+}
+
+impl<'a> FrameGraph<'a> {
+    pub fn new() -> Self {
+        Self { passes: Vec::new() }
+    }
+
+    /// The stage is what the pass's reads and writes wait for and are waited for by. Passes which
+    /// both copy and dispatch can give both stages.
+    pub fn add_pass(&mut self, name: &str, stage: vk::PipelineStageFlags) -> &mut Pass<'a> {
+        self.passes.push(Pass {
+            name: name.to_owned(),
+            stage,
+            accesses: Vec::new(),
+            anchor: Anchor::End,
+            record: Box::new(|_| ()),
+        });
+        self.passes.last_mut().unwrap()
+    }
+
+    fn find(&self, order: &[usize], name: &str) -> usize {
+        order
+            .iter()
+            .position(|&index| self.passes[index].name == name)
+            .unwrap_or_else(|| panic!("There is no pass named {} to anchor to.", name))
+    }
+
+    /// The indices of the passes in the order they will be recorded.
+    fn get_order(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.passes.len());
+        for (index, pass) in self.passes.iter().enumerate() {
+            let position = match &pass.anchor {
+                Anchor::End => order.len(),
+                Anchor::Before(name) => self.find(&order, name),
+                Anchor::After(name) => {
+                    let mut position = self.find(&order, name) + 1;
+                    while position < order.len() {
+                        match &self.passes[order[position]].anchor {
+                            Anchor::After(other) if other == name => position += 1,
+                            _ => break,
+                        }
+                    }
+                    position
+                }
+            };
+            order.insert(position, index);
+        }
+        order
+    }
+
+    /// Works out the order of the passes and the barrier each one needs. A barrier waits for
+    /// every earlier access the pass conflicts with: writes it reads or writes over, and reads it
+    /// writes over.
+    pub fn schedule(&self) -> Vec<ScheduledPass> {
+        let mut states: HashMap<Resource, ResourceState> = HashMap::new();
+        let mut schedule = Vec::with_capacity(self.passes.len());
+        for index in self.get_order() {
+            let pass = &self.passes[index];
+            let mut src = vk::PipelineStageFlags::empty();
+            let mut dst = vk::PipelineStageFlags::empty();
+            for access in &pass.accesses {
+                if let Some(state) = states.get(&access.resource) {
+                    let hazard = state.get_hazard(access.stage, access.write);
+                    if !hazard.is_empty() {
+                        src |= hazard;
+                        dst |= access.stage;
+                    }
+                }
+            }
+            let barrier = if src.is_empty() {
+                None
+            } else {
+                // A memory barrier covers every resource, not just the ones which needed it.
+                for state in states.values_mut() {
+                    state.apply_barrier(src, dst);
+                }
+                Some((src, dst))
+            };
+            for access in &pass.accesses {
+                let state = states.entry(access.resource).or_default();
+                state.apply_access(access.stage, access.write);
+            }
+            schedule.push(ScheduledPass { index, barrier });
+        }
+        schedule
+    }
+
+    pub fn record(&self, buffer: &CommandBuffer) {
+        for scheduled in self.schedule() {
+            if let Some((src, dst)) = scheduled.barrier {
+                buffer.memory_barrier(src, dst);
+            }
+            (self.passes[scheduled.index].record)(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    const COMPUTE: vk::PipelineStageFlags = vk::PipelineStageFlags::COMPUTE_SHADER;
+    const TRANSFER: vk::PipelineStageFlags = vk::PipelineStageFlags::TRANSFER;
+
+    fn test_image(handle: u64) -> Resource {
+        Resource::Image(vk::Image::from_raw(handle))
+    }
+
+    fn get_barriers(graph: &FrameGraph) -> Vec<Option<Barrier>> {
+        graph.schedule().iter().map(|pass| pass.barrier).collect()
+    }
+
+    #[test]
+    fn barriers_follow_hazards() {
+        let (a, b) = (test_image(1), test_image(2));
+        let mut graph = FrameGraph::new();
+        graph.add_pass("write_a", COMPUTE).writes(&[a]);
+        graph.add_pass("read_a", COMPUTE).reads(&[a]);
+        // Already visible to compute shaders.
+        graph.add_pass("read_a_again", COMPUTE).reads(&[a]);
+        graph
+            .add_pass("copy_a_to_b", TRANSFER)
+            .reads(&[a])
+            .writes(&[b]);
+        // Has to wait for the reads before it can overwrite a.
+        graph.add_pass("write_a_again", COMPUTE).writes(&[a]);
+        assert_eq!(
+            get_barriers(&graph),
+            vec![
+                None,
+                Some((COMPUTE, COMPUTE)),
+                None,
+                Some((COMPUTE, TRANSFER)),
+                Some((COMPUTE | TRANSFER, COMPUTE)),
+            ]
+        );
+    }
+
+    #[test]
+    fn independent_passes_need_no_barriers() {
+        let mut graph = FrameGraph::new();
+        graph.add_pass("first", COMPUTE).writes(&[test_image(1)]);
+        graph.add_pass("second", COMPUTE).writes(&[test_image(2)]);
+        graph.add_pass("marker", vk::PipelineStageFlags::empty());
+        assert_eq!(get_barriers(&graph), vec![None, None, None]);
+    }
+
+    #[test]
+    fn anchored_passes_are_moved() {
+        let mut graph = FrameGraph::new();
+        graph.add_pass("raytrace", COMPUTE);
+        graph.add_pass("finalize", COMPUTE);
+        graph.add_pass("present", TRANSFER);
+        graph.add_pass("bloom", COMPUTE).after("finalize");
+        graph.add_pass("debug", COMPUTE).after("finalize");
+        graph.add_pass("encode", COMPUTE).before("present");
+        let order: Vec<_> = graph.schedule().iter().map(|pass| pass.index).collect();
+        assert_eq!(order, vec![0, 1, 3, 4, 5, 2]);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::render::general::command_buffer::CommandBuffer;
+use crate::render::general::core::Core;
+use ash::version::DeviceV1_0;
+use ash::vk;
+use std::rc::Rc;
+
+/// The stages of a frame in the order they are recorded.
+pub const STAGE_NAMES: [&str; 7] = [
+    "raytrace",
+    "temporal",
+    "denoise",
+    "reflections",
+    "finalize",
+    "overlay",
+    "text",
+];
+// One timestamp before the first stage and one after each stage.
+const QUERIES_PER_FRAME: u32 = STAGE_NAMES.len() as u32 + 1;
+
+/// Measures how long each stage takes on the GPU using timestamp queries. Every swapchain image
+/// has its own range of queries since each one has its own command buffer.
+pub struct GpuTimer {
+    core: Rc<Core>,
+    query_pool: vk::QueryPool,
+    nanoseconds_per_tick: f64,
+    // Sum of the time spent in each stage over every collected frame, in milliseconds.
+    totals: [f64; STAGE_NAMES.len()],
+    collected_frames: u32,
+}
+
+impl GpuTimer {
+    /// Returns None if the device does not support timestamps on compute queues.
+    pub fn new(core: Rc<Core>, num_frames: u32) -> Option<GpuTimer> {
+        let limits = core.get_physical_device_limits();
+        if limits.timestamp_compute_and_graphics == vk::FALSE {
+            return None;
+        }
+        let query_pool =
+            core.create_timestamp_query_pool(num_frames * QUERIES_PER_FRAME, "gpu_timer");
+        Some(GpuTimer {
+            core,
+            query_pool,
+            nanoseconds_per_tick: limits.timestamp_period as f64,
+            totals: [0.0; STAGE_NAMES.len()],
+            collected_frames: 0,
+        })
+    }
+
+    /// Should be recorded at the start of the command buffer for the given frame.
+    pub fn record_start(&self, buffer: &CommandBuffer, frame: u32) {
+        let first = frame * QUERIES_PER_FRAME;
+        buffer.reset_query_pool(self.query_pool, first, QUERIES_PER_FRAME);
+        buffer.write_timestamp(self.query_pool, first);
+    }
+
+    /// Should be recorded after the commands for each stage, in the order of STAGE_NAMES.
+    pub fn record_stage_end(&self, buffer: &CommandBuffer, frame: u32, stage: usize) {
+        let query = frame * QUERIES_PER_FRAME + stage as u32 + 1;
+        buffer.write_timestamp(self.query_pool, query);
+    }
+
+    /// Adds the timings of the given frame to the totals. The frame must have finished rendering.
+    pub fn collect(&mut self, frame: u32) {
+        let mut timestamps = [0u64; QUERIES_PER_FRAME as usize];
+        let result = unsafe {
+            self.core.device.get_query_pool_results(
+                self.query_pool,
+                frame * QUERIES_PER_FRAME,
+                QUERIES_PER_FRAME,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        };
+        if result.is_err() {
+            // The frame has not been rendered yet.
+            return;
+        }
+        for (stage, pair) in timestamps.windows(2).enumerate() {
+            let ticks = pair[1].saturating_sub(pair[0]);
+            self.totals[stage] += ticks as f64 * self.nanoseconds_per_tick / 1_000_000.0;
+        }
+        self.collected_frames += 1;
+    }
+
+    /// Returns the average time each stage took in milliseconds, in the order of STAGE_NAMES.
+    pub fn get_average_timings(&self) -> Vec<f64> {
+        let frames = self.collected_frames.max(1) as f64;
+        self.totals.iter().map(|total| total / frames).collect()
+    }
+}
+
+impl Drop for GpuTimer {
+    fn drop(&mut self) {
+        unsafe {
+            self.core.device.destroy_query_pool(self.query_pool, None);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::util::RingBufferAverage;
+use std::time::Instant;
+
+/// How many frames the latencies are averaged over.
+const LATENCY_SAMPLES: usize = 60;
+
+/// Records when each frame passes a few points on its way to the screen, like the latency markers
+/// of other engines, to measure how old the input a frame was rendered with is by the time the
+/// frame is submitted and by the time the GPU has finished it. See Pipeline::begin_frame.
+pub struct LatencyMarkers {
+    // When the game was last updated, which is when input was last applied to the camera.
+    updated_at: Option<Instant>,
+    // When the frame rendered with that update was submitted.
+    submitted_at: Option<Instant>,
+    // Both in microseconds.
+    update_to_submit: RingBufferAverage<u64>,
+    update_to_complete: RingBufferAverage<u64>,
+}
+
+impl LatencyMarkers {
+    pub fn new() -> Self {
+        Self {
+            updated_at: None,
+            submitted_at: None,
+            update_to_submit: RingBufferAverage::new(LATENCY_SAMPLES),
+            update_to_complete: RingBufferAverage::new(LATENCY_SAMPLES),
+        }
+    }
+
+    pub fn on_game_updated(&mut self, now: Instant) {
+        self.updated_at = Some(now);
+    }
+
+    pub fn on_submitted(&mut self, now: Instant) {
+        if let Some(updated_at) = self.updated_at {
+            let micros = (now - updated_at).as_micros() as u64;
+            self.update_to_submit.push_sample(micros);
+            self.submitted_at = Some(now);
+        }
+    }
+
+    /// Should be called as soon as the last frame which was submitted is known to have finished
+    /// rendering, which is an upper bound on when it actually did.
+    pub fn on_completed(&mut self, now: Instant) {
+        if let (Some(updated_at), Some(_)) = (self.updated_at, self.submitted_at.take()) {
+            let micros = (now - updated_at).as_micros() as u64;
+            self.update_to_complete.push_sample(micros);
+        }
+    }
+
+    /// How long frames wait between the game being updated and being submitted on average, in
+    /// milliseconds.
+    pub fn get_submit_latency(&self) -> f64 {
+        self.update_to_submit.average() as f64 / 1000.0
+    }
+
+    /// How long frames take from the game being updated to finishing on the GPU on average, in
+    /// milliseconds.
+    pub fn get_completion_latency(&self) -> f64 {
+        self.update_to_complete.average() as f64 / 1000.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn latency_is_measured_from_update() {
+        let mut markers = LatencyMarkers::new();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        // Nothing was submitted yet, so there is nothing to measure.
+        markers.on_completed(at(0));
+        for frame in 0..LATENCY_SAMPLES as u64 {
+            markers.on_game_updated(at(frame * 10));
+            markers.on_submitted(at(frame * 10 + 2));
+            markers.on_completed(at(frame * 10 + 8));
+        }
+        assert_eq!(markers.get_submit_latency(), 2.0);
+        assert_eq!(markers.get_completion_latency(), 8.0);
+    }
+}
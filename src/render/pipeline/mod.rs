@@ -1,9 +1,20 @@
+pub(self) mod accumulation;
+pub(self) mod beauty_shot;
+pub(self) mod checkpoints;
 pub(self) mod descriptor_sets;
+pub(self) mod frame_graph;
+pub(self) mod gpu_timer;
+pub(self) mod latency;
+pub(self) mod panorama;
 pub(self) mod pipeline;
+pub(self) mod ray_statistics;
 pub(self) mod render_data;
 pub(self) mod shaders;
 pub(self) mod structs;
 pub(self) mod terrain_upload;
 
+pub use beauty_shot::{BeautyShotRequest, DEFAULT_BEAUTY_SHOT_FRAMES};
+pub use panorama::{PanoramaLayout, PanoramaRequest};
 pub use pipeline::Pipeline;
+pub use ray_statistics::RayStatistics;
 pub use terrain_upload::TerrainUploadManager;
@@ -0,0 +1,275 @@
+use super::beauty_shot::{BeautyShot, BeautyShotRequest};
+use crate::util::{self, TripleEulerVector};
+use cgmath::{InnerSpace, Rad, Vector3};
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// The heading and pitch of each face of the cube a panorama is rendered as, in the order they
+/// are rendered and laid out in cubemaps: +X, -X, +Y, -Y, +Z (up) and -Z (down).
+const FACES: [(f32, f32); 6] = [
+    (0.0, 0.0),
+    (PI, 0.0),
+    (FRAC_PI_2, 0.0),
+    (-FRAC_PI_2, 0.0),
+    (FRAC_PI_2, FRAC_PI_2),
+    (FRAC_PI_2, -FRAC_PI_2),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanoramaLayout {
+    /// The six faces side by side in a single row, see FACES for the order.
+    Cubemap,
+    /// Twice as wide as it is tall, centered on the direction the camera was facing.
+    Equirectangular,
+}
+
+impl PanoramaLayout {
+    pub const ALL: [PanoramaLayout; 2] = [PanoramaLayout::Cubemap, PanoramaLayout::Equirectangular];
+
+    pub fn get_name(self) -> &'static str {
+        match self {
+            PanoramaLayout::Cubemap => "cubemap",
+            PanoramaLayout::Equirectangular => "equirectangular",
+        }
+    }
+}
+
+impl FromStr for PanoramaLayout {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<PanoramaLayout, String> {
+        Self::ALL
+            .iter()
+            .cloned()
+            .find(|layout| layout.get_name() == text)
+            .ok_or_else(|| format!("'{}' is not a panorama layout.", text))
+    }
+}
+
+impl Display for PanoramaLayout {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+/// Asks the pipeline to render the view in every direction from the camera and save it as a
+/// single image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PanoramaRequest {
+    pub path: PathBuf,
+    pub layout: PanoramaLayout,
+    /// How many frames are blended together for each face, like BeautyShotRequest::frames.
+    pub frames_per_face: u32,
+}
+
+/// The RGBA pixels of one rendered face. The whole image covers 90 degrees along each axis no
+/// matter its aspect ratio.
+struct FaceImage {
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl FaceImage {
+    /// Bilinearly samples the face at a position from -1 to 1 along each axis, with positive Y
+    /// at the top of the image.
+    fn sample(&self, x: f32, y: f32) -> [u8; 4] {
+        let x = ((x + 1.0) * 0.5 * self.width as f32 - 0.5).max(0.0);
+        let y = ((1.0 - y) * 0.5 * self.height as f32 - 0.5).max(0.0);
+        let (x0, y0) = (x as u32, y as u32);
+        let x0 = x0.min(self.width - 1);
+        let y0 = y0.min(self.height - 1);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+        let texel = |x: u32, y: u32, channel: usize| {
+            self.pixels[((y * self.width + x) * 4) as usize + channel] as f32
+        };
+        let mut result = [0; 4];
+        for channel in 0..4 {
+            let top = texel(x0, y0, channel) * (1.0 - fx) + texel(x1, y0, channel) * fx;
+            let bottom = texel(x0, y1, channel) * (1.0 - fx) + texel(x1, y1, channel) * fx;
+            result[channel] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+        }
+        result
+    }
+}
+
+fn face_vectors(face: usize) -> TripleEulerVector {
+    let (heading, pitch) = FACES[face];
+    util::compute_triple_euler_vector(Rad(heading), Rad(pitch))
+}
+
+/// Returns the color of the faces in the given direction.
+fn sample_direction(faces: &[FaceImage], direction: Vector3<f32>) -> [u8; 4] {
+    let (face, forward) = (0..FACES.len())
+        .map(|face| (face, face_vectors(face).forward.dot(direction)))
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .expect("There should be at least one face.");
+    let vectors = face_vectors(face);
+    let x = vectors.right.dot(direction) / forward;
+    let y = vectors.up.dot(direction) / forward;
+    faces[face].sample(x, y)
+}
+
+/// Stitches the faces into one image, returning its pixels, width and height. Each face becomes
+/// a square as large as the shorter side of the rendered faces.
+fn stitch(faces: &[FaceImage], layout: PanoramaLayout, heading: f32) -> (Vec<u8>, u32, u32) {
+    let size = faces[0].width.min(faces[0].height);
+    let to_unit = |pixel: u32, length: u32| (pixel as f32 + 0.5) / length as f32;
+    match layout {
+        PanoramaLayout::Cubemap => {
+            let (width, height) = (size * FACES.len() as u32, size);
+            let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+            for y in 0..height {
+                for x in 0..width {
+                    let face = &faces[(x / size) as usize];
+                    let face_x = to_unit(x % size, size) * 2.0 - 1.0;
+                    let face_y = 1.0 - to_unit(y, size) * 2.0;
+                    pixels.extend(face.sample(face_x, face_y).iter());
+                }
+            }
+            (pixels, width, height)
+        }
+        PanoramaLayout::Equirectangular => {
+            let (width, height) = (size * 4, size * 2);
+            let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+            for y in 0..height {
+                let pitch = FRAC_PI_2 - to_unit(y, height) * PI;
+                for x in 0..width {
+                    // Turning right decreases the heading.
+                    let x_heading = heading + PI - to_unit(x, width) * PI * 2.0;
+                    let direction =
+                        util::compute_triple_euler_vector(Rad(x_heading), Rad(pitch)).forward;
+                    pixels.extend(sample_direction(faces, direction).iter());
+                }
+            }
+            (pixels, width, height)
+        }
+    }
+}
+
+/// A panorama which is being rendered. Each face is rendered like a beauty shot with the camera
+/// pointed along one of the world axes, then all of them are stitched together once the last one
+/// is done.
+pub struct Panorama {
+    request: PanoramaRequest,
+    // The heading the camera had when the panorama was started.
+    heading: f32,
+    shot: BeautyShot,
+    faces: Vec<FaceImage>,
+}
+
+impl Panorama {
+    pub fn new(request: PanoramaRequest, heading: Rad<f32>) -> Panorama {
+        let shot = Self::make_shot(&request);
+        Panorama {
+            request,
+            heading: heading.0,
+            shot,
+            faces: Vec::new(),
+        }
+    }
+
+    fn make_shot(request: &PanoramaRequest) -> BeautyShot {
+        BeautyShot::new(BeautyShotRequest {
+            path: request.path.clone(),
+            frames: request.frames_per_face,
+            downsample: 1,
+        })
+    }
+
+    /// The directions to render the current face with. Right and up should not be scaled down
+    /// like they are for the interactive view, so that the face covers 90 degrees.
+    pub fn get_face_vectors(&self) -> TripleEulerVector {
+        face_vectors(self.faces.len())
+    }
+
+    pub fn get_history_weight(&self) -> f32 {
+        self.shot.get_history_weight()
+    }
+
+    /// Like BeautyShot::advance, returns true if the frame just submitted finishes the current
+    /// face, meaning it should be passed to add_face.
+    pub fn advance(&mut self) -> bool {
+        self.shot.advance()
+    }
+
+    /// Stores the finished image of the current face and moves on to the next one. Returns true
+    /// once every face has been added, meaning the panorama should be saved.
+    pub fn add_face(&mut self, pixels: Vec<u8>, width: u32, height: u32) -> bool {
+        self.faces.push(FaceImage {
+            pixels,
+            width,
+            height,
+        });
+        self.shot = Self::make_shot(&self.request);
+        self.faces.len() == FACES.len()
+    }
+
+    pub fn save(&self) {
+        let (pixels, width, height) = stitch(&self.faces, self.request.layout, self.heading);
+        let path = &self.request.path;
+        let color_type = image::ColorType::RGBA(8);
+        match image::save_buffer(path, &pixels, width, height, color_type) {
+            Ok(()) => println!("Saved a {}x{} panorama to {:?}.", width, height, path),
+            Err(err) => {
+                println!("WARNING: Failed to save panorama to {:?}.", path);
+                println!("Caused by: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Six 2x2 faces, each filled with its own index.
+    fn numbered_faces() -> Vec<FaceImage> {
+        (0..6)
+            .map(|face| FaceImage {
+                pixels: vec![face as u8; 2 * 2 * 4],
+                width: 2,
+                height: 2,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn directions_sample_the_face_they_point_at() {
+        let faces = numbered_faces();
+        let axes = [
+            Vector3::unit_x(),
+            -Vector3::unit_x(),
+            Vector3::unit_y(),
+            -Vector3::unit_y(),
+            Vector3::unit_z(),
+            -Vector3::unit_z(),
+        ];
+        for (face, axis) in axes.iter().enumerate() {
+            assert_eq!(sample_direction(&faces, *axis), [face as u8; 4]);
+        }
+    }
+
+    #[test]
+    fn layouts_have_the_right_size() {
+        let faces = numbered_faces();
+        let (pixels, width, height) = stitch(&faces, PanoramaLayout::Cubemap, 0.0);
+        assert_eq!((width, height), (12, 2));
+        assert_eq!(&pixels[4 * 4..4 * 5], &[2; 4]);
+        let (pixels, width, height) = stitch(&faces, PanoramaLayout::Equirectangular, 0.0);
+        assert_eq!((width, height), (8, 4));
+        assert_eq!(pixels.len(), 8 * 4 * 4);
+        // The top row looks straight up.
+        assert_eq!(&pixels[0..4], &[4; 4]);
+    }
+
+    #[test]
+    fn layout_names_round_trip() {
+        for layout in PanoramaLayout::ALL.iter() {
+            assert_eq!(layout.to_string().parse(), Ok(*layout));
+        }
+    }
+}
@@ -1,23 +1,100 @@
+use super::accumulation::Accumulation;
+use super::beauty_shot::{self, BeautyShot, BeautyShotRequest};
+use super::checkpoints::Checkpoints;
 use super::descriptor_sets::DescriptorCollection;
+use super::frame_graph::{self, FrameGraph, Resource};
+use super::gpu_timer::{GpuTimer, STAGE_NAMES};
+use super::latency::LatencyMarkers;
+use super::panorama::{Panorama, PanoramaRequest};
+use super::ray_statistics::RayStatistics;
 use super::render_data::RenderData;
 use super::shaders::{self, Stage};
-use super::structs::DenoisePushData;
+use super::structs::{
+    DenoisePushData, EncodeOutputPushData, EstimateVariancePushData, FogPushData,
+    OverlayUniformData, ProbeCapturePushData, TemporalUniformData, WorkListHeader,
+};
 use super::TerrainUploadManager;
-use crate::game::Game;
+use crate::game::{Game, GameState};
 use crate::render::constants::*;
 use crate::render::general::command_buffer::CommandBuffer;
 use crate::render::general::core::Core;
-use crate::util;
+use crate::render::general::image_statistics::{self, ImageStatistics};
+use crate::render::general::recording::HostBuffer;
+use crate::render::general::structures::DataDestination;
+use crate::render::text::{TextBuffer, MAX_GLYPHS};
+use crate::render::tonemap;
+use crate::render::white_balance::WhiteBalance;
+use crate::render::{
+    atmosphere, emission, streaming_map, DebugView, DenoiseSchedule, LightingFormat, OutputFormat,
+    PipCamera, RenderSettings, StageToggles, TemporalSettings, MATERIALS,
+};
+use crate::render::world_mapping;
+use crate::util::{self, prelude::*};
+use crate::world::map;
+use ash::prelude::VkResult;
 use ash::version::DeviceV1_0;
 use ash::vk;
-use cgmath::{Matrix3, SquareMatrix};
+use cgmath::{Matrix3, Rad, SquareMatrix, Vector3};
 use std::rc::Rc;
+use std::time::Instant;
+
+/// How far the camera can get from the region offset along any axis before the region offset is
+/// moved to the camera. Positions sent to the GPU are relative to the region offset, so this
+/// bounds how much precision they lose.
+const REGION_REBASE_DISTANCE: f64 = 512.0;
+
+/// How long the right vector of the main view is relative to its forward vector. The edges of the
+/// screen are this far to the side for each block forward.
+const VIEW_SCALE: f32 = 0.4;
+
+/// Which set of framebuffers a pass renders to, used to pick descriptor set variants.
+const MAIN_VIEW: usize = 0;
+const PIP_VIEW: usize = 1;
+
+/// Returns the block the camera is in if it is too far from the current region offset, otherwise
+/// returns the current region offset.
+fn rebase_region_offset(current: SignedCoord3D, camera_origin: Vector3<f64>) -> SignedCoord3D {
+    let local = util::world_to_local(camera_origin, current);
+    if local.x.abs().max(local.y.abs()).max(local.z.abs()) as f64 > REGION_REBASE_DISTANCE {
+        (
+            camera_origin.x.floor() as isize,
+            camera_origin.y.floor() as isize,
+            camera_origin.z.floor() as isize,
+        )
+    } else {
+        current
+    }
+}
+
+/// How many work groups of SHADER_GROUP_SIZE pixels it takes to cover an image. Rounded up so
+/// that every pixel is covered, the shaders skip pixels outside the images.
+fn count_shader_groups(extent: vk::Extent2D) -> (u32, u32) {
+    let group_size = SHADER_GROUP_SIZE as u32;
+    (extent.width.div_ceil(group_size), extent.height.div_ceil(group_size))
+}
+
+/// Falls back to the default lighting format if the device can't store images in the given one.
+fn get_supported_lighting_format(core: &Core, format: LightingFormat) -> LightingFormat {
+    if core.supports_storage_image_format(format.get_vk_format()) {
+        return format;
+    }
+    println!(
+        "WARNING: The GPU does not support the {} lighting format, using {} instead.",
+        format,
+        LightingFormat::default()
+    );
+    LightingFormat::default()
+}
 
 pub struct Pipeline {
     core: Rc<Core>,
 
+    // Enough work groups to cover the images the main view is rendered into.
     x_shader_groups: u32,
     y_shader_groups: u32,
+    // Enough work groups to cover the swapchain, for the stages which write to it.
+    x_output_groups: u32,
+    y_output_groups: u32,
 
     command_buffers: Vec<CommandBuffer>,
     frame_available_semaphore: vk::Semaphore,
@@ -26,38 +103,183 @@ pub struct Pipeline {
     render_data: RenderData,
     descriptor_collection: DescriptorCollection,
     tum: TerrainUploadManager,
+    text: TextBuffer,
+    // All positions sent to the GPU are relative to this block.
+    region_offset: SignedCoord3D,
+    // Where the camera was last frame, in world space.
+    old_camera_origin: Vector3<f64>,
+    // The block the minimap is centered on, None if it has not been drawn yet.
+    minimap_center: Option<SignedCoord2D>,
+    // The block the distant terrain is centered on, None if it has not been built yet.
+    distant_terrain_center: Option<SignedCoord2D>,
+    // The block at the center of the atmosphere volume, None until it is first built.
+    atmosphere_center: Option<SignedCoord3D>,
+    // None if the device does not support timestamps.
+    gpu_timer: Option<GpuTimer>,
+    // None if the device supports neither kind of checkpoint.
+    checkpoints: Option<Checkpoints>,
+    // The swapchain image rendered last frame, None before the first frame.
+    last_image_index: Option<u32>,
+    // The swapchain image begin_frame acquired for finish_frame to render to.
+    acquired_image_index: Option<u32>,
+    latency: LatencyMarkers,
+    // Set when the window was resized or the swapchain stopped matching it, so that the swapchain
+    // is recreated before the next frame.
+    swapchain_outdated: bool,
+    // The schedules and stages the command buffers were recorded with.
+    denoise_schedule: DenoiseSchedule,
+    denoise_gradient_weight: f32,
+    denoise_variance_weight: f32,
+    comparison_schedule: Option<DenoiseSchedule>,
+    stage_toggles: StageToggles,
+    pip_camera: Option<PipCamera>,
+    // The probes in the reflection probe images, which are captured again whenever the game's
+    // probes are different.
+    captured_probes: Vec<Vector3<f64>>,
+    temporal_settings: TemporalSettings,
+    accumulation: Accumulation,
+    old_sun_angle: f32,
+    // Whether the warning about NaN or infinite lighting has been printed.
+    warned_invalid_lighting: bool,
+    // None when auto white balance is off.
+    white_balance: Option<WhiteBalance>,
+    // Whether the image statistics pass runs even when auto white balance is off. The pass is
+    // left out of the command buffers when neither needs it.
+    measure_image_statistics: bool,
+    // What the pass measured in the last frame which finished rendering.
+    image_statistics: Option<ImageStatistics>,
+    // What the traversal and temporal kernels counted in the last frame which finished rendering,
+    // and summed over every frame so far.
+    ray_statistics: RayStatistics,
+    total_ray_statistics: RayStatistics,
+    // The HUD is hidden and frames are accumulated while this is in progress.
+    beauty_shot: Option<BeautyShot>,
+    // Like beauty_shot, and the camera is pointed along each axis in turn.
+    panorama: Option<Panorama>,
 
-    denoise_stage: Stage,
-    finalize_stage: Stage,
-    raytrace_stage: Stage,
+    stages: Stages,
+}
+
+/// Every compute stage the pipeline dispatches. They only depend on the descriptor set layouts and
+/// the lighting and output formats, so they can be replaced without touching anything else.
+struct Stages {
+    compact_reflections: Stage,
+    denoise: Stage,
+    encode_output: Stage,
+    estimate_variance: Stage,
+    finalize: Stage,
+    fog: Stage,
+    image_statistics: Stage,
+    light_volume: Stage,
+    overlay: Stage,
+    probe_capture: Stage,
+    raygen: Stage,
+    reflection_denoise: Stage,
+    resolve: Stage,
+    shade: Stage,
+    sun_heightmap: Stage,
+    temporal: Stage,
+    terrain_fill: Stage,
+    text: Stage,
+    traverse: Stage,
+    validate_lighting: Stage,
+}
+
+impl Stages {
+    /// The pipelines are created on worker threads, see shaders::create_stages.
+    fn create(
+        core: &Rc<Core>,
+        dc: &DescriptorCollection,
+        format: LightingFormat,
+        output: OutputFormat,
+    ) -> Self {
+        let stage_descriptions = [
+            shaders::describe_compact_reflections_stage(dc),
+            shaders::describe_denoise_stage(dc, format),
+            shaders::describe_encode_output_stage(dc),
+            shaders::describe_estimate_variance_stage(dc, format),
+            shaders::describe_finalize_stage(dc, format, output),
+            shaders::describe_fog_stage(dc),
+            shaders::describe_image_statistics_stage(dc, format),
+            shaders::describe_light_volume_stage(dc),
+            shaders::describe_overlay_stage(dc, output),
+            shaders::describe_probe_capture_stage(dc),
+            shaders::describe_raygen_stage(dc),
+            shaders::describe_reflection_denoise_stage(dc),
+            shaders::describe_resolve_stage(dc, format),
+            shaders::describe_shade_stage(dc),
+            shaders::describe_sun_heightmap_stage(dc),
+            shaders::describe_temporal_stage(dc, format),
+            shaders::describe_terrain_fill_stage(dc),
+            shaders::describe_text_stage(dc, output),
+            shaders::describe_traverse_stage(dc),
+            shaders::describe_validate_lighting_stage(dc, format),
+        ];
+        // In the same order as the descriptions.
+        let mut stages = shaders::create_stages(core, &stage_descriptions).into_iter();
+        let mut next_stage = || stages.next().unwrap();
+        Self {
+            compact_reflections: next_stage(),
+            denoise: next_stage(),
+            encode_output: next_stage(),
+            estimate_variance: next_stage(),
+            finalize: next_stage(),
+            fog: next_stage(),
+            image_statistics: next_stage(),
+            light_volume: next_stage(),
+            overlay: next_stage(),
+            probe_capture: next_stage(),
+            raygen: next_stage(),
+            reflection_denoise: next_stage(),
+            resolve: next_stage(),
+            shade: next_stage(),
+            sun_heightmap: next_stage(),
+            temporal: next_stage(),
+            terrain_fill: next_stage(),
+            text: next_stage(),
+            traverse: next_stage(),
+            validate_lighting: next_stage(),
+        }
+    }
 }
 
 impl Pipeline {
-    pub fn new(core: Rc<Core>, game: &mut Game) -> Pipeline {
+    pub fn new(core: Rc<Core>, game: &mut Game, settings: &RenderSettings) -> Pipeline {
+        let mut settings = settings.clone();
+        settings.lighting_format = get_supported_lighting_format(&core, settings.lighting_format);
+        let settings = &settings;
+        let format = settings.lighting_format;
+        let output = core.borrow_swapchain().output_format;
+
         let frame_available_semaphore = core.create_semaphore("frame_available");
         let frame_complete_semaphore = core.create_semaphore("frame_complete");
         let frame_complete_fence = core.create_fence(true, "frame_complete");
-        let swapchain_length = core.swapchain.swapchain_images.len() as u32;
+        let swapchain_length = core.borrow_swapchain().swapchain_images.len() as u32;
         let command_buffers = CommandBuffer::create_multiple(core.clone(), swapchain_length);
+        let gpu_timer = GpuTimer::new(core.clone(), swapchain_length);
+        let checkpoints = Checkpoints::new(core.clone());
 
-        let swapchain_extent = core.swapchain.swapchain_extent;
-        let x_shader_groups = swapchain_extent.width / SHADER_GROUP_SIZE as u32;
-        let y_shader_groups = swapchain_extent.height / SHADER_GROUP_SIZE as u32 + 1;
+        let swapchain_extent = core.borrow_swapchain().swapchain_extent;
+        let (x_output_groups, y_output_groups) = count_shader_groups(swapchain_extent);
 
-        let mut render_data = RenderData::create(core.clone());
+        let mut render_data = RenderData::create(core.clone(), settings);
         render_data.initialize(game);
+        let (x_shader_groups, y_shader_groups) = count_shader_groups(render_data.extent);
         let descriptor_collection = DescriptorCollection::create(core.clone(), &render_data);
-        let tum = TerrainUploadManager::new(Rc::clone(&core));
+        let tum = TerrainUploadManager::new(Rc::clone(&core), settings);
 
-        let denoise_stage = shaders::create_denoise_stage(core.clone(), &descriptor_collection);
-        let finalize_stage = shaders::create_finalize_stage(core.clone(), &descriptor_collection);
-        let raytrace_stage = shaders::create_raytrace_stage(core.clone(), &descriptor_collection);
+        let stages = Stages::create(&core, &descriptor_collection, format, output);
+
+        let camera_origin = game.borrow_render_camera().origin;
+        let region_offset = rebase_region_offset((0, 0, 0), camera_origin);
 
         let mut pipeline = Pipeline {
             core,
 
             x_shader_groups,
             y_shader_groups,
+            x_output_groups,
+            y_output_groups,
 
             command_buffers,
             frame_available_semaphore,
@@ -66,112 +288,1232 @@ impl Pipeline {
             render_data,
             descriptor_collection,
             tum,
+            text: TextBuffer::new(),
+            region_offset,
+            old_camera_origin: camera_origin,
+            minimap_center: None,
+            distant_terrain_center: None,
+            atmosphere_center: None,
+            gpu_timer,
+            checkpoints,
+            last_image_index: None,
+            acquired_image_index: None,
+            latency: LatencyMarkers::new(),
+            swapchain_outdated: false,
+            denoise_schedule: settings.denoise_schedule.clone(),
+            denoise_gradient_weight: settings.denoise_gradient_weight,
+            denoise_variance_weight: settings.denoise_variance_weight,
+            stage_toggles: StageToggles::default(),
+            pip_camera: None,
+            captured_probes: Vec::new(),
+            comparison_schedule: None,
+            temporal_settings: settings.temporal.clone(),
+            accumulation: Accumulation::new(settings.temporal.accumulation_delay),
+            old_sun_angle: game.get_sun_angle(),
+            warned_invalid_lighting: false,
+            white_balance: if settings.auto_white_balance {
+                Some(WhiteBalance::new())
+            } else {
+                None
+            },
+            measure_image_statistics: false,
+            image_statistics: None,
+            ray_statistics: RayStatistics::default(),
+            total_ray_statistics: RayStatistics::default(),
+            beauty_shot: None,
+            panorama: None,
 
-            denoise_stage,
-            finalize_stage,
-            raytrace_stage,
+            stages,
         };
         pipeline.record_command_buffers();
         pipeline
     }
 
     fn record_command_buffers(&mut self) {
+        // Whatever changed might make the frames look different.
+        self.accumulation.reset();
         for (index, buffer) in self.command_buffers.iter().enumerate() {
-            let swapchain_image = self.core.swapchain.swapchain_images[index];
-
             buffer.set_debug_name(&format!("primary_command_buffer_{}", index));
 
             buffer.begin();
+            if let Some(timer) = &self.gpu_timer {
+                timer.record_start(buffer, index as u32);
+            }
+            if let Some(checkpoints) = &self.checkpoints {
+                checkpoints.record_stage_start(buffer, 0);
+            }
+            let mut graph = FrameGraph::new();
+            self.add_frame_passes(&mut graph, index);
+            self.add_optional_passes(&mut graph, index);
+            graph.record(buffer);
+            buffer.end();
+        }
+    }
 
-            let layout = self.raytrace_stage.pipeline_layout;
-            let set = self.descriptor_collection.raytrace.variants[0];
-            buffer.bind_descriptor_set(layout, 0, set);
-            buffer.bind_pipeline(self.raytrace_stage.vk_pipeline);
-            buffer.dispatch(self.x_shader_groups, self.y_shader_groups, 1);
+    /// Records the timestamp and checkpoint at the end of one of the stages in STAGE_NAMES. Each
+    /// stage starts as soon as the previous one ends.
+    fn record_stage_end(&self, buffer: &CommandBuffer, frame: u32, stage: usize) {
+        if let Some(timer) = &self.gpu_timer {
+            timer.record_stage_end(buffer, frame, stage);
+        }
+        if let Some(checkpoints) = &self.checkpoints {
+            checkpoints.record_stage_end(buffer, stage);
+            if stage + 1 < STAGE_NAMES.len() {
+                checkpoints.record_stage_start(buffer, stage + 1);
+            }
+        }
+    }
 
-            buffer.transition_layout(
-                &swapchain_image,
-                vk::ImageLayout::UNDEFINED,
-                vk::ImageLayout::GENERAL,
-            );
+    /// The image finalize, overlay and text draw to for the given swapchain image.
+    fn get_output(&self, index: usize) -> Resource {
+        match &self.render_data.output_image {
+            Some(output_image) => frame_graph::image(output_image),
+            None => frame_graph::image(&self.core.borrow_swapchain().swapchain_images[index]),
+        }
+    }
 
-            let layout = self.denoise_stage.pipeline_layout;
-            let ping_set = self.descriptor_collection.denoise.variants[0];
-            let pong_set = self.descriptor_collection.denoise.variants[1];
-            buffer.bind_pipeline(self.denoise_stage.vk_pipeline);
+    /// Adds the passes every frame runs to render into the given swapchain image.
+    fn add_frame_passes<'a>(&'a self, graph: &mut FrameGraph<'a>, index: usize) {
+        use frame_graph::{buffer, image};
+        let data = &self.render_data;
+        let dc = &self.descriptor_collection;
+        let compute = vk::PipelineStageFlags::COMPUTE_SHADER;
+        let transfer = vk::PipelineStageFlags::TRANSFER;
+        let swapchain_image = self.core.borrow_swapchain().swapchain_images[index];
+        let output = self.get_output(index);
+        let frame = index as u32;
+        let end_stage = |graph: &mut FrameGraph<'a>, stage: usize| {
+            let name = format!("end_{}", STAGE_NAMES[stage]);
+            graph
+                .add_pass(&name, vk::PipelineStageFlags::empty())
+                .record(move |buffer| self.record_stage_end(buffer, frame, stage));
+        };
 
-            for (index, size) in [1, 2, 4, 8, 8, 16].iter().enumerate() {
-                buffer.bind_descriptor_set(
-                    layout,
-                    0,
-                    if index % 2 == 0 { ping_set } else { pong_set },
-                );
-                buffer.push_constants(
-                    layout,
-                    vk::ShaderStageFlags::COMPUTE,
-                    &DenoisePushData { size: *size },
-                );
+        graph
+            .add_pass("raytrace", compute | transfer)
+            .reads(&[image(&data.history_fog_color_buffer)])
+            .writes(&[
+                image(&data.sun_heightmap),
+                image(&data.light_volume),
+                buffer(&data.chunk_access_mask),
+                buffer(&data.ray_statistics),
+            ])
+            .writes(&self.get_ray_outputs(MAIN_VIEW))
+            .record(move |buffer| self.record_raytrace_stage(buffer));
+        end_stage(graph, 0);
+
+        graph
+            .add_pass("temporal", compute)
+            .reads(&[
+                image(&data.normal_buffer),
+                image(&data.motion_buffer),
+                image(&data.history_lighting_buffer),
+                image(&data.history_depth_buffer),
+                image(&data.history_normal_buffer),
+                image(&data.history_moments_buffer),
+            ])
+            .writes(&[
+                image(&data.lighting_buffer),
+                image(&data.completed_buffer),
+                image(&data.moments_buffer),
+                image(&data.accumulation_buffer),
+                buffer(&data.ray_statistics),
+            ])
+            .record(move |buffer| {
+                let layout = self.stages.temporal.pipeline_layout;
+                buffer.bind_descriptor_set(layout, 0, dc.temporal.variants[0]);
+                buffer.bind_pipeline(self.stages.temporal.vk_pipeline);
                 buffer.dispatch(self.x_shader_groups, self.y_shader_groups, 1);
+            });
+        // Keep what this frame looked like so that the next frame can reuse it.
+        let history_copies = [
+            (&data.completed_buffer, &data.history_lighting_buffer),
+            (&data.depth_buffer, &data.history_depth_buffer),
+            (&data.normal_buffer, &data.history_normal_buffer),
+            (&data.moments_buffer, &data.history_moments_buffer),
+            (&data.fog_color_buffer, &data.history_fog_color_buffer),
+        ];
+        let pass = graph.add_pass("copy_history", transfer);
+        for (source, destination) in history_copies.iter() {
+            pass.reads(&[image(*source)]).writes(&[image(*destination)]);
+        }
+        pass.record(move |buffer| {
+            for (source, destination) in history_copies.iter() {
+                buffer.copy_image(*source, *destination, *source);
             }
+        });
+        // This runs after the history is copied so that the debug colors it draws are not
+        // blended into later frames.
+        graph
+            .add_pass("validate_lighting", compute | transfer)
+            .writes(&[
+                image(&data.lighting_buffer),
+                buffer(&data.invalid_lighting_count),
+            ])
+            .record(move |buffer| {
+                buffer.fill_buffer(&data.invalid_lighting_count, 0);
+                buffer.memory_barrier(transfer, compute);
+                let layout = self.stages.validate_lighting.pipeline_layout;
+                buffer.bind_descriptor_set(layout, 0, dc.validate_lighting.variants[0]);
+                buffer.bind_pipeline(self.stages.validate_lighting.vk_pipeline);
+                buffer.dispatch(self.x_shader_groups, self.y_shader_groups, 1);
+            });
+        end_stage(graph, 1);
 
-            let layout = self.finalize_stage.pipeline_layout;
-            let set = self.descriptor_collection.finalize.variants[0];
+        if data.output_image.is_none() {
+            graph.add_pass("prepare_swapchain", compute).record(move |buffer| {
+                buffer.transition_layout(
+                    &swapchain_image,
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::GENERAL,
+                );
+            });
+        }
+        let denoise_passes = self.get_denoise_passes();
+        graph
+            .add_pass("denoise", compute)
+            .reads(&[
+                image(&data.depth_buffer),
+                image(&data.normal_buffer),
+                image(&data.smooth_normal_buffer),
+                image(&data.moments_buffer),
+            ])
+            .writes(&[
+                image(&data.lighting_buffer),
+                image(&data.lighting_pong_buffer),
+                image(&data.variance_buffer),
+                image(&data.variance_pong_buffer),
+            ])
+            .record(move |buffer| self.record_denoise_passes(buffer, denoise_passes, MAIN_VIEW));
+        end_stage(graph, 2);
+
+        // Only pixels with reflections are denoised, which is usually a small part of the
+        // screen.
+        let work_list = &data.reflection_work_list;
+        graph
+            .add_pass("reset_reflection_work_list", transfer)
+            .writes(&[buffer(work_list)])
+            .record(move |buffer| buffer.update_buffer(work_list, &WorkListHeader::empty()));
+        graph
+            .add_pass("compact_reflections", compute)
+            .reads(&[image(&data.normal_buffer), image(&data.emission_buffer)])
+            .writes(&[buffer(work_list)])
+            .record(move |buffer| {
+                let layout = self.stages.compact_reflections.pipeline_layout;
+                buffer.bind_descriptor_set(layout, 0, dc.compact_reflections.variants[0]);
+                buffer.bind_pipeline(self.stages.compact_reflections.vk_pipeline);
+                buffer.dispatch(self.x_shader_groups, self.y_shader_groups, 1);
+            });
+        graph
+            .add_pass("reflection_denoise", compute)
+            .reads_indirect(buffer(work_list))
+            .reads(&[
+                buffer(work_list),
+                image(&data.depth_buffer),
+                image(&data.normal_buffer),
+                image(&data.emission_buffer),
+            ])
+            .writes(&[
+                image(&data.reflection_buffer),
+                image(&data.reflection_pong_buffer),
+            ])
+            .record(move |buffer| self.record_reflection_denoise(buffer));
+        end_stage(graph, 3);
+
+        graph
+            .add_pass("finalize", compute)
+            .reads(&[
+                image(&data.albedo_buffer),
+                image(&data.emission_buffer),
+                image(&data.fog_color_buffer),
+                image(&data.lighting_buffer),
+                image(&data.lighting_pong_buffer),
+                image(&data.depth_buffer),
+                image(&data.reflection_buffer),
+                image(&data.comparison_buffer),
+            ])
+            .writes(&[output])
+            .record(move |buffer| {
+                let layout = self.stages.finalize.pipeline_layout;
+                let set = dc.finalize.variants[denoise_passes.len() % 2];
+                buffer.bind_descriptor_set(layout, 0, set);
+                buffer.bind_descriptor_set(layout, 1, dc.swapchain.variants[index]);
+                buffer.bind_pipeline(self.stages.finalize.vk_pipeline);
+                // Scales the main view to fit the swapchain, see RenderSettings::render_scale.
+                buffer.dispatch(self.x_output_groups, self.y_output_groups, 1);
+            });
+        end_stage(graph, 4);
+
+        graph
+            .add_pass("overlay", compute)
+            .reads(&[image(&data.pip.output)])
+            .writes(&[output])
+            .record(move |buffer| {
+                let layout = self.stages.overlay.pipeline_layout;
+                buffer.bind_descriptor_set(layout, 0, dc.overlay.variants[0]);
+                buffer.bind_descriptor_set(layout, 1, dc.swapchain.variants[index]);
+                buffer.bind_pipeline(self.stages.overlay.vk_pipeline);
+                buffer.dispatch(self.x_output_groups, self.y_output_groups, 1);
+            });
+        end_stage(graph, 5);
+
+        graph
+            .add_pass("text", compute)
+            .writes(&[output])
+            .record(move |buffer| {
+                let layout = self.stages.text.pipeline_layout;
+                buffer.bind_descriptor_set(layout, 0, dc.text.variants[0]);
+                buffer.bind_descriptor_set(layout, 1, dc.swapchain.variants[index]);
+                buffer.bind_pipeline(self.stages.text.vk_pipeline);
+                // One work group per glyph, extra work groups return immediately.
+                buffer.dispatch(MAX_GLYPHS as u32, 1, 1);
+            });
+        end_stage(graph, 6);
+
+        graph
+            .add_pass("present", transfer)
+            .reads(&[output])
+            .writes(&[image(&swapchain_image)])
+            .record(move |buffer| self.record_present(buffer, swapchain_image));
+    }
+
+    /// Adds the passes which only run with some settings or debug tools turned on, each of which
+    /// is placed relative to the passes from add_frame_passes.
+    fn add_optional_passes<'a>(&'a self, graph: &mut FrameGraph<'a>, index: usize) {
+        use frame_graph::{buffer, image};
+        let data = &self.render_data;
+        let compute = vk::PipelineStageFlags::COMPUTE_SHADER;
+        let transfer = vk::PipelineStageFlags::TRANSFER;
+        let output = self.get_output(index);
+
+        if self.is_measuring_image_statistics() {
+            graph
+                .add_pass("image_statistics", compute | transfer)
+                .after("validate_lighting")
+                .reads(&[image(&data.lighting_buffer)])
+                .writes(&[buffer(&data.image_statistics)])
+                .record(move |buffer| self.record_image_statistics(buffer));
+        }
+        if let Some(schedule) = &self.comparison_schedule {
+            graph
+                .add_pass("comparison", compute | transfer)
+                .before("denoise")
+                .reads(&[
+                    image(&data.depth_buffer),
+                    image(&data.normal_buffer),
+                    image(&data.smooth_normal_buffer),
+                    image(&data.moments_buffer),
+                ])
+                .writes(&[
+                    image(&data.lighting_buffer),
+                    image(&data.lighting_pong_buffer),
+                    image(&data.completed_buffer),
+                    image(&data.comparison_buffer),
+                    image(&data.variance_buffer),
+                    image(&data.variance_pong_buffer),
+                ])
+                .record(move |buffer| self.record_comparison(buffer, &schedule.0));
+        }
+        if self.pip_camera.is_some() {
+            let pip = &data.pip;
+            let denoise_passes = self.get_denoise_passes();
+            graph
+                .add_pass("pip", compute | transfer)
+                .after("finalize")
+                .writes(&[
+                    buffer(&data.ray_queue),
+                    buffer(&data.ray_pong_queue),
+                    buffer(&data.light_accumulators),
+                    image(&pip.lighting_pong_buffer),
+                    image(&pip.variance_buffer),
+                    image(&pip.variance_pong_buffer),
+                    image(&pip.output),
+                ])
+                .writes(&self.get_ray_outputs(PIP_VIEW))
+                .record(move |buffer| self.record_pip(buffer, denoise_passes));
+        }
+        let output_format = self.core.borrow_swapchain().output_format;
+        if output_format.is_hdr() {
+            let dc = &self.descriptor_collection;
+            graph
+                .add_pass("encode_output", compute)
+                .after("text")
+                .writes(&[output])
+                .record(move |buffer| {
+                    let layout = self.stages.encode_output.pipeline_layout;
+                    buffer.bind_descriptor_set(layout, 0, dc.swapchain.variants[index]);
+                    buffer.push_constants(
+                        layout,
+                        vk::ShaderStageFlags::COMPUTE,
+                        &EncodeOutputPushData {
+                            output_format: output_format.to_index(),
+                        },
+                    );
+                    buffer.bind_pipeline(self.stages.encode_output.vk_pipeline);
+                    buffer.dispatch(self.x_output_groups, self.y_output_groups, 1);
+                });
+        }
+    }
+
+    /// The images record_rays writes for the given view.
+    fn get_ray_outputs(&self, view: usize) -> Vec<Resource> {
+        let data = &self.render_data;
+        let images = if view == PIP_VIEW {
+            let pip = &data.pip;
+            [
+                &pip.lighting_buffer,
+                &pip.depth_buffer,
+                &pip.normal_buffer,
+                &pip.motion_buffer,
+                &pip.smooth_normal_buffer,
+                &pip.albedo_buffer,
+                &pip.emission_buffer,
+                &pip.fog_color_buffer,
+                &pip.reflection_buffer,
+            ]
+        } else {
+            [
+                &data.lighting_buffer,
+                &data.depth_buffer,
+                &data.normal_buffer,
+                &data.motion_buffer,
+                &data.smooth_normal_buffer,
+                &data.albedo_buffer,
+                &data.emission_buffer,
+                &data.fog_color_buffer,
+                &data.reflection_buffer,
+            ]
+        };
+        let mut outputs: Vec<_> = images.iter().map(|image| frame_graph::image(*image)).collect();
+        outputs.push(frame_graph::buffer(&data.ray_queue));
+        outputs.push(frame_graph::buffer(&data.ray_pong_queue));
+        outputs.push(frame_graph::buffer(&data.light_accumulators));
+        outputs
+    }
+
+    /// The denoise schedule, or nothing if the denoiser is toggled off.
+    fn get_denoise_passes(&self) -> &[i32] {
+        if self.stage_toggles.denoise {
+            &self.denoise_schedule.0[..]
+        } else {
+            &[]
+        }
+    }
+
+    /// Records the reflection denoiser, which only runs on the pixels compact_reflections.comp
+    /// put in the reflection work list.
+    fn record_reflection_denoise(&self, buffer: &CommandBuffer) {
+        let layout = self.stages.reflection_denoise.pipeline_layout;
+        let smooth_normals = self.render_data.settings.smooth_normals as u32;
+        buffer.bind_pipeline(self.stages.reflection_denoise.vk_pipeline);
+        let reflection_passes = if self.stage_toggles.reflection_denoise {
+            &REFLECTION_DENOISE_SCHEDULE[..]
+        } else {
+            &[]
+        };
+        for (index, size) in reflection_passes.iter().enumerate() {
+            let set = self.descriptor_collection.reflection_denoise.variants[index % 2];
             buffer.bind_descriptor_set(layout, 0, set);
-            let set = self.descriptor_collection.swapchain.variants[index];
-            buffer.bind_descriptor_set(layout, 1, set);
-            buffer.bind_pipeline(self.finalize_stage.vk_pipeline);
-            buffer.dispatch(self.x_shader_groups, self.y_shader_groups, 1);
+            buffer.push_constants(
+                layout,
+                vk::ShaderStageFlags::COMPUTE,
+                &DenoisePushData {
+                    size: *size,
+                    smooth_normals,
+                    gradient_weight: self.denoise_gradient_weight,
+                    variance_weight: self.denoise_variance_weight,
+                },
+            );
+            buffer.dispatch_indirect(&self.render_data.reflection_work_list, 0);
+        }
+    }
 
+    /// Gets the swapchain image ready to be presented, blitting the output image to it first if
+    /// there is one.
+    fn record_present(&self, buffer: &CommandBuffer, swapchain_image: vk::Image) {
+        if let Some(output_image) = &self.render_data.output_image {
+            buffer.transition_layout(
+                &swapchain_image,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            );
+            buffer.blit_image(output_image, &swapchain_image, output_image);
+            buffer.transition_layout(
+                &swapchain_image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::PRESENT_SRC_KHR,
+            );
+        } else {
             buffer.transition_layout(
                 &swapchain_image,
                 vk::ImageLayout::GENERAL,
                 vk::ImageLayout::PRESENT_SRC_KHR,
             );
-            buffer.end();
         }
     }
 
-    pub fn draw_frame(&mut self, game: &mut Game) {
-        let (image_index, _is_suboptimal) = unsafe {
-            self.core
-                .swapchain
-                .swapchain_loader
-                .acquire_next_image(
-                    self.core.swapchain.swapchain,
-                    std::u64::MAX,
-                    self.frame_available_semaphore,
-                    vk::Fence::null(),
-                )
-                .expect("Failed to acquire next swapchain image.")
+    fn is_measuring_image_statistics(&self) -> bool {
+        self.white_balance.is_some() || self.measure_image_statistics
+    }
+
+    /// Records measuring the brightness and color of the light the temporal stage blended, which
+    /// the CPU reads back once the frame is done. See image_statistics.comp.
+    fn record_image_statistics(&self, buffer: &CommandBuffer) {
+        // The barrier this records also waits for validation, which may have replaced invalid
+        // lighting with debug colors.
+        image_statistics::record_image_statistics(
+            buffer,
+            &self.render_data.image_statistics,
+            self.stages.image_statistics.vk_pipeline,
+            self.stages.image_statistics.pipeline_layout,
+            self.descriptor_collection.image_statistics.variants[0],
+            self.render_data.lighting_buffer.extent,
+        );
+    }
+
+    /// How many work groups cover every pixel of the given view.
+    fn get_view_groups(&self, view: usize) -> (u32, u32) {
+        if view == PIP_VIEW {
+            count_shader_groups(self.render_data.pip.extent)
+        } else {
+            (self.x_shader_groups, self.y_shader_groups)
+        }
+    }
+
+    /// Records the variance-guided denoiser, which estimates how noisy the lighting of the given
+    /// view is and then ping-pongs between its two lighting buffers starting from
+    /// lighting_buffer. The variance is estimated again every time since the passes overwrite it.
+    fn record_denoise_passes(&self, buffer: &CommandBuffer, passes: &[i32], view: usize) {
+        if passes.is_empty() {
+            return;
+        }
+        let compute = vk::PipelineStageFlags::COMPUTE_SHADER;
+        let (x_groups, y_groups) = self.get_view_groups(view);
+        let layout = self.stages.estimate_variance.pipeline_layout;
+        let set = self.descriptor_collection.estimate_variance.variants[view];
+        buffer.bind_descriptor_set(layout, 0, set);
+        buffer.push_constants(
+            layout,
+            vk::ShaderStageFlags::COMPUTE,
+            &EstimateVariancePushData {
+                use_moments: (view == MAIN_VIEW) as u32,
+            },
+        );
+        buffer.bind_pipeline(self.stages.estimate_variance.vk_pipeline);
+        buffer.dispatch(x_groups, y_groups, 1);
+
+        let layout = self.stages.denoise.pipeline_layout;
+        let smooth_normals = self.render_data.settings.smooth_normals as u32;
+        let ping_set = self.descriptor_collection.denoise.variants[view * 2];
+        let pong_set = self.descriptor_collection.denoise.variants[view * 2 + 1];
+        buffer.bind_pipeline(self.stages.denoise.vk_pipeline);
+        for (index, size) in passes.iter().enumerate() {
+            // Each pass reads the neighbors of every pixel from the one before.
+            buffer.memory_barrier(compute, compute);
+            buffer.bind_descriptor_set(
+                layout,
+                0,
+                if index % 2 == 0 { ping_set } else { pong_set },
+            );
+            buffer.push_constants(
+                layout,
+                vk::ShaderStageFlags::COMPUTE,
+                &DenoisePushData {
+                    size: *size,
+                    smooth_normals,
+                    gradient_weight: self.denoise_gradient_weight,
+                    variance_weight: self.denoise_variance_weight,
+                },
+            );
+            buffer.dispatch(x_groups, y_groups, 1);
+        }
+    }
+
+    /// Denoises the lighting with the comparison schedule into the comparison buffer, leaving the
+    /// lighting buffer as it was so that the main schedule can run on it afterwards. The completed
+    /// buffer is free to hold the undenoised lighting since the history has already been copied
+    /// from it.
+    fn record_comparison(&self, buffer: &CommandBuffer, passes: &[i32]) {
+        let data = &self.render_data;
+        let compute = vk::PipelineStageFlags::COMPUTE_SHADER;
+        let transfer = vk::PipelineStageFlags::TRANSFER;
+        buffer.copy_image(&data.lighting_buffer, &data.completed_buffer, &data.lighting_buffer);
+        buffer.memory_barrier(transfer, compute);
+        self.record_denoise_passes(buffer, passes, MAIN_VIEW);
+        buffer.memory_barrier(compute, transfer);
+        let result = if passes.len() % 2 == 0 {
+            &data.lighting_buffer
+        } else {
+            &data.lighting_pong_buffer
         };
+        buffer.copy_image(result, &data.comparison_buffer, result);
+        buffer.memory_barrier(transfer, transfer);
+        buffer.copy_image(&data.completed_buffer, &data.lighting_buffer, &data.lighting_buffer);
+    }
 
-        let wait_semaphores = [self.frame_available_semaphore];
-        let signal_semaphores = [self.frame_complete_semaphore];
-        let wait_stage_mask = [vk::PipelineStageFlags::ALL_COMMANDS];
-        let submit_info = vk::SubmitInfo {
-            wait_semaphore_count: 1,
-            p_wait_semaphores: wait_semaphores.as_ptr(),
-            p_wait_dst_stage_mask: wait_stage_mask.as_ptr(),
-            command_buffer_count: 1,
-            p_command_buffers: &self.command_buffers[image_index as usize].get_vk_command_buffer(),
-            signal_semaphore_count: 1,
-            p_signal_semaphores: signal_semaphores.as_ptr(),
-            ..Default::default()
+    /// Records the kernels which trace rays, which pass rays between each other through the two
+    /// ray queues. See raytrace_common.glsl for what each one does.
+    fn record_raytrace_stage(&self, buffer: &CommandBuffer) {
+        let data = &self.render_data;
+        let dc = &self.descriptor_collection;
+        let compute = vk::PipelineStageFlags::COMPUTE_SHADER;
+        let transfer = vk::PipelineStageFlags::TRANSFER;
+
+        buffer.fill_buffer(&data.chunk_access_mask, 0);
+        buffer.fill_buffer(&data.ray_statistics, 0);
+        buffer.memory_barrier(transfer, compute);
+        if data.settings.sun_heightmap {
+            // Rebuilt every frame since the world may have changed. It is only read by shading,
+            // which happens after the barrier following raygen.
+            let layout = self.stages.sun_heightmap.pipeline_layout;
+            buffer.bind_descriptor_set(layout, 0, dc.scene.variants[0]);
+            buffer.bind_descriptor_set(layout, 1, dc.sun_heightmap.variants[0]);
+            buffer.bind_pipeline(self.stages.sun_heightmap.vk_pipeline);
+            let group_size = SHADER_GROUP_SIZE as u32;
+            let groups = (data.settings.root_block_size() as u32 + group_size - 1) / group_size;
+            buffer.dispatch(groups, groups, 1);
+        }
+        if data.settings.light_volume {
+            // Also only read by shading. Each frame updates a different layer of cells in every
+            // LIGHT_VOLUME_UPDATE_INTERVAL, see light_volume.comp.
+            let layout = self.stages.light_volume.pipeline_layout;
+            buffer.bind_descriptor_set(layout, 0, dc.scene.variants[0]);
+            buffer.bind_descriptor_set(layout, 1, dc.light_volume.variants[0]);
+            buffer.bind_pipeline(self.stages.light_volume.vk_pipeline);
+            // The kernel works on groups of 4x4x4 cells.
+            let cells = (data.settings.root_block_size() / LIGHT_VOLUME_CELL_SIZE) as u32;
+            let groups = (cells + 3) / 4;
+            let layers = cells / LIGHT_VOLUME_UPDATE_INTERVAL as u32;
+            buffer.dispatch(groups, groups, (layers + 3) / 4);
+        }
+        self.record_rays(buffer, MAIN_VIEW);
+    }
+
+    /// Records generating, tracing and shading the rays of one view, ending with its lighting
+    /// resolved into its lighting buffer. The views share the ray queues and accumulators.
+    fn record_rays(&self, buffer: &CommandBuffer, view: usize) {
+        let data = &self.render_data;
+        let dc = &self.descriptor_collection;
+        let compute = vk::PipelineStageFlags::COMPUTE_SHADER;
+        let transfer = vk::PipelineStageFlags::TRANSFER;
+        let indirect = vk::PipelineStageFlags::DRAW_INDIRECT;
+        let extent = if view == PIP_VIEW {
+            data.pip.extent
+        } else {
+            data.extent
+        };
+        let (x_groups, y_groups) = self.get_view_groups(view);
+        let scene = dc.scene.variants[view];
+
+        buffer.fill_buffer(&data.light_accumulators, 0);
+        let num_pixels = extent.width * extent.height;
+        buffer.update_buffer(&data.ray_queue, &WorkListHeader::for_items(num_pixels));
+        buffer.memory_barrier(transfer, compute);
+        let layout = self.stages.raygen.pipeline_layout;
+        buffer.bind_descriptor_set(layout, 0, scene);
+        buffer.bind_descriptor_set(layout, 1, dc.raygen.variants[view]);
+        buffer.bind_pipeline(self.stages.raygen.vk_pipeline);
+        buffer.dispatch(x_groups, y_groups, 1);
+        buffer.memory_barrier(compute, indirect | compute);
+
+        let queues = [&data.ray_queue, &data.ray_pong_queue];
+        for pass in 0..RAY_QUEUE_PASSES {
+            let (input, output) = (queues[pass % 2], queues[(pass + 1) % 2]);
+            let layout = self.stages.traverse.pipeline_layout;
+            buffer.bind_descriptor_set(layout, 0, scene);
+            buffer.bind_descriptor_set(layout, 1, dc.traverse.variants[pass % 2]);
+            buffer.bind_pipeline(self.stages.traverse.vk_pipeline);
+            buffer.dispatch_indirect(input, 0);
+
+            // The output queue was read by the previous pass, so it can only be emptied now.
+            buffer.memory_barrier(compute, transfer);
+            buffer.update_buffer(output, &WorkListHeader::empty());
+            buffer.memory_barrier(compute | transfer, compute);
+            let layout = self.stages.shade.pipeline_layout;
+            buffer.bind_descriptor_set(layout, 0, scene);
+            buffer.bind_descriptor_set(layout, 1, dc.shade.variants[view * 2 + pass % 2]);
+            buffer.bind_pipeline(self.stages.shade.vk_pipeline);
+            buffer.dispatch_indirect(input, 0);
+            buffer.memory_barrier(compute, indirect | compute);
+        }
+
+        let layout = self.stages.resolve.pipeline_layout;
+        buffer.bind_descriptor_set(layout, 0, scene);
+        buffer.bind_descriptor_set(layout, 1, dc.resolve.variants[view]);
+        buffer.bind_pipeline(self.stages.resolve.vk_pipeline);
+        buffer.dispatch(x_groups, y_groups, 1);
+
+        // Only finalize reads the fog, which is smooth enough to work out at a lower resolution.
+        let layout = self.stages.fog.pipeline_layout;
+        buffer.bind_descriptor_set(layout, 0, scene);
+        buffer.bind_descriptor_set(layout, 1, dc.fog.variants[view]);
+        buffer.push_constants(
+            layout,
+            vk::ShaderStageFlags::COMPUTE,
+            &FogPushData {
+                use_history: (view == MAIN_VIEW) as u32,
+            },
+        );
+        buffer.bind_pipeline(self.stages.fog.vk_pipeline);
+        let (x_groups, y_groups) = count_shader_groups(RenderData::get_fog_extent(extent));
+        buffer.dispatch(x_groups, y_groups, 1);
+    }
+
+    /// Renders the picture-in-picture view into its output image once the main view has been
+    /// finalized. It skips temporal blending and reflection denoising, which are not worth
+    /// keeping a second history for in a small debug view.
+    fn record_pip(&self, buffer: &CommandBuffer, denoise_passes: &[i32]) {
+        let compute = vk::PipelineStageFlags::COMPUTE_SHADER;
+        self.record_rays(buffer, PIP_VIEW);
+        buffer.memory_barrier(compute, compute);
+        self.record_denoise_passes(buffer, denoise_passes, PIP_VIEW);
+        buffer.memory_barrier(compute, compute);
+
+        let layout = self.stages.finalize.pipeline_layout;
+        let variant = PIP_VIEW * 2 + denoise_passes.len() % 2;
+        let set = self.descriptor_collection.finalize.variants[variant];
+        buffer.bind_descriptor_set(layout, 0, set);
+        let set = self.descriptor_collection.pip_output.variants[0];
+        buffer.bind_descriptor_set(layout, 1, set);
+        buffer.bind_pipeline(self.stages.finalize.vk_pipeline);
+        let (x_groups, y_groups) = self.get_view_groups(PIP_VIEW);
+        buffer.dispatch(x_groups, y_groups, 1);
+    }
+
+    /// Renders every placed probe into the reflection probe images. The uniform buffer must
+    /// already hold this frame's data.
+    fn capture_probes(&mut self) {
+        if self.captured_probes.len() == 0 {
+            return;
+        }
+        let commands = CommandBuffer::create_single(self.core.clone());
+        commands.begin_one_time_submit();
+        let layout = self.stages.probe_capture.pipeline_layout;
+        let dc = &self.descriptor_collection;
+        commands.bind_descriptor_set(layout, 0, dc.scene.variants[0]);
+        commands.bind_descriptor_set(layout, 1, dc.probe_capture.variants[0]);
+        commands.bind_pipeline(self.stages.probe_capture.vk_pipeline);
+        let groups = (PROBE_SIZE + SHADER_GROUP_SIZE as u32 - 1) / SHADER_GROUP_SIZE as u32;
+        for (index, probe) in self.captured_probes.iter().enumerate() {
+            commands.push_constants(
+                layout,
+                vk::ShaderStageFlags::COMPUTE,
+                &ProbeCapturePushData {
+                    position: util::world_to_local(*probe, self.region_offset),
+                    probe: index as u32,
+                },
+            );
+            // One layer of work groups for each face.
+            commands.dispatch(groups, groups, 6);
+        }
+        commands.end();
+        commands.blocking_execute_and_destroy();
+    }
+
+    fn update_overlay_data(&mut self, game: &Game) {
+        let hud_visible = self.is_hud_visible(game);
+        let overlay_data = &mut self.render_data.overlay_uniform_data;
+        let hotbar = game.borrow_hotbar();
+        for (slot, color) in overlay_data.hotbar_colors.iter_mut().enumerate() {
+            *color = if let Some(material_index) = hotbar.get(slot) {
+                // Albedo values in MATERIALS range from 0 to 127.
+                let albedo = MATERIALS[*material_index].albedo;
+                let channel = |value: u16| value as f32 / 127.0;
+                [channel(albedo.0), channel(albedo.1), channel(albedo.2), 1.0].into()
+            } else {
+                [0.0, 0.0, 0.0, 0.0].into()
+            };
+        }
+        overlay_data.hotbar_length = hotbar.len() as u32;
+        overlay_data.selected_slot = game.get_selected_slot() as u32;
+        let mut flags = 0;
+        if hud_visible {
+            flags |= OverlayUniformData::VISIBLE;
+        }
+        if game.borrow_selection().is_some() {
+            flags |= OverlayUniformData::HAS_SELECTION;
+        }
+        if self.tum.is_busy() {
+            flags |= OverlayUniformData::STREAMING;
+        }
+        if game.borrow_world().is_generating() {
+            flags |= OverlayUniformData::GENERATING;
+        }
+        if self.pip_camera.is_some() {
+            flags |= OverlayUniformData::PIP;
+        }
+        overlay_data.flags = flags;
+        if game.get_debug_view() == DebugView::StreamingVolumes {
+            // The streaming map is always centered on the camera.
+            let middle = MINIMAP_SIZE as i32 / 2;
+            overlay_data.minimap_marker = [middle, middle].into();
+        } else if let Some(center) = self.minimap_center {
+            let origin = game.borrow_render_camera().origin;
+            let step = MINIMAP_BLOCKS_PER_PIXEL as f64;
+            // The top of the minimap is +Y.
+            overlay_data.minimap_marker = [
+                ((origin.x - center.0 as f64) / step) as i32 + MINIMAP_SIZE as i32 / 2,
+                ((center.1 as f64 - origin.y) / step) as i32 + MINIMAP_SIZE as i32 / 2,
+            ]
+            .into();
+        }
+
+        let mut buffer_content = self.render_data.overlay_uniform_data_buffer.bind_all();
+        buffer_content[0] = overlay_data.clone();
+        drop(buffer_content);
+    }
+
+    /// Redraws the minimap once the camera moves into a different chunk. This must only be called
+    /// while the GPU is not rendering a frame, since it replaces the minimap image.
+    fn update_minimap(&mut self, game: &Game) {
+        if game.get_debug_view() == DebugView::StreamingVolumes {
+            // Redrawn every frame since the queued slices change constantly. The terrain map is
+            // redrawn once the debug view is turned off.
+            self.minimap_center = None;
+            let state = self.get_streaming_state(game);
+            let image = streaming_map::render_streaming_map(&state, MINIMAP_SIZE);
+            self.load_minimap(&image);
+            return;
+        }
+        let origin = game.borrow_render_camera().origin;
+        let size = CHUNK_SIZE as isize;
+        // Center of the chunk column the camera is in.
+        let center = (
+            (origin.x.floor() as isize).div_euclid(size) * size + size / 2,
+            (origin.y.floor() as isize).div_euclid(size) * size + size / 2,
+        );
+        if self.minimap_center == Some(center) {
+            return;
+        }
+        self.minimap_center = Some(center);
+
+        let image = map::render_map(center, MINIMAP_SIZE, MINIMAP_BLOCKS_PER_PIXEL);
+        self.load_minimap(&image);
+    }
+
+    fn load_minimap(&mut self, image: &[u8]) {
+        self.render_data.minimap.load_from_slice(image);
+        let commands = CommandBuffer::create_single(Rc::clone(&self.core));
+        commands.begin_one_time_submit();
+        commands.transition_layout(
+            &self.render_data.minimap,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        commands.end();
+        commands.blocking_execute_and_destroy();
+    }
+
+    /// Describes what terrain is streamed where for the streaming_volumes debug view.
+    fn get_streaming_state(&self, game: &Game) -> streaming_map::StreamingState {
+        let camera = game.borrow_render_camera();
+        let columns =
+            |(start, end): (SignedCoord3D, SignedCoord3D)| ((start.0, start.1), (end.0, end.1));
+        let distant_enabled = self.render_data.raytrace_uniform_data.distant_terrain != 0;
+        let distant = match self.distant_terrain_center {
+            Some(center) if distant_enabled => {
+                let half = (DISTANT_TERRAIN_SIZE / 2 * DISTANT_TERRAIN_BLOCKS_PER_TEXEL) as isize;
+                Some((
+                    (center.0 - half, center.1 - half),
+                    (center.0 + half, center.1 + half),
+                ))
+            }
+            _ => None,
+        };
+        streaming_map::StreamingState {
+            camera: (
+                camera.origin.x.floor() as isize,
+                camera.origin.y.floor() as isize,
+            ),
+            heading: camera.heading,
+            half_fov: Rad(VIEW_SCALE.atan()),
+            loaded: columns(self.tum.get_loaded_block_range()),
+            distant,
+            queued_slices: self
+                .tum
+                .get_queued_slices()
+                .into_iter()
+                .map(|(range, age)| (columns(range), age))
+                .collect(),
+        }
+    }
+
+    /// Rebuilds the distant terrain around the camera once it moves far enough from the center.
+    /// This must only be called while the GPU is not rendering a frame, since it replaces the
+    /// distant terrain image.
+    fn update_distant_terrain(&mut self, game: &Game) {
+        let origin = game.borrow_render_camera().origin;
+        let camera = (origin.x.floor() as isize, origin.y.floor() as isize);
+        if let Some(center) = self.distant_terrain_center {
+            let distance = (camera.0 - center.0).abs().max((camera.1 - center.1).abs());
+            if distance < DISTANT_TERRAIN_RECENTER_DISTANCE {
+                return;
+            }
+        }
+        // Snapped to the texel grid so that columns stay put when the terrain is rebuilt.
+        let step = DISTANT_TERRAIN_BLOCKS_PER_TEXEL as isize;
+        let center = (
+            camera.0.div_euclid(step) * step,
+            camera.1.div_euclid(step) * step,
+        );
+        self.distant_terrain_center = Some(center);
+
+        let terrain = map::build_distant_terrain(
+            center,
+            DISTANT_TERRAIN_SIZE,
+            DISTANT_TERRAIN_BLOCKS_PER_TEXEL,
+        );
+        self.render_data
+            .distant_terrain
+            .load_from_slice(&terrain.texels);
+        let commands = CommandBuffer::create_single(Rc::clone(&self.core));
+        commands.begin_one_time_submit();
+        commands.transition_layout(
+            &self.render_data.distant_terrain,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        commands.end();
+        commands.blocking_execute_and_destroy();
+
+        let half = (DISTANT_TERRAIN_SIZE / 2 * DISTANT_TERRAIN_BLOCKS_PER_TEXEL) as isize;
+        let uniform_data = &mut self.render_data.raytrace_uniform_data;
+        uniform_data.distant_terrain_origin =
+            [(center.0 - half) as i32, (center.1 - half) as i32].into();
+        uniform_data.distant_terrain_max_height = terrain.max_height as i32;
+    }
+
+    /// Rebuilds the atmosphere volume around the camera once it leaves the cell at the center.
+    /// This must only be called while the GPU is not rendering a frame, since it replaces the
+    /// atmosphere images.
+    fn update_atmosphere(&mut self, game: &Game) {
+        let origin = game.borrow_render_camera().origin;
+        let step = ATMOSPHERE_BLOCKS_PER_CELL as isize;
+        // Snapped to the cell grid so that cells stay put when the volume is rebuilt.
+        let center = (
+            (origin.x.floor() as isize).div_euclid(step) * step,
+            (origin.y.floor() as isize).div_euclid(step) * step,
+            (origin.z.floor() as isize).div_euclid(step) * step,
+        );
+        if self.atmosphere_center == Some(center) {
+            return;
+        }
+        self.atmosphere_center = Some(center);
+
+        let volume = atmosphere::build_atmosphere_volume(
+            center,
+            ATMOSPHERE_VOLUME_SIZE,
+            ATMOSPHERE_BLOCKS_PER_CELL,
+        );
+        let render_data = &self.render_data;
+        render_data.atmosphere_fog.load_from_slice(&volume.fog);
+        render_data.atmosphere_grading.load_from_slice(&volume.grading);
+        let commands = CommandBuffer::create_single(Rc::clone(&self.core));
+        commands.begin_one_time_submit();
+        for image in &[&render_data.atmosphere_fog, &render_data.atmosphere_grading] {
+            commands.transition_layout(
+                *image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            );
+        }
+        commands.end();
+        commands.blocking_execute_and_destroy();
+    }
+
+    /// Where the camera is in the atmosphere volume, from 0 to 1 along each axis.
+    fn get_atmosphere_position(&self, game: &Game) -> Vector3<f32> {
+        let center = match self.atmosphere_center {
+            Some(center) => center,
+            None => return Vector3::new(0.5, 0.5, 0.5),
+        };
+        let width = (ATMOSPHERE_VOLUME_SIZE * ATMOSPHERE_BLOCKS_PER_CELL) as f64;
+        let corner = Vector3::new(center.0 as f64, center.1 as f64, center.2 as f64)
+            - Vector3::new(width, width, width) / 2.0;
+        ((game.borrow_render_camera().origin - corner) / width).cast().unwrap()
+    }
+
+    /// Text added to this will be drawn over the next frame.
+    pub fn borrow_text_mut(&mut self) -> &mut TextBuffer {
+        &mut self.text
+    }
+
+    /// Draws a title and a hint about which keys do what in the middle of the screen.
+    fn draw_menu(&mut self, title: &str, hint: &str) {
+        let extent = self.core.borrow_swapchain().swapchain_extent;
+        let (title_width, title_height) = TextBuffer::measure_text(title, 6);
+        let (hint_width, _) = TextBuffer::measure_text(hint, 2);
+        let top = (extent.height / 2).saturating_sub(title_height);
+        let left = (extent.width.saturating_sub(title_width)) / 2;
+        self.text
+            .draw_text((left, top), 6, [255, 255, 255, 255], title);
+        let left = (extent.width.saturating_sub(hint_width)) / 2;
+        let top = top + title_height + 16;
+        self.text
+            .draw_text((left, top), 2, [200, 200, 200, 255], hint);
+    }
+
+    fn is_hud_visible(&self, game: &Game) -> bool {
+        let playing = game.get_state() == GameState::Playing;
+        let capturing = self.beauty_shot.is_some() || self.panorama.is_some();
+        game.is_hud_visible() && playing && !capturing
+    }
+
+    fn update_temporal_data(&mut self, game: &Game) {
+        // The history can't follow changes in lighting, only surfaces moving around.
+        let sun_motion = (game.get_sun_angle() - self.old_sun_angle).abs();
+        self.old_sun_angle = game.get_sun_angle();
+        let history_scale = 1.0 - (sun_motion / SUN_MOTION_HISTORY_LIMIT).min(1.0);
+        // Beauty shots and panoramas blend frames their own way.
+        let capturing = self.beauty_shot.is_some() || self.panorama.is_some();
+        let can_accumulate = game.get_stage_toggles().temporal && !capturing;
+        if sun_motion > 0.0 || !can_accumulate {
+            self.accumulation.reset();
+        }
+        let accumulated_frames = if can_accumulate {
+            self.accumulation.advance(game.borrow_render_camera())
+        } else {
+            0
+        };
+
+        let settings = &self.temporal_settings;
+        let history_weight = match (&self.beauty_shot, &self.panorama) {
+            (Some(shot), _) => shot.get_history_weight(),
+            (None, Some(panorama)) => panorama.get_history_weight(),
+            (None, None) => settings.history_weight,
+        };
+        // Without any history, the temporal stage passes the current frame through unchanged.
+        let history_weight = if game.get_stage_toggles().temporal {
+            history_weight
+        } else {
+            0.0
+        };
+        let atmosphere_position = self.get_atmosphere_position(game);
+        let output_format = self.core.borrow_swapchain().output_format;
+        let mut buffer_content = self.render_data.temporal_uniform_data_buffer.bind_all();
+        buffer_content[0] = TemporalUniformData {
+            history_weight: history_weight * history_scale,
+            depth_threshold: settings.depth_threshold,
+            normal_threshold: settings.normal_threshold,
+            debug_view: game.get_debug_view().to_index(),
+            // Past the right edge of the screen when not comparing, so nothing uses it.
+            comparison_divider: match game.borrow_comparison_schedule() {
+                Some(..) => game.get_comparison_divider(),
+                None => 2.0,
+            },
+            _padding0: [0; 3],
+            atmosphere_position,
+            _padding1: 0,
+            white_balance: match &self.white_balance {
+                Some(white_balance) => white_balance.to_uniform(),
+                None => WhiteBalance::identity_uniform(),
+            },
+            exposure: tonemap::get_exposure_scale(game.get_exposure()),
+            tonemapper: game.get_tonemapper().to_index(),
+            output_headroom: output_format.get_headroom(),
+            dither_amplitude: output_format.get_dither_amplitude(),
+            accumulated_frames,
+        };
+    }
+
+    /// Prints which stage the GPU was running if the result says that the device was lost.
+    fn report_device_lost<T>(&mut self, result: VkResult<T>) -> VkResult<T> {
+        if let Err(vk::Result::ERROR_DEVICE_LOST) = result {
+            println!("ERROR: The device was lost.");
+            match &mut self.checkpoints {
+                Some(checkpoints) => checkpoints.report(),
+                None => println!("The stage the GPU was running is unknown without checkpoints."),
+            }
+        }
+        result
+    }
+
+    /// Prints how many pixels had NaN or infinite lighting in the previous frame. This is printed
+    /// every frame while the invalid_lighting debug view is on, otherwise only the first time.
+    fn report_invalid_lighting(&mut self, game: &Game) {
+        let count = self.render_data.invalid_lighting_count.bind_all()[0];
+        if count == 0 {
+            return;
+        }
+        if game.get_debug_view() == DebugView::InvalidLighting {
+            println!("{} pixels have NaN or infinite lighting.", count);
+        } else if !self.warned_invalid_lighting {
+            println!(
+                "WARNING: {} pixels have NaN or infinite lighting. Use 'debug_view \
+                invalid_lighting' to see where.",
+                count
+            );
+            self.warned_invalid_lighting = true;
+        }
+    }
+
+    fn update_text_data(&mut self, game: &Game) {
+        match game.get_state() {
+            GameState::Loading => {
+                let world = game.borrow_world();
+                let hint = format!(
+                    "{} chunks generated, {} to go    {:.1} MB uploaded",
+                    world.get_chunks_generated(),
+                    world.get_chunks_pending(),
+                    self.tum.get_bytes_uploaded() as f64 / 1_000_000.0
+                );
+                self.draw_menu("LOADING", &hint);
+            }
+            GameState::MainMenu => self.draw_menu("RAYTRACE", "Enter: Play    Escape: Quit"),
+            GameState::Paused => self.draw_menu("PAUSED", "Escape: Resume    M: Main Menu"),
+            GameState::Playing | GameState::Quitting => (),
+        }
+        if self.is_hud_visible(game) {
+            let origin = game.borrow_render_camera().origin;
+            let position = format!("{:.1} {:.1} {:.1}", origin.x, origin.y, origin.z);
+            // Below the status icons.
+            self.text
+                .draw_text((8, 28), 2, [255, 255, 255, 255], &position);
+            let toggles = game.get_stage_toggles();
+            if toggles.get_disabled().len() > 0 {
+                let text = format!("Stages: {}", toggles);
+                self.text.draw_text((8, 48), 2, [255, 200, 0, 255], &text);
+            }
+            let mut line = 68;
+            if game.is_ray_statistics_visible() {
+                let stats = &self.ray_statistics;
+                let text = format!(
+                    "Rays {:.2}M  Shadow {:.2}M  Steps {:.1}  Reprojected {:.0}%",
+                    stats.get_rays_per_frame() / 1_000_000.0,
+                    stats.get_shadow_rays_per_frame() / 1_000_000.0,
+                    stats.get_average_steps(),
+                    stats.get_reprojection_rate() * 100.0
+                );
+                self.text.draw_text((8, line), 2, [255, 255, 255, 255], &text);
+                line += 20;
+            }
+            if game.are_latency_markers_visible() {
+                let text = format!(
+                    "Update to submit {:.1}ms  Update to GPU done {:.1}ms",
+                    self.latency.get_submit_latency(),
+                    self.latency.get_completion_latency()
+                );
+                self.text.draw_text((8, line), 2, [255, 255, 255, 255], &text);
+            }
+        }
+
+        let glyphs = self.text.borrow_glyphs();
+        let mut buffer_content = self.render_data.text_uniform_data_buffer.bind_all();
+        let text_data = &mut buffer_content[0];
+        text_data.glyph_count = glyphs.len() as u32;
+        text_data.glyphs[..glyphs.len()].copy_from_slice(glyphs);
+        drop(buffer_content);
+        self.text.clear();
+    }
+
+    /// Starts a frame by acquiring a swapchain image, waiting for the previous frame to finish
+    /// and uploading whatever changed in the world. The camera is only read by finish_frame, so
+    /// the game should be updated in between to render it with the freshest input possible.
+    /// Returns false if no image could be acquired, in which case the frame is skipped.
+    pub fn begin_frame(&mut self, game: &mut Game) -> bool {
+        if self.swapchain_outdated && !self.recreate_swapchain() {
+            // There is nothing to render to while the window is minimized.
+            return false;
+        }
+        let acquired = unsafe {
+            let swapchain = self.core.borrow_swapchain();
+            swapchain.swapchain_loader.acquire_next_image(
+                swapchain.swapchain,
+                std::u64::MAX,
+                self.frame_available_semaphore,
+                vk::Fence::null(),
+            )
+        };
+        let image_index = match acquired {
+            // No image was acquired, so the frame is skipped.
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                self.swapchain_outdated = true;
+                return false;
+            }
+            result => {
+                let (image_index, is_suboptimal) =
+                    result.expect("Failed to acquire next swapchain image.");
+                // The image can still be presented, the swapchain is replaced after this frame.
+                self.swapchain_outdated |= is_suboptimal;
+                image_index
+            }
         };
 
         unsafe {
             let wait_fence = self.frame_complete_fence;
-            self.core
+            let result = self
+                .core
                 .device
-                .wait_for_fences(&[wait_fence], true, std::u64::MAX)
+                .wait_for_fences(&[wait_fence], true, std::u64::MAX);
+            self.report_device_lost(result)
                 .expect("Failed to wait for previous frame to finish rendering.");
             self.core
                 .device
                 .reset_fences(&[wait_fence])
                 .expect("Failed to reset fence.");
         }
+        // The previous frame has finished rendering now that the fence has been signaled.
+        self.latency.on_completed(Instant::now());
+        self.core.on_frame_completed();
+        if let (Some(timer), Some(last_image_index)) = (&mut self.gpu_timer, self.last_image_index)
+        {
+            timer.collect(last_image_index);
+        }
+        if self.last_image_index.is_some() {
+            self.report_invalid_lighting(game);
+            if self.is_measuring_image_statistics() {
+                let mut raw = self.render_data.image_statistics.bind_all();
+                self.image_statistics = ImageStatistics::from_raw(raw.as_slice_mut());
+            }
+            if let (Some(white_balance), Some(statistics)) =
+                (&mut self.white_balance, &self.image_statistics)
+            {
+                white_balance.update(statistics);
+            }
+            let mut raw = self.render_data.ray_statistics.bind_all();
+            self.ray_statistics = RayStatistics::from_raw(raw.as_slice_mut());
+            drop(raw);
+            self.total_ray_statistics.add(&self.ray_statistics);
+            let mut access_mask = self.render_data.chunk_access_mask.bind_all();
+            self.tum
+                .prioritize_seen_chunks(game.borrow_world_mut(), access_mask.as_slice_mut());
+        }
+        self.last_image_index = Some(image_index);
+        // Wait for the terrain around the camera so that none of it is missing from the shot.
+        let loading = self.tum.is_busy() || game.borrow_world().is_generating();
+        if self.beauty_shot.is_none() && self.panorama.is_none() && !loading {
+            if let Some(request) = game.take_beauty_shot_request() {
+                self.start_beauty_shot(request);
+                if self.beauty_shot.is_none() {
+                    game.on_beauty_shot_done(false);
+                }
+            } else if let Some(request) = game.take_panorama_request() {
+                let heading = game.borrow_render_camera().heading;
+                self.start_panorama(request, heading);
+            }
+        }
+        // No command buffers are in use after waiting for the fence, so they can be re-recorded.
+        let schedule_changed = game.borrow_denoise_schedule() != &self.denoise_schedule;
+        let toggles_changed = game.get_stage_toggles() != self.stage_toggles;
+        let comparison = self.comparison_schedule.as_ref();
+        let comparison_changed = game.borrow_comparison_schedule() != comparison;
+        // Only showing or hiding the view changes the commands, its camera is in a uniform.
+        let pip_changed = game.get_pip_camera().is_some() != self.pip_camera.is_some();
+        self.pip_camera = game.get_pip_camera();
+        if schedule_changed || toggles_changed || comparison_changed || pip_changed {
+            self.denoise_schedule = game.borrow_denoise_schedule().clone();
+            self.stage_toggles = game.get_stage_toggles();
+            self.comparison_schedule = game.borrow_comparison_schedule().cloned();
+            self.record_command_buffers();
+        }
+
+        self.update_minimap(game);
+        self.update_distant_terrain(game);
+        self.update_atmosphere(game);
+        self.render_data.update_warm_cache(game.borrow_world_mut());
 
-        let camera = game.borrow_camera();
+        let camera = game.borrow_render_camera();
+        self.region_offset = rebase_region_offset(self.region_offset, camera.origin);
         self.tum.request_move_towards((
             camera.origin.x as isize,
             0,
@@ -180,43 +1522,130 @@ impl Pipeline {
 
         let mut upload_commands = CommandBuffer::create_single(Rc::clone(&self.core));
         upload_commands.begin_one_time_submit();
-        self.tum.setup_next_request(
+        let bytes_uploaded = self.tum.get_bytes_uploaded();
+        let fill_count = self.tum.record_upload(
             &mut upload_commands,
             game.borrow_world_mut(),
-            &self.render_data,
+            self.render_data.terrain_images(),
+            &mut self.render_data.terrain_fills,
         );
+        if fill_count > 0 {
+            let layout = self.stages.terrain_fill.pipeline_layout;
+            let set = self.descriptor_collection.terrain_fill.variants[0];
+            upload_commands.bind_descriptor_set(layout, 0, set);
+            upload_commands.bind_pipeline(self.stages.terrain_fill.vk_pipeline);
+            upload_commands.dispatch(fill_count, 1, 1);
+        }
         upload_commands.end();
         upload_commands.blocking_execute_and_destroy();
+        if fill_count > 0 || self.tum.get_bytes_uploaded() != bytes_uploaded {
+            // The terrain changed, so the frames accumulated so far show something else.
+            self.accumulation.reset();
+        }
+        let tum = &mut self.tum;
+        let occupancy = &mut self.render_data.chunk_occupancy;
+        occupancy.with_mapped(|occupancy| tum.write_chunk_occupancy(occupancy));
+        self.acquired_image_index = Some(image_index);
+        true
+    }
+
+    /// Should be called right after the game is updated, see LatencyMarkers.
+    pub fn on_game_updated(&mut self) {
+        self.latency.on_game_updated(Instant::now());
+    }
 
-        let camera = game.borrow_camera();
-        let util::TripleEulerVector { forward, up, right } =
-            util::compute_triple_euler_vector(camera.heading, camera.pitch);
+    /// Latches the camera and everything else in the uniforms, then submits and presents the
+    /// frame started by begin_frame. Does nothing if begin_frame skipped the frame.
+    pub fn finish_frame(&mut self, game: &mut Game) {
+        let image_index = match self.acquired_image_index.take() {
+            Some(image_index) => image_index,
+            None => return,
+        };
+        let recapture = game.take_probe_recapture();
+        let camera = game.borrow_render_camera();
+        // Each face of a panorama covers 90 degrees, a narrower view is used otherwise.
+        let (vectors, view_scale) = match &self.panorama {
+            Some(panorama) => (panorama.get_face_vectors(), 1.0),
+            None => {
+                let vectors = util::compute_triple_euler_vector(camera.heading, camera.pitch);
+                (vectors, VIEW_SCALE)
+            }
+        };
+        let util::TripleEulerVector { forward, up, right } = vectors;
 
+        let region_offset = self.region_offset;
         let uniform_data = &mut self.render_data.raytrace_uniform_data;
-        uniform_data.origin = camera.origin;
+        uniform_data.region_offset = (
+            region_offset.0 as i32,
+            region_offset.1 as i32,
+            region_offset.2 as i32,
+        )
+            .into();
+        uniform_data.origin = util::world_to_local(camera.origin, region_offset);
+        // The region offset might have changed since last frame.
+        uniform_data.old_origin = util::world_to_local(self.old_camera_origin, region_offset);
         uniform_data.forward = forward;
-        uniform_data.up = up * 0.4;
-        uniform_data.right = right * 0.4;
-        // Modulus to prevent overflowing the seed.
-        uniform_data.seed = (uniform_data.seed + 1) % BLUE_NOISE_SIZE as u32;
-        uniform_data.sun_angle = game.get_sun_angle();
+        uniform_data.up = up * view_scale;
+        uniform_data.right = right * view_scale;
+        // The shaders hash this with each pixel to seed their random values.
+        uniform_data.frame_index = uniform_data.frame_index.wrapping_add(1);
+        let lighting = game.borrow_lighting();
+        uniform_data.sun_angle = lighting.sun_angle;
+        uniform_data.sun_intensity = lighting.sun_intensity;
+        uniform_data.fog_density = lighting.fog_density;
+        uniform_data.overcast = lighting.sky.to_uniform();
+        uniform_data.emitter_colors = emission::emitter_colors(game.get_game_time());
+        uniform_data.palette = game.get_palette().to_uniform();
+        uniform_data.probe_count = game.borrow_probes().len() as u32;
+        let probes = game.borrow_probes().iter();
+        for (slot, probe) in uniform_data.probe_positions.iter_mut().zip(probes) {
+            *slot = util::world_to_local(*probe, region_offset).extend(0.0);
+        }
+        uniform_data.reflection_ray_budget = game.get_reflection_ray_budget();
+
+        let center = world_mapping::region_center(self.tum.get_render_offset(), region_offset);
+        let center = (center.0 as i32, center.1 as i32, center.2 as i32).into();
+        uniform_data.rotation = center;
+        uniform_data.space_offset = center;
 
-        let off = self.tum.get_render_offset();
-        let off = (off.0 as i32, off.1 as i32, off.2 as i32).into();
-        uniform_data.rotation = off;
-        uniform_data.space_offset = off;
+        if let Some(selection) = game.borrow_selection() {
+            let block = selection.block.sub(region_offset);
+            uniform_data.selected_block = (block.0 as i32, block.1 as i32, block.2 as i32).into();
+            uniform_data.has_selection = 1;
+        } else {
+            uniform_data.has_selection = 0;
+        }
+        uniform_data.debug_view = game.get_debug_view().to_index();
 
         let mut buffer_content = self.render_data.raytrace_uniform_data_buffer.bind_all();
         buffer_content[0] = uniform_data.clone();
         drop(buffer_content);
+        if let Some(pip_camera) = self.pip_camera {
+            let (origin, vectors) = pip_camera.get_view(camera.origin, camera.heading);
+            let mut buffer_content = self.render_data.pip.uniform_data_buffer.bind_all();
+            buffer_content[0] = uniform_data.clone();
+            buffer_content[0].origin = util::world_to_local(origin, region_offset);
+            buffer_content[0].forward = vectors.forward;
+            buffer_content[0].up = vectors.up * view_scale;
+            buffer_content[0].right = vectors.right * view_scale;
+            drop(buffer_content);
+        }
+        if recapture || game.borrow_probes() != &self.captured_probes[..] {
+            self.captured_probes = game.borrow_probes().to_vec();
+            self.capture_probes();
+        }
+
+        self.update_temporal_data(game);
+        self.update_overlay_data(game);
+        self.update_text_data(game);
 
         // Do this after we set the buffer so that it will only affect the next frame.
+        self.old_camera_origin = camera.origin;
         let uniform_data = &mut self.render_data.raytrace_uniform_data;
-        uniform_data.old_origin = uniform_data.origin;
         let current_transform_matrix = {
             // Multiplying {screenx * depth, screeny * depth, depth} by this gets pixel position in world space.
             let screen_to_world_space =
-                Matrix3::from_cols(right.clone() * 0.4, up.clone() * 0.4, forward.clone());
+                Matrix3::from_cols(right * view_scale, up * view_scale, forward);
             // Inverting it gives us world space to screen space.
             screen_to_world_space
                 .invert()
@@ -226,16 +1655,53 @@ impl Pipeline {
         uniform_data.old_transform_c1 = current_transform_matrix[1].clone();
         uniform_data.old_transform_c2 = current_transform_matrix[2].clone();
 
+        let wait_semaphores = [self.frame_available_semaphore];
+        let signal_semaphores = [self.frame_complete_semaphore];
+        let wait_stage_mask = [vk::PipelineStageFlags::ALL_COMMANDS];
+        let submit_info = vk::SubmitInfo {
+            wait_semaphore_count: 1,
+            p_wait_semaphores: wait_semaphores.as_ptr(),
+            p_wait_dst_stage_mask: wait_stage_mask.as_ptr(),
+            command_buffer_count: 1,
+            p_command_buffers: &self.command_buffers[image_index as usize].get_vk_command_buffer(),
+            signal_semaphore_count: 1,
+            p_signal_semaphores: signal_semaphores.as_ptr(),
+            ..Default::default()
+        };
+
         unsafe {
             let wait_fence = self.frame_complete_fence;
-            self.core
+            let queue = self.core.compute_queue;
+            let result = self
+                .core
                 .device
-                .queue_submit(self.core.compute_queue, &[submit_info], wait_fence)
+                .queue_submit(queue, &[submit_info], wait_fence);
+            self.report_device_lost(result)
                 .expect("Failed to submit command queue.");
         }
+        self.latency.on_submitted(Instant::now());
+        self.core.on_frame_submitted();
+        let shot_finished = match &mut self.beauty_shot {
+            Some(shot) => shot.advance(),
+            None => false,
+        };
+        if shot_finished {
+            self.finish_beauty_shot(image_index);
+            game.on_beauty_shot_done(true);
+        }
+        let face_finished = match &mut self.panorama {
+            Some(panorama) => panorama.advance(),
+            None => false,
+        };
+        if face_finished {
+            self.finish_panorama_face(image_index);
+        }
+        if game.take_screenshot_request() {
+            self.save_screenshot(image_index);
+        }
 
         let wait_semaphores = [self.frame_complete_semaphore];
-        let swapchains = [self.core.swapchain.swapchain];
+        let swapchains = [self.core.borrow_swapchain().swapchain];
         let present_info = vk::PresentInfoKHR {
             wait_semaphore_count: 1,
             p_wait_semaphores: wait_semaphores.as_ptr(),
@@ -245,16 +1711,330 @@ impl Pipeline {
             ..Default::default()
         };
 
-        unsafe {
+        let presented = unsafe {
             self.core
-                .swapchain
+                .borrow_swapchain()
                 .swapchain_loader
                 .queue_present(self.core.present_queue, &present_info)
-                .expect("Failed to present swapchain image.");
+        };
+        match presented {
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.swapchain_outdated = true,
+            result => {
+                let is_suboptimal = result.expect("Failed to present swapchain image.");
+                self.swapchain_outdated |= is_suboptimal;
+            }
         }
     }
 }
 
+impl Pipeline {
+    /// Makes the next frame recreate the swapchain at the new size of the window.
+    pub fn on_window_resized(&mut self) {
+        self.swapchain_outdated = true;
+    }
+
+    /// Replaces the swapchain and everything which depends on its size or number of images.
+    /// Returns false if it can't be replaced yet, such as while the window is minimized.
+    fn recreate_swapchain(&mut self) -> bool {
+        if !self.core.recreate_swapchain() {
+            return false;
+        }
+        self.swapchain_outdated = false;
+        let swapchain = self.core.borrow_swapchain();
+        let swapchain_length = swapchain.swapchain_images.len() as u32;
+        drop(swapchain);
+        // Drivers may hand out a different number of images than before.
+        if swapchain_length as usize != self.command_buffers.len() {
+            self.command_buffers =
+                CommandBuffer::create_multiple(self.core.clone(), swapchain_length);
+            self.gpu_timer = GpuTimer::new(self.core.clone(), swapchain_length);
+            // The queries of the last frame are gone along with the old timer.
+            self.last_image_index = None;
+        }
+        if self.panorama.take().is_some() {
+            println!("WARNING: The window was resized, so the panorama was abandoned.");
+        }
+        let swapchain_extent = self.core.borrow_swapchain().swapchain_extent;
+        let limits = self.core.get_physical_device_limits();
+        let settings = &mut self.render_data.settings;
+        if let Some(downgrade) = settings.fit_ray_queues(swapchain_extent, &limits) {
+            println!("WARNING: {}", downgrade);
+        }
+        self.recreate_framebuffers();
+        true
+    }
+
+    /// Replaces every image whose size depends on the swapchain or the render scale, along with
+    /// the descriptor sets and command buffers which use them. The device must not be using any
+    /// of them anymore.
+    fn recreate_framebuffers(&mut self) {
+        self.render_data.recreate_framebuffers();
+        let swapchain_extent = self.core.borrow_swapchain().swapchain_extent;
+        let (x_output_groups, y_output_groups) = count_shader_groups(swapchain_extent);
+        self.x_output_groups = x_output_groups;
+        self.y_output_groups = y_output_groups;
+        let (x_shader_groups, y_shader_groups) = count_shader_groups(self.render_data.extent);
+        self.x_shader_groups = x_shader_groups;
+        self.y_shader_groups = y_shader_groups;
+        self.descriptor_collection.recreate_sets(&self.render_data);
+        self.record_command_buffers();
+    }
+
+    /// Screenshots and the like are saved as 8 bit images, so the swapchain has to be in the sdr
+    /// output format as well as allow copies.
+    fn can_copy_swapchain(&self) -> bool {
+        let swapchain = self.core.borrow_swapchain();
+        swapchain.output_format == OutputFormat::Sdr
+            && swapchain
+                .swapchain_image_usage
+                .contains(vk::ImageUsageFlags::TRANSFER_SRC)
+    }
+
+    /// Waits for the frame which was just submitted to finish rendering.
+    fn wait_for_frame(&mut self) {
+        unsafe {
+            let wait_fence = self.frame_complete_fence;
+            let result = self
+                .core
+                .device
+                .wait_for_fences(&[wait_fence], true, std::u64::MAX);
+            self.report_device_lost(result)
+                .expect("Failed to wait for frame to finish rendering.");
+        }
+    }
+
+    fn start_beauty_shot(&mut self, request: BeautyShotRequest) {
+        if !self.can_copy_swapchain() {
+            println!(
+                "WARNING: The swapchain can't be copied from, so beauty shots can't be saved."
+            );
+            return;
+        }
+        println!(
+            "Rendering a beauty shot over {} frames, keep the camera still.",
+            request.frames
+        );
+        self.beauty_shot = Some(BeautyShot::new(request));
+    }
+
+    /// Saves the frame which was just submitted and goes back to rendering interactively.
+    fn finish_beauty_shot(&mut self, image_index: u32) {
+        let shot = match self.beauty_shot.take() {
+            Some(shot) => shot,
+            None => return,
+        };
+        self.wait_for_frame();
+        // The image has not been presented yet, so it can still be copied from.
+        beauty_shot::save_swapchain_image(&self.core, image_index, shot.borrow_request());
+    }
+
+    /// Saves the frame which was just submitted, exactly as it will be presented.
+    fn save_screenshot(&mut self, image_index: u32) {
+        if !self.can_copy_swapchain() {
+            println!("WARNING: The swapchain can't be copied from, so screenshots can't be saved.");
+            return;
+        }
+        self.wait_for_frame();
+        beauty_shot::save_screenshot(&self.core, image_index);
+    }
+
+    fn start_panorama(&mut self, request: PanoramaRequest, heading: Rad<f32>) {
+        if !self.can_copy_swapchain() {
+            println!("WARNING: The swapchain can't be copied from, so panoramas can't be saved.");
+            return;
+        }
+        println!(
+            "Rendering a {} panorama over {} frames, keep the camera still.",
+            request.layout,
+            request.frames_per_face * 6
+        );
+        self.panorama = Some(Panorama::new(request, heading));
+    }
+
+    /// Keeps the face which was just submitted, saving the panorama if it was the last one.
+    fn finish_panorama_face(&mut self, image_index: u32) {
+        self.wait_for_frame();
+        let pixels = beauty_shot::read_swapchain_image(&self.core, image_index);
+        let extent = self.core.borrow_swapchain().swapchain_extent;
+        let finished = match &mut self.panorama {
+            Some(panorama) => panorama.add_face(pixels, extent.width, extent.height),
+            None => return,
+        };
+        if finished {
+            if let Some(panorama) = self.panorama.take() {
+                panorama.save();
+            }
+        }
+    }
+
+    pub fn is_taking_beauty_shot(&self) -> bool {
+        self.beauty_shot.is_some()
+    }
+
+    /// Returns the average time each stage of a frame took on the GPU in milliseconds, or an
+    /// empty list if the device does not support timing them.
+    pub fn get_gpu_stage_timings(&self) -> Vec<(&'static str, f64)> {
+        match &self.gpu_timer {
+            Some(timer) => STAGE_NAMES
+                .iter()
+                .cloned()
+                .zip(timer.get_average_timings())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn set_temporal_settings(&mut self, settings: &TemporalSettings) {
+        self.temporal_settings = settings.clone();
+        self.accumulation.set_delay(settings.accumulation_delay);
+    }
+
+    /// Changes how many pixels are rendered for each pixel of the window, see
+    /// RenderSettings::render_scale. Waits for the frame in flight to finish if anything has to
+    /// be recreated.
+    pub fn set_render_scale(&mut self, render_scale: f32) {
+        if render_scale == self.render_data.settings.render_scale {
+            return;
+        }
+        self.render_data.settings.render_scale = render_scale;
+        self.wait_for_frame();
+        self.recreate_framebuffers();
+    }
+
+    /// Switches the lighting buffers and the stages which use them to another format. Unlike
+    /// building a new pipeline, the world does not have to be uploaded again. The next frame must
+    /// not have started rendering yet.
+    pub fn set_lighting_format(&mut self, format: LightingFormat) {
+        let format = get_supported_lighting_format(&self.core, format);
+        if format == self.render_data.settings.lighting_format {
+            return;
+        }
+        self.wait_for_frame();
+        self.render_data.set_lighting_format(format);
+        let output = self.core.borrow_swapchain().output_format;
+        self.stages = Stages::create(&self.core, &self.descriptor_collection, format, output);
+        self.descriptor_collection.recreate_sets(&self.render_data);
+        self.record_command_buffers();
+    }
+
+    pub fn set_distant_terrain(&mut self, enabled: bool) {
+        self.render_data.raytrace_uniform_data.distant_terrain = enabled as u32;
+    }
+
+    /// Turns auto white balance on or off, which re-records the command buffers if the image
+    /// statistics pass has to be added or removed. The next frame must not have started rendering
+    /// yet.
+    pub fn set_auto_white_balance(&mut self, enabled: bool) {
+        if enabled == self.white_balance.is_some() {
+            return;
+        }
+        let was_measuring = self.is_measuring_image_statistics();
+        self.white_balance = if enabled {
+            Some(WhiteBalance::new())
+        } else {
+            None
+        };
+        self.on_image_statistics_toggled(was_measuring);
+    }
+
+    /// Makes the image statistics pass run every frame even if auto white balance is off, so that
+    /// get_image_statistics has something to return. The next frame must not have started
+    /// rendering yet.
+    pub fn set_measure_image_statistics(&mut self, enabled: bool) {
+        let was_measuring = self.is_measuring_image_statistics();
+        self.measure_image_statistics = enabled;
+        self.on_image_statistics_toggled(was_measuring);
+    }
+
+    fn on_image_statistics_toggled(&mut self, was_measuring: bool) {
+        if self.is_measuring_image_statistics() == was_measuring {
+            return;
+        }
+        self.image_statistics = None;
+        self.record_command_buffers();
+    }
+
+    /// The brightness of the light in the last frame which finished rendering. None if the image
+    /// statistics pass is off, see set_measure_image_statistics, or the frame was completely
+    /// black.
+    pub fn get_image_statistics(&self) -> Option<&ImageStatistics> {
+        self.image_statistics.as_ref()
+    }
+
+    /// Re-records the command buffers if the weight changed, so the next frame must not have
+    /// started rendering yet.
+    pub fn set_denoise_gradient_weight(&mut self, weight: f32) {
+        if weight != self.denoise_gradient_weight {
+            self.denoise_gradient_weight = weight;
+            self.record_command_buffers();
+        }
+    }
+
+    /// Re-records the command buffers if the weight changed, so the next frame must not have
+    /// started rendering yet.
+    pub fn set_denoise_variance_weight(&mut self, weight: f32) {
+        if weight != self.denoise_variance_weight {
+            self.denoise_variance_weight = weight;
+            self.record_command_buffers();
+        }
+    }
+
+    /// Takes the radius in degrees.
+    pub fn set_sun_angular_radius(&mut self, degrees: f32) {
+        self.render_data.raytrace_uniform_data.sun_angular_radius = degrees.to_radians();
+    }
+
+    /// Fills this pipeline's temporal history with the history of one it replaces, stretching it
+    /// if the two render at different resolutions. This avoids the noisy frames the temporal
+    /// stage would produce while building up history from nothing. Does nothing if the device
+    /// can't blit between the lighting formats.
+    pub fn resample_history_from(&mut self, old: &Pipeline) {
+        let old_format = old.render_data.settings.lighting_format;
+        if !self.render_data.can_resample_history(old_format) {
+            println!("WARNING: Temporal history can't be resampled, so it will be rebuilt.");
+            return;
+        }
+        unsafe {
+            self.core
+                .device
+                .device_wait_idle()
+                .expect("Failed to wait for device to finish rendering.");
+        }
+        let commands = CommandBuffer::create_single(self.core.clone());
+        commands.begin_one_time_submit();
+        let old_history = old.render_data.get_history();
+        self.render_data.record_history_resample(&commands, old_history);
+        commands.end();
+        commands.blocking_execute_and_destroy();
+    }
+
+    /// What the GPU counted while tracing every frame rendered so far, for the performance report.
+    pub fn get_total_ray_statistics(&self) -> &RayStatistics {
+        &self.total_ray_statistics
+    }
+
+    /// Total size of the terrain data streamed to the GPU after startup, in bytes.
+    pub fn get_bytes_uploaded(&self) -> u64 {
+        self.tum.get_bytes_uploaded()
+    }
+
+    /// Describes what terrain is loaded and waiting to be uploaded, for crash reports.
+    pub fn describe_streaming(&self) -> String {
+        let (start, end) = self.tum.get_loaded_block_range();
+        let queued = self.tum.get_queued_slices();
+        let oldest = queued.iter().map(|(_, age)| *age).max().unwrap_or(0);
+        format!(
+            "loaded_blocks = {:?} to {:?}\nqueued_slices = {}\noldest_slice_age = {}\n\
+            bytes_uploaded = {}\n",
+            start,
+            end,
+            queued.len(),
+            oldest,
+            self.tum.get_bytes_uploaded()
+        )
+    }
+}
+
 impl Drop for Pipeline {
     fn drop(&mut self) {
         unsafe {
@@ -0,0 +1,99 @@
+/// How many counters the ray statistics buffer holds: rays cast, traversal steps taken by those
+/// rays, shadow rays cast, pixels the temporal stage tried to reproject and pixels it reprojected
+/// successfully. Must match traverse.comp and temporal.comp.
+pub const RAY_STATISTICS_LEN: usize = 5;
+
+/// What the GPU counted while tracing one or more frames, read back from the ray statistics
+/// buffer. The counters are 32 bits on the GPU, which is plenty for a single frame, so totals are
+/// summed on the CPU.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RayStatistics {
+    pub frames: u64,
+    pub rays_cast: u64,
+    pub traversal_steps: u64,
+    pub shadow_rays: u64,
+    pub reprojection_tested: u64,
+    pub reprojection_accepted: u64,
+}
+
+impl RayStatistics {
+    /// The statistics of a single frame.
+    pub fn from_raw(values: &[u32]) -> Self {
+        assert_eq!(values.len(), RAY_STATISTICS_LEN);
+        Self {
+            frames: 1,
+            rays_cast: values[0] as u64,
+            traversal_steps: values[1] as u64,
+            shadow_rays: values[2] as u64,
+            reprojection_tested: values[3] as u64,
+            reprojection_accepted: values[4] as u64,
+        }
+    }
+
+    pub fn add(&mut self, other: &RayStatistics) {
+        self.frames += other.frames;
+        self.rays_cast += other.rays_cast;
+        self.traversal_steps += other.traversal_steps;
+        self.shadow_rays += other.shadow_rays;
+        self.reprojection_tested += other.reprojection_tested;
+        self.reprojection_accepted += other.reprojection_accepted;
+    }
+
+    fn per_frame(&self, value: u64) -> f64 {
+        value as f64 / self.frames.max(1) as f64
+    }
+
+    pub fn get_rays_per_frame(&self) -> f64 {
+        self.per_frame(self.rays_cast)
+    }
+
+    pub fn get_shadow_rays_per_frame(&self) -> f64 {
+        self.per_frame(self.shadow_rays)
+    }
+
+    /// How many steps through the minefield each ray took on average, not counting rays which
+    /// left the loaded region and fell back to the distant terrain.
+    pub fn get_average_steps(&self) -> f64 {
+        self.traversal_steps as f64 / self.rays_cast.max(1) as f64
+    }
+
+    /// The fraction of pixels which are not sky that the temporal stage found history for.
+    pub fn get_reprojection_rate(&self) -> f64 {
+        self.reprojection_accepted as f64 / self.reprojection_tested.max(1) as f64
+    }
+
+    /// The averages which go in the performance report.
+    pub fn get_report_values(&self) -> Vec<(&'static str, f64)> {
+        vec![
+            ("rays_per_frame", self.get_rays_per_frame()),
+            ("shadow_rays_per_frame", self.get_shadow_rays_per_frame()),
+            ("average_steps", self.get_average_steps()),
+            ("reprojection_rate", self.get_reprojection_rate()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_averaged() {
+        let mut total = RayStatistics::default();
+        total.add(&RayStatistics::from_raw(&[1000, 20000, 300, 80, 60]));
+        total.add(&RayStatistics::from_raw(&[3000, 20000, 100, 120, 100]));
+        assert_eq!(total.frames, 2);
+        assert_eq!(total.get_rays_per_frame(), 2000.0);
+        assert_eq!(total.get_shadow_rays_per_frame(), 200.0);
+        assert_eq!(total.get_average_steps(), 10.0);
+        assert_eq!(total.get_reprojection_rate(), 0.8);
+    }
+
+    #[test]
+    fn empty_statistics_are_zero() {
+        let empty = RayStatistics::default();
+        for (_, value) in empty.get_report_values() {
+            assert_eq!(value, 0.0);
+        }
+    }
+}
@@ -1,42 +1,192 @@
-use super::structs::RaytraceUniformData;
+use super::ray_statistics::RAY_STATISTICS_LEN;
+use super::structs::{
+    OverlayUniformData, RaytraceUniformData, TemporalUniformData, TerrainFill, TextUniformData,
+    WorkListHeader,
+};
+use super::terrain_upload::{self, TerrainImages};
 use crate::game::Game;
 use crate::render::constants::*;
 use crate::render::general::command_buffer::CommandBuffer;
 use crate::render::general::core::Core;
+use crate::render::general::image_statistics::STATISTICS_BUFFER_LEN;
 use crate::render::general::structures::{
     Buffer, BufferWrapper, DataDestination, ExtentWrapper, ImageOptions, ImageWrapper,
     SampledImage, SamplerOptions, StorageImage,
 };
+use crate::render::text::{self, ATLAS_HEIGHT, ATLAS_WIDTH};
+use crate::render::{emission, LightingFormat, Palette, RenderSettings};
 use crate::util::{self, prelude::*};
-use crate::world::ChunkStorage;
+use crate::world::{ChunkStorage, WarmCache, WarmCacheKey};
 use ash::vk;
 use std::rc::Rc;
 
+/// A smaller copy of the framebuffers the raytrace, denoise and finalize stages write to, which
+/// the picture-in-picture view is rendered with. See Pipeline::record_pip.
+pub struct PipBuffers {
+    pub extent: vk::Extent2D,
+    pub lighting_buffer: StorageImage,
+    pub lighting_pong_buffer: StorageImage,
+    pub albedo_buffer: StorageImage,
+    pub emission_buffer: StorageImage,
+    // Smaller than the other buffers, see RenderData::fog_color_buffer.
+    pub fog_color_buffer: StorageImage,
+    pub depth_buffer: StorageImage,
+    pub normal_buffer: StorageImage,
+    pub motion_buffer: StorageImage,
+    pub smooth_normal_buffer: StorageImage,
+    pub reflection_buffer: StorageImage,
+    // Estimated from the lighting alone, since this view has no moments.
+    pub variance_buffer: StorageImage,
+    pub variance_pong_buffer: StorageImage,
+    // Written by the finalize stage instead of the swapchain, then drawn in a corner of the
+    // screen by the overlay stage.
+    pub output: StorageImage,
+    // The same as raytrace_uniform_data apart from the camera.
+    pub uniform_data_buffer: Buffer<RaytraceUniformData>,
+}
+
+impl PipBuffers {
+    fn get_images(&self) -> [&StorageImage; 13] {
+        [
+            &self.lighting_buffer,
+            &self.lighting_pong_buffer,
+            &self.albedo_buffer,
+            &self.emission_buffer,
+            &self.fog_color_buffer,
+            &self.depth_buffer,
+            &self.normal_buffer,
+            &self.motion_buffer,
+            &self.smooth_normal_buffer,
+            &self.reflection_buffer,
+            &self.variance_buffer,
+            &self.variance_pong_buffer,
+            &self.output,
+        ]
+    }
+}
+
 pub struct RenderData {
     pub core: Rc<Core>,
+    pub settings: RenderSettings,
+    // How large the images the main view is rendered into are, which the finalize stage scales to
+    // fit the swapchain. See RenderSettings::get_render_extent.
+    pub extent: vk::Extent2D,
 
     pub material_image: SampledImage,
     pub minefield_image: SampledImage,
+    // One texel for each column of the world images, see sun_heightmap.comp.
+    pub sun_heightmap: StorageImage,
+    // One texel for every few blocks of the world images, see light_volume.comp.
+    pub light_volume: StorageImage,
+    // Six layers for each reflection probe, one for each face of its cubemap. See
+    // probe_capture.comp.
+    pub reflection_probes: StorageImage,
 
     pub lighting_buffer: StorageImage,
     pub completed_buffer: StorageImage,
     pub depth_buffer: StorageImage,
     pub normal_buffer: StorageImage,
+    // Where the surface seen through each pixel was last frame, written by the shading kernel.
+    pub motion_buffer: StorageImage,
+    // Only written when smooth normals are enabled, see smooth_normal in raytrace_common.glsl.
+    pub smooth_normal_buffer: StorageImage,
+    // Luminance moments and how many frames they were accumulated over, written by the temporal
+    // stage. See estimate_variance.comp.
+    pub moments_buffer: StorageImage,
+    // How noisy the lighting is, which the denoiser ping-pongs between along with the lighting
+    // buffers.
+    pub variance_buffer: StorageImage,
+    pub variance_pong_buffer: StorageImage,
+    // The sum of every frame since the camera stopped moving, see Accumulation. Full precision
+    // floats so that thousands of frames can be summed without losing the small ones.
+    pub accumulation_buffer: StorageImage,
 
     pub lighting_pong_buffer: StorageImage,
+    // Rendered to instead of the swapchain images when they can't be used as storage images, then
+    // blitted to them at the end of each frame. See SwapChainInfo::is_storage. Always used with
+    // output formats other than sdr, in which case it holds 16 bit floats.
+    pub output_image: Option<StorageImage>,
+    // Lighting denoised with the comparison schedule, see Pipeline::record_comparison.
+    pub comparison_buffer: StorageImage,
     pub albedo_buffer: StorageImage,
     pub emission_buffer: StorageImage,
+    // The color of the sky behind each pixel, which terrain fades into with distance, with the
+    // fog density in alpha. It is FOG_SCALE times narrower and shorter than the other buffers,
+    // see fog.comp.
     pub fog_color_buffer: StorageImage,
+    // Reflections get their own denoiser, which ping-pongs between these two.
+    pub reflection_buffer: StorageImage,
+    pub reflection_pong_buffer: StorageImage,
+    // A WorkListHeader followed by every pixel that has a reflection.
+    pub reflection_work_list: Buffer<u32>,
+
+    // The raytrace stage alternates between these, each is a WorkListHeader followed by rays.
+    pub ray_queue: Buffer<u32>,
+    pub ray_pong_queue: Buffer<u32>,
+    // Light found by the raytrace stage, stored in fixed point so that it can be summed with
+    // atomics.
+    pub light_accumulators: Buffer<u32>,
+    // One value for each chunk of the world images, set by the traversal kernel for chunks the
+    // camera sees and read back once each frame is done.
+    pub chunk_access_mask: Buffer<u32>,
+    // Counters filled in by the traversal and temporal kernels, read back once each frame is
+    // done. See RayStatistics.
+    pub ray_statistics: Buffer<u32>,
+    // Whether each chunk of the world images is empty, solid or neither, see
+    // TerrainUploadManager::write_chunk_occupancy.
+    pub chunk_occupancy: Buffer<u32>,
+    // Regions of the world images for terrain_fill.comp to fill in, written by the CPU before
+    // each upload. See TerrainUploadManager::take_fills.
+    pub terrain_fills: Buffer<TerrainFill>,
+    // A coarse heightmap that rays fall back to once they leave the loaded region, see
+    // map::build_distant_terrain.
+    pub distant_terrain: SampledImage,
+    // The fog and color grading of the biomes around the camera, see
+    // atmosphere::build_atmosphere_volume.
+    pub atmosphere_fog: SampledImage,
+    pub atmosphere_grading: SampledImage,
+
+    // What the lighting, depth and normal buffers contained last frame, before denoising.
+    pub history_lighting_buffer: StorageImage,
+    pub history_depth_buffer: StorageImage,
+    pub history_normal_buffer: StorageImage,
+    // What the moments buffer contained last frame, cleared to zero when there is none.
+    pub history_moments_buffer: StorageImage,
+    // What the fog color buffer contained last frame, cleared to zero alpha when there is none.
+    pub history_fog_color_buffer: StorageImage,
 
     pub blue_noise: SampledImage,
 
     pub raytrace_uniform_data: RaytraceUniformData,
     pub raytrace_uniform_data_buffer: Buffer<RaytraceUniformData>,
+
+    pub temporal_uniform_data_buffer: Buffer<TemporalUniformData>,
+    // How many pixels had NaN or infinite lighting, read back once each frame is done.
+    pub invalid_lighting_count: Buffer<u32>,
+    // How bright and what color the light was, read back once each frame is done. See
+    // image_statistics.comp.
+    pub image_statistics: Buffer<u32>,
+
+    pub overlay_uniform_data: OverlayUniformData,
+    pub overlay_uniform_data_buffer: Buffer<OverlayUniformData>,
+    pub minimap: SampledImage,
+
+    pub font_atlas: SampledImage,
+    pub text_uniform_data_buffer: Buffer<TextUniformData>,
+
+    pub pip: PipBuffers,
+
+    // Whether update_warm_cache still needs to save the warm cache.
+    warm_cache_pending: bool,
 }
 
 impl RenderData {
-    fn create_framebuffer(core: Rc<Core>, name: &str, format: vk::Format) -> StorageImage {
-        let dimensions = core.swapchain.swapchain_extent;
+    fn create_framebuffer(
+        core: Rc<Core>,
+        name: &str,
+        format: vk::Format,
+        dimensions: vk::Extent2D,
+    ) -> StorageImage {
         let options = ImageOptions {
             typ: vk::ImageType::TYPE_2D,
             extent: vk::Extent3D {
@@ -45,22 +195,136 @@ impl RenderData {
                 depth: 1,
             },
             format,
-            usage: vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::STORAGE,
+            usage: vk::ImageUsageFlags::TRANSFER_SRC
+                | vk::ImageUsageFlags::TRANSFER_DST
+                | vk::ImageUsageFlags::STORAGE,
             ..Default::default()
         };
         StorageImage::create(core, name, &options)
     }
 
-    fn create_material_image(core: Rc<Core>) -> SampledImage {
+    /// How large the fog color buffer of a view with the given extent is.
+    pub fn get_fog_extent(view: vk::Extent2D) -> vk::Extent2D {
+        vk::Extent2D {
+            width: view.width.div_ceil(FOG_SCALE),
+            height: view.height.div_ceil(FOG_SCALE),
+        }
+    }
+
+    fn create_fog_buffer(core: Rc<Core>, name: &str, view: vk::Extent2D) -> StorageImage {
+        let format = vk::Format::R16G16B16A16_SFLOAT;
+        Self::create_framebuffer(core, name, format, Self::get_fog_extent(view))
+    }
+
+    /// The format finalize, overlay and text write, which must match OUTPUT_FORMAT in their
+    /// shaders. See OutputFormat::uses_float_output.
+    fn get_output_format(core: &Core) -> vk::Format {
+        if core.borrow_swapchain().output_format.uses_float_output() {
+            vk::Format::R16G16B16A16_SFLOAT
+        } else {
+            vk::Format::R8G8B8A8_UNORM
+        }
+    }
+
+    fn create_output_image(core: Rc<Core>) -> Option<StorageImage> {
+        let swapchain = core.borrow_swapchain();
+        if swapchain.is_storage() {
+            return None;
+        }
+        let (format, extent) = (Self::get_output_format(&core), swapchain.swapchain_extent);
+        drop(swapchain);
+        Some(Self::create_framebuffer(
+            core,
+            "output_image",
+            format,
+            extent,
+        ))
+    }
+
+    fn create_pip_buffers(core: Rc<Core>, lighting: vk::Format) -> PipBuffers {
+        let screen = core.borrow_swapchain().swapchain_extent;
+        let extent = vk::Extent2D {
+            width: (screen.width / PIP_SCALE).max(1),
+            height: (screen.height / PIP_SCALE).max(1),
+        };
+        let create = |name: &str, format: vk::Format| {
+            Self::create_framebuffer(core.clone(), name, format, extent)
+        };
+        let rgba8_unorm = vk::Format::R8G8B8A8_UNORM;
+        PipBuffers {
+            extent,
+            lighting_buffer: create("pip_lighting_buf", lighting),
+            lighting_pong_buffer: create("pip_lighting_pong_buf", lighting),
+            albedo_buffer: create("pip_albedo_buf", rgba8_unorm),
+            emission_buffer: create("pip_emission_buf", rgba8_unorm),
+            fog_color_buffer: Self::create_fog_buffer(core.clone(), "pip_fog_color_buf", extent),
+            depth_buffer: create("pip_depth_buf", vk::Format::R16_UINT),
+            normal_buffer: create("pip_normal_buf", vk::Format::R8_UINT),
+            motion_buffer: create("pip_motion_buf", vk::Format::R16G16B16A16_SFLOAT),
+            smooth_normal_buffer: create("pip_smooth_normal_buf", vk::Format::R8G8B8A8_SNORM),
+            reflection_buffer: create("pip_reflection_buf", vk::Format::R16G16B16A16_UNORM),
+            variance_buffer: create("pip_variance_buf", vk::Format::R16_SFLOAT),
+            variance_pong_buffer: create("pip_variance_pong_buf", vk::Format::R16_SFLOAT),
+            output: create("pip_output", Self::get_output_format(&core)),
+            uniform_data_buffer: Buffer::create(
+                core.clone(),
+                "pip_uniform_data",
+                1,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+            ),
+        }
+    }
+
+    fn create_work_list(core: Rc<Core>, name: &str, dimensions: vk::Extent2D) -> Buffer<u32> {
+        let header_size = std::mem::size_of::<WorkListHeader>() / std::mem::size_of::<u32>();
+        let num_items = header_size as u64 + dimensions.width as u64 * dimensions.height as u64;
+        Buffer::create(
+            core,
+            name,
+            num_items,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::INDIRECT_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_DST,
+        )
+    }
+
+    fn create_ray_queue(core: Rc<Core>, name: &str, dimensions: vk::Extent2D) -> Buffer<u32> {
+        let header_size = std::mem::size_of::<WorkListHeader>() / std::mem::size_of::<u32>();
+        let num_rays = dimensions.width as u64 * dimensions.height as u64 * RAYS_PER_PIXEL as u64;
+        Buffer::create_device_local(
+            core,
+            name,
+            header_size as u64 + num_rays * RAY_SIZE as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::INDIRECT_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_DST,
+        )
+    }
+
+    fn create_light_accumulators(core: Rc<Core>, dimensions: vk::Extent2D) -> Buffer<u32> {
+        let num_pixels = dimensions.width as u64 * dimensions.height as u64;
+        Buffer::create_device_local(
+            core,
+            "light_accumulators",
+            num_pixels * ACCUMULATORS_PER_PIXEL as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+        )
+    }
+
+    fn create_material_image(core: Rc<Core>, settings: &RenderSettings) -> SampledImage {
+        let size = settings.root_block_size() as u32;
         let image_options = ImageOptions {
             typ: vk::ImageType::TYPE_3D,
             extent: vk::Extent3D {
-                width: ROOT_BLOCK_SIZE as u32,
-                height: ROOT_BLOCK_SIZE as u32,
-                depth: ROOT_BLOCK_SIZE as u32,
+                width: size,
+                height: size,
+                depth: size,
             },
             format: vk::Format::R32_UINT,
-            usage: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            // Written to by terrain_fill.comp as well as copied to.
+            usage: vk::ImageUsageFlags::TRANSFER_DST
+                | vk::ImageUsageFlags::SAMPLED
+                | vk::ImageUsageFlags::STORAGE,
             ..Default::default()
         };
         let sampler_options = SamplerOptions {
@@ -79,23 +343,28 @@ impl RenderData {
         )
     }
 
-    fn create_minefield(core: Rc<Core>) -> SampledImage {
+    fn create_minefield(core: Rc<Core>, settings: &RenderSettings) -> SampledImage {
+        let size = settings.root_block_size() as u32;
         let image_options = ImageOptions {
             typ: vk::ImageType::TYPE_3D,
             extent: vk::Extent3D {
-                width: ROOT_BLOCK_SIZE as u32,
-                height: ROOT_BLOCK_SIZE as u32,
-                depth: ROOT_BLOCK_SIZE as u32,
+                width: size,
+                height: size,
+                depth: size,
             },
             format: vk::Format::R8_UINT,
-            usage: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            usage: vk::ImageUsageFlags::TRANSFER_DST
+                | vk::ImageUsageFlags::SAMPLED
+                | vk::ImageUsageFlags::STORAGE,
             ..Default::default()
         };
         let sampler_options = SamplerOptions {
             min_filter: vk::Filter::NEAREST,
             mag_filter: vk::Filter::NEAREST,
             address_mode: vk::SamplerAddressMode::CLAMP_TO_BORDER,
-            border_color: vk::BorderColor::INT_OPAQUE_BLACK,
+            // Lookups which round onto the border read as a step of one, meaning empty space. A
+            // step of zero would show up as solid blocks around the edge of the loaded region.
+            border_color: vk::BorderColor::INT_OPAQUE_WHITE,
             unnormalized_coordinates: true,
             ..Default::default()
         };
@@ -107,6 +376,54 @@ impl RenderData {
         )
     }
 
+    fn create_sun_heightmap(core: Rc<Core>, settings: &RenderSettings) -> StorageImage {
+        let size = settings.root_block_size() as u32;
+        let options = ImageOptions {
+            typ: vk::ImageType::TYPE_2D,
+            extent: vk::Extent3D {
+                width: size,
+                height: size,
+                depth: 1,
+            },
+            format: vk::Format::R16_UINT,
+            usage: vk::ImageUsageFlags::STORAGE,
+            ..Default::default()
+        };
+        StorageImage::create(core, "sun_heightmap", &options)
+    }
+
+    fn create_light_volume(core: Rc<Core>, settings: &RenderSettings) -> StorageImage {
+        let size = (settings.root_block_size() / LIGHT_VOLUME_CELL_SIZE) as u32;
+        let options = ImageOptions {
+            typ: vk::ImageType::TYPE_3D,
+            extent: vk::Extent3D {
+                width: size,
+                height: size,
+                depth: size,
+            },
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_DST,
+            ..Default::default()
+        };
+        StorageImage::create(core, "light_volume", &options)
+    }
+
+    fn create_reflection_probes(core: Rc<Core>) -> StorageImage {
+        let options = ImageOptions {
+            typ: vk::ImageType::TYPE_2D,
+            extent: vk::Extent3D {
+                width: PROBE_SIZE,
+                height: PROBE_SIZE,
+                depth: 1,
+            },
+            format: vk::Format::R16G16B16A16_SFLOAT,
+            usage: vk::ImageUsageFlags::STORAGE,
+            array_layers: (MAX_PROBES * 6) as u32,
+            ..Default::default()
+        };
+        StorageImage::create(core, "reflection_probes", &options)
+    }
+
     fn create_blue_noise(core: Rc<Core>) -> SampledImage {
         let image_options = ImageOptions {
             typ: vk::ImageType::TYPE_2D,
@@ -132,10 +449,103 @@ impl RenderData {
         tex
     }
 
-    fn create_raytrace_uniform_data() -> RaytraceUniformData {
+    fn create_font_atlas(core: Rc<Core>) -> SampledImage {
+        let image_options = ImageOptions {
+            typ: vk::ImageType::TYPE_2D,
+            extent: vk::Extent3D {
+                width: ATLAS_WIDTH as u32,
+                height: ATLAS_HEIGHT as u32,
+                depth: 1,
+            },
+            format: vk::Format::R8_UNORM,
+            usage: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            ..Default::default()
+        };
+        let sampler_options = SamplerOptions {
+            min_filter: vk::Filter::NEAREST,
+            mag_filter: vk::Filter::NEAREST,
+            address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            unnormalized_coordinates: true,
+            ..Default::default()
+        };
+        let tex =
+            SampledImage::create(core.clone(), "font_atlas", &image_options, &sampler_options);
+        tex.load_from_slice(&text::generate_font_atlas());
+        tex
+    }
+
+    fn create_minimap(core: Rc<Core>) -> SampledImage {
+        let image_options = ImageOptions {
+            typ: vk::ImageType::TYPE_2D,
+            extent: vk::Extent3D {
+                width: MINIMAP_SIZE as u32,
+                height: MINIMAP_SIZE as u32,
+                depth: 1,
+            },
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            ..Default::default()
+        };
+        let sampler_options = SamplerOptions {
+            min_filter: vk::Filter::NEAREST,
+            mag_filter: vk::Filter::NEAREST,
+            address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            unnormalized_coordinates: true,
+            ..Default::default()
+        };
+        SampledImage::create(core, "minimap", &image_options, &sampler_options)
+    }
+
+    fn create_distant_terrain(core: Rc<Core>) -> SampledImage {
+        let image_options = ImageOptions {
+            typ: vk::ImageType::TYPE_2D,
+            extent: vk::Extent3D {
+                width: DISTANT_TERRAIN_SIZE as u32,
+                height: DISTANT_TERRAIN_SIZE as u32,
+                depth: 1,
+            },
+            format: vk::Format::R32G32_UINT,
+            usage: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            ..Default::default()
+        };
+        let sampler_options = SamplerOptions {
+            min_filter: vk::Filter::NEAREST,
+            mag_filter: vk::Filter::NEAREST,
+            address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            unnormalized_coordinates: true,
+            ..Default::default()
+        };
+        SampledImage::create(core, "distant_terrain", &image_options, &sampler_options)
+    }
+
+    fn create_atmosphere_volume(core: Rc<Core>, name: &str) -> SampledImage {
+        let size = ATMOSPHERE_VOLUME_SIZE as u32;
+        let image_options = ImageOptions {
+            typ: vk::ImageType::TYPE_3D,
+            extent: vk::Extent3D {
+                width: size,
+                height: size,
+                depth: size,
+            },
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            ..Default::default()
+        };
+        // Linear filtering blends between the biomes of neighboring cells.
+        let sampler_options = SamplerOptions {
+            min_filter: vk::Filter::LINEAR,
+            mag_filter: vk::Filter::LINEAR,
+            address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            ..Default::default()
+        };
+        SampledImage::create(core, name, &image_options, &sampler_options)
+    }
+
+    fn create_raytrace_uniform_data(settings: &RenderSettings) -> RaytraceUniformData {
         RaytraceUniformData {
             sun_angle: 0.0,
-            seed: 0,
+            frame_index: 0,
+            root_block_width: settings.root_block_size() as u32,
             origin: [0.0, 0.0, 0.0].into(),
             forward: [0.0, 0.0, 0.0].into(),
             up: [0.0, 0.0, 0.0].into(),
@@ -147,6 +557,28 @@ impl RenderData {
             region_offset: [0, 0, 0].into(),
             rotation: [-64, -64, 0].into(),
             space_offset: [-64, -64, 0].into(),
+            selected_block: [0, 0, 0].into(),
+            has_selection: 0,
+            sun_angular_radius: settings.sun_angular_radius.to_radians(),
+            use_sun_heightmap: settings.sun_heightmap as u32,
+            smooth_normals: settings.smooth_normals as u32,
+            wrap_axes: settings.world_wrap.seamless_axes(settings.root_chunk_size),
+            debug_view: 0,
+            distant_terrain: settings.distant_terrain as u32,
+            distant_terrain_origin: [0, 0].into(),
+            distant_terrain_max_height: 0,
+            sun_intensity: 1.0,
+            fog_density: 1.0,
+            overcast: [0.0; 4].into(),
+            _padding12: 0,
+            emitter_colors: emission::emitter_colors(0.0),
+            light_volume: settings.light_volume as u32,
+            _padding13: [0; 3],
+            palette: Palette::Default.to_uniform(),
+            probe_count: 0,
+            reflection_ray_budget: 1.0,
+            _padding14: [0; 2],
+            probe_positions: [[0.0, 0.0, 0.0, 0.0].into(); MAX_PROBES],
             _padding0: 0,
             _padding1: 0,
             _padding2: 0,
@@ -162,86 +594,352 @@ impl RenderData {
         }
     }
 
-    pub fn create(core: Rc<Core>) -> RenderData {
+    fn create_overlay_uniform_data() -> OverlayUniformData {
+        OverlayUniformData {
+            hotbar_colors: [[0.0, 0.0, 0.0, 0.0].into(); MAX_HOTBAR_SLOTS],
+            hotbar_length: 0,
+            selected_slot: 0,
+            flags: 0,
+            _padding0: 0,
+            minimap_marker: [0, 0].into(),
+        }
+    }
+
+    pub fn create(core: Rc<Core>, settings: &RenderSettings) -> RenderData {
         let rgba16_unorm = vk::Format::R16G16B16A16_UNORM;
+        // Also used for the buffers which hold lighting from previous frames, since they are
+        // copied to and from the lighting buffer.
+        let lighting = settings.lighting_format.get_vk_format();
         let rgba8_unorm = vk::Format::R8G8B8A8_UNORM;
+        let rgba8_snorm = vk::Format::R8G8B8A8_SNORM;
+        let rgba16_sfloat = vk::Format::R16G16B16A16_SFLOAT;
+        let rgba32_sfloat = vk::Format::R32G32B32A32_SFLOAT;
         let r16_uint = vk::Format::R16_UINT;
+        let r16_sfloat = vk::Format::R16_SFLOAT;
         let r8_uint = vk::Format::R8_UINT;
+        let screen = core.borrow_swapchain().swapchain_extent;
+        let extent = settings.get_render_extent(screen);
 
         RenderData {
             core: core.clone(),
+            settings: settings.clone(),
+            extent,
 
-            material_image: Self::create_material_image(core.clone()),
-            minefield_image: Self::create_minefield(core.clone()),
+            material_image: Self::create_material_image(core.clone(), settings),
+            minefield_image: Self::create_minefield(core.clone(), settings),
+            sun_heightmap: Self::create_sun_heightmap(core.clone(), settings),
+            light_volume: Self::create_light_volume(core.clone(), settings),
+            reflection_probes: Self::create_reflection_probes(core.clone()),
 
-            lighting_buffer: Self::create_framebuffer(core.clone(), "lighting_buf", rgba16_unorm),
-            completed_buffer: Self::create_framebuffer(core.clone(), "completed_buf", rgba16_unorm),
-            depth_buffer: Self::create_framebuffer(core.clone(), "depth_buf", r16_uint),
-            normal_buffer: Self::create_framebuffer(core.clone(), "normal_buf", r8_uint),
+            lighting_buffer: Self::create_framebuffer(
+                core.clone(),
+                "lighting_buf",
+                lighting,
+                extent,
+            ),
+            completed_buffer: Self::create_framebuffer(
+                core.clone(),
+                "completed_buf",
+                lighting,
+                extent,
+            ),
+            depth_buffer: Self::create_framebuffer(core.clone(), "depth_buf", r16_uint, extent),
+            normal_buffer: Self::create_framebuffer(core.clone(), "normal_buf", r8_uint, extent),
+            motion_buffer: Self::create_framebuffer(
+                core.clone(),
+                "motion_buf",
+                rgba16_sfloat,
+                extent,
+            ),
+            smooth_normal_buffer: Self::create_framebuffer(
+                core.clone(),
+                "smooth_normal_buf",
+                rgba8_snorm,
+                extent,
+            ),
+            moments_buffer: Self::create_framebuffer(
+                core.clone(),
+                "moments_buf",
+                rgba16_sfloat,
+                extent,
+            ),
+            variance_buffer: Self::create_framebuffer(
+                core.clone(),
+                "variance_buf",
+                r16_sfloat,
+                extent,
+            ),
+            variance_pong_buffer: Self::create_framebuffer(
+                core.clone(),
+                "variance_pong_buf",
+                r16_sfloat,
+                extent,
+            ),
+            accumulation_buffer: Self::create_framebuffer(
+                core.clone(),
+                "accumulation_buf",
+                rgba32_sfloat,
+                extent,
+            ),
 
             lighting_pong_buffer: Self::create_framebuffer(
                 core.clone(),
                 "lighting_pong_buf",
+                lighting,
+                extent,
+            ),
+            output_image: Self::create_output_image(core.clone()),
+            comparison_buffer: Self::create_framebuffer(
+                core.clone(),
+                "comparison_buf",
+                lighting,
+                extent,
+            ),
+            albedo_buffer: Self::create_framebuffer(
+                core.clone(),
+                "albedo_buf",
+                rgba8_unorm,
+                extent,
+            ),
+            emission_buffer: Self::create_framebuffer(
+                core.clone(),
+                "emission_buf",
+                rgba8_unorm,
+                extent,
+            ),
+            fog_color_buffer: Self::create_fog_buffer(core.clone(), "fog_color_buf", extent),
+            reflection_buffer: Self::create_framebuffer(
+                core.clone(),
+                "reflection_buf",
+                rgba16_unorm,
+                extent,
+            ),
+            reflection_pong_buffer: Self::create_framebuffer(
+                core.clone(),
+                "reflection_pong_buf",
                 rgba16_unorm,
+                extent,
+            ),
+            reflection_work_list: Self::create_work_list(
+                core.clone(),
+                "reflection_work_list",
+                extent,
+            ),
+
+            ray_queue: Self::create_ray_queue(core.clone(), "ray_queue", extent),
+            ray_pong_queue: Self::create_ray_queue(core.clone(), "ray_pong_queue", extent),
+            light_accumulators: Self::create_light_accumulators(core.clone(), extent),
+            chunk_access_mask: Buffer::create(
+                core.clone(),
+                "chunk_access_mask",
+                (settings.root_chunk_size * settings.root_chunk_size * settings.root_chunk_size)
+                    as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            ),
+            ray_statistics: Buffer::create(
+                core.clone(),
+                "ray_statistics",
+                RAY_STATISTICS_LEN as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            ),
+            chunk_occupancy: Buffer::create(
+                core.clone(),
+                "chunk_occupancy",
+                terrain_upload::chunk_occupancy_len(settings.root_chunk_size) as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            ),
+            terrain_fills: Buffer::create(
+                core.clone(),
+                "terrain_fills",
+                terrain_upload::max_terrain_fills(settings.root_chunk_size) as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            ),
+            distant_terrain: Self::create_distant_terrain(core.clone()),
+            atmosphere_fog: Self::create_atmosphere_volume(core.clone(), "atmosphere_fog"),
+            atmosphere_grading: Self::create_atmosphere_volume(core.clone(), "atmosphere_grading"),
+
+            history_lighting_buffer: Self::create_framebuffer(
+                core.clone(),
+                "history_lighting_buf",
+                lighting,
+                extent,
+            ),
+            history_depth_buffer: Self::create_framebuffer(
+                core.clone(),
+                "history_depth_buf",
+                r16_uint,
+                extent,
+            ),
+            history_normal_buffer: Self::create_framebuffer(
+                core.clone(),
+                "history_normal_buf",
+                r8_uint,
+                extent,
+            ),
+            history_moments_buffer: Self::create_framebuffer(
+                core.clone(),
+                "history_moments_buf",
+                rgba16_sfloat,
+                extent,
+            ),
+            history_fog_color_buffer: Self::create_fog_buffer(
+                core.clone(),
+                "history_fog_color_buf",
+                extent,
             ),
-            albedo_buffer: Self::create_framebuffer(core.clone(), "albedo_buf", rgba8_unorm),
-            emission_buffer: Self::create_framebuffer(core.clone(), "emission_buf", rgba8_unorm),
-            fog_color_buffer: Self::create_framebuffer(core.clone(), "fog_color_buf", rgba8_unorm),
 
             blue_noise: Self::create_blue_noise(core.clone()),
 
-            raytrace_uniform_data: Self::create_raytrace_uniform_data(),
+            raytrace_uniform_data: Self::create_raytrace_uniform_data(settings),
             raytrace_uniform_data_buffer: Buffer::create(
                 core.clone(),
                 "raytrace_uniform_data",
                 1,
                 vk::BufferUsageFlags::UNIFORM_BUFFER,
             ),
+
+            temporal_uniform_data_buffer: Buffer::create(
+                core.clone(),
+                "temporal_uniform_data",
+                1,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+            ),
+            invalid_lighting_count: Buffer::create(
+                core.clone(),
+                "invalid_lighting_count",
+                1,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            ),
+            image_statistics: Buffer::create(
+                core.clone(),
+                "image_statistics",
+                STATISTICS_BUFFER_LEN as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            ),
+
+            overlay_uniform_data: Self::create_overlay_uniform_data(),
+            overlay_uniform_data_buffer: Buffer::create(
+                core.clone(),
+                "overlay_uniform_data",
+                1,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+            ),
+            minimap: Self::create_minimap(core.clone()),
+
+            font_atlas: Self::create_font_atlas(core.clone()),
+            text_uniform_data_buffer: Buffer::create(
+                core.clone(),
+                "text_uniform_data",
+                1,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+            ),
+            pip: Self::create_pip_buffers(core.clone(), lighting),
+
+            warm_cache_pending: false,
         }
     }
 
+    /// Copies the chunks the world images start out holding into the given arrays, which are
+    /// laid out like the images. Unless wait is true, chunks which have not been generated yet
+    /// are generated in the background, nearest to the center first, and left empty for now.
+    fn copy_starting_region(
+        world: &mut ChunkStorage,
+        root_chunk_size: usize,
+        materials: &mut [u32],
+        minefield: &mut [u8],
+        wait: bool,
+    ) {
+        let root_block_size = root_chunk_size * CHUNK_SIZE;
+        for chunk_coord in util::coord_iter_3d(root_chunk_size) {
+            let world_coord = chunk_coord
+                .signed()
+                .sub((root_chunk_size as isize / 2).repeat());
+            let chunk = if wait {
+                world.borrow_packed_chunk_data(&world_coord)
+            } else {
+                let priority =
+                    (world_coord.0.abs() + world_coord.1.abs() + world_coord.2.abs()) as u32;
+                world.borrow_packed_chunk_data_or_placeholder(&world_coord, priority)
+            };
+            let offset = util::scale_coord_3d(&chunk_coord, CHUNK_SIZE).signed();
+            chunk.copy_materials(offset, materials, root_block_size);
+            chunk.copy_minefield(offset, minefield, root_block_size);
+        }
+    }
+
+    /// The world images which TerrainUploadManager streams terrain into.
+    pub fn terrain_images(&self) -> TerrainImages {
+        TerrainImages {
+            material: self.material_image.get_vk_image(),
+            minefield: self.minefield_image.get_vk_image(),
+        }
+    }
+
+    /// Saves the warm cache once every chunk the world images started out holding has been
+    /// generated, if it was enabled but could not be used. See RenderSettings::warm_cache.
+    pub fn update_warm_cache(&mut self, world: &mut ChunkStorage) {
+        if !self.warm_cache_pending || !world.is_settled() {
+            return;
+        }
+        self.warm_cache_pending = false;
+        let root_chunk_size = self.settings.root_chunk_size;
+        let volume = self.settings.root_block_volume();
+        let mut cache = WarmCache {
+            materials: vec![0; volume],
+            minefield: vec![0; volume],
+        };
+        Self::copy_starting_region(
+            world,
+            root_chunk_size,
+            &mut cache.materials,
+            &mut cache.minefield,
+            true,
+        );
+        let key = WarmCacheKey::new(root_chunk_size, self.settings.world_wrap);
+        world.save_warm_cache(key, &cache);
+    }
+
     fn make_world_upload_buffers(&mut self, world: &mut ChunkStorage) -> (Buffer<u32>, Buffer<u8>) {
+        let root_chunk_size = self.settings.root_chunk_size;
+        let root_block_volume = self.settings.root_block_volume();
         let mut material_buffer = Buffer::create(
             self.core.clone(),
             "material_buf",
-            ROOT_BLOCK_VOLUME as u64,
+            root_block_volume as u64,
             vk::BufferUsageFlags::TRANSFER_SRC,
         );
         let mut minefield_buffer = Buffer::create(
             self.core.clone(),
             "minefield_buf",
-            ROOT_BLOCK_VOLUME as u64,
+            root_block_volume as u64,
             vk::BufferUsageFlags::TRANSFER_SRC,
         );
 
         let mut material_buffer_data = material_buffer.bind_all();
         let mut minefield_buffer_data = minefield_buffer.bind_all();
-        let mut gen_time = 0;
-        let mut copy_time = 0;
-        for chunk_coord in util::coord_iter_3d(ROOT_CHUNK_SIZE) {
-            let world_coord = chunk_coord.signed().sub((
-                (ROOT_CHUNK_SIZE as isize / 2),
-                (ROOT_CHUNK_SIZE as isize / 2),
-                (ROOT_CHUNK_SIZE as isize / 2),
-            ));
-            let timer = std::time::Instant::now();
-            let chunk =
-                world.borrow_packed_chunk_data(&(world_coord.0, world_coord.1, world_coord.2));
-            gen_time += timer.elapsed().as_millis();
-            let timer = std::time::Instant::now();
-            chunk.copy_materials(
-                util::scale_coord_3d(&chunk_coord, CHUNK_SIZE).signed(),
+        let key = WarmCacheKey::new(root_chunk_size, self.settings.world_wrap);
+        let cache = if self.settings.warm_cache {
+            world.load_warm_cache(key)
+        } else {
+            None
+        };
+        self.warm_cache_pending = self.settings.warm_cache && cache.is_none();
+        if let Some(cache) = cache {
+            material_buffer_data
+                .as_slice_mut()
+                .copy_from_slice(&cache.materials);
+            minefield_buffer_data
+                .as_slice_mut()
+                .copy_from_slice(&cache.minefield);
+        } else {
+            Self::copy_starting_region(
+                world,
+                root_chunk_size,
                 material_buffer_data.as_slice_mut(),
-                ROOT_BLOCK_SIZE,
-            );
-            chunk.copy_minefield(
-                util::scale_coord_3d(&chunk_coord, CHUNK_SIZE).signed(),
                 minefield_buffer_data.as_slice_mut(),
-                ROOT_BLOCK_SIZE,
+                false,
             );
-            copy_time += timer.elapsed().as_millis();
         }
-        println!("Gen time: {}ms, copy time: {}ms", gen_time, copy_time);
         drop(material_buffer_data);
         drop(minefield_buffer_data);
 
@@ -266,36 +964,201 @@ impl RenderData {
         );
     }
 
-    pub fn initialize(&mut self, game: &mut Game) {
-        let world = game.borrow_world_mut();
-        let (material_buffer, minefield_buffer) = self.make_world_upload_buffers(world);
-
-        let mut commands = CommandBuffer::create_single(self.core.clone());
-        commands.begin_one_time_submit();
-        Self::upload_buf_commands(&mut commands, &material_buffer, &self.material_image);
-        Self::upload_buf_commands(&mut commands, &minefield_buffer, &self.minefield_image);
-        let generic_layout_images = [
+    /// Every image whose size depends on the screen or the picture-in-picture view, all of which
+    /// are kept in GENERAL layout.
+    fn get_framebuffers(&self) -> Vec<&StorageImage> {
+        let mut framebuffers = vec![
+            &self.accumulation_buffer,
             &self.albedo_buffer,
+            &self.comparison_buffer,
             &self.completed_buffer,
             &self.depth_buffer,
             &self.emission_buffer,
             &self.fog_color_buffer,
+            &self.history_depth_buffer,
+            &self.history_fog_color_buffer,
+            &self.history_lighting_buffer,
+            &self.history_moments_buffer,
+            &self.history_normal_buffer,
             &self.lighting_buffer,
             &self.lighting_pong_buffer,
+            &self.moments_buffer,
+            &self.motion_buffer,
             &self.normal_buffer,
+            &self.reflection_buffer,
+            &self.reflection_pong_buffer,
+            &self.smooth_normal_buffer,
+            &self.variance_buffer,
+            &self.variance_pong_buffer,
         ];
-        for image in generic_layout_images.iter() {
-            commands.transition_layout(
-                *image,
-                vk::ImageLayout::UNDEFINED,
-                vk::ImageLayout::GENERAL,
-            );
+        framebuffers.extend(self.pip.get_images().iter());
+        framebuffers.extend(self.output_image.iter());
+        framebuffers
+    }
+
+    /// Replaces every buffer whose size depends on the screen with one sized for the swapchain as
+    /// it is now and the render scale in settings, after either changed. The temporal history is
+    /// stretched over the new history buffers if possible, the fog history starts over. The
+    /// device must not be using any of the old buffers anymore.
+    pub fn recreate_framebuffers(&mut self) {
+        self.recreate_framebuffers_from(self.settings.lighting_format);
+    }
+
+    /// Replaces the lighting buffers with ones in another format, along with every other buffer
+    /// recreate_framebuffers replaces. The device must not be using any of the old buffers
+    /// anymore.
+    pub fn set_lighting_format(&mut self, format: LightingFormat) {
+        let old_format = self.settings.lighting_format;
+        self.settings.lighting_format = format;
+        self.recreate_framebuffers_from(old_format);
+    }
+
+    /// Like recreate_framebuffers, with the old history buffers in the given format.
+    fn recreate_framebuffers_from(&mut self, history_format: LightingFormat) {
+        let core = &self.core;
+        let screen = core.borrow_swapchain().swapchain_extent;
+        let extent = self.settings.get_render_extent(screen);
+        self.extent = extent;
+        let framebuffer = |name: &str, format: vk::Format| {
+            Self::create_framebuffer(core.clone(), name, format, extent)
+        };
+        let rgba16_unorm = vk::Format::R16G16B16A16_UNORM;
+        let lighting = self.settings.lighting_format.get_vk_format();
+        let rgba8_unorm = vk::Format::R8G8B8A8_UNORM;
+        let rgba8_snorm = vk::Format::R8G8B8A8_SNORM;
+        let rgba16_sfloat = vk::Format::R16G16B16A16_SFLOAT;
+        let rgba32_sfloat = vk::Format::R32G32B32A32_SFLOAT;
+        let r16_uint = vk::Format::R16_UINT;
+        let r16_sfloat = vk::Format::R16_SFLOAT;
+        let r8_uint = vk::Format::R8_UINT;
+
+        self.lighting_buffer = framebuffer("lighting_buf", lighting);
+        self.completed_buffer = framebuffer("completed_buf", lighting);
+        self.depth_buffer = framebuffer("depth_buf", r16_uint);
+        self.normal_buffer = framebuffer("normal_buf", r8_uint);
+        self.motion_buffer = framebuffer("motion_buf", rgba16_sfloat);
+        self.smooth_normal_buffer = framebuffer("smooth_normal_buf", rgba8_snorm);
+        self.moments_buffer = framebuffer("moments_buf", rgba16_sfloat);
+        self.variance_buffer = framebuffer("variance_buf", r16_sfloat);
+        self.variance_pong_buffer = framebuffer("variance_pong_buf", r16_sfloat);
+        self.accumulation_buffer = framebuffer("accumulation_buf", rgba32_sfloat);
+        self.lighting_pong_buffer = framebuffer("lighting_pong_buf", lighting);
+        self.output_image = Self::create_output_image(core.clone());
+        self.comparison_buffer = framebuffer("comparison_buf", lighting);
+        self.albedo_buffer = framebuffer("albedo_buf", rgba8_unorm);
+        self.emission_buffer = framebuffer("emission_buf", rgba8_unorm);
+        self.fog_color_buffer = Self::create_fog_buffer(core.clone(), "fog_color_buf", extent);
+        self.reflection_buffer = framebuffer("reflection_buf", rgba16_unorm);
+        self.reflection_pong_buffer = framebuffer("reflection_pong_buf", rgba16_unorm);
+        self.reflection_work_list =
+            Self::create_work_list(core.clone(), "reflection_work_list", extent);
+        self.ray_queue = Self::create_ray_queue(core.clone(), "ray_queue", extent);
+        self.ray_pong_queue = Self::create_ray_queue(core.clone(), "ray_pong_queue", extent);
+        self.light_accumulators = Self::create_light_accumulators(core.clone(), extent);
+        let old_history = [
+            std::mem::replace(
+                &mut self.history_lighting_buffer,
+                framebuffer("history_lighting_buf", lighting),
+            ),
+            std::mem::replace(
+                &mut self.history_depth_buffer,
+                framebuffer("history_depth_buf", r16_uint),
+            ),
+            std::mem::replace(
+                &mut self.history_normal_buffer,
+                framebuffer("history_normal_buf", r8_uint),
+            ),
+            std::mem::replace(
+                &mut self.history_moments_buffer,
+                framebuffer("history_moments_buf", rgba16_sfloat),
+            ),
+        ];
+        self.history_fog_color_buffer =
+            Self::create_fog_buffer(core.clone(), "history_fog_color_buf", extent);
+        self.pip = Self::create_pip_buffers(core.clone(), lighting);
+
+        let commands = CommandBuffer::create_single(self.core.clone());
+        commands.begin_one_time_submit();
+        for image in self.get_framebuffers() {
+            commands.transition_layout(image, vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL);
         }
-        commands.transition_layout(
-            &self.blue_noise,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-        );
+        commands.clear_image(&self.history_fog_color_buffer, vk::ImageLayout::GENERAL);
+        commands.clear_image(&self.history_moments_buffer, vk::ImageLayout::GENERAL);
+        if self.can_resample_history(history_format) {
+            let [old_lighting, old_depth, old_normal, old_moments] = &old_history;
+            let old_history = [old_lighting, old_depth, old_normal, old_moments];
+            self.record_history_resample(&commands, old_history);
+        }
+        commands.end();
+        commands.blocking_execute_and_destroy();
+    }
+
+    /// The lighting, depth, normal and moments buffers from last frame, in that order.
+    pub fn get_history(&self) -> [&StorageImage; 4] {
+        [
+            &self.history_lighting_buffer,
+            &self.history_depth_buffer,
+            &self.history_normal_buffer,
+            &self.history_moments_buffer,
+        ]
+    }
+
+    /// Whether history in the given lighting format can be stretched over this data's history
+    /// buffers by record_history_resample.
+    pub fn can_resample_history(&self, old_format: LightingFormat) -> bool {
+        let new_format = self.settings.lighting_format.get_vk_format();
+        let linear = vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+        self.core.supports_format_features(
+            old_format.get_vk_format(),
+            vk::FormatFeatureFlags::BLIT_SRC | linear,
+        ) && self
+            .core
+            .supports_format_features(new_format, vk::FormatFeatureFlags::BLIT_DST)
+    }
+
+    /// Records stretching history buffers, in the order get_history returns them, over this
+    /// data's history buffers. Lighting and moments are filtered bilinearly, depth and normals
+    /// are not since blending them would invent surfaces which do not exist. Both must be in
+    /// GENERAL layout.
+    pub fn record_history_resample(&self, commands: &CommandBuffer, old: [&StorageImage; 4]) {
+        let filters = [
+            vk::Filter::LINEAR,
+            vk::Filter::NEAREST,
+            vk::Filter::NEAREST,
+            vk::Filter::LINEAR,
+        ];
+        let new = self.get_history();
+        for ((old, new), filter) in old.iter().zip(new.iter()).zip(filters.iter()) {
+            commands.resample_image(*old, *new, *filter);
+        }
+    }
+
+    pub fn initialize(&mut self, game: &mut Game) {
+        let world = game.borrow_world_mut();
+        // The world has to wrap the same way the shaders expect before anything is uploaded.
+        world.set_wrap(self.settings.world_wrap);
+        let (material_buffer, minefield_buffer) = self.make_world_upload_buffers(world);
+
+        let mut commands = CommandBuffer::create_single(self.core.clone());
+        commands.begin_one_time_submit();
+        Self::upload_buf_commands(&mut commands, &material_buffer, &self.material_image);
+        Self::upload_buf_commands(&mut commands, &minefield_buffer, &self.minefield_image);
+        let mut images = self.get_framebuffers();
+        images.push(&self.light_volume);
+        images.push(&self.reflection_probes);
+        images.push(&self.sun_heightmap);
+        for image in images {
+            commands.transition_layout(image, vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL);
+        }
+        // The light volume is built up over many frames from whatever it held before.
+        commands.clear_image(&self.light_volume, vk::ImageLayout::GENERAL);
+        // So that fog.comp and temporal.comp know there is no history yet.
+        commands.clear_image(&self.history_fog_color_buffer, vk::ImageLayout::GENERAL);
+        commands.clear_image(&self.history_moments_buffer, vk::ImageLayout::GENERAL);
+        // These were filled in when they were created.
+        let read_only = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        commands.transition_to(&self.blue_noise, read_only);
+        commands.transition_to(&self.font_atlas, read_only);
         commands.end();
         commands.blocking_execute_and_destroy();
     }
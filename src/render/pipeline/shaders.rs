@@ -4,9 +4,13 @@ use std::ffi::CString;
 use std::rc::Rc;
 
 use crate::render::general::core::Core;
+use crate::render::{LightingFormat, OutputFormat};
 
 use super::descriptor_sets::DescriptorCollection;
-use super::structs::DenoisePushData;
+use super::structs::{
+    DenoisePushData, EncodeOutputPushData, EstimateVariancePushData, FogPushData,
+    ProbeCapturePushData,
+};
 
 pub struct Stage {
     pub core: Rc<Core>,
@@ -16,6 +20,7 @@ pub struct Stage {
 
 impl Drop for Stage {
     fn drop(&mut self) {
+        self.core.forget_pipeline_layout(self.pipeline_layout);
         unsafe {
             self.core
                 .device
@@ -25,34 +30,83 @@ impl Drop for Stage {
     }
 }
 
-fn create_shader_module(
-    core: Rc<Core>,
-    shader_source: *const u8,
-    length: usize,
-) -> vk::ShaderModule {
+// Shaders which use the lighting buffers are compiled once for each LightingFormat by build.rs.
+// The suffix picks between the variants of shaders which are also compiled for other formats.
+macro_rules! include_lighting_shader {
+    ($format:expr, $name:literal $(, $suffix:literal)?) => {
+        match $format {
+            LightingFormat::Unorm16 => &include_bytes!(concat!(
+                "../../../shaders/spirv/", $name, ".unorm16", $($suffix,)? ".spirv"
+            ))[..],
+            LightingFormat::Float16 => &include_bytes!(concat!(
+                "../../../shaders/spirv/", $name, ".float16", $($suffix,)? ".spirv"
+            ))[..],
+            LightingFormat::Packed => &include_bytes!(concat!(
+                "../../../shaders/spirv/", $name, ".packed", $($suffix,)? ".spirv"
+            ))[..],
+        }
+    };
+}
+
+// Shaders which write the output image are compiled once for 8 bit and once for float output by
+// build.rs, see OutputFormat::uses_float_output.
+macro_rules! include_output_shader {
+    ($output:expr, $name:literal) => {
+        if $output.uses_float_output() {
+            &include_bytes!(concat!("../../../shaders/spirv/", $name, ".float16.spirv"))[..]
+        } else {
+            &include_bytes!(concat!("../../../shaders/spirv/", $name, ".unorm8.spirv"))[..]
+        }
+    };
+}
+
+/// Everything needed to create a compute stage, which can be sent to another thread.
+pub struct StageDescription {
+    name: &'static str,
+    shader_source: &'static [u8],
+    entry_point: &'static str,
+    descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+}
+
+impl StageDescription {
+    fn new(
+        name: &'static str,
+        shader_source: &'static [u8],
+        entry_point: &'static str,
+        descriptor_set_layouts: &[vk::DescriptorSetLayout],
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) -> Self {
+        Self {
+            name,
+            shader_source,
+            entry_point,
+            descriptor_set_layouts: descriptor_set_layouts.to_owned(),
+            push_constant_ranges: push_constant_ranges.to_owned(),
+        }
+    }
+}
+
+fn create_shader_module(device: &ash::Device, shader_source: &[u8]) -> vk::ShaderModule {
     let shader_module_create_info = vk::ShaderModuleCreateInfo {
-        code_size: length,
-        p_code: shader_source as *const u32,
+        code_size: shader_source.len(),
+        p_code: shader_source.as_ptr() as *const u32,
         ..Default::default()
     };
     unsafe {
-        core.device
+        device
             .create_shader_module(&shader_module_create_info, None)
             .expect("Failed to create shader module.")
     }
 }
 
-fn create_compute_shader_stage(
-    core: Rc<Core>,
-    name: &str,
-    shader_source: &[u8],
-    entry_point: &str,
-    descriptor_set_layouts: &[vk::DescriptorSetLayout],
-    push_constant_ranges: &[vk::PushConstantRange],
-) -> Stage {
-    let shader_module =
-        create_shader_module(core.clone(), shader_source.as_ptr(), shader_source.len());
-    let entry_point_cstring = CString::new(entry_point).unwrap();
+/// Only uses the device so that it can run on any thread. Returns the pipeline and its layout.
+fn create_compute_pipeline(
+    device: &ash::Device,
+    description: &StageDescription,
+) -> (vk::Pipeline, vk::PipelineLayout) {
+    let shader_module = create_shader_module(device, description.shader_source);
+    let entry_point_cstring = CString::new(description.entry_point).unwrap();
     let vk_stage = vk::PipelineShaderStageCreateInfo {
         module: shader_module,
         p_name: entry_point_cstring.as_ptr(),
@@ -60,6 +114,8 @@ fn create_compute_shader_stage(
         ..Default::default()
     };
 
+    let descriptor_set_layouts = &description.descriptor_set_layouts;
+    let push_constant_ranges = &description.push_constant_ranges;
     let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo {
         set_layout_count: descriptor_set_layouts.len() as u32,
         p_set_layouts: descriptor_set_layouts.as_ptr(),
@@ -68,11 +124,10 @@ fn create_compute_shader_stage(
         ..Default::default()
     };
     let pipeline_layout = unsafe {
-        core.device
+        device
             .create_pipeline_layout(&pipeline_layout_create_info, None)
             .expect("Failed to create pipeline layout.")
     };
-    core.set_debug_name(pipeline_layout, &format!("{}_layout", name));
 
     let pipeline_create_info = vk::ComputePipelineCreateInfo {
         stage: vk_stage,
@@ -80,27 +135,77 @@ fn create_compute_shader_stage(
         ..Default::default()
     };
     let pipeline = unsafe {
-        core.device
+        device
             .create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_create_info], None)
             .expect("Failed to create compute pipeline.")[0]
     };
-    core.set_debug_name(pipeline, name);
 
     unsafe {
-        core.device.destroy_shader_module(shader_module, None);
-    }
-    Stage {
-        core,
-        vk_pipeline: pipeline,
-        pipeline_layout,
+        device.destroy_shader_module(shader_module, None);
     }
+    (pipeline, pipeline_layout)
+}
+
+/// Creates a stage for each description, in the same order. Drivers compile shaders while
+/// pipelines are created, which is slow, so each pipeline is created on its own thread and this
+/// waits for all of them to finish.
+pub fn create_stages(core: &Rc<Core>, descriptions: &[StageDescription]) -> Vec<Stage> {
+    let device = &core.device;
+    let pipelines: Vec<_> = std::thread::scope(|scope| {
+        let workers: Vec<_> = descriptions
+            .iter()
+            .map(|description| {
+                std::thread::Builder::new()
+                    .name(format!("create_{}", description.name))
+                    .spawn_scoped(scope, move || create_compute_pipeline(device, description))
+                    .expect("Failed to spawn pipeline creation thread.")
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("Pipeline creation thread panicked."))
+            .collect()
+    });
+
+    // The debug names and descriptor registry live in the core, which can't leave this thread.
+    let stages_and_descriptions = pipelines.into_iter().zip(descriptions.iter());
+    stages_and_descriptions
+        .map(|((vk_pipeline, pipeline_layout), description)| {
+            let layout_name = format!("{}_layout", description.name);
+            core.set_debug_name(pipeline_layout, &layout_name);
+            core.register_pipeline_layout(
+                pipeline_layout,
+                &layout_name,
+                &description.descriptor_set_layouts,
+            );
+            core.set_debug_name(vk_pipeline, description.name);
+            Stage {
+                core: core.clone(),
+                vk_pipeline,
+                pipeline_layout,
+            }
+        })
+        .collect()
 }
 
-pub fn create_denoise_stage(core: Rc<Core>, dc: &DescriptorCollection) -> Stage {
-    let shader_source = include_bytes!("../../../shaders/spirv/bilateral_denoise.comp.spirv");
-    create_compute_shader_stage(
-        core,
-        "raytrace",
+pub fn describe_compact_reflections_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/compact_reflections.comp.spirv");
+    StageDescription::new(
+        "compact_reflections",
+        shader_source,
+        "main",
+        &[dc.compact_reflections.layout],
+        &[],
+    )
+}
+
+pub fn describe_denoise_stage(
+    dc: &DescriptorCollection,
+    format: LightingFormat,
+) -> StageDescription {
+    let shader_source = include_lighting_shader!(format, "svgf_denoise.comp");
+    StageDescription::new(
+        "denoise",
         shader_source,
         "main",
         &[dc.denoise.layout],
@@ -112,10 +217,50 @@ pub fn create_denoise_stage(core: Rc<Core>, dc: &DescriptorCollection) -> Stage
     )
 }
 
-pub fn create_finalize_stage(core: Rc<Core>, dc: &DescriptorCollection) -> Stage {
-    let shader_source = include_bytes!("../../../shaders/spirv/finalize.comp.spirv");
-    create_compute_shader_stage(
-        core,
+pub fn describe_encode_output_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/encode_output.comp.spirv");
+    StageDescription::new(
+        "encode_output",
+        shader_source,
+        "main",
+        &[dc.swapchain.layout],
+        &[vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<EncodeOutputPushData>() as u32,
+        }],
+    )
+}
+
+pub fn describe_estimate_variance_stage(
+    dc: &DescriptorCollection,
+    format: LightingFormat,
+) -> StageDescription {
+    let shader_source = include_lighting_shader!(format, "estimate_variance.comp");
+    StageDescription::new(
+        "estimate_variance",
+        shader_source,
+        "main",
+        &[dc.estimate_variance.layout],
+        &[vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<EstimateVariancePushData>() as u32,
+        }],
+    )
+}
+
+pub fn describe_finalize_stage(
+    dc: &DescriptorCollection,
+    format: LightingFormat,
+    output: OutputFormat,
+) -> StageDescription {
+    let shader_source = if output.uses_float_output() {
+        include_lighting_shader!(format, "finalize.comp", ".float16")
+    } else {
+        include_lighting_shader!(format, "finalize.comp", ".unorm8")
+    };
+    StageDescription::new(
         "finalize",
         shader_source,
         "main",
@@ -124,14 +269,191 @@ pub fn create_finalize_stage(core: Rc<Core>, dc: &DescriptorCollection) -> Stage
     )
 }
 
-pub fn create_raytrace_stage(core: Rc<Core>, dc: &DescriptorCollection) -> Stage {
-    let shader_source = include_bytes!("../../../shaders/spirv/raytrace.comp.spirv");
-    create_compute_shader_stage(
-        core,
-        "raytrace",
+pub fn describe_fog_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/fog.comp.spirv");
+    StageDescription::new(
+        "fog",
+        shader_source,
+        "main",
+        &[dc.scene.layout, dc.fog.layout],
+        &[vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<FogPushData>() as u32,
+        }],
+    )
+}
+
+pub fn describe_light_volume_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/light_volume.comp.spirv");
+    StageDescription::new(
+        "light_volume",
+        shader_source,
+        "main",
+        &[dc.scene.layout, dc.light_volume.layout],
+        &[],
+    )
+}
+
+pub fn describe_probe_capture_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/probe_capture.comp.spirv");
+    StageDescription::new(
+        "probe_capture",
+        shader_source,
+        "main",
+        &[dc.scene.layout, dc.probe_capture.layout],
+        &[vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<ProbeCapturePushData>() as u32,
+        }],
+    )
+}
+
+pub fn describe_overlay_stage(dc: &DescriptorCollection, output: OutputFormat) -> StageDescription {
+    let shader_source = include_output_shader!(output, "overlay.comp");
+    StageDescription::new(
+        "overlay",
+        shader_source,
+        "main",
+        &[dc.overlay.layout, dc.swapchain.layout],
+        &[],
+    )
+}
+
+pub fn describe_raygen_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/raygen.comp.spirv");
+    StageDescription::new(
+        "raygen",
+        shader_source,
+        "main",
+        &[dc.scene.layout, dc.raygen.layout],
+        &[],
+    )
+}
+
+pub fn describe_reflection_denoise_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/reflection_denoise.comp.spirv");
+    StageDescription::new(
+        "reflection_denoise",
+        shader_source,
+        "main",
+        &[dc.reflection_denoise.layout],
+        &[vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<DenoisePushData>() as u32,
+        }],
+    )
+}
+
+pub fn describe_resolve_stage(
+    dc: &DescriptorCollection,
+    format: LightingFormat,
+) -> StageDescription {
+    let shader_source = include_lighting_shader!(format, "resolve.comp");
+    StageDescription::new(
+        "resolve",
+        shader_source,
+        "main",
+        &[dc.scene.layout, dc.resolve.layout],
+        &[],
+    )
+}
+
+pub fn describe_shade_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/shade.comp.spirv");
+    StageDescription::new(
+        "shade",
+        shader_source,
+        "main",
+        &[dc.scene.layout, dc.shade.layout],
+        &[],
+    )
+}
+
+pub fn describe_sun_heightmap_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/sun_heightmap.comp.spirv");
+    StageDescription::new(
+        "sun_heightmap",
+        shader_source,
+        "main",
+        &[dc.scene.layout, dc.sun_heightmap.layout],
+        &[],
+    )
+}
+
+pub fn describe_temporal_stage(
+    dc: &DescriptorCollection,
+    format: LightingFormat,
+) -> StageDescription {
+    let shader_source = include_lighting_shader!(format, "temporal.comp");
+    StageDescription::new(
+        "temporal",
+        shader_source,
+        "main",
+        &[dc.temporal.layout],
+        &[],
+    )
+}
+
+pub fn describe_terrain_fill_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/terrain_fill.comp.spirv");
+    StageDescription::new(
+        "terrain_fill",
+        shader_source,
+        "main",
+        &[dc.terrain_fill.layout],
+        &[],
+    )
+}
+
+pub fn describe_text_stage(dc: &DescriptorCollection, output: OutputFormat) -> StageDescription {
+    let shader_source = include_output_shader!(output, "text.comp");
+    StageDescription::new(
+        "text",
+        shader_source,
+        "main",
+        &[dc.text.layout, dc.swapchain.layout],
+        &[],
+    )
+}
+
+pub fn describe_traverse_stage(dc: &DescriptorCollection) -> StageDescription {
+    let shader_source = include_bytes!("../../../shaders/spirv/traverse.comp.spirv");
+    StageDescription::new(
+        "traverse",
+        shader_source,
+        "main",
+        &[dc.scene.layout, dc.traverse.layout],
+        &[],
+    )
+}
+
+pub fn describe_image_statistics_stage(
+    dc: &DescriptorCollection,
+    format: LightingFormat,
+) -> StageDescription {
+    let shader_source = include_lighting_shader!(format, "image_statistics.comp");
+    StageDescription::new(
+        "image_statistics",
+        shader_source,
+        "main",
+        &[dc.image_statistics.layout],
+        &[],
+    )
+}
+
+pub fn describe_validate_lighting_stage(
+    dc: &DescriptorCollection,
+    format: LightingFormat,
+) -> StageDescription {
+    let shader_source = include_lighting_shader!(format, "validate_lighting.comp");
+    StageDescription::new(
+        "validate_lighting",
         shader_source,
         "main",
-        &[dc.raytrace.layout],
+        &[dc.validate_lighting.layout],
         &[],
     )
 }
@@ -1,11 +1,14 @@
-use cgmath::Vector3;
+use crate::render::constants::*;
+use crate::render::text::{GlyphInstance, MAX_GLYPHS};
+use cgmath::{Vector2, Vector3, Vector4};
 
 #[repr(C)]
 #[derive(Clone, Debug)]
 pub struct RaytraceUniformData {
     pub sun_angle: f32,
-    pub seed: u32,
-    pub _padding0: u64,
+    pub frame_index: u32,
+    pub root_block_width: u32,
+    pub _padding0: u32,
     pub origin: Vector3<f32>,
     pub _padding1: u32,
     pub forward: Vector3<f32>,
@@ -28,10 +31,192 @@ pub struct RaytraceUniformData {
     pub _padding10: u32,
     pub space_offset: Vector3<i32>,
     pub _padding11: u32,
+    pub selected_block: Vector3<i32>,
+    pub has_selection: u32,
+    // In radians.
+    pub sun_angular_radius: f32,
+    pub use_sun_heightmap: u32,
+    pub smooth_normals: u32,
+    // See WorldWrap::seamless_axes.
+    pub wrap_axes: u32,
+    pub debug_view: u32,
+    pub distant_terrain: u32,
+    // The block at the -X -Y corner of the distant terrain heightmap.
+    pub distant_terrain_origin: Vector2<i32>,
+    pub distant_terrain_max_height: i32,
+    // From the time of day, see Lighting.
+    pub sun_intensity: f32,
+    pub fog_density: f32,
+    pub _padding12: u32,
+    // See Sky::to_uniform.
+    pub overcast: Vector4<f32>,
+    // The current emission of each emitter slot, see emission::emitter_colors.
+    pub emitter_colors: [Vector4<f32>; EMITTER_SLOTS],
+    pub light_volume: u32,
+    pub _padding13: [u32; 3],
+    // See Palette::to_uniform.
+    pub palette: [Vector4<f32>; 4],
+    pub probe_count: u32,
+    // See Game::get_reflection_ray_budget.
+    pub reflection_ray_budget: f32,
+    pub _padding14: [u32; 2],
+    // Relative to region_offset, the W of each is unused.
+    pub probe_positions: [Vector4<f32>; MAX_PROBES],
+}
+
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct OverlayUniformData {
+    pub hotbar_colors: [Vector4<f32>; MAX_HOTBAR_SLOTS],
+    pub hotbar_length: u32,
+    pub selected_slot: u32,
+    pub flags: u32,
+    pub _padding0: u32,
+    // Where the camera is on the minimap, in pixels.
+    pub minimap_marker: Vector2<i32>,
+}
+
+impl OverlayUniformData {
+    // These must match the flags in overlay.comp.
+    pub const VISIBLE: u32 = 1 << 0;
+    pub const HAS_SELECTION: u32 = 1 << 1;
+    pub const STREAMING: u32 = 1 << 2;
+    pub const GENERATING: u32 = 1 << 3;
+    pub const PIP: u32 = 1 << 4;
+}
+
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct TemporalUniformData {
+    pub history_weight: f32,
+    pub depth_threshold: f32,
+    pub normal_threshold: f32,
+    pub debug_view: u32,
+    // The fraction of the width of the screen after which the comparison buffer is shown.
+    pub comparison_divider: f32,
+    pub _padding0: [u32; 3],
+    // Where the camera is in the atmosphere volume, from 0 to 1 along each axis.
+    pub atmosphere_position: Vector3<f32>,
+    pub _padding1: u32,
+    // See WhiteBalance::to_uniform.
+    pub white_balance: [Vector4<f32>; 3],
+    // What light is multiplied by before tonemapping, see tonemap::get_exposure_scale.
+    pub exposure: f32,
+    // See Tonemapper::to_index.
+    pub tonemapper: u32,
+    // See OutputFormat::get_headroom.
+    pub output_headroom: f32,
+    pub dither_amplitude: f32,
+    // How many frames have been summed into the accumulation buffer including this one, zero
+    // when not accumulating. See Accumulation.
+    pub accumulated_frames: u32,
+}
+
+#[repr(C)]
+pub struct TextUniformData {
+    pub glyph_count: u32,
+    pub _padding0: [u32; 3],
+    pub glyphs: [GlyphInstance; MAX_GLYPHS],
+}
+
+/// The start of a buffer filled by a compaction stage, followed by one entry per item. Must match
+/// the WorkList blocks in the shaders.
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct WorkListHeader {
+    // Arguments for vkCmdDispatchIndirect, counting one work group per WORK_LIST_GROUP_SIZE items.
+    pub group_count_x: u32,
+    pub group_count_y: u32,
+    pub group_count_z: u32,
+    pub count: u32,
+}
+
+impl WorkListHeader {
+    pub fn empty() -> Self {
+        Self {
+            group_count_x: 0,
+            group_count_y: 1,
+            group_count_z: 1,
+            count: 0,
+        }
+    }
+
+    /// A header for a list whose items were written by the CPU instead of a compaction stage.
+    pub fn for_items(count: u32) -> Self {
+        let group_size = WORK_LIST_GROUP_SIZE as u32;
+        Self {
+            group_count_x: (count + group_size - 1) / group_size,
+            group_count_y: 1,
+            group_count_z: 1,
+            count,
+        }
+    }
+}
+
+/// A region of the world images where every block is the same, see terrain_fill.comp.
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct TerrainFill {
+    // In texels of the world images.
+    pub start: Vector3<u32>,
+    pub material: u32,
+    pub size: Vector3<u32>,
+    pub minefield: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct ProbeCapturePushData {
+    // Relative to region_offset.
+    pub position: Vector3<f32>,
+    pub probe: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct FogPushData {
+    // Whether fog.comp blends in the fog from last frame. The picture-in-picture view never does,
+    // since the old transform in its uniform belongs to the main camera.
+    pub use_history: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct EncodeOutputPushData {
+    // See OutputFormat::to_index.
+    pub output_format: u32,
 }
 
 #[repr(C)]
 #[derive(Clone, Debug)]
 pub struct DenoisePushData {
     pub size: i32,
+    // Only read by the lighting denoiser.
+    pub smooth_normals: u32,
+    // Only read by the lighting denoiser, see RenderSettings::denoise_gradient_weight.
+    pub gradient_weight: f32,
+    // Only read by the lighting denoiser, see RenderSettings::denoise_variance_weight.
+    pub variance_weight: f32,
+}
+
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct EstimateVariancePushData {
+    // Whether estimate_variance.comp can use the moments from the temporal stage. The
+    // picture-in-picture view has no temporal history, so it never does.
+    pub use_moments: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn work_list_groups_round_up() {
+        assert_eq!(WorkListHeader::for_items(0).group_count_x, 0);
+        assert_eq!(WorkListHeader::for_items(1).group_count_x, 1);
+        let group_size = WORK_LIST_GROUP_SIZE as u32;
+        assert_eq!(WorkListHeader::for_items(group_size).group_count_x, 1);
+        assert_eq!(WorkListHeader::for_items(group_size + 1).group_count_x, 2);
+    }
 }
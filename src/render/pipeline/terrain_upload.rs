@@ -1,21 +1,93 @@
 use crate::render::constants::*;
-use crate::render::general::command_buffer::CommandBuffer;
 use crate::render::general::core::Core;
+use crate::render::general::recording::{HostBuffer, TransferCommands};
 use crate::render::general::structures::Buffer;
-use crate::render::pipeline::render_data::RenderData;
+use crate::render::pipeline::structs::TerrainFill;
+use crate::render::world_mapping::block_to_texel;
+use crate::render::RenderSettings;
 use crate::util::{self, prelude::*};
-use crate::world::ChunkStorage;
+use crate::world::{ChunkOccupancy, ChunkStorage, PackedChunkData};
 use ash::vk;
+use cgmath::Vector3;
+use rayon::prelude::*;
+use std::collections::HashMap;
 use std::rc::Rc;
 
+/// How many queued slices along the same axis can be packed and uploaded in a single step.
+const MAX_MERGED_SLICES: usize = 4;
+/// How many modified chunks can be re-uploaded in a single step.
+const MAX_DIRTY_CHUNKS_PER_STEP: usize = 8;
+/// Added to the priority of chunks which the camera has not seen, so that every chunk which has
+/// been seen is generated first.
+const UNSEEN_CHUNK_PRIORITY: u32 = 1 << 16;
+/// Each block is stored as a u32 in the material image and a u8 in the minefield image.
+const BYTES_PER_BLOCK: usize = std::mem::size_of::<u32>() + std::mem::size_of::<u8>();
+
 /// Upon consuming this request, the next slice along the specified axis will be uploaded.
 struct TerrainUploadRequest {
     origin: SignedCoord3D,
-    // [0, ROOT_BLOCK_SIZE / SLICE_SIZE), how many slices to offset in each axis.
+    // [0, root_block_size / SLICE_SIZE), how many slices to offset in each axis.
     num_slices: Coord3D,
     axis: Axis,
+    // True if this request was made by request_increase, false if by request_decrease.
+    increase: bool,
     // What position the buffer will be at after the request is completed.
     new_position: Position,
+    // Which upload step the request was made during, see TerrainUploadManager::step.
+    queued_step: u64,
+}
+
+impl TerrainUploadRequest {
+    /// True if executing this request immediately after other would undo other's effects.
+    fn cancels(&self, other: &Self) -> bool {
+        self.axis == other.axis && self.increase != other.increase
+    }
+
+    /// True if this request can be uploaded in the same step as other.
+    fn can_merge_with(&self, other: &Self) -> bool {
+        self.axis == other.axis && self.increase == other.increase
+    }
+
+    /// Returns the first block (inclusive) and last block (exclusive) of the world that the slice
+    /// loaded by this request covers.
+    fn block_range(&self) -> (SignedCoord3D, SignedCoord3D) {
+        let root_block_size = self.new_position.root_block_size() as isize;
+        let slice_size = SLICE_SIZE as isize;
+        let start = self
+            .origin
+            .scale(CHUNK_SIZE as _)
+            .add(self.num_slices.scale(SLICE_SIZE).signed());
+        let size = match self.axis {
+            Axis::X => (slice_size, root_block_size, root_block_size),
+            Axis::Y => (root_block_size, slice_size, root_block_size),
+            Axis::Z => (root_block_size, root_block_size, slice_size),
+        };
+        (start, start.add(size))
+    }
+}
+
+/// Adds a request to the end of the queue. If the request undoes the last request in the queue,
+/// both are dropped instead since executing them would just do useless work.
+fn enqueue_request(queue: &mut Vec<TerrainUploadRequest>, request: TerrainUploadRequest) {
+    if let Some(last) = queue.last() {
+        if request.cancels(last) {
+            queue.pop();
+            return;
+        }
+    }
+    queue.push(request);
+}
+
+/// Removes a run of requests from the start of the queue that can all be uploaded in one step.
+fn take_merged_requests(queue: &mut Vec<TerrainUploadRequest>) -> Vec<TerrainUploadRequest> {
+    let mut count = 0;
+    while count < queue.len().min(MAX_MERGED_SLICES) {
+        if count > 0 && !queue[count].can_merge_with(&queue[0]) {
+            break;
+        }
+        count += 1;
+    }
+    queue.drain(0..count).collect()
 }
 
 // This stores the origin of the current region and how many slices of the next region have been
@@ -24,328 +96,901 @@ struct TerrainUploadRequest {
 struct Position {
     origin: SignedCoord3D,
     num_loaded_slices: Coord3D,
+    // How many chunks a region spans along each axis.
+    root_chunk_size: usize,
 }
 
 impl Position {
+    /// Creates a position where the region is centered around the origin of the world.
+    fn new(root_chunk_size: usize) -> Self {
+        let coord = root_chunk_size as isize / 2;
+        Self {
+            origin: (-coord, -coord, -coord),
+            num_loaded_slices: (0, 0, 0),
+            root_chunk_size,
+        }
+    }
+
+    fn root_block_size(&self) -> usize {
+        self.root_chunk_size * CHUNK_SIZE
+    }
+
+    /// Moves the region forward by one slice along the given axis. Returns the origin and slice
+    /// counts of the request which loads the slice that just came into range.
+    fn advance(&mut self, axis: Axis) -> (SignedCoord3D, Coord3D) {
+        let old_origin = self.origin;
+        let old_num_slices = self.num_loaded_slices;
+        let slices_per_region = self.root_block_size() / SLICE_SIZE;
+        let root_chunk_size = self.root_chunk_size as isize;
+        let (coord, num_slices) = self.axis_mut(axis);
+        *num_slices += 1;
+        if *num_slices == slices_per_region {
+            *num_slices = 0;
+            *coord += root_chunk_size;
+        }
+        // This makes it load the data from the next region instead of the current region.
+        let origin_offset = match axis {
+            Axis::X => (root_chunk_size, 0, 0),
+            Axis::Y => (0, root_chunk_size, 0),
+            Axis::Z => (0, 0, root_chunk_size),
+        };
+        (old_origin.add(origin_offset), old_num_slices)
+    }
+
+    /// Moves the region back by one slice along the given axis. Returns the origin and slice
+    /// counts of the request which loads the slice that just came into range.
+    fn retreat(&mut self, axis: Axis) -> (SignedCoord3D, Coord3D) {
+        let slices_per_region = self.root_block_size() / SLICE_SIZE;
+        let root_chunk_size = self.root_chunk_size as isize;
+        // Rewind the coordinate to the previous slice and then load it from the current region.
+        let (coord, num_slices) = self.axis_mut(axis);
+        if *num_slices == 0 {
+            *num_slices = slices_per_region;
+            *coord -= root_chunk_size;
+        }
+        *num_slices -= 1;
+        (self.origin, self.num_loaded_slices)
+    }
+
+    fn axis_mut(&mut self, axis: Axis) -> (&mut isize, &mut usize) {
+        match axis {
+            Axis::X => (&mut self.origin.0, &mut self.num_loaded_slices.0),
+            Axis::Y => (&mut self.origin.1, &mut self.num_loaded_slices.1),
+            Axis::Z => (&mut self.origin.2, &mut self.num_loaded_slices.2),
+        }
+    }
+
     fn render_offset(&self) -> SignedCoord3D {
-        const COORD: isize = ROOT_CHUNK_SIZE as isize / 2;
+        let coord = self.root_chunk_size as isize / 2;
         self.origin
-            .add(COORD.repeat())
+            .add(coord.repeat())
             .scale(CHUNK_SIZE as _)
             .add(self.num_loaded_slices.scale(SLICE_SIZE).signed())
     }
+
+    /// Returns the first block (inclusive) and last block (exclusive) of the world that is stored
+    /// in the world images when the buffer is at this position.
+    fn loaded_block_range(&self) -> (SignedCoord3D, SignedCoord3D) {
+        let start = self
+            .origin
+            .scale(CHUNK_SIZE as _)
+            .add(self.num_loaded_slices.scale(SLICE_SIZE).signed());
+        (start, start.add((self.root_block_size() as isize).repeat()))
+    }
 }
 
-impl Default for Position {
-    fn default() -> Self {
-        const COORD: isize = ROOT_CHUNK_SIZE as isize / 2;
-        Self {
-            origin: (-COORD, -COORD, -COORD),
-            num_loaded_slices: (0, 0, 0),
+/// Returns how urgently a chunk needs to be generated, based on whether the camera has seen it and
+/// how far it is from the center of the region at the given position. Lower values are more
+/// urgent.
+fn chunk_priority(position: &Position, chunk_coord: SignedCoord3D, seen: bool) -> u32 {
+    let half = position.root_chunk_size as isize / 2;
+    let delta = chunk_coord.sub(position.origin.add(half.repeat()));
+    let distance = (delta.0.abs() + delta.1.abs() + delta.2.abs()) as u32;
+    if seen {
+        distance
+    } else {
+        distance + UNSEEN_CHUNK_PRIORITY
+    }
+}
+
+/// Returns which chunk a block is in.
+fn block_to_chunk(block: SignedCoord3D) -> SignedCoord3D {
+    let size = CHUNK_SIZE as isize;
+    (
+        block.0.div_euclid(size),
+        block.1.div_euclid(size),
+        block.2.div_euclid(size),
+    )
+}
+
+/// Must match the CHUNK_ constants in raytrace_common.glsl.
+fn occupancy_bits(occupancy: ChunkOccupancy) -> u32 {
+    match occupancy {
+        ChunkOccupancy::Mixed => 0,
+        ChunkOccupancy::Empty => 1,
+        ChunkOccupancy::Solid => 2,
+    }
+}
+
+fn make_fill(texel: Coord3D, size: Coord3D, chunk: &PackedChunkData) -> TerrainFill {
+    TerrainFill {
+        start: Vector3::new(texel.0 as u32, texel.1 as u32, texel.2 as u32),
+        material: chunk.materials[0],
+        size: Vector3::new(size.0 as u32, size.1 as u32, size.2 as u32),
+        minefield: chunk.minefield[0] as u32,
+    }
+}
+
+/// The dimensions of the data packed into the upload buffers for a single slice.
+fn slice_data_shape(axis: Axis, root_block_size: usize) -> Coord3D {
+    match axis {
+        Axis::X => (SLICE_SIZE, root_block_size, root_block_size),
+        Axis::Y => (root_block_size, SLICE_SIZE, root_block_size),
+        Axis::Z => (root_block_size, root_block_size, SLICE_SIZE),
+    }
+}
+
+/// Returns which texel of the world images the corner of a slice is stored at, given how many
+/// slices the request it belongs to is offset by.
+fn slice_texel_offset(axis: Axis, num_slices: Coord3D, root_block_size: usize) -> Coord3D {
+    let axis_num_slices = match axis {
+        Axis::X => num_slices.0,
+        Axis::Y => num_slices.1,
+        Axis::Z => num_slices.2,
+    };
+    let axis_offset = axis_num_slices % (root_block_size / SLICE_SIZE) * SLICE_SIZE;
+    match axis {
+        Axis::X => (axis_offset, 0, 0),
+        Axis::Y => (0, axis_offset, 0),
+        Axis::Z => (0, 0, axis_offset),
+    }
+}
+
+/// The part of a slice which comes from a single chunk.
+#[derive(Clone, Debug, PartialEq)]
+struct SlicePiece {
+    // Which chunk the piece comes from, relative to the origin of the request.
+    chunk_offset: Coord3D,
+    // The coordinate inside the chunk to start copying from.
+    copy_start: Coord3D,
+    copy_size: Coord3D,
+    // Where the piece goes, in blocks from the corner of the slice.
+    target_start: Coord3D,
+}
+
+/// Splits a slice into the pieces which come from each chunk it touches. Together the pieces
+/// cover every block of slice_data_shape exactly once.
+fn slice_pieces(axis: Axis, num_slices: Coord3D, root_chunk_size: usize) -> Vec<SlicePiece> {
+    // The maximum boundaries of the data that will be copied from each chunk.
+    let chunk_area_shape = match axis {
+        Axis::X => (SLICE_SIZE, CHUNK_SIZE, CHUNK_SIZE),
+        Axis::Y => (CHUNK_SIZE, SLICE_SIZE, CHUNK_SIZE),
+        Axis::Z => (CHUNK_SIZE, CHUNK_SIZE, SLICE_SIZE),
+    };
+    // We only need to start copying chunks at this offset (+ the request origin).
+    let chunk_offset = num_slices.shrink(SLICES_PER_CHUNK);
+    // How far into the first chunk we should start copying from. (Also how much we need to copy
+    // from the last chunk.)
+    let area_start = num_slices.wrap(SLICES_PER_CHUNK.repeat()).scale(SLICE_SIZE);
+    let mut pieces = Vec::new();
+    for (d1, d2) in util::coord_iter_2d(root_chunk_size + 1) {
+        // Which piece of the slice we are currently copying.
+        let piece_offset = match axis {
+            Axis::X => (0, d1, d2),
+            Axis::Y => (d1, 0, d2),
+            Axis::Z => (d1, d2, 0),
+        };
+        let mut copy_start = (0, 0, 0);
+        // Basically if we are copying from a chunk at the start of a particular axis, the
+        // coordinate we start copying from inside that chunk should have the start coordinate
+        // specified by area_start on that axis.
+        if piece_offset.0 == 0 {
+            copy_start.0 = area_start.0;
+        }
+        if piece_offset.1 == 0 {
+            copy_start.1 = area_start.1;
+        }
+        if piece_offset.2 == 0 {
+            copy_start.2 = area_start.2;
+        }
+        // Copying should end before this coordinate on all axes. size = copy_end - copy_start.
+        let mut copy_end = (CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE);
+        // Basically if we are copying from a chunk at the end of a particular axis, the
+        // coordinate we start copying before should be specified by area_start. In combination
+        // with the previous effect, we will always end up copying a root_block_size sized
+        // chunk of data.
+        if piece_offset.0 == root_chunk_size {
+            copy_end.0 = area_start.0;
+        }
+        if piece_offset.1 == root_chunk_size {
+            copy_end.1 = area_start.1;
+        }
+        if piece_offset.2 == root_chunk_size {
+            copy_end.2 = area_start.2;
+        }
+        // Also we should end copying at start + SLICE_SIZE along the main axis of the slice.
+        match axis {
+            Axis::X => copy_end.0 = copy_start.0 + SLICE_SIZE,
+            Axis::Y => copy_end.1 = copy_start.1 + SLICE_SIZE,
+            Axis::Z => copy_end.2 = copy_start.2 + SLICE_SIZE,
+        }
+        let copy_size = copy_end.sub(copy_start);
+        if copy_size.0 == 0 || copy_size.1 == 0 || copy_size.2 == 0 {
+            continue;
+        }
+        assert!(copy_size.inside(chunk_area_shape));
+        // Compute generally where we should copy the data to (which chunk)
+        let target_start = piece_offset
+            .add(match axis {
+                Axis::X => (0, chunk_offset.1, chunk_offset.2),
+                Axis::Y => (chunk_offset.0, 0, chunk_offset.2),
+                Axis::Z => (chunk_offset.0, chunk_offset.1, 0),
+            })
+            .wrap(root_chunk_size.repeat())
+            .scale(CHUNK_SIZE);
+        // If we copied with an offset on an off axis, the destination should have that same
+        // offset on that same off axis. Don't copy the main axis offset because that one picks
+        // out data for this particular slice, and the buffer is only one slice long along the
+        // main axis.
+        let target_offset = match axis {
+            Axis::X => (0, copy_start.1, copy_start.2),
+            Axis::Y => (copy_start.0, 0, copy_start.2),
+            Axis::Z => (copy_start.0, copy_start.1, 0),
+        };
+        pieces.push(SlicePiece {
+            chunk_offset: piece_offset.add(chunk_offset),
+            copy_start,
+            copy_size,
+            target_start: target_start.add(target_offset),
+        });
+    }
+    pieces
+}
+
+/// Splits the pieces of a slice into batches which can each be loaded and packed by pack_pieces
+/// at once. Pieces which only differ along Z always end up in the same batch, and whole columns
+/// of them are added to a batch until it has at least batch_size pieces. Slices along X or Y
+/// have columns of pieces stacked along Z, slices along Z are only one piece deep.
+fn slice_batches(mut pieces: Vec<SlicePiece>, batch_size: usize) -> Vec<Vec<SlicePiece>> {
+    pieces.sort_by_key(|piece| (piece.target_start.1, piece.target_start.0));
+    let mut batches: Vec<Vec<SlicePiece>> = Vec::new();
+    for piece in pieces {
+        let in_column = |batch: &Vec<SlicePiece>| {
+            let last = batch.last().unwrap().target_start;
+            (last.0, last.1) == (piece.target_start.0, piece.target_start.1)
+        };
+        match batches.last_mut() {
+            Some(batch) if batch.len() < batch_size || in_column(batch) => batch.push(piece),
+            _ => batches.push(vec![piece]),
         }
     }
+    batches
 }
 
-pub struct TerrainUploadManager {
-    core: Rc<Core>,
-    minefield_upload_buffer: Buffer<u8>,
-    material_upload_buffer: Buffer<u32>,
+/// Copies each piece out of the data of the chunk it comes from into the data for a slice, which
+/// has the shape data_shape. The slice is split into bands along Z which are packed in parallel,
+/// each of which only copies the parts of the pieces that fall inside it.
+fn pack_pieces<T: Copy + Send + Sync>(
+    pieces: &[(&[T], &SlicePiece)],
+    target: &mut [T],
+    data_shape: Coord3D,
+) {
+    let layer_len = data_shape.0 * data_shape.1;
+    let band_depth = data_shape.2.div_ceil(rayon::current_num_threads());
+    target
+        .par_chunks_mut(layer_len * band_depth)
+        .enumerate()
+        .for_each(|(band, band_data)| {
+            let band_start = (0, 0, (band * band_depth) as isize);
+            let band_shape = (data_shape.0, data_shape.1, band_data.len() / layer_len);
+            for (source, piece) in pieces {
+                util::copy_3d_bounded_auto_clip(
+                    piece.copy_size,
+                    source,
+                    CHUNK_SIZE.repeat(),
+                    piece.copy_start,
+                    band_data,
+                    band_shape,
+                    piece.target_start.signed().sub(band_start),
+                );
+            }
+        });
+}
+
+/// How many fills can be queued in a single step, one for each piece of each merged slice and
+/// one for each copy of each dirty chunk.
+pub fn max_terrain_fills(root_chunk_size: usize) -> usize {
+    let pieces = (root_chunk_size + 1) * (root_chunk_size + 1);
+    // A chunk can be split across the edges of the region along all three axes.
+    MAX_MERGED_SLICES * pieces + MAX_DIRTY_CHUNKS_PER_STEP * 8
+}
+
+/// How many u32s write_chunk_occupancy needs for a region of the given size.
+pub fn chunk_occupancy_len(root_chunk_size: usize) -> usize {
+    (root_chunk_size * root_chunk_size * root_chunk_size + 15) / 16
+}
+
+/// The world images which terrain is uploaded to.
+#[derive(Clone, Copy)]
+pub struct TerrainImages {
+    pub material: vk::Image,
+    pub minefield: vk::Image,
+}
+
+impl TerrainImages {
+    fn all(&self) -> [vk::Image; 2] {
+        [self.material, self.minefield]
+    }
+}
+
+/// The buffer types default to the ones the pipeline uses, tests substitute mock buffers so that
+/// no device is needed.
+pub struct TerrainUploadManager<MaterialBuffer = Buffer<u32>, MinefieldBuffer = Buffer<u8>> {
+    root_chunk_size: usize,
+    root_block_size: usize,
+    // The number of elements in the upload buffers taken up by a single slice.
+    slice_volume: usize,
+    minefield_upload_buffer: MinefieldBuffer,
+    material_upload_buffer: MaterialBuffer,
+    dirty_minefield_upload_buffer: MinefieldBuffer,
+    dirty_material_upload_buffer: MaterialBuffer,
     request_queue: Vec<TerrainUploadRequest>,
     cpu_position: Position,
     gpu_position: Position,
+    // Total size of all the terrain data copied to the GPU so far.
+    bytes_uploaded: u64,
+    // How many times record_upload has been called, used to tell how long requests have waited.
+    step: u64,
+    // The occupancy of each chunk at least partly copied to the GPU, by world chunk coordinate.
+    uploaded_occupancy: HashMap<SignedCoord3D, ChunkOccupancy>,
+    // Whether uploaded_occupancy has changed since write_chunk_occupancy was last called.
+    occupancy_changed: bool,
+    // Regions to be filled in by terrain_fill.comp instead of copied, along with the world chunk
+    // coordinate of the chunk each one is part of.
+    pending_fills: Vec<(SignedCoord3D, TerrainFill)>,
+    // Chunks which pack_slice reads from disk are read into these, so that they only have to be
+    // allocated once.
+    chunk_buffers: Vec<PackedChunkData>,
+}
+
+/// How many blocks the upload buffers for merged slices need to hold.
+fn slice_upload_len(root_block_size: usize) -> usize {
+    // Enough space to upload several merged slices at a time.
+    root_block_size * root_block_size * SLICE_SIZE * MAX_MERGED_SLICES
 }
 
+/// How many blocks the upload buffers for dirty chunks need to hold.
+const DIRTY_UPLOAD_LEN: usize = CHUNK_VOLUME * MAX_DIRTY_CHUNKS_PER_STEP;
+
 impl TerrainUploadManager {
-    pub fn new(core: Rc<Core>) -> Self {
-        // Enough space to upload one slice at a time.
-        const SIZE: usize = ROOT_BLOCK_SIZE * ROOT_BLOCK_SIZE * SLICE_SIZE;
+    pub fn new(core: Rc<Core>, settings: &RenderSettings) -> Self {
+        let size = slice_upload_len(settings.root_block_size());
         let minefield_upload_buffer = Buffer::create(
             Rc::clone(&core),
             "tum_minefield_upload",
-            SIZE as u64,
+            size as u64,
             vk::BufferUsageFlags::TRANSFER_SRC,
         );
         let material_upload_buffer = Buffer::create(
             Rc::clone(&core),
             "tum_material_upload",
-            SIZE as u64,
+            size as u64,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+        );
+        let dirty_minefield_upload_buffer = Buffer::create(
+            Rc::clone(&core),
+            "tum_dirty_minefield_upload",
+            DIRTY_UPLOAD_LEN as u64,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+        );
+        let dirty_material_upload_buffer = Buffer::create(
+            Rc::clone(&core),
+            "tum_dirty_material_upload",
+            DIRTY_UPLOAD_LEN as u64,
             vk::BufferUsageFlags::TRANSFER_SRC,
         );
+        Self::with_buffers(
+            settings,
+            material_upload_buffer,
+            minefield_upload_buffer,
+            dirty_material_upload_buffer,
+            dirty_minefield_upload_buffer,
+        )
+    }
+}
+
+impl<MaterialBuffer, MinefieldBuffer> TerrainUploadManager<MaterialBuffer, MinefieldBuffer>
+where
+    MaterialBuffer: HostBuffer<u32>,
+    MinefieldBuffer: HostBuffer<u8>,
+{
+    /// The slice buffers must hold slice_upload_len items and the dirty buffers DIRTY_UPLOAD_LEN.
+    fn with_buffers(
+        settings: &RenderSettings,
+        material_upload_buffer: MaterialBuffer,
+        minefield_upload_buffer: MinefieldBuffer,
+        dirty_material_upload_buffer: MaterialBuffer,
+        dirty_minefield_upload_buffer: MinefieldBuffer,
+    ) -> Self {
+        let root_block_size = settings.root_block_size();
+        let slice_volume = root_block_size * root_block_size * SLICE_SIZE;
         Self {
-            core,
+            root_chunk_size: settings.root_chunk_size,
+            root_block_size,
+            slice_volume,
             minefield_upload_buffer,
             material_upload_buffer,
+            dirty_minefield_upload_buffer,
+            dirty_material_upload_buffer,
             request_queue: Vec::new(),
-            cpu_position: Position::default(),
-            gpu_position: Position::default(),
+            cpu_position: Position::new(settings.root_chunk_size),
+            gpu_position: Position::new(settings.root_chunk_size),
+            bytes_uploaded: 0,
+            step: 0,
+            uploaded_occupancy: HashMap::new(),
+            // Nothing has been uploaded yet, but the buffer still needs clearing.
+            occupancy_changed: true,
+            pending_fills: Vec::new(),
+            chunk_buffers: Vec::new(),
         }
     }
 
-    fn upload_slice(
+    /// Copies the data for the slice described by the request into the section of the upload
+    /// buffers reserved for the given slot. Pieces of chunks where every block is the same are
+    /// queued to be filled in instead. Returns the start and size of each piece that was copied,
+    /// in blocks from the corner of the slice.
+    fn pack_slice(
         &mut self,
-        commands: &mut CommandBuffer,
         chunks: &mut ChunkStorage,
-        data: &RenderData,
-        request: TerrainUploadRequest,
-    ) {
-        let mut mat_data = self.material_upload_buffer.bind_all();
-        let mut min_data = self.minefield_upload_buffer.bind_all();
+        request: &TerrainUploadRequest,
+        slot: usize,
+    ) -> Vec<(Coord3D, Coord3D)> {
+        let slot_range = slot * self.slice_volume..(slot + 1) * self.slice_volume;
+        let cpu_position = self.cpu_position.clone();
+        let chunk_buffers = &mut self.chunk_buffers;
+        let slice_offset =
+            slice_texel_offset(request.axis, request.num_slices, self.root_block_size);
         // The dimensions of the data that will be copied into the buffer and eventually copied
         // to the images on the GPU.
-        let data_shape = match request.axis {
-            Axis::X => (SLICE_SIZE, ROOT_BLOCK_SIZE, ROOT_BLOCK_SIZE),
-            Axis::Y => (ROOT_BLOCK_SIZE, SLICE_SIZE, ROOT_BLOCK_SIZE),
-            Axis::Z => (ROOT_BLOCK_SIZE, ROOT_BLOCK_SIZE, SLICE_SIZE),
-        };
-        // The maximum boundaries of the data that will be copied from each chunk.
-        let chunk_area_shape = match request.axis {
-            Axis::X => (SLICE_SIZE, CHUNK_SIZE, CHUNK_SIZE),
-            Axis::Y => (CHUNK_SIZE, SLICE_SIZE, CHUNK_SIZE),
-            Axis::Z => (CHUNK_SIZE, CHUNK_SIZE, SLICE_SIZE),
-        };
-        // We only need to start copying chunks at this offset (+ the request origin).
-        let chunk_offset = request.num_slices.shrink(SLICES_PER_CHUNK);
-        // How far into the first chunk we should start copying from. (Also how much we need to copy
-        // from the last chunk.)
-        let area_start = request
-            .num_slices
-            .wrap(SLICES_PER_CHUNK.repeat())
-            .scale(SLICE_SIZE);
-        for (d1, d2) in util::coord_iter_2d(ROOT_CHUNK_SIZE + 1) {
-            // Which piece of the slice we are currently copying.
-            let piece_offset = match request.axis {
-                Axis::X => (0, d1, d2),
-                Axis::Y => (d1, 0, d2),
-                Axis::Z => (d1, d2, 0),
+        let data_shape = slice_data_shape(request.axis, self.root_block_size);
+        let slice_pieces = slice_pieces(request.axis, request.num_slices, self.root_chunk_size);
+        let mut pieces = Vec::new();
+        let uploaded_occupancy = &mut self.uploaded_occupancy;
+        let pending_fills = &mut self.pending_fills;
+        let minefield_upload_buffer = &mut self.minefield_upload_buffer;
+        self.material_upload_buffer.with_mapped(|mat_data| {
+            minefield_upload_buffer.with_mapped(|min_data| {
+                // Only one batch of chunks is kept in memory at a time. The chunks in a batch are
+                // read in parallel and then copied from in parallel.
+                let batch_size = rayon::current_num_threads();
+                for batch in slice_batches(slice_pieces, batch_size) {
+                    // Which chunk each piece is loaded from.
+                    let requests: Vec<_> = batch
+                        .iter()
+                        .map(|piece| {
+                            let world_coord = piece.chunk_offset.signed().add(request.origin);
+                            let priority = chunk_priority(&cpu_position, world_coord, false);
+                            (world_coord, priority)
+                        })
+                        .collect();
+                    let loaded =
+                        chunks.load_packed_chunks_or_placeholders(&requests, chunk_buffers);
+                    let mut copies = Vec::new();
+                    for (index, piece) in batch.into_iter().enumerate() {
+                        let (chunk, world_coord) = (&loaded[index], requests[index].0);
+                        uploaded_occupancy.insert(world_coord, chunk.occupancy);
+                        if chunk.occupancy != ChunkOccupancy::Mixed {
+                            // Every block is the same, so there is no need to copy them one at a
+                            // time.
+                            let texel = slice_offset.add(piece.target_start);
+                            let fill = make_fill(texel, piece.copy_size, chunk);
+                            pending_fills.push((world_coord, fill));
+                            continue;
+                        }
+                        pieces.push((piece.target_start, piece.copy_size));
+                        copies.push((chunk, piece));
+                    }
+                    if copies.is_empty() {
+                        continue;
+                    }
+                    let materials: Vec<_> = copies
+                        .iter()
+                        .map(|(chunk, piece)| (&chunk.materials[..], piece))
+                        .collect();
+                    let minefield: Vec<_> = copies
+                        .iter()
+                        .map(|(chunk, piece)| (&chunk.minefield[..], piece))
+                        .collect();
+                    let mat_data = &mut mat_data[slot_range.clone()];
+                    let min_data = &mut min_data[slot_range.clone()];
+                    rayon::join(
+                        || pack_pieces(&materials, mat_data, data_shape),
+                        || pack_pieces(&minefield, min_data, data_shape),
+                    );
+                }
+            })
+        });
+        self.occupancy_changed = true;
+        pieces
+    }
+
+    /// Records commands to copy the pieces of the slice packed into the given slot to the world
+    /// images. The images must already be in the TRANSFER_DST_OPTIMAL layout.
+    fn record_slice_copy(
+        &mut self,
+        commands: &mut impl TransferCommands,
+        images: TerrainImages,
+        request: &TerrainUploadRequest,
+        slot: usize,
+        pieces: &[(Coord3D, Coord3D)],
+    ) {
+        let data_shape = slice_data_shape(request.axis, self.root_block_size);
+        let slice_offset =
+            slice_texel_offset(request.axis, request.num_slices, self.root_block_size);
+        for (start, size) in pieces {
+            let texel = slice_offset.add(*start);
+            let target_offset = vk::Offset3D {
+                x: texel.0 as i32,
+                y: texel.1 as i32,
+                z: texel.2 as i32,
             };
-            // Which chunk we are loading from.
-            let world_coord = piece_offset.add(chunk_offset).signed().add(request.origin);
-            let chunk =
-                chunks.borrow_packed_chunk_data(&(world_coord.0, world_coord.1, world_coord.2));
-            // The coordinate inside the chunk to start copying from.
-            let mut copy_start = (0, 0, 0);
-            // Basically if we are copying from a chunk at the start of a particular axis, the
-            // coordinate we start copying from inside that chunk should have the start coordinate
-            // specified by area_start on that axis.
-            if piece_offset.0 == 0 {
-                copy_start.0 = area_start.0;
-            }
-            if piece_offset.1 == 0 {
-                copy_start.1 = area_start.1;
-            }
-            if piece_offset.2 == 0 {
-                copy_start.2 = area_start.2;
-            }
-            // Copying should end before this coordinate on all axes. size = copy_end - copy_start.
-            let mut copy_end = (CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE);
-            // Basically if we are copying from a chunk at the end of a particular axis, the
-            // coordinate we start copying before should be specified by area_start. In combination
-            // with the previous effect, we will always end up copying a ROOT_BLOCK_SIZE sized
-            // chunk of data.
-            if piece_offset.0 == ROOT_CHUNK_SIZE {
-                copy_end.0 = area_start.0;
-            }
-            if piece_offset.1 == ROOT_CHUNK_SIZE {
-                copy_end.1 = area_start.1;
-            }
-            if piece_offset.2 == ROOT_CHUNK_SIZE {
-                copy_end.2 = area_start.2;
-            }
-            // Also we should end copying at start + SLICE_SIZE along the main axis of the slice.
-            match request.axis {
-                Axis::X => copy_end.0 = copy_start.0 + SLICE_SIZE,
-                Axis::Y => copy_end.1 = copy_start.1 + SLICE_SIZE,
-                Axis::Z => copy_end.2 = copy_start.2 + SLICE_SIZE,
-            }
-            // The size of the data that will be copied.
-            let copy_size = copy_end.sub(copy_start);
-            if copy_size.0 == 0 || copy_size.1 == 0 || copy_size.2 == 0 {
-                continue;
-            }
-            assert!(copy_size.inside(chunk_area_shape));
-            // Compute generally where we should copy the data to (which chunk)
-            let target_start = piece_offset
-                .add(match request.axis {
-                    Axis::X => (0, chunk_offset.1, chunk_offset.2),
-                    Axis::Y => (chunk_offset.0, 0, chunk_offset.2),
-                    Axis::Z => (chunk_offset.0, chunk_offset.1, 0),
-                })
-                .wrap(ROOT_CHUNK_SIZE.repeat())
-                .scale(CHUNK_SIZE)
-                .signed();
-            // If we copied with an offset on an off axis, the destination should have that same
-            // offset on that same off axis. Don't copy the main axis offset because that one picks
-            // out data for this particular slice, and the buffer is only one slice long along the
-            // main axis.
-            let target_offset = match request.axis {
-                Axis::X => (0, copy_start.1, copy_start.2),
-                Axis::Y => (copy_start.0, 0, copy_start.2),
-                Axis::Z => (copy_start.0, copy_start.1, 0),
+            let extent = vk::Extent3D {
+                width: size.0 as u32,
+                height: size.1 as u32,
+                depth: size.2 as u32,
             };
-            let target_start = target_start.add(target_offset.signed());
-            util::copy_3d_bounded_auto_clip(
-                copy_size,
-                &chunk.materials,
-                (CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE),
-                copy_start,
-                mat_data.as_slice_mut(),
-                data_shape,
-                target_start,
+            let offset = (slot * self.slice_volume + start.to_index(data_shape)) as u64;
+            self.bytes_uploaded += (size.0 * size.1 * size.2 * BYTES_PER_BLOCK) as u64;
+            commands.copy_buffer_to_image_offset(
+                &self.material_upload_buffer,
+                offset * std::mem::size_of::<u32>() as u64,
+                data_shape.0 as u32,
+                data_shape.1 as u32,
+                &images.material,
+                target_offset,
+                &extent,
             );
-            util::copy_3d_bounded_auto_clip(
-                copy_size,
-                &chunk.minefield,
-                (CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE),
-                copy_start,
-                min_data.as_slice_mut(),
-                data_shape,
-                target_start,
+            commands.copy_buffer_to_image_offset(
+                &self.minefield_upload_buffer,
+                offset * std::mem::size_of::<u8>() as u64,
+                data_shape.0 as u32,
+                data_shape.1 as u32,
+                &images.minefield,
+                target_offset,
+                &extent,
             );
         }
-        drop(mat_data);
-        drop(min_data);
-        let axis_num_slices = match request.axis {
-            Axis::X => request.num_slices.0,
-            Axis::Y => request.num_slices.1,
-            Axis::Z => request.num_slices.2,
-        };
-        let axis_offset = axis_num_slices % (ROOT_BLOCK_VOLUME / SLICE_SIZE) * SLICE_SIZE;
-        let target_offset = match request.axis {
-            Axis::X => vk::Offset3D {
-                x: axis_offset as i32,
-                y: 0,
-                z: 0,
-            },
-            Axis::Y => vk::Offset3D {
-                x: 0,
-                y: axis_offset as i32,
-                z: 0,
-            },
-            Axis::Z => vk::Offset3D {
-                x: 0,
-                y: 0,
-                z: axis_offset as i32,
-            },
-        };
-        let data_shape = vk::Extent3D {
-            width: data_shape.0 as u32,
-            height: data_shape.1 as u32,
-            depth: data_shape.2 as u32,
-        };
-        commands.transition_layout(
-            &data.material_image,
-            vk::ImageLayout::GENERAL,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-        );
-        commands.copy_buffer_to_image_offset(
-            &self.material_upload_buffer,
-            0,
-            data_shape.width,
-            data_shape.height,
-            &data.material_image,
-            target_offset,
-            &data_shape,
-        );
-        commands.transition_layout(
-            &data.material_image,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            vk::ImageLayout::GENERAL,
-        );
-        commands.transition_layout(
-            &data.minefield_image,
-            vk::ImageLayout::GENERAL,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-        );
-        commands.copy_buffer_to_image_offset(
-            &self.minefield_upload_buffer,
-            0,
-            data_shape.width,
-            data_shape.height,
-            &data.minefield_image,
-            target_offset,
-            &data_shape,
-        );
-        commands.transition_layout(
-            &data.minefield_image,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            vk::ImageLayout::GENERAL,
-        );
+    }
 
-        self.gpu_position = request.new_position;
+    fn setup_next_request(
+        &mut self,
+        commands: &mut impl TransferCommands,
+        chunks: &mut ChunkStorage,
+        images: TerrainImages,
+    ) {
+        let requests = take_merged_requests(&mut self.request_queue);
+        if requests.len() == 0 {
+            return;
+        }
+        let mut pieces = Vec::new();
+        for (slot, request) in requests.iter().enumerate() {
+            pieces.push(self.pack_slice(chunks, request, slot));
+        }
+        for image in images.all().iter() {
+            commands.transition_layout(
+                image,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            );
+        }
+        for (slot, request) in requests.iter().enumerate() {
+            self.record_slice_copy(commands, images, request, slot, &pieces[slot]);
+        }
+        for image in images.all().iter() {
+            commands.transition_layout(
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::GENERAL,
+            );
+        }
+        self.gpu_position = requests.last().unwrap().new_position.clone();
     }
 
-    pub fn setup_next_request(
+    /// Re-uploads the parts of chunks modified since they were last uploaded which are currently
+    /// stored on the GPU. Should be called after setup_next_request so that the region being
+    /// updated reflects any slices that were just loaded.
+    fn upload_dirty_chunks(
         &mut self,
-        commands: &mut CommandBuffer,
+        commands: &mut impl TransferCommands,
         chunks: &mut ChunkStorage,
-        data: &RenderData,
+        images: TerrainImages,
     ) {
-        if self.request_queue.len() == 0 {
+        let dirty_chunks = chunks.take_dirty_chunks(MAX_DIRTY_CHUNKS_PER_STEP);
+        if dirty_chunks.len() == 0 {
             return;
         }
-        let request = self.request_queue.remove(0);
-        self.upload_slice(commands, chunks, data, request);
+        let (loaded_start, loaded_end) = self.gpu_position.loaded_block_range();
+        // (buffer offset, size, texel) for each region that needs to be copied to the world images.
+        let mut copies = Vec::new();
+        let wrap = chunks.get_wrap();
+        let first_chunk = block_to_chunk(loaded_start);
+        let root_block_size = self.root_block_size;
+        let uploaded_occupancy = &mut self.uploaded_occupancy;
+        let pending_fills = &mut self.pending_fills;
+        let mut occupancy_changed = false;
+        let dirty_minefield_upload_buffer = &mut self.dirty_minefield_upload_buffer;
+        self.dirty_material_upload_buffer.with_mapped(|mat_data| {
+            dirty_minefield_upload_buffer.with_mapped(|min_data| {
+                for (slot, chunk_coord) in dirty_chunks.iter().enumerate() {
+                    // If the world wraps, more than one copy of the chunk can be partly loaded at
+                    // once. Together they never cover more than one chunk, so they all fit in the
+                    // same slot.
+                    let mut slot_start = slot * CHUNK_VOLUME;
+                    for copy_coord in wrap.copies_from(*chunk_coord, first_chunk) {
+                        let chunk_start = copy_coord.scale(CHUNK_SIZE as _);
+                        let chunk_end = chunk_start.add((CHUNK_SIZE as isize).repeat());
+                        let start = chunk_start.ewmax(loaded_start);
+                        let end = chunk_end.ewmin(loaded_end);
+                        if start.0 >= end.0 || start.1 >= end.1 || start.2 >= end.2 {
+                            // The chunk is not currently on the GPU, it will be loaded with the
+                            // new data whenever it comes into range.
+                            continue;
+                        }
+                        let size = end.sub(start);
+                        let size = (size.0 as usize, size.1 as usize, size.2 as usize);
+                        let source_start = start.sub(chunk_start);
+                        let source_start = (
+                            source_start.0 as usize,
+                            source_start.1 as usize,
+                            source_start.2 as usize,
+                        );
+                        let slot_range = slot_start..slot_start + size.0 * size.1 * size.2;
+                        let chunk = chunks.borrow_packed_chunk_data(chunk_coord);
+                        uploaded_occupancy.insert(copy_coord, chunk.occupancy);
+                        occupancy_changed = true;
+                        // A slice uploaded this step may have queued a fill with the old data.
+                        pending_fills.retain(|(coord, _)| *coord != copy_coord);
+                        let texel = block_to_texel(start, root_block_size);
+                        if chunk.occupancy != ChunkOccupancy::Mixed {
+                            let fill = make_fill(texel, size, chunk);
+                            pending_fills.push((copy_coord, fill));
+                            continue;
+                        }
+                        util::copy_3d(
+                            size,
+                            &chunk.materials,
+                            CHUNK_SIZE.repeat(),
+                            source_start,
+                            &mut mat_data[slot_range.clone()],
+                            size,
+                            (0, 0, 0),
+                        );
+                        util::copy_3d(
+                            size,
+                            &chunk.minefield,
+                            CHUNK_SIZE.repeat(),
+                            source_start,
+                            &mut min_data[slot_range.clone()],
+                            size,
+                            (0, 0, 0),
+                        );
+                        copies.push((slot_start, size, texel));
+                        slot_start = slot_range.end;
+                    }
+                }
+            })
+        });
+        if occupancy_changed {
+            self.occupancy_changed = true;
+        }
+        if copies.len() == 0 {
+            return;
+        }
+
+        for image in images.all().iter() {
+            commands.transition_layout(
+                image,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            );
+        }
+        for (slot_start, size, texel) in copies {
+            let slot_offset = slot_start as u64;
+            let offset = vk::Offset3D {
+                x: texel.0 as i32,
+                y: texel.1 as i32,
+                z: texel.2 as i32,
+            };
+            let extent = vk::Extent3D {
+                width: size.0 as u32,
+                height: size.1 as u32,
+                depth: size.2 as u32,
+            };
+            self.bytes_uploaded += (size.0 * size.1 * size.2 * BYTES_PER_BLOCK) as u64;
+            commands.copy_buffer_to_image_offset(
+                &self.dirty_material_upload_buffer,
+                slot_offset * std::mem::size_of::<u32>() as u64,
+                extent.width,
+                extent.height,
+                &images.material,
+                offset,
+                &extent,
+            );
+            commands.copy_buffer_to_image_offset(
+                &self.dirty_minefield_upload_buffer,
+                slot_offset * std::mem::size_of::<u8>() as u64,
+                extent.width,
+                extent.height,
+                &images.minefield,
+                offset,
+                &extent,
+            );
+        }
+        for image in images.all().iter() {
+            commands.transition_layout(
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::GENERAL,
+            );
+        }
+    }
+
+    /// Records everything needed to bring the world images up to date for this step: the next
+    /// merged slices, then any dirty chunks. The regions which should be filled in instead are
+    /// written to the fills buffer, and a barrier is recorded so that terrain_fill.comp sees the
+    /// copies. Returns how many fills need to be dispatched.
+    pub fn record_upload(
+        &mut self,
+        commands: &mut impl TransferCommands,
+        chunks: &mut ChunkStorage,
+        images: TerrainImages,
+        fills: &mut impl HostBuffer<TerrainFill>,
+    ) -> u32 {
+        self.step += 1;
+        self.setup_next_request(commands, chunks, images);
+        self.upload_dirty_chunks(commands, chunks, images);
+        let fill_count = fills.with_mapped(|fills| self.take_fills(fills));
+        if fill_count > 0 {
+            // The fills can cover parts of chunks that were just copied over.
+            commands.memory_barrier(
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+            );
+        }
+        fill_count
+    }
+
+    /// Makes chunks which the camera saw in a frame more urgent to generate than the ones it did
+    /// not, so that the placeholders in view are filled in first. The mask holds a value for each
+    /// chunk of the world images, see ChunkAccessMask in traverse.comp.
+    pub fn prioritize_seen_chunks(&self, chunks: &mut ChunkStorage, access_mask: &[u32]) {
+        let (start, end) = self.gpu_position.loaded_block_range();
+        let first = block_to_chunk(start);
+        let last = block_to_chunk(end.sub(1isize.repeat()));
+        let size = self.root_chunk_size as isize;
+        // The region is not always aligned to chunks, so it can touch one more chunk than it
+        // spans along each axis. Those two chunks share a value in the mask.
+        for offset in util::coord_iter_3d(self.root_chunk_size + 1) {
+            let coord = first.add(offset.signed());
+            if !coord.inside(last) {
+                continue;
+            }
+            let slot = (
+                coord.0.rem_euclid(size) as usize,
+                coord.1.rem_euclid(size) as usize,
+                coord.2.rem_euclid(size) as usize,
+            );
+            if access_mask[slot.to_index(self.root_chunk_size.repeat())] != 0 {
+                let priority = chunk_priority(&self.cpu_position, coord, true);
+                chunks.prioritize_placeholder(&coord, priority);
+            }
+        }
+    }
+
+    /// Writes two bits for each chunk of the world images to the given buffer, laid out like the
+    /// images themselves. Where the region is not aligned to chunks, the chunks which share part
+    /// of the images only count as homogeneous if they are the same. Does nothing if no chunks
+    /// have been uploaded since it was last called. See get_chunk_occupancy in
+    /// raytrace_common.glsl.
+    pub fn write_chunk_occupancy(&mut self, target: &mut [u32]) {
+        if !self.occupancy_changed {
+            return;
+        }
+        self.occupancy_changed = false;
+        let (start, end) = self.gpu_position.loaded_block_range();
+        let first = block_to_chunk(start);
+        let last = block_to_chunk(end.sub(1isize.repeat()));
+        // Chunks which have left the region will be uploaded again if they come back.
+        self.uploaded_occupancy
+            .retain(|coord, _| first.inside(*coord) && coord.inside(last));
+        let slots = self.root_chunk_size.repeat();
+        let mut occupancy = vec![None; slots.0 * slots.1 * slots.2];
+        for offset in util::coord_iter_3d(self.root_chunk_size + 1) {
+            let coord = first.add(offset.signed());
+            if !coord.inside(last) {
+                continue;
+            }
+            let texel = block_to_texel(coord.scale(CHUNK_SIZE as _), self.root_block_size);
+            let slot = &mut occupancy[texel.shrink(CHUNK_SIZE).to_index(slots)];
+            let chunk = self.uploaded_occupancy.get(&coord).cloned();
+            let chunk = chunk.unwrap_or(ChunkOccupancy::Mixed);
+            *slot = match *slot {
+                Some(other) if other != chunk => Some(ChunkOccupancy::Mixed),
+                _ => Some(chunk),
+            };
+        }
+        for value in target.iter_mut() {
+            *value = 0;
+        }
+        for (index, slot) in occupancy.iter().enumerate() {
+            let bits = occupancy_bits(slot.unwrap_or(ChunkOccupancy::Mixed));
+            target[index / 16] |= bits << (index % 16 * 2);
+        }
+    }
+
+    /// Writes the regions which setup_next_request and upload_dirty_chunks queued to be filled in
+    /// to the given buffer, returning how many there are. See terrain_fill.comp.
+    pub fn take_fills(&mut self, target: &mut [TerrainFill]) -> u32 {
+        let count = self.pending_fills.len();
+        for (index, (_, fill)) in self.pending_fills.drain(..).enumerate() {
+            target[index] = fill;
+        }
+        count as u32
+    }
+
+    /// True if there are slices which have been requested but not uploaded yet.
+    pub fn is_busy(&self) -> bool {
+        self.request_queue.len() > 0
     }
 
     pub fn get_render_offset(&self) -> SignedCoord3D {
         self.gpu_position.render_offset()
     }
 
+    /// Returns the first block (inclusive) and last block (exclusive) of the world currently
+    /// stored in the world images.
+    pub fn get_loaded_block_range(&self) -> (SignedCoord3D, SignedCoord3D) {
+        self.gpu_position.loaded_block_range()
+    }
+
+    /// Returns the blocks covered by each slice which has been requested but not uploaded yet,
+    /// along with how many upload steps ago it was requested.
+    pub fn get_queued_slices(&self) -> Vec<((SignedCoord3D, SignedCoord3D), u64)> {
+        self.request_queue
+            .iter()
+            .map(|request| (request.block_range(), self.step - request.queued_step))
+            .collect()
+    }
+
+    /// Total size of the terrain data streamed to the GPU so far, in bytes.
+    pub fn get_bytes_uploaded(&self) -> u64 {
+        self.bytes_uploaded
+    }
+
     pub fn request_increase(&mut self, axis: Axis) {
-        // Load the next slice then increment the number of loaded slices.
-        let old_position = self.cpu_position.clone();
-        let num_slices = match axis {
-            Axis::X => &mut self.cpu_position.num_loaded_slices.0,
-            Axis::Y => &mut self.cpu_position.num_loaded_slices.1,
-            Axis::Z => &mut self.cpu_position.num_loaded_slices.2,
-        };
-        let coord = match axis {
-            Axis::X => &mut self.cpu_position.origin.0,
-            Axis::Y => &mut self.cpu_position.origin.1,
-            Axis::Z => &mut self.cpu_position.origin.2,
-        };
-        *num_slices += 1;
-        if *num_slices == ROOT_BLOCK_SIZE / SLICE_SIZE {
-            *num_slices = 0;
-            *coord += (ROOT_BLOCK_SIZE / CHUNK_SIZE) as isize;
-        }
-        // This makes it load the data from the next region instead of the current region.
-        let origin_offset = match axis {
-            Axis::X => (ROOT_CHUNK_SIZE, 0, 0),
-            Axis::Y => (0, ROOT_CHUNK_SIZE, 0),
-            Axis::Z => (0, 0, ROOT_CHUNK_SIZE),
-        };
-        self.request_queue.push(TerrainUploadRequest {
-            origin: old_position.origin.add(origin_offset.signed()),
-            num_slices: old_position.num_loaded_slices,
+        let (origin, num_slices) = self.cpu_position.advance(axis);
+        let request = TerrainUploadRequest {
+            origin,
+            num_slices,
             axis,
+            increase: true,
             new_position: self.cpu_position.clone(),
-        });
+            queued_step: self.step,
+        };
+        enqueue_request(&mut self.request_queue, request);
     }
 
     pub fn request_decrease(&mut self, axis: Axis) {
-        // Rewind the coordinate to the previous slice and then load it from the current region.
-        let num_slices = match axis {
-            Axis::X => &mut self.cpu_position.num_loaded_slices.0,
-            Axis::Y => &mut self.cpu_position.num_loaded_slices.1,
-            Axis::Z => &mut self.cpu_position.num_loaded_slices.2,
-        };
-        let coord = match axis {
-            Axis::X => &mut self.cpu_position.origin.0,
-            Axis::Y => &mut self.cpu_position.origin.1,
-            Axis::Z => &mut self.cpu_position.origin.2,
-        };
-        if *num_slices == 0 {
-            *num_slices = ROOT_BLOCK_SIZE / SLICE_SIZE;
-            *coord -= (ROOT_BLOCK_SIZE / CHUNK_SIZE) as isize;
-        }
-        *num_slices -= 1;
-        self.request_queue.push(TerrainUploadRequest {
-            origin: self.cpu_position.origin,
-            num_slices: self.cpu_position.num_loaded_slices,
+        let (origin, num_slices) = self.cpu_position.retreat(axis);
+        let request = TerrainUploadRequest {
+            origin,
+            num_slices,
             axis,
+            increase: false,
             new_position: self.cpu_position.clone(),
-        });
+            queued_step: self.step,
+        };
+        enqueue_request(&mut self.request_queue, request);
     }
 
     pub fn request_move_towards(&mut self, desired_center: SignedCoord3D) {
@@ -366,3 +1011,420 @@ impl TerrainUploadManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::general::recording::mock::{MockBuffer, MockCommands, RecordedCommand};
+    use crate::render::Material;
+    use crate::world::test_util::make_temp_dir;
+    use ash::vk::Handle;
+    use std::path::PathBuf;
+
+    fn make_request(axis: Axis, increase: bool) -> TerrainUploadRequest {
+        TerrainUploadRequest {
+            origin: (0, 0, 0),
+            num_slices: (0, 0, 0),
+            axis,
+            increase,
+            new_position: Position::new(4),
+            queued_step: 0,
+        }
+    }
+
+    #[test]
+    fn opposite_requests_cancel() {
+        let mut queue = Vec::new();
+        enqueue_request(&mut queue, make_request(Axis::X, true));
+        enqueue_request(&mut queue, make_request(Axis::X, true));
+        enqueue_request(&mut queue, make_request(Axis::X, false));
+        assert_eq!(queue.len(), 1);
+        enqueue_request(&mut queue, make_request(Axis::Y, false));
+        assert_eq!(queue.len(), 2);
+        enqueue_request(&mut queue, make_request(Axis::Y, true));
+        enqueue_request(&mut queue, make_request(Axis::X, false));
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn initial_position_maps_to_whole_image() {
+        for &root_chunk_size in &[2, 4, 8] {
+            let position = Position::new(root_chunk_size);
+            let root_block_size = position.root_block_size();
+            let (start, end) = position.loaded_block_range();
+            assert_eq!(block_to_texel(start, root_block_size), (0, 0, 0));
+            let last = end.sub(1isize.repeat());
+            assert_eq!(
+                block_to_texel(last, root_block_size),
+                (root_block_size - 1).repeat()
+            );
+        }
+    }
+
+    #[test]
+    fn seen_chunks_come_first() {
+        let position = Position::new(4);
+        let far_seen = chunk_priority(&position, (6, 6, 6), true);
+        let near_unseen = chunk_priority(&position, (0, 0, 0), false);
+        assert!(far_seen < near_unseen);
+        assert!(chunk_priority(&position, (0, 0, 0), true) < far_seen);
+        assert_eq!(block_to_chunk((-1, 63, 64)), (-1, 0, 1));
+    }
+
+    #[test]
+    fn merges_runs_along_same_axis() {
+        let mut queue = Vec::new();
+        for _ in 0..MAX_MERGED_SLICES + 1 {
+            enqueue_request(&mut queue, make_request(Axis::Z, true));
+        }
+        enqueue_request(&mut queue, make_request(Axis::X, true));
+        assert_eq!(take_merged_requests(&mut queue).len(), MAX_MERGED_SLICES);
+        assert_eq!(take_merged_requests(&mut queue).len(), 1);
+        assert_eq!(take_merged_requests(&mut queue).len(), 1);
+        assert_eq!(take_merged_requests(&mut queue).len(), 0);
+    }
+
+    #[test]
+    fn slice_pieces_cover_slice_once() {
+        let root_chunk_size = 4;
+        let root_block_size = root_chunk_size * CHUNK_SIZE;
+        for &axis in &[Axis::X, Axis::Y, Axis::Z] {
+            for num_slices in &[(0, 0, 0), (1, 2, 3), (5, 0, 15), (15, 15, 15)] {
+                let shape = slice_data_shape(axis, root_block_size);
+                let mut covered = vec![0; shape.0 * shape.1 * shape.2];
+                for piece in slice_pieces(axis, *num_slices, root_chunk_size) {
+                    assert!(piece
+                        .copy_start
+                        .add(piece.copy_size)
+                        .inside(CHUNK_SIZE.repeat()));
+                    for offset in util::coord_iter_3d(CHUNK_SIZE) {
+                        if offset.inside(piece.copy_size.sub(1usize.repeat())) {
+                            let target = piece.target_start.add(offset);
+                            covered[target.to_index(shape)] += 1;
+                        }
+                    }
+                }
+                assert!(covered.iter().all(|&count| count == 1));
+            }
+        }
+    }
+
+    #[test]
+    fn batches_pack_like_serial_copies() {
+        let root_chunk_size = 2;
+        let root_block_size = root_chunk_size * CHUNK_SIZE;
+        for &axis in &[Axis::X, Axis::Y, Axis::Z] {
+            let shape = slice_data_shape(axis, root_block_size);
+            let pieces = slice_pieces(axis, (1, 2, 3), root_chunk_size);
+            // Every block of every chunk is different.
+            let chunk_data = |piece: &SlicePiece| -> Vec<u32> {
+                let chunk_index = piece.chunk_offset.to_index((root_chunk_size + 1).repeat());
+                (0..CHUNK_VOLUME as u32)
+                    .map(|index| index | (chunk_index as u32) << 20)
+                    .collect()
+            };
+            let mut expected = vec![0; shape.0 * shape.1 * shape.2];
+            for piece in &pieces {
+                util::copy_3d_bounded_auto_clip(
+                    piece.copy_size,
+                    &chunk_data(piece),
+                    CHUNK_SIZE.repeat(),
+                    piece.copy_start,
+                    &mut expected,
+                    shape,
+                    piece.target_start.signed(),
+                );
+            }
+            for &batch_size in &[1, 3] {
+                let mut packed = vec![0; expected.len()];
+                for batch in slice_batches(pieces.clone(), batch_size) {
+                    let data: Vec<_> = batch.iter().map(chunk_data).collect();
+                    let sources: Vec<_> = data.iter().map(|data| &data[..]).zip(&batch).collect();
+                    pack_pieces(&sources, &mut packed, shape);
+                }
+                assert!(packed == expected);
+            }
+        }
+    }
+
+    #[test]
+    fn slice_offset_wraps_around_images() {
+        let root_block_size = 2 * CHUNK_SIZE;
+        let slices = root_block_size / SLICE_SIZE;
+        assert_eq!(
+            slice_texel_offset(Axis::Y, (3, 2, 1), root_block_size),
+            (0, 2 * SLICE_SIZE, 0)
+        );
+        assert_eq!(
+            slice_texel_offset(Axis::Z, (0, 0, slices + 1), root_block_size),
+            (0, 0, SLICE_SIZE)
+        );
+        assert_eq!(
+            slice_texel_offset(Axis::X, (slices, 0, 0), root_block_size),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn advance_and_retreat_undo_each_other() {
+        let mut position = Position::new(2);
+        let slices = position.root_block_size() / SLICE_SIZE;
+        for _ in 0..slices + 3 {
+            position.advance(Axis::X);
+        }
+        assert_eq!(position.origin, (1, -1, -1));
+        assert_eq!(position.num_loaded_slices, (3, 0, 0));
+        for _ in 0..2 * slices {
+            position.retreat(Axis::Z);
+        }
+        assert_eq!(position.origin, (1, -1, -5));
+        for _ in 0..slices + 3 {
+            position.retreat(Axis::X);
+        }
+        for _ in 0..2 * slices {
+            position.advance(Axis::Z);
+        }
+        assert_eq!(position.origin, Position::new(2).origin);
+        assert_eq!(position.num_loaded_slices, (0, 0, 0));
+    }
+
+    /// Every block a request packs should end up in the texel block_to_texel says it is stored in,
+    /// and inside the region once the request has been completed.
+    fn check_request_texels(position: &Position, axis: Axis, origin: SignedCoord3D, num: Coord3D) {
+        let root_block_size = position.root_block_size();
+        let slice_offset = slice_texel_offset(axis, num, root_block_size);
+        let (start, end) = position.loaded_block_range();
+        for piece in slice_pieces(axis, num, position.root_chunk_size) {
+            let chunk_start = piece
+                .chunk_offset
+                .signed()
+                .add(origin)
+                .scale(CHUNK_SIZE as _);
+            let last = piece.copy_size.sub(1usize.repeat());
+            for corner in &[(0, 0, 0), last] {
+                let block = chunk_start.add(piece.copy_start.add(*corner).signed());
+                assert!(start.inside(block) && block.inside(end.sub(1isize.repeat())));
+                let texel = slice_offset.add(piece.target_start).add(*corner);
+                assert_eq!(block_to_texel(block, root_block_size), texel);
+            }
+        }
+    }
+
+    #[test]
+    fn requests_pack_blocks_into_their_texels() {
+        let mut position = Position::new(2);
+        let slices = position.root_block_size() / SLICE_SIZE;
+        // Go far enough along each axis to wrap around the images more than once.
+        let steps = [
+            (Axis::X, true, slices * 2 + 1),
+            (Axis::Y, false, slices + 5),
+            (Axis::Z, true, 3),
+            (Axis::X, false, slices * 3),
+            (Axis::Z, false, slices + 1),
+            (Axis::Y, true, slices * 2),
+        ];
+        for &(axis, increase, count) in &steps {
+            for _ in 0..count {
+                let (origin, num) = if increase {
+                    position.advance(axis)
+                } else {
+                    position.retreat(axis)
+                };
+                check_request_texels(&position, axis, origin, num);
+            }
+        }
+    }
+
+    type MockManager = TerrainUploadManager<MockBuffer<u32>, MockBuffer<u8>>;
+
+    fn make_mock_manager(settings: &RenderSettings) -> MockManager {
+        let slice_len = slice_upload_len(settings.root_block_size());
+        TerrainUploadManager::with_buffers(
+            settings,
+            MockBuffer::new(1, slice_len),
+            MockBuffer::new(2, slice_len),
+            MockBuffer::new(3, DIRTY_UPLOAD_LEN),
+            MockBuffer::new(4, DIRTY_UPLOAD_LEN),
+        )
+    }
+
+    fn make_images() -> TerrainImages {
+        TerrainImages {
+            material: vk::Image::from_raw(10),
+            minefield: vk::Image::from_raw(11),
+        }
+    }
+
+    fn make_fills(settings: &RenderSettings) -> MockBuffer<TerrainFill> {
+        let empty = TerrainFill {
+            start: Vector3::new(0, 0, 0),
+            material: 0,
+            size: Vector3::new(0, 0, 0),
+            minefield: 0,
+        };
+        MockBuffer {
+            handle: vk::Buffer::from_raw(5),
+            data: vec![empty; max_terrain_fills(settings.root_chunk_size)],
+        }
+    }
+
+    fn make_temp_storage() -> (ChunkStorage, PathBuf) {
+        let path = make_temp_dir();
+        (ChunkStorage::with_storage_dir(path.clone()), path)
+    }
+
+    fn cleanup(storage: ChunkStorage, dir: PathBuf) {
+        // Waits for any chunks still being generated in the background.
+        drop(storage);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn make_settings() -> RenderSettings {
+        RenderSettings {
+            root_chunk_size: 2,
+            ..Default::default()
+        }
+    }
+
+    fn transitions(
+        images: TerrainImages,
+        from: vk::ImageLayout,
+        to: vk::ImageLayout,
+    ) -> Vec<RecordedCommand> {
+        images
+            .all()
+            .iter()
+            .map(|&image| RecordedCommand::TransitionLayout { image, from, to })
+            .collect()
+    }
+
+    #[test]
+    fn idle_upload_records_nothing() {
+        let settings = make_settings();
+        let (mut storage, dir) = make_temp_storage();
+        let mut manager = make_mock_manager(&settings);
+        let mut commands = MockCommands::default();
+        let mut fills = make_fills(&settings);
+        let count = manager.record_upload(&mut commands, &mut storage, make_images(), &mut fills);
+        assert_eq!(count, 0);
+        assert_eq!(commands.commands, vec![]);
+        cleanup(storage, dir);
+    }
+
+    #[test]
+    fn dirty_chunk_is_copied_to_its_texel() {
+        let settings = make_settings();
+        let (mut storage, dir) = make_temp_storage();
+        let mut manager = make_mock_manager(&settings);
+        let mut commands = MockCommands::default();
+        let mut fills = make_fills(&settings);
+        let images = make_images();
+        let material = Material {
+            albedo: (1, 2, 3),
+            emission: (0, 0, 0),
+            roughness: 127,
+            solid: true,
+            transparent: false,
+            emitter: 0,
+        };
+        storage.set_block(&(1, 2, 3), material.clone());
+        let count = manager.record_upload(&mut commands, &mut storage, images, &mut fills);
+        assert_eq!(count, 0);
+
+        let texel = block_to_texel((0, 0, 0), settings.root_block_size());
+        let extent = vk::Extent3D {
+            width: CHUNK_SIZE as u32,
+            height: CHUNK_SIZE as u32,
+            depth: CHUNK_SIZE as u32,
+        };
+        let copy = |buffer: u64, image| RecordedCommand::CopyBufferToImage {
+            buffer: vk::Buffer::from_raw(buffer),
+            buffer_offset: 0,
+            buffer_size: (CHUNK_SIZE as u32, CHUNK_SIZE as u32),
+            image,
+            offset: (texel.0 as i32, texel.1 as i32, texel.2 as i32),
+            extent,
+        };
+        let general = vk::ImageLayout::GENERAL;
+        let transfer = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+        let mut expected = transitions(images, general, transfer);
+        expected.push(copy(3, images.material));
+        expected.push(copy(4, images.minefield));
+        expected.append(&mut transitions(images, transfer, general));
+        assert_eq!(commands.commands, expected);
+
+        let index = (1, 2, 3).to_index(CHUNK_SIZE.repeat());
+        assert_eq!(
+            manager.dirty_material_upload_buffer.data[index],
+            material.pack()
+        );
+        let volume = (CHUNK_VOLUME * BYTES_PER_BLOCK) as u64;
+        assert_eq!(manager.get_bytes_uploaded(), volume);
+        cleanup(storage, dir);
+    }
+
+    #[test]
+    fn slice_of_placeholders_is_filled() {
+        let settings = make_settings();
+        let (mut storage, dir) = make_temp_storage();
+        let mut manager = make_mock_manager(&settings);
+        let mut commands = MockCommands::default();
+        let mut fills = make_fills(&settings);
+        manager.request_increase(Axis::X);
+        let count = manager.record_upload(&mut commands, &mut storage, make_images(), &mut fills);
+        // None of the chunks have been generated yet, so every piece is an empty placeholder.
+        let pieces = slice_pieces(Axis::X, (0, 0, 0), settings.root_chunk_size);
+        assert_eq!(count as usize, pieces.len());
+        assert_eq!(fills.data[0].size, Vector3::new(SLICE_SIZE as u32, 64, 64));
+        let copies = commands.commands.iter().filter(|command| match command {
+            RecordedCommand::CopyBufferToImage { .. } => true,
+            _ => false,
+        });
+        assert_eq!(copies.count(), 0);
+        assert_eq!(
+            commands.commands.last(),
+            Some(&RecordedCommand::MemoryBarrier {
+                src_stage: vk::PipelineStageFlags::TRANSFER,
+                dst_stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+            })
+        );
+        assert!(!manager.is_busy());
+        cleanup(storage, dir);
+    }
+
+    #[test]
+    fn queued_slices_are_at_edges_of_new_region() {
+        let settings = make_settings();
+        let (mut storage, dir) = make_temp_storage();
+        let mut manager = make_mock_manager(&settings);
+        let mut commands = MockCommands::default();
+        let mut fills = make_fills(&settings);
+        manager.request_increase(Axis::X);
+        manager.request_decrease(Axis::Y);
+        let (start, end) = manager.cpu_position.loaded_block_range();
+        let slice_size = SLICE_SIZE as isize;
+        let queued = manager.get_queued_slices();
+        assert_eq!(
+            queued[0],
+            (
+                (
+                    (end.0 - slice_size, start.1 + slice_size, start.2),
+                    end.add((0, slice_size, 0))
+                ),
+                0
+            )
+        );
+        assert_eq!(
+            queued[1],
+            ((start, (end.0, start.1 + slice_size, end.2)), 0)
+        );
+        manager.record_upload(&mut commands, &mut storage, make_images(), &mut fills);
+        // Only the first slice can be uploaded in that step since they are on different axes.
+        assert_eq!(manager.get_queued_slices()[0].1, 1);
+        assert_eq!(
+            manager.get_loaded_block_range().0,
+            (start.0, start.1 + slice_size, start.2)
+        );
+        cleanup(storage, dir);
+    }
+}
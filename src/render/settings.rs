@@ -0,0 +1,881 @@
+use crate::config::{CommandLine, ConfigFile};
+use crate::render::constants::*;
+use crate::world::WorldWrap;
+use ash::vk;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// The largest the sun can be configured to appear, in degrees.
+const MAX_SUN_ANGULAR_RADIUS: f32 = 10.0;
+/// The range render_scale is clamped to.
+const MIN_RENDER_SCALE: f32 = 0.25;
+const MAX_RENDER_SCALE: f32 = 2.0;
+/// The most passes the denoiser can be configured to run.
+const MAX_DENOISE_PASSES: usize = 16;
+/// The largest step size a denoiser pass can use, in pixels.
+const MAX_DENOISE_STEP: i32 = 64;
+
+/// The step size of each pass of the à-trous denoiser, in pixels. An empty schedule turns the
+/// denoiser off. In the settings file this is written like `1, 2, 4` or `off`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DenoiseSchedule(pub Vec<i32>);
+
+impl Default for DenoiseSchedule {
+    fn default() -> Self {
+        Self(vec![1, 2, 4, 8, 8, 16])
+    }
+}
+
+impl FromStr for DenoiseSchedule {
+    type Err = String;
+
+    /// Steps can be separated by commas or spaces.
+    fn from_str(text: &str) -> Result<DenoiseSchedule, String> {
+        let text = text.trim();
+        if text == "off" {
+            return Ok(DenoiseSchedule(Vec::new()));
+        }
+        let mut steps = Vec::new();
+        for step in text.split(|c: char| c == ',' || c.is_whitespace()) {
+            if step.len() == 0 {
+                continue;
+            }
+            match step.parse() {
+                Ok(step) if step >= 1 && step <= MAX_DENOISE_STEP => steps.push(step),
+                _ => {
+                    return Err(format!(
+                        "'{}' is not a step size from 1 to {}.",
+                        step, MAX_DENOISE_STEP
+                    ))
+                }
+            }
+        }
+        if steps.len() == 0 {
+            return Err("Use 'off' to turn the denoiser off.".to_owned());
+        }
+        if steps.len() > MAX_DENOISE_PASSES {
+            return Err(format!(
+                "The denoiser can run at most {} passes.",
+                MAX_DENOISE_PASSES
+            ));
+        }
+        Ok(DenoiseSchedule(steps))
+    }
+}
+
+impl Display for DenoiseSchedule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.0.len() == 0 {
+            return write!(f, "off");
+        }
+        let steps: Vec<String> = self.0.iter().map(|step| step.to_string()).collect();
+        write!(f, "{}", steps.join(", "))
+    }
+}
+
+/// The format of the lighting buffers, which everything before the finalize stage accumulates
+/// light into. In the settings file this is written as its name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LightingFormat {
+    /// 16 bit fixed point, which clips very bright light.
+    Unorm16,
+    /// 16 bit floating point, which keeps the full range of the light.
+    Float16,
+    /// 32 bits per pixel instead of 64, which halves the memory bandwidth of the denoiser at the
+    /// cost of some precision. Not every GPU supports it.
+    Packed,
+}
+
+impl LightingFormat {
+    /// Must match LIGHTING_FORMATS in build.rs.
+    pub const ALL: [LightingFormat; 3] = [
+        LightingFormat::Unorm16,
+        LightingFormat::Float16,
+        LightingFormat::Packed,
+    ];
+
+    pub fn get_name(self) -> &'static str {
+        match self {
+            LightingFormat::Unorm16 => "unorm16",
+            LightingFormat::Float16 => "float16",
+            LightingFormat::Packed => "packed",
+        }
+    }
+
+    pub fn get_vk_format(self) -> vk::Format {
+        match self {
+            LightingFormat::Unorm16 => vk::Format::R16G16B16A16_UNORM,
+            LightingFormat::Float16 => vk::Format::R16G16B16A16_SFLOAT,
+            LightingFormat::Packed => vk::Format::B10G11R11_UFLOAT_PACK32,
+        }
+    }
+}
+
+impl Default for LightingFormat {
+    fn default() -> Self {
+        LightingFormat::Unorm16
+    }
+}
+
+impl FromStr for LightingFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<LightingFormat, String> {
+        let text = text.trim();
+        Self::ALL
+            .iter()
+            .cloned()
+            .find(|format| format.get_name() == text)
+            .ok_or_else(|| format!("'{}' is not a lighting format.", text))
+    }
+}
+
+impl Display for LightingFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+/// How bright paper white is on HDR displays, in nits. The HUD and anything the tonemapper maps
+/// to 1 are shown at this brightness. Must match encode_output.comp.
+pub const HDR_PAPER_WHITE_NITS: f32 = 200.0;
+/// The brightest light is shown at on HDR displays, in nits.
+pub const HDR_PEAK_NITS: f32 = 1000.0;
+
+/// What the swapchain images hold, which decides how many bits of color reach the display and
+/// whether it is driven in HDR. Formats the surface does not support fall back to Sdr. In the
+/// settings file this is written as its name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// 8 bits per channel in the sRGB color space, which every display supports.
+    Sdr,
+    /// 10 bits per channel in the sRGB color space, which removes banding from dark gradients.
+    Sdr10,
+    /// 10 bits per channel encoded with the PQ curve in the BT.2020 color space, which is what
+    /// HDR monitors and TVs expect.
+    Hdr10,
+    /// 16 bit floats in linear extended sRGB, where 1 is 80 nits. Windows composites HDR in this.
+    ScRgb,
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 4] = [
+        OutputFormat::Sdr,
+        OutputFormat::Sdr10,
+        OutputFormat::Hdr10,
+        OutputFormat::ScRgb,
+    ];
+
+    /// The value shaders compare against. Must match the constants in encode_output.comp.
+    pub fn to_index(self) -> u32 {
+        self as u32
+    }
+
+    pub fn get_name(self) -> &'static str {
+        match self {
+            OutputFormat::Sdr => "sdr",
+            OutputFormat::Sdr10 => "sdr10",
+            OutputFormat::Hdr10 => "hdr10",
+            OutputFormat::ScRgb => "scrgb",
+        }
+    }
+
+    /// The swapchain formats which can be used, in order of preference.
+    pub fn get_vk_formats(self) -> &'static [vk::Format] {
+        match self {
+            OutputFormat::Sdr => &[vk::Format::B8G8R8A8_UNORM, vk::Format::R8G8B8A8_UNORM],
+            OutputFormat::Sdr10 | OutputFormat::Hdr10 => &[
+                vk::Format::A2B10G10R10_UNORM_PACK32,
+                vk::Format::A2R10G10B10_UNORM_PACK32,
+            ],
+            OutputFormat::ScRgb => &[vk::Format::R16G16B16A16_SFLOAT],
+        }
+    }
+
+    /// HDR10 and scRGB need VK_EXT_swapchain_colorspace.
+    pub fn get_color_space(self) -> vk::ColorSpaceKHR {
+        match self {
+            OutputFormat::Sdr | OutputFormat::Sdr10 => vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            OutputFormat::Hdr10 => vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+            OutputFormat::ScRgb => vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+        }
+    }
+
+    pub fn is_hdr(self) -> bool {
+        self == OutputFormat::Hdr10 || self == OutputFormat::ScRgb
+    }
+
+    /// Every format but Sdr is rendered into a 16 bit float image which is converted and blitted
+    /// to the swapchain at the end of the frame, so that no precision is lost along the way.
+    pub fn uses_float_output(self) -> bool {
+        self != OutputFormat::Sdr
+    }
+
+    /// How many times brighter than paper white the finalize stage lets light get, after gamma
+    /// encoding. The tonemap curves roll off towards this instead of towards 1.
+    pub fn get_headroom(self) -> f32 {
+        if self.is_hdr() {
+            (HDR_PEAK_NITS / HDR_PAPER_WHITE_NITS).powf(1.0 / 2.2)
+        } else {
+            1.0
+        }
+    }
+
+    /// How much blue noise the finalize stage adds to hide banding, which is less for formats
+    /// with more bits per channel.
+    pub fn get_dither_amplitude(self) -> f32 {
+        if self == OutputFormat::Sdr {
+            1.0 / 128.0
+        } else {
+            1.0 / 512.0
+        }
+    }
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Sdr
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<OutputFormat, String> {
+        let text = text.trim();
+        Self::ALL
+            .iter()
+            .cloned()
+            .find(|format| format.get_name() == text)
+            .ok_or_else(|| format!("'{}' is not an output format.", text))
+    }
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+/// Bundles the settings which trade image quality for speed. In the settings file this is
+/// written as its name, and any of the bundled settings which are also in the file override the
+/// preset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QualityPreset {
+    /// Picks one of the other presets from the kind of GPU and how much memory it has.
+    Auto,
+    Low,
+    Medium,
+    /// The same as the defaults of each setting.
+    High,
+    Ultra,
+}
+
+impl QualityPreset {
+    pub const ALL: [QualityPreset; 5] = [
+        QualityPreset::Auto,
+        QualityPreset::Low,
+        QualityPreset::Medium,
+        QualityPreset::High,
+        QualityPreset::Ultra,
+    ];
+
+    pub fn get_name(self) -> &'static str {
+        match self {
+            QualityPreset::Auto => "auto",
+            QualityPreset::Low => "low",
+            QualityPreset::Medium => "medium",
+            QualityPreset::High => "high",
+            QualityPreset::Ultra => "ultra",
+        }
+    }
+
+    /// Picks a preset for a GPU of the given type with the given amount of device local memory.
+    pub fn detect(device_type: vk::PhysicalDeviceType, memory: u64) -> QualityPreset {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        match device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU if memory >= 8 * GIB => QualityPreset::Ultra,
+            vk::PhysicalDeviceType::DISCRETE_GPU if memory >= 4 * GIB => QualityPreset::High,
+            vk::PhysicalDeviceType::DISCRETE_GPU => QualityPreset::Medium,
+            vk::PhysicalDeviceType::INTEGRATED_GPU if memory >= 2 * GIB => QualityPreset::Medium,
+            _ => QualityPreset::Low,
+        }
+    }
+
+    /// Changes the settings this preset bundles. Auto does not change anything, it should be
+    /// replaced by a detected preset first.
+    fn apply(self, settings: &mut RenderSettings) {
+        let (root_chunk_size, denoise_schedule, distant_terrain, light_volume) = match self {
+            QualityPreset::Auto => return,
+            QualityPreset::Low => (2, vec![1, 2, 4], false, false),
+            QualityPreset::Medium => (4, vec![1, 2, 4, 8], true, false),
+            QualityPreset::High => (4, DenoiseSchedule::default().0, true, true),
+            QualityPreset::Ultra => (8, DenoiseSchedule::default().0, true, true),
+        };
+        settings.root_chunk_size = root_chunk_size;
+        settings.denoise_schedule = DenoiseSchedule(denoise_schedule);
+        settings.distant_terrain = distant_terrain;
+        settings.light_volume = light_volume;
+        settings.smooth_normals = self == QualityPreset::Ultra;
+    }
+}
+
+impl Default for QualityPreset {
+    fn default() -> Self {
+        QualityPreset::Auto
+    }
+}
+
+impl FromStr for QualityPreset {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<QualityPreset, String> {
+        let text = text.trim();
+        Self::ALL
+            .iter()
+            .cloned()
+            .find(|preset| preset.get_name() == text)
+            .ok_or_else(|| format!("'{}' is not a quality preset.", text))
+    }
+}
+
+impl Display for QualityPreset {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+/// Controls how much of the previous frame is blended into the current one, and when the
+/// previous frame is rejected because the surface under a pixel was hidden last frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TemporalSettings {
+    /// From 0 to 1, how much of the previous frame is kept. Zero turns blending off.
+    pub history_weight: f32,
+    /// How far the depth of the previous frame can be from the expected depth before it is
+    /// rejected, relative to the expected depth.
+    pub depth_threshold: f32,
+    /// From -1 to 1, the smallest dot product between the current and previous normals for
+    /// which the previous frame is kept.
+    pub normal_threshold: f32,
+    /// Once the camera has been still for this many frames, every frame after that is averaged
+    /// evenly, which converges to a noiseless image. Zero turns this off.
+    pub accumulation_delay: u32,
+}
+
+impl Default for TemporalSettings {
+    fn default() -> Self {
+        Self {
+            history_weight: 0.8,
+            depth_threshold: 0.05,
+            normal_threshold: 0.9,
+            accumulation_delay: 0,
+        }
+    }
+}
+
+impl TemporalSettings {
+    pub fn from_config(config: &ConfigFile) -> TemporalSettings {
+        let default = Self::default();
+        TemporalSettings {
+            history_weight: config
+                .get("history_weight", default.history_weight)
+                .max(0.0)
+                .min(1.0),
+            depth_threshold: config
+                .get("disocclusion_depth_threshold", default.depth_threshold)
+                .max(0.0),
+            normal_threshold: config
+                .get("disocclusion_normal_threshold", default.normal_threshold)
+                .max(-1.0)
+                .min(1.0),
+            accumulation_delay: config.get("accumulation_delay", default.accumulation_delay),
+        }
+    }
+}
+
+/// Dimensions chosen at startup which all images and buffers are sized from.
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    pub window_width: u32,
+    pub window_height: u32,
+    /// How many pixels are rendered for each pixel of the window along each axis. The finalize
+    /// stage scales what was rendered to fit the window, so values below 1 trade sharpness for
+    /// speed and values above 1 supersample. This can be changed while the game is running by
+    /// editing the settings file.
+    pub render_scale: f32,
+    /// Sets the defaults of root_chunk_size, denoise_schedule, distant_terrain, light_volume and
+    /// smooth_normals.
+    pub quality: QualityPreset,
+    /// How many chunks the region of the world stored on the GPU spans along each axis.
+    pub root_chunk_size: usize,
+    /// Waits for the display to refresh before presenting each frame, which also keeps the CPU
+    /// from rendering frames that will never be shown.
+    pub vsync: bool,
+    /// Frames are delayed so that no more than this many are drawn per second. Zero means there
+    /// is no limit. This can be changed while the game is running with the max_fps command.
+    pub max_fps: u32,
+    /// Updates the game after waiting for the previous frame to finish instead of before, so that
+    /// the camera a frame is rendered with is as fresh as possible when it is submitted. Turning
+    /// this off is only useful to compare the two with the latency command.
+    pub late_latch: bool,
+    /// This can be changed while the game is running with the denoise command.
+    pub denoise_schedule: DenoiseSchedule,
+    /// How strongly the denoiser keeps apart surfaces which are close together but not on the
+    /// same plane, like blocks of grass on a hillside. Zero only compares distances and normals.
+    pub denoise_gradient_weight: f32,
+    /// How many standard deviations of the noise left after the temporal stage the brightness of
+    /// two pixels can differ by before the denoiser mostly keeps them apart. Larger values blur
+    /// more, zero ignores brightness entirely.
+    pub denoise_variance_weight: f32,
+    /// These can be changed while the game is running by editing the settings file.
+    pub temporal: TemporalSettings,
+    /// Half the angle the sun covers in the sky, in degrees. Larger suns cast softer shadows and
+    /// zero gives perfectly hard ones.
+    pub sun_angular_radius: f32,
+    /// Builds a map of the highest block in each column of the world every frame, so that
+    /// surfaces seen directly which nothing could shade from the sun skip tracing a shadow ray.
+    /// Changing this recreates the renderer.
+    pub sun_heightmap: bool,
+    /// Shades surfaces with normals estimated from the blocks around them instead of the normals
+    /// of their faces, which softens the blocky look. Changing this recreates the renderer.
+    pub smooth_normals: bool,
+    /// Makes the world wrap around, see WorldWrap. Axes which wrap at exactly root_chunk_size are
+    /// seamless, since the whole world fits in the world images and rays carry on across the
+    /// edge of the region. Changing this recreates the renderer.
+    pub world_wrap: WorldWrap,
+    /// Rays which leave the loaded region carry on over a coarse heightmap of the terrain around
+    /// it, so that distant mountains stay visible on the horizon.
+    pub distant_terrain: bool,
+    /// Keeps a coarse volume of how much sky and block light reaches each part of the world up to
+    /// date, which diffuse paths fall back to once they run out of bounces. This lights up caves
+    /// the sun never reaches. Changing this recreates the renderer.
+    pub light_volume: bool,
+    /// Measures the color of the light each frame and partly corrects it, so that caves and
+    /// sunsets do not look uniformly orange. See white_balance.rs.
+    pub auto_white_balance: bool,
+    /// Saves the terrain the world images start out holding to a single file once it has been
+    /// generated, and starts from that file on later runs instead of waiting for the terrain to
+    /// load chunk by chunk. Editing any block discards the file.
+    pub warm_cache: bool,
+    /// Changing this recreates the renderer.
+    pub lighting_format: LightingFormat,
+    /// Falls back to sdr if the display does not support it. Changes will not apply until the
+    /// game is restarted.
+    pub output_format: OutputFormat,
+    /// Enables the Vulkan validation layers. Defaults to on in debug builds. Changes will not
+    /// apply until the game is restarted.
+    pub validation: bool,
+    /// Keeps the window hidden. This can only be set from the command line.
+    pub headless: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            window_width: 1024,
+            window_height: 1024,
+            render_scale: 1.0,
+            quality: QualityPreset::default(),
+            root_chunk_size: 4,
+            vsync: false,
+            max_fps: 0,
+            late_latch: true,
+            denoise_schedule: DenoiseSchedule::default(),
+            denoise_gradient_weight: 16.0,
+            denoise_variance_weight: 4.0,
+            temporal: TemporalSettings::default(),
+            sun_angular_radius: 1.5,
+            sun_heightmap: true,
+            smooth_normals: false,
+            world_wrap: WorldWrap::default(),
+            distant_terrain: true,
+            light_volume: true,
+            auto_white_balance: false,
+            warm_cache: false,
+            lighting_format: LightingFormat::default(),
+            output_format: OutputFormat::default(),
+            validation: ENABLE_DEBUG,
+            headless: false,
+        }
+    }
+}
+
+impl RenderSettings {
+    /// The detected preset is used if the quality is set to auto, see QualityPreset::detect.
+    pub fn from_config(config: &ConfigFile, detected: QualityPreset) -> RenderSettings {
+        let mut default = Self::default();
+        let quality = config.get("quality", default.quality);
+        match quality {
+            QualityPreset::Auto => detected.apply(&mut default),
+            _ => quality.apply(&mut default),
+        }
+        RenderSettings {
+            window_width: config.get("window_width", default.window_width),
+            window_height: config.get("window_height", default.window_height),
+            render_scale: config
+                .get("render_scale", default.render_scale)
+                .max(MIN_RENDER_SCALE)
+                .min(MAX_RENDER_SCALE),
+            quality,
+            root_chunk_size: config.get("root_chunk_size", default.root_chunk_size),
+            vsync: config.get("vsync", default.vsync),
+            max_fps: config.get("max_fps", default.max_fps),
+            late_latch: config.get("late_latch", default.late_latch),
+            denoise_schedule: config.get("denoise_schedule", default.denoise_schedule),
+            denoise_gradient_weight: config
+                .get("denoise_gradient_weight", default.denoise_gradient_weight)
+                .max(0.0),
+            denoise_variance_weight: config
+                .get("denoise_variance_weight", default.denoise_variance_weight)
+                .max(0.0),
+            temporal: TemporalSettings::from_config(config),
+            sun_angular_radius: config
+                .get("sun_angular_radius", default.sun_angular_radius)
+                .max(0.0)
+                .min(MAX_SUN_ANGULAR_RADIUS),
+            sun_heightmap: config.get("sun_heightmap", default.sun_heightmap),
+            smooth_normals: config.get("smooth_normals", default.smooth_normals),
+            world_wrap: config.get("world_wrap", default.world_wrap),
+            distant_terrain: config.get("distant_terrain", default.distant_terrain),
+            light_volume: config.get("light_volume", default.light_volume),
+            auto_white_balance: config.get("auto_white_balance", default.auto_white_balance),
+            warm_cache: config.get("warm_cache", default.warm_cache),
+            lighting_format: config.get("lighting_format", default.lighting_format),
+            output_format: config.get("output_format", default.output_format),
+            validation: config.get("validation", default.validation),
+            headless: default.headless,
+        }
+    }
+
+    /// Applies the settings which can be given on the command line, which take priority over the
+    /// settings file.
+    pub fn apply_command_line(&mut self, command_line: &CommandLine) {
+        if let Some((width, height)) = command_line.resolution {
+            self.window_width = width;
+            self.window_height = height;
+        }
+        // Stills are rendered larger than the window would be, then scaled back down.
+        if let Some(still) = &command_line.render_still {
+            self.window_width *= still.scale;
+            self.window_height *= still.scale;
+        }
+        self.headless = command_line.headless;
+        // The smoke test fails on validation errors, which are only reported with validation on.
+        // Release builds leave it off by default.
+        if command_line.frames.is_some() {
+            self.validation = true;
+        }
+    }
+
+    /// How many blocks the region of the world stored on the GPU spans along each axis.
+    pub fn root_block_size(&self) -> usize {
+        self.root_chunk_size * CHUNK_SIZE
+    }
+
+    /// How large the images the raytrace, denoise and finalize stages work on are when the
+    /// window is the given size.
+    pub fn get_render_extent(&self, window: vk::Extent2D) -> vk::Extent2D {
+        let scale = |size: u32| ((size as f32 * self.render_scale).round() as u32).max(1);
+        vk::Extent2D {
+            width: scale(window.width),
+            height: scale(window.height),
+        }
+    }
+
+    /// How many bytes each ray queue takes up when the window is the given size. The queues hold
+    /// a few rays for every rendered pixel, so they are the buffers most likely to be larger than
+    /// the device can bind.
+    pub fn get_ray_queue_size(&self, window: vk::Extent2D) -> u64 {
+        let render = self.get_render_extent(window);
+        let num_rays = render.width as u64 * render.height as u64 * RAYS_PER_PIXEL as u64;
+        // The rays come after a WorkListHeader, which is four u32s.
+        (4 + num_rays * RAY_SIZE as u64) * 4
+    }
+
+    fn get_window_extent(&self) -> vk::Extent2D {
+        vk::Extent2D {
+            width: self.window_width,
+            height: self.window_height,
+        }
+    }
+
+    pub fn root_block_volume(&self) -> usize {
+        self.root_block_size() * self.root_block_size() * self.root_block_size()
+    }
+
+    /// Shrinks the settings which would otherwise make the world images or the ray queues larger
+    /// than the device supports, returning a description of each downgrade so that it can be
+    /// shown to the user.
+    pub fn fit_to_limits(&mut self, limits: &vk::PhysicalDeviceLimits) -> Vec<String> {
+        let mut downgrades = Vec::new();
+        let max_3d = limits.max_image_dimension3_d as usize;
+        let requested = self.root_chunk_size;
+        while self.root_chunk_size > 2 && self.root_block_size() > max_3d {
+            self.root_chunk_size /= 2;
+        }
+        if self.root_chunk_size != requested {
+            downgrades.push(format!(
+                "root_chunk_size was lowered from {} to {} since the GPU only supports world \
+                images {} blocks wide.",
+                requested, self.root_chunk_size, max_3d
+            ));
+        }
+        downgrades.extend(self.fit_ray_queues(self.get_window_extent(), limits));
+        downgrades
+    }
+
+    /// Lowers render_scale until the ray queues fit in a storage buffer when the window is the
+    /// given size. The swapchain can end up larger than the configured window, for example when
+    /// the window is maximized, so this is checked again whenever it is recreated.
+    pub fn fit_ray_queues(
+        &mut self,
+        window: vk::Extent2D,
+        limits: &vk::PhysicalDeviceLimits,
+    ) -> Option<String> {
+        let max_range = limits.max_storage_buffer_range as u64;
+        let requested = self.render_scale;
+        while self.render_scale > MIN_RENDER_SCALE && self.get_ray_queue_size(window) > max_range {
+            // Rounded so that the warning doesn't show floating point error.
+            let lowered = ((self.render_scale - 0.05) * 100.0).round() / 100.0;
+            self.render_scale = lowered.max(MIN_RENDER_SCALE);
+        }
+        if self.render_scale == requested {
+            return None;
+        }
+        Some(format!(
+            "render_scale was lowered from {} to {} since the GPU only supports storage buffers \
+            {} bytes long.",
+            requested, self.render_scale, max_range
+        ))
+    }
+
+    /// Checks the settings which do not depend on the device, so that problems with them can be
+    /// reported before the window is created. Returns a description of every problem found.
+    pub fn validate_without_device(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        if self.window_width == 0 {
+            problems.push("window_width must be nonzero.".to_owned());
+        }
+        if self.window_height == 0 {
+            problems.push("window_height must be nonzero.".to_owned());
+        }
+        // The region is centered around the origin, so it must be split evenly in half.
+        if self.root_chunk_size < 2 || !self.root_chunk_size.is_power_of_two() {
+            problems.push(format!(
+                "root_chunk_size ({}) must be a power of two that is at least 2.",
+                self.root_chunk_size
+            ));
+        }
+        if let Err(problem) = self.world_wrap.validate(self.root_chunk_size) {
+            problems.push(problem);
+        }
+        join_problems(problems)
+    }
+
+    /// Checks that the settings are usable on a device with the given limits, returning a
+    /// description of every problem found if they are not. The settings must have passed
+    /// validate_without_device already.
+    pub fn validate(&self, limits: &vk::PhysicalDeviceLimits) -> Result<(), String> {
+        let mut problems = Vec::new();
+        let max_2d = limits.max_image_dimension2_d;
+        if self.window_width > max_2d || self.window_height > max_2d {
+            problems.push(format!(
+                "The window ({}x{}) is larger than the largest image the GPU supports ({}).",
+                self.window_width, self.window_height, max_2d
+            ));
+        }
+        let render = self.get_render_extent(self.get_window_extent());
+        if render.width > max_2d || render.height > max_2d {
+            problems.push(format!(
+                "render_scale ({}) makes the rendered image {}x{}, larger than the largest image \
+                the GPU supports ({}).",
+                self.render_scale, render.width, render.height, max_2d
+            ));
+        }
+        let ray_queue_size = self.get_ray_queue_size(self.get_window_extent());
+        if ray_queue_size > limits.max_storage_buffer_range as u64 {
+            problems.push(format!(
+                "The ray queues for a {}x{} image take {} bytes each, more than the GPU can bind \
+                ({}). Lower render_scale or the window size.",
+                render.width, render.height, ray_queue_size, limits.max_storage_buffer_range
+            ));
+        }
+        if self.root_block_size() > limits.max_image_dimension3_d as usize {
+            problems.push(format!(
+                "root_chunk_size ({}) makes the world images {} blocks wide, but the GPU only \
+                supports {}.",
+                self.root_chunk_size,
+                self.root_block_size(),
+                limits.max_image_dimension3_d
+            ));
+        }
+        join_problems(problems)
+    }
+}
+
+fn join_problems(problems: Vec<String>) -> Result<(), String> {
+    if problems.len() == 0 {
+        Ok(())
+    } else {
+        Err(problems.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_limits() -> vk::PhysicalDeviceLimits {
+        vk::PhysicalDeviceLimits {
+            max_image_dimension2_d: 4096,
+            max_image_dimension3_d: 512,
+            max_storage_buffer_range: 1 << 30,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_settings_are_valid() {
+        let settings = RenderSettings::default();
+        assert!(settings.validate_without_device().is_ok());
+        assert!(settings.validate(&make_limits()).is_ok());
+    }
+
+    #[test]
+    fn world_images_are_fit_to_limits() {
+        let limits = vk::PhysicalDeviceLimits {
+            max_image_dimension3_d: (CHUNK_SIZE * 4) as u32,
+            ..make_limits()
+        };
+        let mut settings = RenderSettings::default();
+        settings.root_chunk_size = 16;
+        assert_eq!(settings.fit_to_limits(&limits).len(), 1);
+        assert_eq!(settings.root_chunk_size, 4);
+        assert!(settings.validate(&limits).is_ok());
+        assert_eq!(settings.fit_to_limits(&limits), Vec::<String>::new());
+    }
+
+    #[test]
+    fn ray_queues_are_fit_to_limits() {
+        let limits = vk::PhysicalDeviceLimits {
+            max_storage_buffer_range: 1 << 27,
+            ..make_limits()
+        };
+        let mut settings = RenderSettings::default();
+        settings.window_width = 1920;
+        settings.window_height = 1080;
+        assert!(settings.validate(&limits).is_err());
+        assert_eq!(settings.fit_to_limits(&limits).len(), 1);
+        assert!(settings.render_scale < 1.0);
+        assert!(settings.validate(&limits).is_ok());
+        settings.window_width = 4096;
+        settings.window_height = 4096;
+        settings.fit_to_limits(&limits);
+        assert_eq!(settings.render_scale, MIN_RENDER_SCALE);
+        assert!(settings.validate(&limits).is_err());
+    }
+
+    #[test]
+    fn ray_queues_are_fit_to_swapchain() {
+        let limits = vk::PhysicalDeviceLimits {
+            max_storage_buffer_range: 1 << 27,
+            ..make_limits()
+        };
+        let mut settings = RenderSettings::default();
+        settings.window_width = 1280;
+        settings.window_height = 720;
+        assert_eq!(settings.fit_to_limits(&limits), Vec::<String>::new());
+        let maximized = vk::Extent2D {
+            width: 2560,
+            height: 1440,
+        };
+        assert!(settings.fit_ray_queues(maximized, &limits).is_some());
+        assert!(settings.get_ray_queue_size(maximized) <= 1 << 27);
+        assert_eq!(settings.fit_ray_queues(maximized, &limits), None);
+    }
+
+    #[test]
+    fn render_extent_follows_scale() {
+        let config = ConfigFile::parse("render_scale = 0.5\n");
+        let settings = RenderSettings::from_config(&config, QualityPreset::High);
+        let window = vk::Extent2D {
+            width: 1279,
+            height: 720,
+        };
+        let render = settings.get_render_extent(window);
+        assert_eq!((render.width, render.height), (640, 360));
+        let config = ConfigFile::parse("render_scale = 0.001\n");
+        let settings = RenderSettings::from_config(&config, QualityPreset::High);
+        assert_eq!(settings.render_scale, MIN_RENDER_SCALE);
+    }
+
+    #[test]
+    fn parse_denoise_schedule() {
+        let schedule: DenoiseSchedule = "1, 2,4 8".parse().unwrap();
+        assert_eq!(schedule, DenoiseSchedule(vec![1, 2, 4, 8]));
+        assert_eq!(schedule.to_string(), "1, 2, 4, 8");
+        let off: DenoiseSchedule = "off".parse().unwrap();
+        assert_eq!(off.to_string(), "off");
+        assert!("".parse::<DenoiseSchedule>().is_err());
+        assert!("1, 0".parse::<DenoiseSchedule>().is_err());
+        assert!("1, 128".parse::<DenoiseSchedule>().is_err());
+    }
+
+    #[test]
+    fn parse_lighting_format() {
+        for format in LightingFormat::ALL.iter() {
+            assert_eq!(format.to_string().parse(), Ok(*format));
+        }
+        assert!("rgba32".parse::<LightingFormat>().is_err());
+    }
+
+    #[test]
+    fn parse_output_format() {
+        for format in OutputFormat::ALL.iter() {
+            assert_eq!(format.to_string().parse(), Ok(*format));
+        }
+        assert!("hdr".parse::<OutputFormat>().is_err());
+        assert_eq!(OutputFormat::Sdr10.get_headroom(), 1.0);
+        assert!(OutputFormat::Hdr10.get_headroom() > 2.0);
+    }
+
+    #[test]
+    fn settings_override_quality_presets() {
+        let config = ConfigFile::parse("quality = low\nlight_volume = true\n");
+        let settings = RenderSettings::from_config(&config, QualityPreset::Ultra);
+        assert_eq!(settings.quality, QualityPreset::Low);
+        assert_eq!(settings.root_chunk_size, 2);
+        assert!(settings.light_volume);
+        let auto = RenderSettings::from_config(&ConfigFile::parse(""), QualityPreset::Ultra);
+        assert_eq!(auto.root_chunk_size, 8);
+        let discrete = vk::PhysicalDeviceType::DISCRETE_GPU;
+        assert_eq!(
+            QualityPreset::detect(discrete, 1 << 30),
+            QualityPreset::Medium
+        );
+        let cpu = vk::PhysicalDeviceType::CPU;
+        assert_eq!(QualityPreset::detect(cpu, 1 << 40), QualityPreset::Low);
+    }
+
+    #[test]
+    fn rejects_invalid_dimensions() {
+        let limits = make_limits();
+        let mut settings = RenderSettings::default();
+        settings.root_chunk_size = 6;
+        assert!(settings.validate_without_device().is_err());
+        settings.root_chunk_size = 16;
+        assert!(settings.validate_without_device().is_ok());
+        assert!(settings.validate(&limits).is_err());
+        settings = RenderSettings::default();
+        settings.window_width = 0;
+        assert!(settings.validate_without_device().is_err());
+        settings.window_width = 1001;
+        assert!(settings.validate_without_device().is_ok());
+        assert!(settings.validate(&limits).is_ok());
+        settings.window_width = 3000;
+        settings.render_scale = 2.0;
+        assert!(settings.validate(&limits).is_err());
+        settings.world_wrap = WorldWrap((2, 2, 0));
+        assert!(settings.validate_without_device().is_err());
+    }
+}
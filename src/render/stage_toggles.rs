@@ -0,0 +1,90 @@
+use std::fmt::{self, Display, Formatter};
+
+/// Stages of the pipeline which can be turned off while the game is running, to find out which
+/// one causes an artifact. Turning off every stage shows the raw results of raytracing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StageToggles {
+    /// The variance-guided denoiser passes from the denoise schedule.
+    pub denoise: bool,
+    pub reflection_denoise: bool,
+    /// Blending in the reprojected lighting of previous frames.
+    pub temporal: bool,
+}
+
+impl StageToggles {
+    /// What each stage is called in the stage command. raw turns every other stage off or on.
+    pub const NAMES: [&'static str; 4] = ["denoise", "reflection_denoise", "temporal", "raw"];
+
+    /// Turns the stage with the given name on or off.
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        match name {
+            "denoise" => self.denoise = enabled,
+            "reflection_denoise" => self.reflection_denoise = enabled,
+            "temporal" => self.temporal = enabled,
+            "raw" => {
+                *self = StageToggles {
+                    denoise: !enabled,
+                    reflection_denoise: !enabled,
+                    temporal: !enabled,
+                }
+            }
+            _ => return Err(format!("'{}' is not a stage.", name)),
+        }
+        Ok(())
+    }
+
+    /// The names of the stages which are turned off.
+    pub fn get_disabled(&self) -> Vec<&'static str> {
+        let stages = [
+            ("denoise", self.denoise),
+            ("reflection_denoise", self.reflection_denoise),
+            ("temporal", self.temporal),
+        ];
+        stages
+            .iter()
+            .filter(|(_, enabled)| !enabled)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+impl Default for StageToggles {
+    fn default() -> Self {
+        Self {
+            denoise: true,
+            reflection_denoise: true,
+            temporal: true,
+        }
+    }
+}
+
+impl Display for StageToggles {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let disabled = self.get_disabled();
+        if disabled.len() == 0 {
+            return write!(f, "all stages on");
+        }
+        write!(f, "{} off", disabled.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_turns_every_stage_off() {
+        let mut toggles = StageToggles::default();
+        assert_eq!(toggles.to_string(), "all stages on");
+        toggles.set("temporal", false).unwrap();
+        assert_eq!(toggles.get_disabled(), vec!["temporal"]);
+        toggles.set("raw", true).unwrap();
+        assert_eq!(
+            toggles.to_string(),
+            "denoise, reflection_denoise, temporal off"
+        );
+        toggles.set("raw", false).unwrap();
+        assert_eq!(toggles, StageToggles::default());
+        assert!(toggles.set("shade", false).is_err());
+    }
+}
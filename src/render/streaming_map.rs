@@ -0,0 +1,184 @@
+use crate::util::prelude::*;
+use cgmath::Rad;
+
+/// A rectangle of columns in world blocks, from the first (inclusive) to the last (exclusive).
+pub type ColumnRange = (SignedCoord2D, SignedCoord2D);
+
+/// Where terrain is streamed at each level of detail, as seen from above. Shown on the minimap by
+/// the streaming_volumes debug view.
+#[derive(Clone, Debug)]
+pub struct StreamingState {
+    /// The column the camera is in, which the map is centered on.
+    pub camera: SignedCoord2D,
+    pub heading: Rad<f32>,
+    /// Half of the horizontal field of view of the camera.
+    pub half_fov: Rad<f32>,
+    /// The columns stored in the world images, where terrain is traced at full detail.
+    pub loaded: ColumnRange,
+    /// The columns covered by the distant terrain heightmap, None if it is disabled.
+    pub distant: Option<ColumnRange>,
+    /// The columns of each slice waiting to be uploaded, along with how many upload steps ago it
+    /// was requested.
+    pub queued_slices: Vec<(ColumnRange, u64)>,
+}
+
+/// Slices which have been waiting for this many steps are drawn fully red.
+const MAX_SLICE_AGE: u64 = 60;
+
+const EMPTY_COLOR: [u8; 3] = [24, 24, 24];
+const DISTANT_COLOR: [u8; 3] = [40, 60, 110];
+const LOADED_COLOR: [u8; 3] = [40, 100, 50];
+const LOADED_EDGE_COLOR: [u8; 3] = [120, 240, 120];
+const NEW_SLICE_COLOR: [u8; 3] = [255, 230, 60];
+const OLD_SLICE_COLOR: [u8; 3] = [255, 40, 40];
+const FRUSTUM_COLOR: [u8; 3] = [240, 240, 240];
+
+fn contains(range: ColumnRange, column: SignedCoord2D) -> bool {
+    let (start, end) = range;
+    column.0 >= start.0 && column.1 >= start.1 && column.0 < end.0 && column.1 < end.1
+}
+
+fn mix(a: [u8; 3], b: [u8; 3], alpha: f32) -> [u8; 3] {
+    let channel = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * alpha).round() as u8;
+    [
+        channel(a[0], b[0]),
+        channel(a[1], b[1]),
+        channel(a[2], b[2]),
+    ]
+}
+
+/// How many blocks each pixel of a map size pixels wide covers, chosen so that the full detail
+/// region takes up half of the map.
+pub fn blocks_per_pixel(state: &StreamingState, size: usize) -> usize {
+    let (start, end) = state.loaded;
+    let width = (end.0 - start.0).max(end.1 - start.1) as usize;
+    (width * 2 / size).max(1)
+}
+
+/// Renders the state as an RGBA8 image which is size pixels wide and tall, laid out like
+/// map::render_map with the camera at the center. Columns are tinted by the most detailed level
+/// covering them, queued slices are tinted from yellow to red as they wait longer, and the edges of
+/// the camera's view are drawn as white lines.
+pub fn render_streaming_map(state: &StreamingState, size: usize) -> Vec<u8> {
+    let step = blocks_per_pixel(state, size) as isize;
+    let half = size as isize / 2;
+    let edges = [
+        state.heading.0 - state.half_fov.0,
+        state.heading.0 + state.half_fov.0,
+    ];
+    let mut image = vec![0; size * size * 4];
+    for py in 0..size {
+        for px in 0..size {
+            // The top of the map is +Y.
+            let offset = (px as isize - half, half - py as isize);
+            let column = (
+                state.camera.0 + offset.0 * step,
+                state.camera.1 + offset.1 * step,
+            );
+            let (start, end) = state.loaded;
+            let mut color = if contains(state.loaded, column) {
+                let edge_distance = (column.0 - start.0)
+                    .min(end.0 - 1 - column.0)
+                    .min(column.1 - start.1)
+                    .min(end.1 - 1 - column.1);
+                if edge_distance < step {
+                    LOADED_EDGE_COLOR
+                } else {
+                    LOADED_COLOR
+                }
+            } else if state.distant.map_or(false, |range| contains(range, column)) {
+                DISTANT_COLOR
+            } else {
+                EMPTY_COLOR
+            };
+            for (range, age) in &state.queued_slices {
+                if contains(*range, column) {
+                    let age = (*age).min(MAX_SLICE_AGE) as f32 / MAX_SLICE_AGE as f32;
+                    color = mix(color, mix(NEW_SLICE_COLOR, OLD_SLICE_COLOR, age), 0.6);
+                }
+            }
+            let offset = (offset.0 as f32, offset.1 as f32);
+            for angle in edges.iter() {
+                let direction = (angle.cos(), angle.sin());
+                let along = offset.0 * direction.0 + offset.1 * direction.1;
+                let across = offset.0 * direction.1 - offset.1 * direction.0;
+                if along > 0.0 && across.abs() < 0.5 {
+                    color = FRUSTUM_COLOR;
+                }
+            }
+            let index = (py * size + px) * 4;
+            image[index..index + 3].copy_from_slice(&color);
+            image[index + 3] = 255;
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(image: &[u8], size: usize, px: usize, py: usize) -> [u8; 3] {
+        let index = (py * size + px) * 4;
+        [image[index], image[index + 1], image[index + 2]]
+    }
+
+    fn make_state() -> StreamingState {
+        StreamingState {
+            camera: (0, 0),
+            // Looking towards +X.
+            heading: Rad(0.0),
+            half_fov: Rad(0.4),
+            loaded: ((-128, -128), (128, 128)),
+            distant: Some(((-1024, -1024), (1024, 1024))),
+            queued_slices: vec![],
+        }
+    }
+
+    #[test]
+    fn columns_show_most_detailed_level() {
+        let state = make_state();
+        let size = 128;
+        assert_eq!(blocks_per_pixel(&state, size), 4);
+        let image = render_streaming_map(&state, size);
+        // Behind the camera, so away from the lines showing its view.
+        assert_eq!(pixel(&image, size, 40, 64), LOADED_COLOR);
+        assert_eq!(pixel(&image, size, 32, 64), LOADED_EDGE_COLOR);
+        assert_eq!(pixel(&image, size, 10, 64), DISTANT_COLOR);
+
+        let state = StreamingState {
+            distant: None,
+            ..state
+        };
+        let image = render_streaming_map(&state, size);
+        assert_eq!(pixel(&image, size, 10, 64), EMPTY_COLOR);
+    }
+
+    #[test]
+    fn older_slices_are_redder() {
+        let slice = ((-128, 112), (128, 128));
+        let state = StreamingState {
+            queued_slices: vec![(slice, 0)],
+            ..make_state()
+        };
+        let new = pixel(&render_streaming_map(&state, 128), 128, 40, 34);
+        let state = StreamingState {
+            queued_slices: vec![(slice, MAX_SLICE_AGE * 2)],
+            ..make_state()
+        };
+        let old = pixel(&render_streaming_map(&state, 128), 128, 40, 34);
+        assert!(old[1] < new[1]);
+        assert_ne!(new, LOADED_COLOR);
+    }
+
+    #[test]
+    fn view_edges_point_forward() {
+        let state = StreamingState {
+            half_fov: Rad(0.0),
+            ..make_state()
+        };
+        let image = render_streaming_map(&state, 128);
+        assert_eq!(pixel(&image, 128, 100, 64), FRUSTUM_COLOR);
+        assert_ne!(pixel(&image, 128, 28, 64), FRUSTUM_COLOR);
+    }
+}
@@ -0,0 +1,269 @@
+/// How many glyphs can be drawn in a single frame. Must match text.comp.
+pub const MAX_GLYPHS: usize = 1000;
+/// The size of each glyph in the font atlas, including one pixel of spacing on the right and
+/// bottom. Must match text.comp.
+pub const GLYPH_WIDTH: usize = 6;
+pub const GLYPH_HEIGHT: usize = 8;
+/// How many glyphs are stored in each row of the font atlas. Must match text.comp.
+pub const ATLAS_COLUMNS: usize = 16;
+pub const ATLAS_WIDTH: usize = ATLAS_COLUMNS * GLYPH_WIDTH;
+pub const ATLAS_HEIGHT: usize = (FONT.len() + ATLAS_COLUMNS - 1) / ATLAS_COLUMNS * GLYPH_HEIGHT;
+
+/// The first character stored in the font.
+const FIRST_CHARACTER: char = ' ';
+/// Drawn in place of characters which are not in the font.
+const FALLBACK_CHARACTER: char = '?';
+
+/// A single character to be drawn on the screen. The layout matches the Glyph struct in text.comp.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GlyphInstance {
+    /// X coordinate of the top left pixel in the lower 16 bits, Y coordinate in the upper 16 bits.
+    pub position: u32,
+    /// Which glyph of the font atlas to draw.
+    pub index: u32,
+    /// How many screen pixels wide each pixel of the font is.
+    pub scale: u32,
+    /// RGBA, with R in the least significant byte.
+    pub color: u32,
+}
+
+/// Collects text to be drawn over the next frame.
+pub struct TextBuffer {
+    glyphs: Vec<GlyphInstance>,
+}
+
+impl TextBuffer {
+    pub fn new() -> TextBuffer {
+        TextBuffer {
+            glyphs: Vec::with_capacity(MAX_GLYPHS),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.glyphs.clear();
+    }
+
+    /// Queues text to be drawn with its top left corner at the given pixel. Newlines start a new
+    /// line below the first one. Characters that are not in the font are drawn as question marks.
+    /// Anything past MAX_GLYPHS glyphs in a single frame is not drawn.
+    pub fn draw_text(&mut self, position: (u32, u32), scale: u32, color: [u8; 4], text: &str) {
+        let color = u32::from_le_bytes(color);
+        let (mut x, mut y) = position;
+        for character in text.chars() {
+            if character == '\n' {
+                x = position.0;
+                y += GLYPH_HEIGHT as u32 * scale;
+                continue;
+            }
+            if character != ' ' && self.glyphs.len() < MAX_GLYPHS {
+                self.glyphs.push(GlyphInstance {
+                    position: x & 0xFFFF | y << 16,
+                    index: glyph_index(character) as u32,
+                    scale,
+                    color,
+                });
+            }
+            x += GLYPH_WIDTH as u32 * scale;
+        }
+    }
+
+    /// Returns how many pixels wide and tall text would be if drawn with draw_text.
+    pub fn measure_text(text: &str, scale: u32) -> (u32, u32) {
+        let width = text
+            .lines()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        let height = text.lines().count();
+        (
+            (width * GLYPH_WIDTH) as u32 * scale,
+            (height * GLYPH_HEIGHT) as u32 * scale,
+        )
+    }
+
+    pub fn borrow_glyphs(&self) -> &[GlyphInstance] {
+        &self.glyphs
+    }
+}
+
+/// Returns which glyph in the font atlas should be used to draw a character.
+fn glyph_index(character: char) -> usize {
+    let index = (character as usize).wrapping_sub(FIRST_CHARACTER as usize);
+    if index < FONT.len() {
+        index
+    } else {
+        FALLBACK_CHARACTER as usize - FIRST_CHARACTER as usize
+    }
+}
+
+/// Creates an ATLAS_WIDTH by ATLAS_HEIGHT single channel image containing every glyph of the font.
+/// Filled pixels are 255, empty pixels are 0.
+pub fn generate_font_atlas() -> Vec<u8> {
+    let mut atlas = vec![0; ATLAS_WIDTH * ATLAS_HEIGHT];
+    for (index, columns) in FONT.iter().enumerate() {
+        let start_x = index % ATLAS_COLUMNS * GLYPH_WIDTH;
+        let start_y = index / ATLAS_COLUMNS * GLYPH_HEIGHT;
+        for (x, column) in columns.iter().enumerate() {
+            // The least significant bit is the top row of the glyph.
+            for y in 0..7 {
+                if column >> y & 1 == 1 {
+                    atlas[(start_y + y) * ATLAS_WIDTH + start_x + x] = 255;
+                }
+            }
+        }
+    }
+    atlas
+}
+
+// A 5x7 font covering printable ASCII, starting at FIRST_CHARACTER. Each glyph is stored as five
+// columns from left to right.
+#[rustfmt::skip]
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // '!'
+    [0x00, 0x07, 0x00, 0x07, 0x00], // '"'
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // '#'
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // '$'
+    [0x23, 0x13, 0x08, 0x64, 0x62], // '%'
+    [0x36, 0x49, 0x55, 0x22, 0x50], // '&'
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '''
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // '('
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // ')'
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08], // '*'
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // '+'
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ','
+    [0x08, 0x08, 0x08, 0x08, 0x08], // '-'
+    [0x00, 0x60, 0x60, 0x00, 0x00], // '.'
+    [0x20, 0x10, 0x08, 0x04, 0x02], // '/'
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // '0'
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // '1'
+    [0x42, 0x61, 0x51, 0x49, 0x46], // '2'
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // '3'
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // '4'
+    [0x27, 0x45, 0x45, 0x45, 0x39], // '5'
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // '6'
+    [0x01, 0x71, 0x09, 0x05, 0x03], // '7'
+    [0x36, 0x49, 0x49, 0x49, 0x36], // '8'
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // '9'
+    [0x00, 0x36, 0x36, 0x00, 0x00], // ':'
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ';'
+    [0x08, 0x14, 0x22, 0x41, 0x00], // '<'
+    [0x14, 0x14, 0x14, 0x14, 0x14], // '='
+    [0x00, 0x41, 0x22, 0x14, 0x08], // '>'
+    [0x02, 0x01, 0x51, 0x09, 0x06], // '?'
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // '@'
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // 'A'
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // 'B'
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // 'C'
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // 'D'
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // 'E'
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // 'F'
+    [0x3E, 0x41, 0x49, 0x49, 0x7A], // 'G'
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // 'H'
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // 'I'
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // 'J'
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // 'K'
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // 'L'
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], // 'M'
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // 'N'
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // 'O'
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // 'P'
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // 'Q'
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // 'R'
+    [0x46, 0x49, 0x49, 0x49, 0x31], // 'S'
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // 'T'
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // 'U'
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // 'V'
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // 'W'
+    [0x63, 0x14, 0x08, 0x14, 0x63], // 'X'
+    [0x07, 0x08, 0x70, 0x08, 0x07], // 'Y'
+    [0x61, 0x51, 0x49, 0x45, 0x43], // 'Z'
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // '['
+    [0x02, 0x04, 0x08, 0x10, 0x20], // '\'
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ']'
+    [0x04, 0x02, 0x01, 0x02, 0x04], // '^'
+    [0x40, 0x40, 0x40, 0x40, 0x40], // '_'
+    [0x00, 0x01, 0x02, 0x04, 0x00], // '`'
+    [0x20, 0x54, 0x54, 0x54, 0x78], // 'a'
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // 'b'
+    [0x38, 0x44, 0x44, 0x44, 0x20], // 'c'
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // 'd'
+    [0x38, 0x54, 0x54, 0x54, 0x18], // 'e'
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // 'f'
+    [0x0C, 0x52, 0x52, 0x52, 0x3E], // 'g'
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // 'h'
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // 'i'
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // 'j'
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // 'k'
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // 'l'
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // 'm'
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // 'n'
+    [0x38, 0x44, 0x44, 0x44, 0x38], // 'o'
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // 'p'
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // 'q'
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // 'r'
+    [0x48, 0x54, 0x54, 0x54, 0x20], // 's'
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // 't'
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // 'u'
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // 'v'
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // 'w'
+    [0x44, 0x28, 0x10, 0x28, 0x44], // 'x'
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // 'y'
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // 'z'
+    [0x00, 0x08, 0x36, 0x41, 0x00], // '{'
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // '|'
+    [0x00, 0x41, 0x36, 0x08, 0x00], // '}'
+    [0x02, 0x01, 0x02, 0x04, 0x02], // '~'
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_characters_use_fallback() {
+        assert_eq!(glyph_index(' '), 0);
+        assert_eq!(glyph_index('A'), 'A' as usize - ' ' as usize);
+        assert_eq!(glyph_index('\u{e9}'), glyph_index('?'));
+        assert_eq!(glyph_index('\t'), glyph_index('?'));
+    }
+
+    #[test]
+    fn draw_text_lays_out_lines() {
+        let mut text = TextBuffer::new();
+        text.draw_text((10, 20), 2, [255, 0, 0, 255], "a b\nc");
+        let glyphs = text.borrow_glyphs();
+        // The space does not need a glyph.
+        assert_eq!(glyphs.len(), 3);
+        assert_eq!(glyphs[0].position, 10 | 20 << 16);
+        assert_eq!(glyphs[1].position, (10 + 4 * GLYPH_WIDTH as u32) | 20 << 16);
+        assert_eq!(
+            glyphs[2].position,
+            10 | (20 + 2 * GLYPH_HEIGHT as u32) << 16
+        );
+        assert_eq!(glyphs[0].color, 0xFF0000FF);
+    }
+
+    #[test]
+    fn measure_text_uses_longest_line() {
+        let size = TextBuffer::measure_text("ab\nabcd\n", 3);
+        assert_eq!(
+            size,
+            (4 * GLYPH_WIDTH as u32 * 3, 2 * GLYPH_HEIGHT as u32 * 3)
+        );
+    }
+
+    #[test]
+    fn atlas_contains_glyphs() {
+        let atlas = generate_font_atlas();
+        assert_eq!(atlas.len(), ATLAS_WIDTH * ATLAS_HEIGHT);
+        // The top of the vertical bar in '|'.
+        let index = glyph_index('|');
+        let x = index % ATLAS_COLUMNS * GLYPH_WIDTH + 2;
+        let y = index / ATLAS_COLUMNS * GLYPH_HEIGHT;
+        assert_eq!(atlas[y * ATLAS_WIDTH + x], 255);
+        // Spaces are empty.
+        assert!(atlas[0..GLYPH_WIDTH].iter().all(|pixel| *pixel == 0));
+    }
+}
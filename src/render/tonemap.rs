@@ -0,0 +1,87 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// How far exposure can be adjusted either way, in stops.
+pub const MAX_EXPOSURE: f32 = 8.0;
+/// How many stops each press of the exposure controls adjusts exposure by.
+pub const EXPOSURE_STEP: f32 = 0.5;
+
+/// The curve the finalize stage maps light to the range of the display with, after exposure is
+/// applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tonemapper {
+    /// A piecewise curve with a quadratic toe and shoulder, which keeps shadows deep.
+    Filmic,
+    /// Divides each channel by one more than itself, which never clips but looks flat.
+    Reinhard,
+    /// Krzysztof Narkowicz's fit of the ACES reference rendering transform, which has more
+    /// contrast than Filmic and desaturates bright light.
+    Aces,
+}
+
+impl Tonemapper {
+    pub const ALL: [Tonemapper; 3] = [Tonemapper::Filmic, Tonemapper::Reinhard, Tonemapper::Aces];
+
+    /// The value shaders compare against. Must match the TONEMAPPER constants in finalize.comp.
+    pub fn to_index(self) -> u32 {
+        self as u32
+    }
+
+    pub fn get_name(self) -> &'static str {
+        match self {
+            Tonemapper::Filmic => "filmic",
+            Tonemapper::Reinhard => "reinhard",
+            Tonemapper::Aces => "aces",
+        }
+    }
+}
+
+impl Default for Tonemapper {
+    fn default() -> Self {
+        Tonemapper::Filmic
+    }
+}
+
+impl FromStr for Tonemapper {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Tonemapper, String> {
+        Self::ALL
+            .iter()
+            .cloned()
+            .find(|tonemapper| tonemapper.get_name() == text)
+            .ok_or_else(|| format!("'{}' is not a tonemapper.", text))
+    }
+}
+
+impl Display for Tonemapper {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+/// What light is multiplied by before tonemapping for an exposure in stops, which is clamped to
+/// MAX_EXPOSURE either way.
+pub fn get_exposure_scale(exposure: f32) -> f32 {
+    exposure.max(-MAX_EXPOSURE).min(MAX_EXPOSURE).exp2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for tonemapper in Tonemapper::ALL.iter() {
+            assert_eq!(tonemapper.to_string().parse(), Ok(*tonemapper));
+        }
+        assert!("hable".parse::<Tonemapper>().is_err());
+    }
+
+    #[test]
+    fn exposure_is_in_stops() {
+        assert_eq!(get_exposure_scale(0.0), 1.0);
+        assert_eq!(get_exposure_scale(-1.0), 0.5);
+        assert_eq!(get_exposure_scale(100.0), 256.0);
+    }
+}
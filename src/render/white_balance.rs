@@ -0,0 +1,155 @@
+use cgmath::{Vector3, Vector4};
+
+use super::general::image_statistics::{ImageStatistics, HISTOGRAM_VALUES_PER_BIN};
+
+/// Must match image_statistics.comp.
+const CHROMA_SCALE: f32 = 1024.0;
+/// The darkest and brightest pixels are left out of the estimate, since they are mostly the sky,
+/// emissive blocks and unlit corners rather than surfaces lit by the scene's light.
+const IGNORED_FRACTION: f32 = 0.1;
+/// How much of the measured cast is corrected. Leaving some of it keeps sunsets and torch-lit
+/// caves warm instead of making them look like noon.
+const STRENGTH: f32 = 0.6;
+/// How far the correction moves towards the latest estimate each frame, so that it does not
+/// flicker as the camera turns.
+const ADAPTATION_RATE: f32 = 0.05;
+const MIN_GAIN: f32 = 0.5;
+const MAX_GAIN: f32 = 2.0;
+// How much red, green and blue each contribute to brightness.
+const LUMA: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Returns the average color of the light in a histogram read back from the GPU, scaled so that
+/// neutral light is (1, 1, 1). Returns None if no pixels counted towards it.
+pub fn estimate_cast(histogram: &[u32]) -> Option<Vector3<f32>> {
+    let bins: Vec<&[u32]> = histogram.chunks(HISTOGRAM_VALUES_PER_BIN).collect();
+    let total: u64 = bins.iter().map(|bin| bin[0] as u64).sum();
+    if total == 0 {
+        return None;
+    }
+    let low = total as f32 * IGNORED_FRACTION;
+    let high = total as f32 * (1.0 - IGNORED_FRACTION);
+    let mut below = 0u64;
+    let mut count = 0u64;
+    let mut sum = [0u64; 3];
+    for bin in bins {
+        // Bins are kept if their middle falls between the ignored ends.
+        let middle = below as f32 + bin[0] as f32 / 2.0;
+        below += bin[0] as u64;
+        if middle < low || middle > high {
+            continue;
+        }
+        count += bin[0] as u64;
+        for channel in 0..3 {
+            sum[channel] += bin[channel + 1] as u64;
+        }
+    }
+    if count == 0 {
+        return None;
+    }
+    let average = |channel: usize| sum[channel] as f32 / count as f32 / CHROMA_SCALE * 3.0;
+    Some(Vector3::new(average(0), average(1), average(2)))
+}
+
+/// Returns how much each channel should be multiplied by to partly cancel out a cast, keeping
+/// the brightness of the light the same.
+pub fn correction_for(cast: Vector3<f32>) -> Vector3<f32> {
+    let gain = |channel: f32| 1.0 + (1.0 / channel.max(0.01) - 1.0) * STRENGTH;
+    let gains = Vector3::new(gain(cast.x), gain(cast.y), gain(cast.z));
+    let corrected = [gains.x * cast.x, gains.y * cast.y, gains.z * cast.z];
+    let brightness = LUMA[0] * corrected[0] + LUMA[1] * corrected[1] + LUMA[2] * corrected[2];
+    let clamp = |gain: f32| (gain / brightness).clamp(MIN_GAIN, MAX_GAIN);
+    Vector3::new(clamp(gains.x), clamp(gains.y), clamp(gains.z))
+}
+
+/// Adjusts the color of the light in each frame to cancel out the color cast measured in earlier
+/// frames, see image_statistics.comp.
+#[derive(Clone, Debug)]
+pub struct WhiteBalance {
+    gains: Vector3<f32>,
+}
+
+impl WhiteBalance {
+    pub fn new() -> Self {
+        Self {
+            gains: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    /// Moves the correction towards the one for the cast measured in the given statistics.
+    pub fn update(&mut self, statistics: &ImageStatistics) {
+        if let Some(cast) = estimate_cast(statistics.get_histogram()) {
+            self.gains += (correction_for(cast) - self.gains) * ADAPTATION_RATE;
+        }
+    }
+
+    /// The columns of the matrix the finalize stage multiplies colors by.
+    pub fn to_uniform(&self) -> [Vector4<f32>; 3] {
+        [
+            Vector4::new(self.gains.x, 0.0, 0.0, 0.0),
+            Vector4::new(0.0, self.gains.y, 0.0, 0.0),
+            Vector4::new(0.0, 0.0, self.gains.z, 0.0),
+        ]
+    }
+
+    /// The matrix used when white balance is off.
+    pub fn identity_uniform() -> [Vector4<f32>; 3] {
+        Self::new().to_uniform()
+    }
+}
+
+impl Default for WhiteBalance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::general::image_statistics::HISTOGRAM_BINS;
+
+    fn histogram_of(pixels: &[(usize, [f32; 3])]) -> Vec<u32> {
+        let mut histogram = vec![0; HISTOGRAM_BINS * HISTOGRAM_VALUES_PER_BIN];
+        for (bin, color) in pixels {
+            let total = color[0] + color[1] + color[2];
+            let index = bin * HISTOGRAM_VALUES_PER_BIN;
+            histogram[index] += 1;
+            for channel in 0..3 {
+                histogram[index + channel + 1] += (color[channel] / total * CHROMA_SCALE) as u32;
+            }
+        }
+        histogram
+    }
+
+    #[test]
+    fn empty_histogram_has_no_cast() {
+        let histogram = vec![0; HISTOGRAM_BINS * HISTOGRAM_VALUES_PER_BIN];
+        assert_eq!(estimate_cast(&histogram), None);
+    }
+
+    #[test]
+    fn neutral_light_needs_no_correction() {
+        let histogram = histogram_of(&[(10, [1.0, 1.0, 1.0]); 20]);
+        let correction = correction_for(estimate_cast(&histogram).unwrap());
+        for gain in &[correction.x, correction.y, correction.z] {
+            assert!((gain - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn orange_light_is_cooled() {
+        let histogram = histogram_of(&[(10, [1.0, 0.6, 0.3]); 20]);
+        let correction = correction_for(estimate_cast(&histogram).unwrap());
+        assert!(correction.x < correction.y && correction.y < correction.z);
+    }
+
+    #[test]
+    fn extremes_are_ignored() {
+        let mut pixels = vec![(10, [1.0, 1.0, 1.0]); 18];
+        // A single very bright and very dark pixel, like the sun and an unlit corner.
+        pixels.push((0, [1.0, 0.0, 0.0]));
+        pixels.push((31, [0.0, 0.0, 1.0]));
+        let cast = estimate_cast(&histogram_of(&pixels)).unwrap();
+        assert!((cast.x - 1.0).abs() < 0.01 && (cast.z - 1.0).abs() < 0.01);
+    }
+}
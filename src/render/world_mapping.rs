@@ -0,0 +1,144 @@
+// How blocks of the world map to texels of the world images. build.rs also includes this file to
+// write GEN_WORLD_MAPPING.glsl, so the shaders get the same mapping. That means it can only use
+// std, and blocks are plain tuples instead of SignedCoord3D.
+//
+// The world images wrap around, root_block_width texels along each axis, which is always a power
+// of two. The loaded region is a cube of that size centered on the render offset, and the block at
+// the -X -Y -Z corner of the region the world starts out with is stored at texel (0, 0, 0). The
+// shaders work with positions relative to region_offset, so that they stay precise far from the
+// origin of the world.
+
+pub type Block = (isize, isize, isize);
+pub type Texel = (usize, usize, usize);
+
+fn map(a: Block, b: Block, f: impl Fn(isize, isize) -> isize) -> Block {
+    (f(a.0, b.0), f(a.1, b.1), f(a.2, b.2))
+}
+
+/// Returns which texel of the world images a block of the world is stored in.
+pub fn block_to_texel(block: Block, root_block_width: usize) -> Texel {
+    let width = root_block_width as isize;
+    let texel = map(block, block, |value, _| {
+        (value + width / 2).rem_euclid(width)
+    });
+    (texel.0 as usize, texel.1 as usize, texel.2 as usize)
+}
+
+/// What is added to a position relative to region_offset to find its texel, before wrapping
+/// around. Since the width is a power of two, the & wraps negative offsets correctly.
+pub fn world_image_offset(region_offset: Block, root_block_width: usize) -> Block {
+    let width = root_block_width as isize;
+    map(region_offset, region_offset, |value, _| {
+        width / 2 + (value & (width - 1))
+    })
+}
+
+/// Like block_to_texel, but for a block relative to region_offset, the way the shaders do it.
+pub fn local_to_texel(local: Block, region_offset: Block, root_block_width: usize) -> Texel {
+    let width = root_block_width as isize;
+    let offset = world_image_offset(region_offset, root_block_width);
+    let texel = map(local, offset, |value, offset| {
+        (value + offset).rem_euclid(width)
+    });
+    (texel.0 as usize, texel.1 as usize, texel.2 as usize)
+}
+
+/// The center of the loaded region relative to region_offset, which is what the rotation and
+/// space_offset uniforms hold. The shaders call them lr and lso.
+pub fn region_center(render_offset: Block, region_offset: Block) -> Block {
+    map(render_offset, region_offset, |value, offset| value - offset)
+}
+
+/// Returns the block relative to region_offset that a texel holds. Every texel holds exactly one
+/// block of the loaded region, the inverse of local_to_texel for blocks inside it.
+pub fn texel_to_local(
+    texel: Texel,
+    region_offset: Block,
+    region_center: Block,
+    root_block_width: usize,
+) -> Block {
+    let width = root_block_width as isize;
+    let offset = world_image_offset(region_offset, root_block_width);
+    let start = map(region_center, region_center, |value, _| value - width / 2);
+    let texel = (texel.0 as isize, texel.1 as isize, texel.2 as isize);
+    let from_offset = map(texel, offset, |texel, offset| texel - offset);
+    map(from_offset, start, |value, start| {
+        start + (value - start).rem_euclid(width)
+    })
+}
+
+/// The GLSL versions of world_image_offset, local_to_texel and texel_to_local. They work on
+/// positions instead of blocks, so fractions of a block are carried through unchanged. Must be
+/// included after uniform_data and ROOT_BLOCK_WIDTH are defined.
+pub fn glsl_source() -> String {
+    let functions = [
+        (
+            "vec3 world_image_offset()",
+            "vec3(ROOT_BLOCK_WIDTH / 2)\n        \
+             + vec3(uniform_data.region_offset & ivec3(ROOT_BLOCK_WIDTH - 1))",
+        ),
+        (
+            "vec3 world_image_texel(vec3 position)",
+            "mod(position + world_image_offset(), float(ROOT_BLOCK_WIDTH))",
+        ),
+        (
+            "vec3 world_image_position(vec3 texel)",
+            "vec3(uniform_data.lr - ivec3(ROOT_BLOCK_WIDTH / 2))\n        \
+             + mod(texel - world_image_offset() - vec3(uniform_data.lr)\n            \
+             + float(ROOT_BLOCK_WIDTH / 2), float(ROOT_BLOCK_WIDTH))",
+        ),
+    ];
+    let mut source = String::from(
+        "// Generated by build.rs from src/render/world_mapping.rs, edit that instead.\n",
+    );
+    for (signature, body) in &functions {
+        source.push_str(&format!("\n{} {{\n    return {};\n}}\n", signature, body));
+    }
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTHS: [usize; 3] = [64, 128, 512];
+    const REGION_OFFSETS: [Block; 4] = [(0, 0, 0), (37, -5, 1), (-1000, 4097, -63), (64, 64, 64)];
+
+    #[test]
+    fn shader_mapping_matches_uploads() {
+        for &width in &WIDTHS {
+            for &region_offset in &REGION_OFFSETS {
+                for &block in &[(0, 0, 0), (-1, 31, -300), (width as isize * 3, 7, -9)] {
+                    let local = map(block, region_offset, |value, offset| value - offset);
+                    assert_eq!(
+                        local_to_texel(local, region_offset, width),
+                        block_to_texel(block, width)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn texels_round_trip_through_loaded_region() {
+        for &width in &WIDTHS {
+            let half = width as isize / 2;
+            for &region_offset in &REGION_OFFSETS {
+                let render_offset = map(region_offset, (5, -9, 130), |a, b| a + b);
+                let center = region_center(render_offset, region_offset);
+                for &corner in &[(-half, -half, -half), (half - 1, half - 1, half - 1)] {
+                    let local = map(center, corner, |a, b| a + b);
+                    let texel = local_to_texel(local, region_offset, width);
+                    assert!(texel.0 < width && texel.1 < width && texel.2 < width);
+                    assert_eq!(texel_to_local(texel, region_offset, center, width), local);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generated_glsl_is_up_to_date() {
+        let generated = std::fs::read_to_string("shaders/glsl/GEN_WORLD_MAPPING.glsl").unwrap();
+        assert_eq!(generated, glsl_source());
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Statistics collected over a run of the game, saved as JSON when it closes so that runs from
+/// different commits can be compared.
+pub struct PerformanceReport {
+    // How long each frame took, in milliseconds.
+    frame_times: Vec<f32>,
+    /// The average time each stage of a frame took on the GPU, in milliseconds.
+    pub gpu_stage_timings: Vec<(&'static str, f64)>,
+    /// Averages of what the GPU counted while tracing, see RayStatistics::get_report_values.
+    pub ray_statistics: Vec<(&'static str, f64)>,
+    pub chunks_generated: usize,
+    /// Terrain data streamed to the GPU after startup.
+    pub bytes_uploaded: u64,
+}
+
+/// Returns the value below which the given fraction of the samples fall. The samples must be
+/// sorted.
+fn percentile(sorted: &[f32], fraction: f32) -> f32 {
+    if sorted.len() == 0 {
+        return 0.0;
+    }
+    let index = ((sorted.len() as f32 * fraction).ceil() as usize).max(1) - 1;
+    sorted[index.min(sorted.len() - 1)]
+}
+
+/// Writes named values as a JSON object on its own lines, followed by a comma.
+fn write_values(json: &mut String, key: &str, values: &[(&'static str, f64)]) {
+    let _ = write!(json, "  \"{}\": {{", key);
+    for (index, (name, value)) in values.iter().enumerate() {
+        let separator = if index == 0 { "" } else { "," };
+        let _ = write!(json, "{}\n    \"{}\": {:.3}", separator, name, value);
+    }
+    if values.len() > 0 {
+        json.push_str("\n  ");
+    }
+    json.push_str("},\n");
+}
+
+/// Returns the most memory the process has used at once in bytes, if the platform reports it.
+fn get_peak_memory() -> Option<u64> {
+    // Only Linux is supported for now, where this is the VmHWM line of /proc/self/status.
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+impl PerformanceReport {
+    pub fn new() -> PerformanceReport {
+        PerformanceReport {
+            frame_times: Vec::new(),
+            gpu_stage_timings: Vec::new(),
+            ray_statistics: Vec::new(),
+            chunks_generated: 0,
+            bytes_uploaded: 0,
+        }
+    }
+
+    pub fn get_default_path() -> PathBuf {
+        dirs::config_dir()
+            .expect("System somehow doesn't have a config dir?")
+            .join("raytrace")
+            .join("performance_report.json")
+    }
+
+    pub fn push_frame_time(&mut self, millis: f32) {
+        self.frame_times.push(millis);
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut sorted = self.frame_times.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let average = if sorted.len() == 0 {
+            0.0
+        } else {
+            sorted.iter().sum::<f32>() / sorted.len() as f32
+        };
+
+        // Writing to a String can't fail, so the results are ignored.
+        let mut json = String::new();
+        json.push_str("{\n");
+        let _ = writeln!(json, "  \"frames\": {},", sorted.len());
+        json.push_str("  \"frame_time_ms\": {\n");
+        let _ = writeln!(json, "    \"average\": {:.3},", average);
+        for (name, fraction) in &[("p50", 0.5), ("p90", 0.9), ("p99", 0.99)] {
+            let _ = writeln!(
+                json,
+                "    \"{}\": {:.3},",
+                name,
+                percentile(&sorted, *fraction)
+            );
+        }
+        let _ = writeln!(json, "    \"max\": {:.3}", percentile(&sorted, 1.0));
+        json.push_str("  },\n");
+        write_values(&mut json, "gpu_stage_ms", &self.gpu_stage_timings);
+        write_values(&mut json, "rays", &self.ray_statistics);
+        let _ = writeln!(json, "  \"chunks_generated\": {},", self.chunks_generated);
+        let _ = writeln!(json, "  \"bytes_uploaded\": {},", self.bytes_uploaded);
+        match get_peak_memory() {
+            Some(bytes) => {
+                let _ = writeln!(json, "  \"peak_memory_bytes\": {}", bytes);
+            }
+            None => json.push_str("  \"peak_memory_bytes\": null\n"),
+        }
+        json.push_str("}\n");
+        json
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, self.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let samples: Vec<f32> = (1..=100).map(|sample| sample as f32).collect();
+        assert_eq!(percentile(&samples, 0.5), 50.0);
+        assert_eq!(percentile(&samples, 0.99), 99.0);
+        assert_eq!(percentile(&samples, 1.0), 100.0);
+        assert_eq!(percentile(&[], 0.5), 0.0);
+    }
+
+    #[test]
+    fn report_contains_every_field() {
+        let mut report = PerformanceReport::new();
+        report.push_frame_time(10.0);
+        report.push_frame_time(20.0);
+        report.gpu_stage_timings = vec![("raytrace", 1.5), ("denoise", 0.25)];
+        report.ray_statistics = vec![("average_steps", 12.5)];
+        report.chunks_generated = 7;
+        let json = report.to_json();
+        assert!(json.contains("\"frames\": 2,"));
+        assert!(json.contains("\"average\": 15.000,"));
+        assert!(json.contains("\"raytrace\": 1.500,\n    \"denoise\": 0.250\n  },"));
+        assert!(json.contains("\"rays\": {\n    \"average_steps\": 12.500\n  },"));
+        assert!(json.contains("\"chunks_generated\": 7,"));
+        assert!(json.contains("\"peak_memory_bytes\": "));
+    }
+}
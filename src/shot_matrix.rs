@@ -0,0 +1,182 @@
+use crate::config::ConfigFile;
+use std::path::{Path, PathBuf};
+
+/// Keys of a matrix file which describe the matrix itself instead of a setting to vary.
+const MATRIX_KEYS: [&str; 3] = ["sessions", "output", "samples"];
+/// How many frames are blended together for each shot if the matrix file does not say.
+const DEFAULT_MATRIX_SAMPLES: u32 = 64;
+
+/// One image of a shot matrix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatrixShot {
+    /// A file saved with the save_session console command.
+    pub session: PathBuf,
+    /// Render settings which replace the ones in the settings file.
+    pub settings: Vec<(String, String)>,
+    /// Replaces the sun angle saved in the session.
+    pub sun_angle: Option<f32>,
+    pub output: PathBuf,
+}
+
+/// Every combination of a few values for some render settings, rendered from each of a list of
+/// saved sessions. Files use the same format as the settings file, for example:
+///
+/// ```text
+/// sessions = cave.txt, tower.txt
+/// output = comparisons
+/// samples = 64
+/// denoise_schedule = off | 1 2 4 | 1 2 4 8
+/// sun_angle = 0.3 | 1.5
+/// ```
+///
+/// Every key other than sessions, output and samples is a render setting, or sun_angle, which is
+/// tried with each of the values separated by |. Images are named after the session and the
+/// values which differ between shots.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShotMatrix {
+    /// How many frames are blended together for each shot.
+    pub samples: u32,
+    pub shots: Vec<MatrixShot>,
+}
+
+/// Makes a value safe to use in a file name.
+fn label_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+impl ShotMatrix {
+    pub fn parse(text: &str) -> Result<ShotMatrix, String> {
+        let file = ConfigFile::parse(text);
+        let sessions: String = file.get("sessions", String::new());
+        let sessions: Vec<PathBuf> = sessions
+            .split(',')
+            .map(str::trim)
+            .filter(|session| !session.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        if sessions.is_empty() {
+            return Err("The matrix needs at least one session.".to_owned());
+        }
+        let output = PathBuf::from(file.get("output", ".".to_owned()));
+        let samples = match file.get("samples", DEFAULT_MATRIX_SAMPLES) {
+            0 => return Err("samples must be at least 1.".to_owned()),
+            samples => samples,
+        };
+        // Sorted so that images are always named the same way.
+        let mut keys: Vec<&str> = file
+            .keys()
+            .filter(|key| !MATRIX_KEYS.contains(key))
+            .collect();
+        keys.sort();
+        let mut axes: Vec<(&str, Vec<String>)> = Vec::new();
+        for key in keys {
+            let values: String = file.get(key, String::new());
+            let values: Vec<String> = values.split('|').map(|v| v.trim().to_owned()).collect();
+            if values.iter().any(String::is_empty) {
+                return Err(format!("{} has an empty value.", key));
+            }
+            if key == "sun_angle" && values.iter().any(|v| v.parse::<f32>().is_err()) {
+                return Err("Every sun_angle must be a number.".to_owned());
+            }
+            axes.push((key, values));
+        }
+
+        let mut shots = Vec::new();
+        for session in &sessions {
+            let stem = session.file_stem().unwrap_or_default().to_string_lossy();
+            // Counts through every combination of values like the digits of a number.
+            let mut choices = vec![0; axes.len()];
+            loop {
+                let mut shot = MatrixShot {
+                    session: session.clone(),
+                    settings: Vec::new(),
+                    sun_angle: None,
+                    output: PathBuf::new(),
+                };
+                let mut label = label_value(&stem);
+                for ((key, values), choice) in axes.iter().zip(choices.iter()) {
+                    let value = &values[*choice];
+                    if *key == "sun_angle" {
+                        shot.sun_angle = value.parse().ok();
+                    } else {
+                        shot.settings.push((key.to_string(), value.clone()));
+                    }
+                    if values.len() > 1 {
+                        label += &format!("_{}-{}", key, label_value(value));
+                    }
+                }
+                shot.output = output.join(format!("{}.png", label));
+                shots.push(shot);
+                let next = choices
+                    .iter()
+                    .zip(axes.iter())
+                    .rposition(|(choice, (_, values))| choice + 1 < values.len());
+                match next {
+                    Some(axis) => {
+                        choices[axis] += 1;
+                        for choice in &mut choices[axis + 1..] {
+                            *choice = 0;
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+        Ok(ShotMatrix { samples, shots })
+    }
+
+    pub fn load(path: &Path) -> Result<ShotMatrix, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Self::parse(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_combination_is_rendered() {
+        let matrix = ShotMatrix::parse(
+            "sessions = a/cave.txt, tower.txt\noutput = shots\nsamples = 8\n\
+            denoise_schedule = off | 1 2 4\nsun_angle = 0.5 | 1.5 | 2\nsmooth_normals = true",
+        )
+        .unwrap();
+        assert_eq!(matrix.samples, 8);
+        assert_eq!(matrix.shots.len(), 2 * 2 * 3);
+        let first = &matrix.shots[0];
+        assert_eq!(first.session, PathBuf::from("a/cave.txt"));
+        assert_eq!(first.sun_angle, Some(0.5));
+        assert_eq!(
+            first.output,
+            PathBuf::from("shots/cave_denoise_schedule-off_sun_angle-0.5.png")
+        );
+        let last = &matrix.shots[11];
+        assert_eq!(last.sun_angle, Some(2.0));
+        assert_eq!(
+            last.settings,
+            vec![
+                ("denoise_schedule".to_owned(), "1 2 4".to_owned()),
+                ("smooth_normals".to_owned(), "true".to_owned()),
+            ]
+        );
+        assert_eq!(
+            last.output,
+            PathBuf::from("shots/tower_denoise_schedule-1_2_4_sun_angle-2.png")
+        );
+    }
+
+    #[test]
+    fn rejects_bad_matrices() {
+        assert!(ShotMatrix::parse("output = shots").is_err());
+        assert!(ShotMatrix::parse("sessions = a.txt\nsamples = 0").is_err());
+        assert!(ShotMatrix::parse("sessions = a.txt\nsun_angle = 1 | noon").is_err());
+        assert!(ShotMatrix::parse("sessions = a.txt\nvsync = true ||").is_err());
+    }
+}
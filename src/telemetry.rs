@@ -0,0 +1,163 @@
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Frames older than this many seconds are forgotten.
+const FRAME_HISTORY_SECONDS: f32 = 10.0;
+/// Only this many of the most recent Vulkan debug messages are kept.
+const MAX_DEBUG_MESSAGES: usize = 32;
+
+/// What the game was doing recently, written to a crash report if it panics so that bug reports
+/// carry enough context to act on. Everything is kept in fixed size buffers so that it can be
+/// recorded for the whole session.
+#[derive(Default)]
+pub struct Telemetry {
+    // How long each recent frame took, in milliseconds, oldest first.
+    frame_times: VecDeque<f32>,
+    // The sum of frame_times.
+    history_millis: f32,
+    debug_messages: VecDeque<String>,
+    camera: String,
+    streaming: String,
+}
+
+impl Telemetry {
+    pub fn record_frame(&mut self, millis: f32) {
+        self.frame_times.push_back(millis);
+        self.history_millis += millis;
+        // Always keep the newest frame, even if it took longer than the whole history.
+        while self.frame_times.len() > 1 && self.history_millis > FRAME_HISTORY_SECONDS * 1000.0 {
+            self.history_millis -= self.frame_times.pop_front().unwrap();
+        }
+    }
+
+    pub fn record_debug_message(&mut self, message: String) {
+        if self.debug_messages.len() == MAX_DEBUG_MESSAGES {
+            self.debug_messages.pop_front();
+        }
+        self.debug_messages.push_back(message);
+    }
+
+    /// Replaces the description of where the camera is.
+    pub fn set_camera(&mut self, camera: String) {
+        self.camera = camera;
+    }
+
+    /// Replaces the description of what terrain is being streamed.
+    pub fn set_streaming(&mut self, streaming: String) {
+        self.streaming = streaming;
+    }
+
+    /// Puts everything together into a plain text report.
+    pub fn format_report(&self, panic_message: &str) -> String {
+        // Writing to a String can't fail, so the results are ignored.
+        let mut report = String::new();
+        let _ = writeln!(report, "The game crashed: {}\n", panic_message);
+        let _ = writeln!(
+            report,
+            "Last {} frames ({:.1}s), in milliseconds:",
+            self.frame_times.len(),
+            self.history_millis / 1000.0
+        );
+        for (index, millis) in self.frame_times.iter().enumerate() {
+            let separator = if index % 16 == 15 { "\n" } else { " " };
+            let _ = write!(report, "{:.1}{}", millis, separator);
+        }
+        let _ = writeln!(report, "\n\nCamera:\n{}", self.camera);
+        let _ = writeln!(report, "Streaming:\n{}", self.streaming);
+        let _ = writeln!(
+            report,
+            "Last {} Vulkan debug messages:",
+            self.debug_messages.len()
+        );
+        for message in &self.debug_messages {
+            let _ = writeln!(report, "{}", message);
+        }
+        report
+    }
+}
+
+lazy_static! {
+    static ref TELEMETRY: Mutex<Telemetry> = Mutex::new(Telemetry::default());
+}
+
+/// Lets f record something about the current session. Nothing happens if another thread
+/// panicked while recording.
+pub fn record(f: impl FnOnce(&mut Telemetry)) {
+    if let Ok(mut telemetry) = TELEMETRY.lock() {
+        f(&mut telemetry);
+    }
+}
+
+pub fn get_crash_report_path() -> PathBuf {
+    dirs::config_dir()
+        .expect("System somehow doesn't have a config dir?")
+        .join("raytrace")
+        .join("crash_report.txt")
+}
+
+fn write_crash_report(panic_message: &str) {
+    // The panic may have happened while the telemetry was locked, in which case waiting for it
+    // would never finish.
+    let telemetry = match TELEMETRY.try_lock() {
+        Ok(telemetry) => telemetry,
+        Err(..) => return,
+    };
+    let path = get_crash_report_path();
+    let report = telemetry.format_report(panic_message);
+    let result =
+        std::fs::create_dir_all(path.parent().unwrap()).and_then(|_| std::fs::write(&path, report));
+    match result {
+        Ok(()) => println!("\nSaved a crash report to {:?}.", path),
+        Err(err) => {
+            println!("\nWARNING: Failed to save a crash report to {:?}.", path);
+            println!("Caused by: {}", err);
+        }
+    }
+}
+
+/// Makes panics on any thread write a crash report before the usual message is printed.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        write_crash_report(&info.to_string());
+        default_hook(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_frames_are_forgotten() {
+        let mut telemetry = Telemetry::default();
+        for _ in 0..1000 {
+            telemetry.record_frame(16.0);
+        }
+        assert_eq!(telemetry.frame_times.len(), 625);
+        telemetry.record_frame(20_000.0);
+        assert_eq!(telemetry.frame_times.len(), 1);
+    }
+
+    #[test]
+    fn report_contains_recent_state() {
+        let mut telemetry = Telemetry::default();
+        for index in 0..MAX_DEBUG_MESSAGES + 1 {
+            telemetry.record_debug_message(format!("message {}", index));
+        }
+        telemetry.record_frame(12.5);
+        telemetry.set_camera("x = 1\n".to_owned());
+        telemetry.set_streaming("3 slices queued\n".to_owned());
+        let report = telemetry.format_report("oh no");
+        assert!(report.starts_with("The game crashed: oh no\n"));
+        assert!(report.contains("12.5"));
+        assert!(report.contains("Camera:\nx = 1\n"));
+        assert!(report.contains("Streaming:\n3 slices queued\n"));
+        assert!(!report.contains("message 0\n"));
+        assert!(report.contains(&format!("message {}\n", MAX_DEBUG_MESSAGES)));
+    }
+}
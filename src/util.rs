@@ -1,4 +1,5 @@
 use cgmath::{Rad, Vector3};
+use std::time::{Duration, Instant};
 
 pub struct TripleEulerVector {
     pub forward: Vector3<f32>,
@@ -21,6 +22,16 @@ pub fn compute_triple_euler_vector(heading: Rad<f32>, pitch: Rad<f32>) -> Triple
     TripleEulerVector { forward, up, right }
 }
 
+/// Converts a world space position to one relative to the given block. The subtraction is done at
+/// double precision, so the result stays accurate as long as the block is near the position.
+pub fn world_to_local(position: Vector3<f64>, relative_to: SignedCoord3D) -> Vector3<f32> {
+    Vector3 {
+        x: (position.x - relative_to.0 as f64) as f32,
+        y: (position.y - relative_to.1 as f64) as f32,
+        z: (position.z - relative_to.2 as f64) as f32,
+    }
+}
+
 pub type Coord2D = (usize, usize);
 pub type SignedCoord2D = (isize, isize);
 
@@ -220,6 +231,89 @@ where
     }
 }
 
+/// Sleeping is imprecise, so FrameLimiter only sleeps until this long before the end of a frame
+/// and spins for the rest of it.
+const SPIN_DURATION: Duration = Duration::from_millis(2);
+
+/// Keeps frames from being drawn faster than a given rate.
+pub struct FrameLimiter {
+    last_frame: Instant,
+}
+
+impl FrameLimiter {
+    pub fn new() -> Self {
+        Self {
+            last_frame: Instant::now(),
+        }
+    }
+
+    /// Blocks until at least 1 / max_fps seconds have passed since the last call. Returns
+    /// immediately if max_fps is zero.
+    pub fn wait(&mut self, max_fps: u32) {
+        let now = Instant::now();
+        if max_fps == 0 {
+            self.last_frame = now;
+            return;
+        }
+        let deadline = self.last_frame + Duration::from_secs_f64(1.0 / max_fps as f64);
+        if deadline <= now {
+            // Already late, so don't try to catch up by making the next frame shorter.
+            self.last_frame = now;
+            return;
+        }
+        let remaining = deadline - now;
+        if remaining > SPIN_DURATION {
+            std::thread::sleep(remaining - SPIN_DURATION);
+        }
+        while Instant::now() < deadline {
+            std::thread::yield_now();
+        }
+        self.last_frame = deadline;
+    }
+}
+
+/// Converts variable frame times into a whole number of fixed length steps, carrying the
+/// remainder over to the next frame.
+pub struct FixedTimestep {
+    step: f32,
+    max_steps: usize,
+    accumulator: f32,
+}
+
+impl FixedTimestep {
+    /// If a frame takes longer than max_steps steps, the extra time is dropped so that a slow
+    /// frame does not cause even more work on the next one.
+    pub fn new(step: f32, max_steps: usize) -> Self {
+        Self {
+            step,
+            max_steps,
+            accumulator: 0.0,
+        }
+    }
+
+    pub fn get_step(&self) -> f32 {
+        self.step
+    }
+
+    /// Returns how many steps should be taken to account for dt seconds passing.
+    pub fn advance(&mut self, dt: f32) -> usize {
+        self.accumulator += dt;
+        let steps = (self.accumulator / self.step) as usize;
+        self.accumulator -= steps as f32 * self.step;
+        if steps > self.max_steps {
+            self.accumulator = 0.0;
+            self.max_steps
+        } else {
+            steps
+        }
+    }
+
+    /// How far between the last step and the next one the current time is, from 0 to 1.
+    pub fn get_alpha(&self) -> f32 {
+        (self.accumulator / self.step).max(0.0).min(1.0)
+    }
+}
+
 pub trait CoordUtil<ElementType> {
     fn add(self, other: Self) -> Self;
     fn sub(self, other: Self) -> Self;
@@ -360,7 +454,7 @@ impl<T: Copy> CoordRepeat<(T, T, T)> for T {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Axis {
     X,
     Y,
@@ -436,7 +530,8 @@ fn test_copy_3d() {
 
 /// Copies all the data from source to target in the area that they overlap. The target data is
 /// considered to be placed at (0, 0, 0), and the source data can be thought as being placed inside
-/// the target data at source_offset.
+/// the target data at source_offset. Both arrays must be cubes, the strides are the lengths of
+/// their sides. Use copy_3d_bounded_auto_clip for other shapes.
 pub fn copy_3d_auto_clip<T: Copy>(
     source: &[T],
     source_stride: usize,
@@ -468,17 +563,18 @@ pub fn copy_3d_auto_clip<T: Copy>(
     // Shrink the boundaries if copying would end up going out of bounds.
     data_size.0 = data_size
         .0
-        .min(source_stride - source_start.0)
-        .min(target_stride - target_position.0);
+        .min(source_stride.saturating_sub(source_start.0))
+        .min(target_stride.saturating_sub(target_position.0));
     data_size.1 = data_size
         .1
-        .min(source_stride - source_start.1)
-        .min(target_stride - target_position.1);
+        .min(source_stride.saturating_sub(source_start.1))
+        .min(target_stride.saturating_sub(target_position.1));
     data_size.2 = data_size
         .2
-        .min(source_stride - source_start.2)
-        .min(target_stride - target_position.2);
-    if data_size == (0, 0, 0) {
+        .min(source_stride.saturating_sub(source_start.2))
+        .min(target_stride.saturating_sub(target_position.2));
+    // The areas don't overlap if any axis is empty.
+    if data_size.0 == 0 || data_size.1 == 0 || data_size.2 == 0 {
         return;
     }
     // Do the actual copy
@@ -519,7 +615,9 @@ pub fn copy_3d_bounded_auto_clip<T: Copy>(
     target_dims: Coord3D,
     target_start: SignedCoord3D,
 ) {
-    let mut data_size = source_dims.ewmin(target_dims).ewmin(size);
+    // Only clipped to the dimensions once the negative part of the target has been skipped,
+    // otherwise a large copy to a negative position would lose data it should have copied.
+    let mut data_size = size;
     // Where to *actually* copy the data to.
     let mut target_position = (0, 0, 0);
     // If the target starts at a negative coordinate, source_start should be increased by the
@@ -563,10 +661,20 @@ pub fn copy_3d_bounded_auto_clip<T: Copy>(
             return;
         }
     }
-    // Shrink the boundaries if copying would end up going out of bounds.
-    data_size = data_size
-        .ewmin(source_dims.sub(source_start))
-        .ewmin(target_dims.sub(target_position));
+    // Shrink the boundaries if copying would end up going out of bounds. Skipping the negative
+    // part of the target can move source_start past the end of the source.
+    data_size.0 = data_size
+        .0
+        .min(source_dims.0.saturating_sub(source_start.0))
+        .min(target_dims.0 - target_position.0);
+    data_size.1 = data_size
+        .1
+        .min(source_dims.1.saturating_sub(source_start.1))
+        .min(target_dims.1 - target_position.1);
+    data_size.2 = data_size
+        .2
+        .min(source_dims.2.saturating_sub(source_start.2))
+        .min(target_dims.2 - target_position.2);
     if data_size.0 == 0 || data_size.1 == 0 || data_size.2 == 0 {
         return;
     }
@@ -645,24 +753,350 @@ pub fn fill_slice_3d_auto_clip<T: Copy>(
 ) {
     let mut real_slice_start = (0, 0, 0);
     if slice_start.0 < 0 {
-        slice_size.0 -= -slice_start.0 as usize;
+        slice_size.0 = slice_size.0.saturating_sub(-slice_start.0 as usize);
     } else {
         real_slice_start.0 = slice_start.0 as usize;
     }
     if slice_start.1 < 0 {
-        slice_size.1 -= -slice_start.1 as usize;
+        slice_size.1 = slice_size.1.saturating_sub(-slice_start.1 as usize);
     } else {
         real_slice_start.1 = slice_start.1 as usize;
     }
     if slice_start.2 < 0 {
-        slice_size.2 -= -slice_start.2 as usize;
+        slice_size.2 = slice_size.2.saturating_sub(-slice_start.2 as usize);
     } else {
         real_slice_start.2 = slice_start.2 as usize;
     }
     // Shrink the boundaries if filling would end up going out of bounds.
-    slice_size.0 = slice_size.0.min(target_stride - real_slice_start.0);
-    slice_size.1 = slice_size.1.min(target_stride - real_slice_start.1);
-    slice_size.2 = slice_size.2.min(target_stride - real_slice_start.2);
+    slice_size.0 = slice_size
+        .0
+        .min(target_stride.saturating_sub(real_slice_start.0));
+    slice_size.1 = slice_size
+        .1
+        .min(target_stride.saturating_sub(real_slice_start.1));
+    slice_size.2 = slice_size
+        .2
+        .min(target_stride.saturating_sub(real_slice_start.2));
+    if slice_size.0 == 0 || slice_size.1 == 0 || slice_size.2 == 0 {
+        return;
+    }
     // Do the actual operation
     fill_slice_3d(value, target, target_stride, real_slice_start, slice_size);
 }
+
+/// Like copy_3d, but sets every element of the area to value instead of copying it from another
+/// array. Panics if filling would go out of bounds.
+pub fn fill_3d<T: Copy>(
+    value: T,
+    data_size: Coord3D,
+    target: &mut [T],
+    target_dims: Coord3D,
+    target_position: Coord3D,
+) {
+    assert!(target.len() == target_dims.0 * target_dims.1 * target_dims.2);
+    assert!(target_position.add(data_size).inside(target_dims));
+    for z in 0..data_size.2 {
+        for y in 0..data_size.1 {
+            let start = target_position.add((0, y, z)).to_index(target_dims);
+            for element in &mut target[start..start + data_size.0] {
+                *element = value;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fill_3d() {
+    let mut target = vec![0; 4 * 3 * 2];
+    fill_3d(7, (2, 2, 1), &mut target[..], (4, 3, 2), (1, 1, 1));
+    assert_eq!(target[(1, 1, 1).to_index((4, 3, 2))], 7);
+    assert_eq!(target[(2, 2, 1).to_index((4, 3, 2))], 7);
+    assert_eq!(target[(3, 2, 1).to_index((4, 3, 2))], 0);
+    assert_eq!(target.iter().filter(|value| **value == 7).count(), 4);
+}
+
+#[test]
+fn test_frame_limiter() {
+    let mut limiter = FrameLimiter::new();
+    let start = Instant::now();
+    for _ in 0..5 {
+        limiter.wait(200);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test]
+fn test_fixed_timestep() {
+    let mut timestep = FixedTimestep::new(0.25, 4);
+    assert_eq!(timestep.advance(0.1), 0);
+    assert!((timestep.get_alpha() - 0.4).abs() < 1e-5);
+    assert_eq!(timestep.advance(0.6), 2);
+    assert!((timestep.get_alpha() - 0.8).abs() < 1e-5);
+    // Time past the maximum number of steps is dropped.
+    assert_eq!(timestep.advance(10.0), 4);
+    assert_eq!(timestep.get_alpha(), 0.0);
+}
+
+#[test]
+fn test_world_to_local() {
+    let position = Vector3::new(10_000_000.25, -10_000_000.5, 3.0);
+    let local = world_to_local(position, (10_000_000, -10_000_000, 0));
+    assert_eq!(local, Vector3::new(0.25, -0.5, 3.0));
+}
+
+#[cfg(test)]
+mod copy_fill_properties {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn dims() -> impl Strategy<Value = Coord3D> {
+        (1usize..7, 1usize..7, 1usize..7)
+    }
+
+    fn offset() -> impl Strategy<Value = SignedCoord3D> {
+        (-8isize..8, -8isize..8, -8isize..8)
+    }
+
+    /// Picks a coordinate of each axis in 0..=dims using the raw values.
+    fn fit(raw: Coord3D, dims: Coord3D) -> Coord3D {
+        (
+            raw.0 % (dims.0 + 1),
+            raw.1 % (dims.1 + 1),
+            raw.2 % (dims.2 + 1),
+        )
+    }
+
+    fn inside_signed(coord: SignedCoord3D, dims: Coord3D) -> bool {
+        coord.0 >= 0
+            && coord.1 >= 0
+            && coord.2 >= 0
+            && (coord.0 as usize) < dims.0
+            && (coord.1 as usize) < dims.1
+            && (coord.2 as usize) < dims.2
+    }
+
+    fn numbered(dims: Coord3D) -> Vec<u32> {
+        (1..=(dims.0 * dims.1 * dims.2) as u32).collect()
+    }
+
+    /// Copies one element at a time from source to target wherever both coordinates are inside
+    /// their arrays, which is what every copy function should end up doing.
+    fn reference_copy(
+        size: Coord3D,
+        source: &[u32],
+        source_dims: Coord3D,
+        source_start: SignedCoord3D,
+        target: &mut [u32],
+        target_dims: Coord3D,
+        target_start: SignedCoord3D,
+    ) {
+        for z in 0..size.2 {
+            for y in 0..size.1 {
+                for x in 0..size.0 {
+                    let offset = (x, y, z).signed();
+                    let from = source_start.add(offset);
+                    let to = target_start.add(offset);
+                    if inside_signed(from, source_dims) && inside_signed(to, target_dims) {
+                        let from = (from.0 as usize, from.1 as usize, from.2 as usize);
+                        let to = (to.0 as usize, to.1 as usize, to.2 as usize);
+                        target[to.to_index(target_dims)] = source[from.to_index(source_dims)];
+                    }
+                }
+            }
+        }
+    }
+
+    fn reference_fill(
+        value: u32,
+        size: Coord3D,
+        target: &mut [u32],
+        target_dims: Coord3D,
+        target_start: SignedCoord3D,
+    ) {
+        let source = vec![value; size.0 * size.1 * size.2];
+        reference_copy(
+            size,
+            &source,
+            size,
+            (0, 0, 0),
+            target,
+            target_dims,
+            target_start,
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn copy_3d_matches_reference(
+            source_dims in dims(),
+            target_dims in dims(),
+            raw_size in (0usize..7, 0usize..7, 0usize..7),
+            raw_source_start in (0usize..7, 0usize..7, 0usize..7),
+            raw_target_position in (0usize..7, 0usize..7, 0usize..7),
+        ) {
+            let size = fit(raw_size, source_dims.ewmin(target_dims));
+            let source_start = fit(raw_source_start, source_dims.sub(size));
+            let target_position = fit(raw_target_position, target_dims.sub(size));
+            let source = numbered(source_dims);
+            let mut expected = vec![0; target_dims.0 * target_dims.1 * target_dims.2];
+            let mut actual = expected.clone();
+            reference_copy(
+                size,
+                &source,
+                source_dims,
+                source_start.signed(),
+                &mut expected,
+                target_dims,
+                target_position.signed(),
+            );
+            copy_3d(
+                size,
+                &source,
+                source_dims,
+                source_start,
+                &mut actual,
+                target_dims,
+                target_position,
+            );
+            prop_assert_eq!(actual, expected);
+        }
+
+        #[test]
+        fn copy_3d_auto_clip_matches_reference(
+            source_stride in 1usize..7,
+            target_stride in 1usize..7,
+            source_offset in offset(),
+        ) {
+            let source = numbered(source_stride.repeat());
+            let mut expected = vec![0; target_stride.pow(3)];
+            let mut actual = expected.clone();
+            reference_copy(
+                source_stride.repeat(),
+                &source,
+                source_stride.repeat(),
+                (0, 0, 0),
+                &mut expected,
+                target_stride.repeat(),
+                source_offset,
+            );
+            copy_3d_auto_clip(&source, source_stride, source_offset, &mut actual, target_stride);
+            prop_assert_eq!(actual, expected);
+        }
+
+        #[test]
+        fn copy_3d_bounded_auto_clip_matches_reference(
+            size in (0usize..9, 0usize..9, 0usize..9),
+            source_dims in dims(),
+            target_dims in dims(),
+            raw_source_start in (0usize..7, 0usize..7, 0usize..7),
+            target_start in offset(),
+        ) {
+            let source_start = fit(raw_source_start, source_dims);
+            let source = numbered(source_dims);
+            let mut expected = vec![0; target_dims.0 * target_dims.1 * target_dims.2];
+            let mut actual = expected.clone();
+            reference_copy(
+                size,
+                &source,
+                source_dims,
+                source_start.signed(),
+                &mut expected,
+                target_dims,
+                target_start,
+            );
+            copy_3d_bounded_auto_clip(
+                size,
+                &source,
+                source_dims,
+                source_start,
+                &mut actual,
+                target_dims,
+                target_start,
+            );
+            prop_assert_eq!(actual, expected);
+        }
+
+        #[test]
+        fn fill_slice_3d_auto_clip_matches_reference(
+            target_stride in 1usize..7,
+            slice_start in offset(),
+            slice_size in (0usize..9, 0usize..9, 0usize..9),
+        ) {
+            let mut expected = vec![0; target_stride.pow(3)];
+            let mut actual = expected.clone();
+            reference_fill(7, slice_size, &mut expected, target_stride.repeat(), slice_start);
+            fill_slice_3d_auto_clip(7, &mut actual, target_stride, slice_start, slice_size);
+            prop_assert_eq!(actual, expected);
+        }
+
+        #[test]
+        fn fill_3d_matches_reference(
+            target_dims in dims(),
+            raw_size in (0usize..7, 0usize..7, 0usize..7),
+            raw_position in (0usize..7, 0usize..7, 0usize..7),
+        ) {
+            let size = fit(raw_size, target_dims);
+            let position = fit(raw_position, target_dims.sub(size));
+            let mut expected = vec![0; target_dims.0 * target_dims.1 * target_dims.2];
+            let mut actual = expected.clone();
+            reference_fill(7, size, &mut expected, target_dims, position.signed());
+            fill_3d(7, size, &mut actual, target_dims, position);
+            prop_assert_eq!(actual, expected);
+        }
+    }
+    #[test]
+    fn copy_3d_auto_clip_ignores_disjoint_areas() {
+        let source = numbered((1, 1, 1));
+        let mut target = vec![0];
+        copy_3d_auto_clip(&source, 1, (0, 0, -2), &mut target, 1);
+        copy_3d_auto_clip(&source, 1, (3, 0, 0), &mut target, 1);
+        assert_eq!(target, vec![0]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn copy_3d_auto_clip_only_accepts_cubes() {
+        // A 4x4x2 source would be read as if it were 4x4x4.
+        let source = numbered((4, 4, 2));
+        let mut target = vec![0; 64];
+        copy_3d_auto_clip(&source, 4, (0, 0, 0), &mut target, 4);
+    }
+
+    #[test]
+    fn copy_3d_auto_clip_uses_each_stride() {
+        // The copied area is a cube as big as the smaller array, not the larger one.
+        let source = numbered((2, 2, 2));
+        let mut target = vec![0; 27];
+        copy_3d_auto_clip(&source, 2, (1, 1, 1), &mut target, 3);
+        assert_eq!(target[(1, 1, 1).to_index((3, 3, 3))], 1);
+        assert_eq!(target[(2, 2, 2).to_index((3, 3, 3))], 8);
+        assert_eq!(target.iter().filter(|value| **value != 0).count(), 8);
+    }
+
+    #[test]
+    fn copy_3d_bounded_auto_clip_skips_negative_target() {
+        let source = numbered((4, 1, 1));
+        let mut target = vec![0; 4 * 3 * 2];
+        copy_3d_bounded_auto_clip(
+            (4, 0, 0),
+            &source,
+            (4, 1, 1),
+            (2, 0, 0),
+            &mut target,
+            (4, 3, 2),
+            (-3, 0, 0),
+        );
+        assert!(target.iter().all(|value| *value == 0));
+        // Larger than the target, but only the part which lands inside it is copied.
+        copy_3d_bounded_auto_clip(
+            (8, 1, 1),
+            &numbered((6, 1, 1)),
+            (6, 1, 1),
+            (0, 0, 0),
+            &mut target,
+            (4, 3, 2),
+            (-3, 0, 0),
+        );
+        assert_eq!(target[..3], [4, 5, 6]);
+    }
+}
@@ -0,0 +1,414 @@
+use super::{ChunkStorage, ChunkStorageCoord, PackedChunkData};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Lists the chunks of a save which has been completely written to temporary files. Once it
+/// exists, the save counts as done even if renaming the temporary files is interrupted.
+const JOURNAL_NAME: &str = "journal";
+/// Chunks being saved are written to files with this extension before being renamed.
+const SAVING_EXTENSION: &str = "saving";
+/// The extension of the temporary files used by write_atomically.
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Counts temporary files so that threads writing the same file at once use different ones.
+static NEXT_TEMP_FILE: AtomicUsize = AtomicUsize::new(0);
+
+pub type SavedChunks = Vec<(ChunkStorageCoord, Arc<PackedChunkData>)>;
+/// Held while chunk files are renamed into place, so that a chunk which finished generating can
+/// check that nothing was saved over it in the meantime without racing the autosaver.
+pub type ChunkFileLock = Arc<Mutex<()>>;
+
+fn get_saving_path(storage_dir: &PathBuf, coord: &ChunkStorageCoord) -> PathBuf {
+    ChunkStorage::get_path_for(storage_dir, coord).with_extension(SAVING_EXTENSION)
+}
+
+/// Flushes the entries of a directory to disk, so that files which were created or renamed in it
+/// survive a power loss. Directories can only be opened as files on Unix, elsewhere renames are
+/// already durable once they return.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// A temporary file next to path which no other thread or process is using.
+fn get_temp_path(path: &Path) -> PathBuf {
+    let index = NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let process = std::process::id();
+    let temp_name = format!("{}.{}-{}.{}", name, process, index, TEMP_EXTENSION);
+    path.with_file_name(temp_name)
+}
+
+fn get_parent(path: &Path) -> &Path {
+    path.parent().unwrap_or_else(|| Path::new("."))
+}
+
+/// Writes to a temporary file which is flushed to disk and then handed to finish, which is
+/// expected to move it into place.
+fn write_through_temp(
+    path: &Path,
+    write: impl FnOnce(&PathBuf) -> io::Result<()>,
+    finish: impl FnOnce(&PathBuf) -> io::Result<()>,
+) -> io::Result<()> {
+    let temp_path = get_temp_path(path);
+    let result = write(&temp_path)
+        .and_then(|_| File::open(&temp_path)?.sync_all())
+        .and_then(|_| finish(&temp_path));
+    if result.is_err() {
+        // Nothing else will ever use the file, so it is only cleaned up on the next start.
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result?;
+    sync_dir(get_parent(path))
+}
+
+/// Writes to a temporary file which is renamed to path once it has been flushed to disk, so that
+/// path always holds either the old contents or the new contents.
+pub(super) fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&PathBuf) -> io::Result<()>,
+) -> io::Result<()> {
+    write_through_temp(path, write, |temp_path| std::fs::rename(temp_path, path))
+}
+
+/// Like write_atomically, but the file is only renamed into place while holding lock, so that it
+/// can't interleave with a save or a generated chunk replacing the same file.
+pub(super) fn write_atomically_locked(
+    path: &Path,
+    lock: &ChunkFileLock,
+    write: impl FnOnce(&PathBuf) -> io::Result<()>,
+) -> io::Result<()> {
+    write_through_temp(path, write, |temp_path| {
+        let _guard = lock.lock().unwrap();
+        std::fs::rename(temp_path, path)
+    })
+}
+
+/// Like write_atomically, but throws the new contents away if path already exists by the time
+/// they have been written. Chunks generated in the background are stored this way so that they
+/// never replace a chunk which was saved while they were being generated. Returns whether the
+/// new contents were kept.
+pub(super) fn write_if_absent(
+    path: &Path,
+    lock: &ChunkFileLock,
+    write: impl FnOnce(&PathBuf) -> io::Result<()>,
+) -> io::Result<bool> {
+    let mut kept = false;
+    write_through_temp(path, write, |temp_path| {
+        let _guard = lock.lock().unwrap();
+        if path.exists() {
+            std::fs::remove_file(temp_path)
+        } else {
+            kept = true;
+            std::fs::rename(temp_path, path)
+        }
+    })?;
+    Ok(kept)
+}
+
+fn write_journal(storage_dir: &PathBuf, chunks: &SavedChunks) -> io::Result<()> {
+    write_atomically(&storage_dir.join(JOURNAL_NAME), |path| {
+        let mut file = File::create(path)?;
+        for (coord, _) in chunks {
+            writeln!(file, "{} {} {}", coord.0, coord.1, coord.2)?;
+        }
+        Ok(())
+    })
+}
+
+fn read_journal(path: &Path) -> io::Result<Vec<ChunkStorageCoord>> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed journal entry.");
+    let mut coords = Vec::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        let values: Vec<isize> = line
+            .split_whitespace()
+            .map(|value| value.parse().map_err(|_| malformed()))
+            .collect::<Result<_, _>>()?;
+        match &values[..] {
+            [x, y, z] => coords.push((*x, *y, *z)),
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(coords)
+}
+
+/// Stores every chunk at once. The new contents of every chunk are written to temporary files
+/// first, then the journal is written, then the temporary files replace the old ones. If this is
+/// interrupted at any point, recover either finishes the save or throws it away completely, so
+/// the saved world never contains only some of the chunks from a save. The directory is flushed
+/// after each step so that a power loss can't reorder them.
+pub fn save_chunks(
+    storage_dir: &PathBuf,
+    lock: &ChunkFileLock,
+    chunks: &SavedChunks,
+) -> io::Result<()> {
+    for (coord, data) in chunks {
+        let path = get_saving_path(storage_dir, coord);
+        ChunkStorage::write_packed_chunk_data(&path, data)?;
+        File::open(&path)?.sync_all()?;
+    }
+    sync_dir(storage_dir)?;
+    write_journal(storage_dir, chunks)?;
+    {
+        let _guard = lock.lock().unwrap();
+        for (coord, _) in chunks {
+            std::fs::rename(
+                get_saving_path(storage_dir, coord),
+                ChunkStorage::get_path_for(storage_dir, coord),
+            )?;
+        }
+    }
+    sync_dir(storage_dir)?;
+    std::fs::remove_file(storage_dir.join(JOURNAL_NAME))?;
+    sync_dir(storage_dir)
+}
+
+/// Cleans up after a save which was interrupted by the game closing unexpectedly. Must be called
+/// before any chunks are read from the directory.
+pub fn recover(storage_dir: &PathBuf) -> io::Result<()> {
+    let journal_path = storage_dir.join(JOURNAL_NAME);
+    if journal_path.exists() {
+        println!("Finishing a save which was interrupted.");
+        for coord in read_journal(&journal_path)? {
+            let saving_path = get_saving_path(storage_dir, &coord);
+            // Chunks which were already renamed have no temporary file left.
+            if saving_path.exists() {
+                std::fs::rename(saving_path, ChunkStorage::get_path_for(storage_dir, &coord))?;
+            }
+        }
+        sync_dir(storage_dir)?;
+        std::fs::remove_file(&journal_path)?;
+    }
+    // Anything left over is from a save which never got as far as writing its journal, or from
+    // a write_atomically which was interrupted.
+    for entry in std::fs::read_dir(storage_dir)? {
+        let path = entry?.path();
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        if extension == Some(SAVING_EXTENSION) || extension == Some(TEMP_EXTENSION) {
+            std::fs::remove_file(path)?;
+        }
+    }
+    sync_dir(storage_dir)
+}
+
+/// Saves chunks on a background thread so that the game does not stall while they are written.
+pub struct Autosaver {
+    // Taken when the autosaver is dropped, which stops the thread.
+    requests: Option<Sender<SavedChunks>>,
+    completed: Receiver<SavedChunks>,
+    num_in_flight: usize,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Autosaver {
+    pub fn new(storage_dir: PathBuf, lock: ChunkFileLock) -> Self {
+        let (requests, request_receiver) = mpsc::channel::<SavedChunks>();
+        let (completed_sender, completed) = mpsc::channel();
+        let worker = thread::Builder::new()
+            .name("autosave".to_owned())
+            .spawn(move || {
+                for chunks in request_receiver {
+                    if let Err(err) = save_chunks(&storage_dir, &lock, &chunks) {
+                        println!("WARNING: Failed to save {} chunks.", chunks.len());
+                        println!("Caused by: {}", err);
+                        // Report nothing as saved so that they are tried again next time.
+                        let _ = completed_sender.send(Vec::new());
+                        continue;
+                    }
+                    // The receiver only disappears when the autosaver is being dropped.
+                    let _ = completed_sender.send(chunks);
+                }
+            })
+            .expect("Failed to spawn autosave thread.");
+        Self {
+            requests: Some(requests),
+            completed,
+            num_in_flight: 0,
+            worker: Some(worker),
+        }
+    }
+
+    /// Starts saving the chunks in the background.
+    pub fn save(&mut self, chunks: SavedChunks) {
+        self.num_in_flight += 1;
+        self.requests
+            .as_ref()
+            .unwrap()
+            .send(chunks)
+            .expect("Autosave thread stopped unexpectedly.");
+    }
+
+    pub fn is_saving(&self) -> bool {
+        self.num_in_flight > 0
+    }
+
+    /// Returns the chunks from every save which has finished since the last call. Chunks which
+    /// failed to save are left out.
+    pub fn poll_completed(&mut self) -> SavedChunks {
+        let mut saved = Vec::new();
+        for chunks in self.completed.try_iter() {
+            self.num_in_flight -= 1;
+            saved.extend(chunks);
+        }
+        saved
+    }
+
+    /// Like poll_completed, but waits for every save in progress to finish first.
+    pub fn wait_for_completed(&mut self) -> SavedChunks {
+        let mut saved = Vec::new();
+        while self.num_in_flight > 0 {
+            let chunks = self
+                .completed
+                .recv()
+                .expect("Autosave thread stopped unexpectedly.");
+            self.num_in_flight -= 1;
+            saved.extend(chunks);
+        }
+        saved
+    }
+}
+
+impl Drop for Autosaver {
+    fn drop(&mut self) {
+        // Lets the thread finish whatever it is saving and then stop.
+        self.requests = None;
+        if let Some(worker) = self.worker.take() {
+            worker.join().expect("Autosave thread panicked.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{constants::*, Material};
+    use crate::world::{test_util::make_temp_dir, UnpackedChunkData};
+
+    fn make_chunk(material: &Material) -> Arc<PackedChunkData> {
+        let mut unpacked = UnpackedChunkData::new();
+        unpacked.fill(material);
+        let mut packed = PackedChunkData::new();
+        unpacked.pack_into(&mut packed);
+        Arc::new(packed)
+    }
+
+    fn read_chunk(storage_dir: &PathBuf, coord: &ChunkStorageCoord) -> u32 {
+        let mut data = PackedChunkData::new();
+        let path = ChunkStorage::get_path_for(storage_dir, coord);
+        ChunkStorage::read_into_packed_chunk_data(&path, &mut data).unwrap();
+        data.materials[CHUNK_VOLUME - 1]
+    }
+
+    fn stone() -> Material {
+        Material {
+            solid: true,
+            albedo: (10, 20, 30),
+            ..Material::air()
+        }
+    }
+
+    fn num_files(storage_dir: &PathBuf) -> usize {
+        std::fs::read_dir(storage_dir).unwrap().count()
+    }
+
+    #[test]
+    fn save_leaves_only_chunks() {
+        let dir = make_temp_dir();
+        let chunks = vec![
+            ((0, 0, 0), make_chunk(&stone())),
+            ((-1, 2, 3), make_chunk(&stone())),
+        ];
+        save_chunks(&dir, &Default::default(), &chunks).unwrap();
+        assert_eq!(num_files(&dir), 2);
+        assert_eq!(read_chunk(&dir, &(-1, 2, 3)), stone().pack());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn journaled_save_is_finished() {
+        let dir = make_temp_dir();
+        let old = vec![((0, 0, 0), make_chunk(&Material::air()))];
+        save_chunks(&dir, &Default::default(), &old).unwrap();
+        // Pretend the game closed right after writing the journal.
+        let new = vec![
+            ((0, 0, 0), make_chunk(&stone())),
+            ((1, 0, 0), make_chunk(&stone())),
+        ];
+        for (coord, data) in &new {
+            ChunkStorage::write_packed_chunk_data(&get_saving_path(&dir, coord), data).unwrap();
+        }
+        write_journal(&dir, &new).unwrap();
+
+        recover(&dir).unwrap();
+        assert_eq!(num_files(&dir), 2);
+        assert_eq!(read_chunk(&dir, &(0, 0, 0)), stone().pack());
+        assert_eq!(read_chunk(&dir, &(1, 0, 0)), stone().pack());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_writes_are_cleaned_up() {
+        let dir = make_temp_dir();
+        let path = ChunkStorage::get_path_for(&dir, &(0, 0, 0));
+        // Threads writing the same chunk at once must not share a temporary file.
+        let temp_path = get_temp_path(&path);
+        assert_ne!(temp_path, get_temp_path(&path));
+        ChunkStorage::write_packed_chunk_data(&temp_path, &make_chunk(&stone())).unwrap();
+
+        recover(&dir).unwrap();
+        assert_eq!(num_files(&dir), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn generated_chunks_do_not_replace_saved_ones() {
+        let dir = make_temp_dir();
+        let lock = ChunkFileLock::default();
+        save_chunks(&dir, &lock, &vec![((0, 0, 0), make_chunk(&stone()))]).unwrap();
+        let path = ChunkStorage::get_path_for(&dir, &(0, 0, 0));
+        let kept = write_if_absent(&path, &lock, |temp_path| {
+            ChunkStorage::write_packed_chunk_data(temp_path, &make_chunk(&Material::air()))
+        })
+        .unwrap();
+        assert!(!kept);
+        assert_eq!(num_files(&dir), 1);
+        assert_eq!(read_chunk(&dir, &(0, 0, 0)), stone().pack());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn locked_writes_replace_corrupt_chunks() {
+        let dir = make_temp_dir();
+        let path = ChunkStorage::get_path_for(&dir, &(0, 0, 0));
+        std::fs::write(&path, b"not a chunk").unwrap();
+        write_atomically_locked(&path, &ChunkFileLock::default(), |temp_path| {
+            ChunkStorage::write_packed_chunk_data(temp_path, &make_chunk(&stone()))
+        })
+        .unwrap();
+        assert_eq!(num_files(&dir), 1);
+        assert_eq!(read_chunk(&dir, &(0, 0, 0)), stone().pack());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unjournaled_save_is_thrown_away() {
+        let dir = make_temp_dir();
+        let old = vec![((0, 0, 0), make_chunk(&Material::air()))];
+        save_chunks(&dir, &Default::default(), &old).unwrap();
+        // Pretend the game closed while the new chunks were still being written.
+        let new = make_chunk(&stone());
+        ChunkStorage::write_packed_chunk_data(&get_saving_path(&dir, &(0, 0, 0)), &new).unwrap();
+
+        recover(&dir).unwrap();
+        assert_eq!(num_files(&dir), 1);
+        assert_eq!(read_chunk(&dir, &(0, 0, 0)), Material::air().pack());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use super::generate;
+use lazy_static::lazy_static;
+use noise::{NoiseFn, OpenSimplex};
+
+lazy_static! {
+    static ref CLIMATE_NOISE: OpenSimplex = OpenSimplex::new();
+}
+
+/// How many blocks wide the features of the climate noise are.
+const CLIMATE_SCALE: f64 = 2400.0;
+/// Everything at least this high up is snowy, whatever the climate below it is.
+const SNOW_LINE: isize = 180;
+/// Swamps only form on terrain lower than this.
+const SWAMP_MAX_HEIGHT: isize = 40;
+
+/// Which kind of region of the world a block is in. Biomes do not change which blocks are
+/// generated, only how the air around them looks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Biome {
+    Plains,
+    Swamp,
+    Desert,
+    Snow,
+}
+
+/// Returns the biome of the block at the given position. Biomes cover whole columns of the world
+/// except above the snow line. This only uses the generated heightmap, so it can be called for
+/// regions which have not been generated yet.
+pub fn biome(x: isize, y: isize, z: isize) -> Biome {
+    if z >= SNOW_LINE {
+        return Biome::Snow;
+    }
+    let (sx, sy) = (x as f64 / CLIMATE_SCALE, y as f64 / CLIMATE_SCALE);
+    // Sampled far apart so that the two values are unrelated.
+    let temperature = CLIMATE_NOISE.get([sx, sy]);
+    let moisture = CLIMATE_NOISE.get([sx + 1000.0, sy - 1000.0]);
+    if temperature < -0.3 {
+        Biome::Snow
+    } else if moisture > 0.2 && generate::height(x, y) < SWAMP_MAX_HEIGHT {
+        Biome::Swamp
+    } else if moisture < -0.2 && temperature > 0.1 {
+        Biome::Desert
+    } else {
+        Biome::Plains
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn above_snow_line_is_snowy() {
+        assert_eq!(biome(0, 0, SNOW_LINE), Biome::Snow);
+        assert_eq!(biome(-5000, 7000, SNOW_LINE + 100), Biome::Snow);
+    }
+
+    #[test]
+    fn every_biome_appears_at_ground_level() {
+        let mut found = Vec::new();
+        for x in (-40_000..40_000).step_by(800) {
+            for y in (-40_000..40_000).step_by(800) {
+                let biome = biome(x, y, 0);
+                if !found.contains(&biome) {
+                    found.push(biome);
+                }
+            }
+        }
+        assert_eq!(found.len(), 4);
+    }
+}
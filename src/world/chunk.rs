@@ -49,10 +49,23 @@ impl PackedChunk {
     }
 }
 
+/// Whether every block of a chunk is the same, which lets the uploader fill it in instead of
+/// copying it and lets rays skip over it without reading the minefield.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkOccupancy {
+    /// Every block is air.
+    Empty,
+    /// Every block is the same solid material.
+    Solid,
+    Mixed,
+}
+
 #[derive(Clone, PartialEq)]
 pub struct PackedChunkData {
     pub minefield: Vec<u8>,
     pub materials: Vec<u32>,
+    // Kept up to date by pack_into and update_occupancy.
+    pub occupancy: ChunkOccupancy,
 }
 
 impl PackedChunkData {
@@ -60,9 +73,22 @@ impl PackedChunkData {
         PackedChunkData {
             minefield: vec![0; CHUNK_VOLUME],
             materials: vec![0; CHUNK_VOLUME],
+            occupancy: ChunkOccupancy::Mixed,
         }
     }
 
+    /// Works out the occupancy from the materials, for data which did not come from pack_into.
+    pub fn update_occupancy(&mut self) {
+        let first = self.materials[0];
+        self.occupancy = if self.materials.iter().any(|material| *material != first) {
+            ChunkOccupancy::Mixed
+        } else if self.minefield[0] == 0 {
+            ChunkOccupancy::Solid
+        } else {
+            ChunkOccupancy::Empty
+        };
+    }
+
     pub fn copy_materials(
         &self,
         source_offset: util::SignedCoord3D,
@@ -157,6 +183,7 @@ impl UnpackedChunkData {
                 packed_data.materials[index] = Material::air().pack();
                 packed_data.minefield[index] = MAX_CHUNK_LOD as u8;
             }
+            packed_data.occupancy = ChunkOccupancy::Empty;
             return;
         }
 
@@ -181,5 +208,29 @@ impl UnpackedChunkData {
                 current_lod += 1;
             }
         }
+        packed_data.update_occupancy();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn homogeneous_chunks_are_detected() {
+        let mut unpacked = UnpackedChunkData::new();
+        let mut packed = PackedChunkData::new();
+        unpacked.pack_into(&mut packed);
+        assert_eq!(packed.occupancy, ChunkOccupancy::Empty);
+        let stone = Material {
+            solid: true,
+            ..Material::air()
+        };
+        unpacked.fill(&stone);
+        unpacked.pack_into(&mut packed);
+        assert_eq!(packed.occupancy, ChunkOccupancy::Solid);
+        unpacked.set_block(&(3, 4, 5), Material::air());
+        unpacked.pack_into(&mut packed);
+        assert_eq!(packed.occupancy, ChunkOccupancy::Mixed);
     }
 }
@@ -0,0 +1,197 @@
+use super::autosave::{self, ChunkFileLock};
+use super::{ChunkStorage, ChunkStorageCoord, Heightmap, PackedChunkData, UnpackedChunkData};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+const NUM_WORKER_THREADS: usize = 3;
+
+/// A request for a chunk to be generated. Requests with a lower priority value are completed first.
+#[derive(PartialEq, Eq)]
+struct ChunkRequest {
+    coord: ChunkStorageCoord,
+    priority: u32,
+}
+
+impl Ord for ChunkRequest {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap pops the largest item first, so reverse the comparison to make it pop the
+        // smallest priority value first.
+        other
+            .priority
+            .cmp(&self.priority)
+            .then_with(|| self.coord.cmp(&other.coord))
+    }
+}
+
+impl PartialOrd for ChunkRequest {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct SharedQueue {
+    requests: Mutex<BinaryHeap<ChunkRequest>>,
+    requests_available: Condvar,
+    shutdown: AtomicBool,
+}
+
+/// Generates chunks that do not exist in storage yet on a pool of worker threads. Generated chunks
+/// are written to storage, and their coordinates are reported back through poll_completed.
+pub struct ChunkProvider {
+    queue: Arc<SharedQueue>,
+    completed: Receiver<(ChunkStorageCoord, bool)>,
+    // Chunks which have been requested but have not been reported as completed yet, along with
+    // the most urgent priority they have been requested with.
+    pending: HashMap<ChunkStorageCoord, u32>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ChunkProvider {
+    pub fn new(storage_dir: PathBuf, lock: ChunkFileLock) -> ChunkProvider {
+        let queue = Arc::new(SharedQueue {
+            requests: Mutex::new(BinaryHeap::new()),
+            requests_available: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+        let (sender, completed) = mpsc::channel();
+        let workers = (0..NUM_WORKER_THREADS)
+            .map(|index| {
+                let queue = Arc::clone(&queue);
+                let sender = sender.clone();
+                let storage_dir = storage_dir.clone();
+                let lock = Arc::clone(&lock);
+                thread::Builder::new()
+                    .name(format!("chunk_provider_{}", index))
+                    .spawn(move || worker_main(queue, sender, storage_dir, lock))
+                    .expect("Failed to spawn chunk provider thread.")
+            })
+            .collect();
+        ChunkProvider {
+            queue,
+            completed,
+            pending: HashMap::new(),
+            workers,
+        }
+    }
+
+    /// Asks for a chunk to be generated in the background. If the chunk has already been requested
+    /// with a less urgent priority, it is queued again with the new priority.
+    pub fn request(&mut self, coord: &ChunkStorageCoord, priority: u32) {
+        if let Some(old_priority) = self.pending.get(coord) {
+            if *old_priority <= priority {
+                return;
+            }
+        }
+        self.pending.insert(*coord, priority);
+        self.queue.requests.lock().unwrap().push(ChunkRequest {
+            coord: *coord,
+            priority,
+        });
+        self.queue.requests_available.notify_one();
+    }
+
+    /// Returns how many requested chunks have not finished generating yet.
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns the coordinates of all chunks which have finished generating since the last call,
+    /// along with whether they were generated by the provider. A chunk can be reported more than
+    /// once if it was requested again with a different priority, but it is only generated once.
+    pub fn poll_completed(&mut self) -> Vec<(ChunkStorageCoord, bool)> {
+        let completed: Vec<_> = self.completed.try_iter().collect();
+        for (coord, _) in &completed {
+            self.pending.remove(coord);
+        }
+        completed
+    }
+}
+
+impl Drop for ChunkProvider {
+    fn drop(&mut self) {
+        self.queue.shutdown.store(true, AtomicOrdering::SeqCst);
+        self.queue.requests_available.notify_all();
+        for worker in self.workers.drain(..) {
+            worker.join().expect("Chunk provider thread panicked.");
+        }
+    }
+}
+
+fn worker_main(
+    queue: Arc<SharedQueue>,
+    sender: Sender<(ChunkStorageCoord, bool)>,
+    storage_dir: PathBuf,
+    lock: ChunkFileLock,
+) {
+    let mut heightmap = Heightmap::new();
+    let mut unpacked_data = UnpackedChunkData::new();
+    let mut packed_data = PackedChunkData::new();
+    loop {
+        let request = {
+            let mut requests = queue.requests.lock().unwrap();
+            loop {
+                if queue.shutdown.load(AtomicOrdering::SeqCst) {
+                    return;
+                }
+                if let Some(request) = requests.pop() {
+                    break request;
+                }
+                requests = queue.requests_available.wait(requests).unwrap();
+            }
+        };
+        let coord = request.coord;
+        let path = ChunkStorage::get_path_for(&storage_dir, &coord);
+        // The same chunk may have been queued more than once with different priorities.
+        let mut generated = false;
+        if !path.exists() {
+            super::generate_heightmap(&mut heightmap, &(coord.0, coord.1));
+            super::generate_chunk(&mut unpacked_data, &coord, &heightmap);
+            unpacked_data.pack_into(&mut packed_data);
+            // Written to a temporary file first so that the main thread never reads a partially
+            // written chunk. The chunk may have been edited and saved while it was generating, in
+            // which case the generated one is thrown away.
+            let result = autosave::write_if_absent(&path, &lock, |temp_path| {
+                ChunkStorage::write_packed_chunk_data(temp_path, &packed_data)
+            });
+            match result {
+                Ok(kept) => generated = kept,
+                Err(err) => {
+                    println!("WARNING: Failed to write chunk data for {:?}.", coord);
+                    println!("Caused by: {}", err);
+                }
+            }
+        }
+        // The receiver only disappears when the provider is being dropped.
+        let _ = sender.send((coord, generated));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_urgent_request_first() {
+        let mut heap = BinaryHeap::new();
+        heap.push(ChunkRequest {
+            coord: (0, 0, 0),
+            priority: 5,
+        });
+        heap.push(ChunkRequest {
+            coord: (1, 0, 0),
+            priority: 1,
+        });
+        heap.push(ChunkRequest {
+            coord: (2, 0, 0),
+            priority: 3,
+        });
+        assert_eq!(heap.pop().unwrap().coord, (1, 0, 0));
+        assert_eq!(heap.pop().unwrap().coord, (2, 0, 0));
+        assert_eq!(heap.pop().unwrap().coord, (0, 0, 0));
+    }
+}
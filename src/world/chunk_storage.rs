@@ -1,21 +1,67 @@
-use super::{Heightmap, PackedChunkData, UnpackedChunkData};
+use super::autosave::{self, Autosaver, ChunkFileLock};
+use super::{
+    ChunkProvider, Heightmap, PackedChunkData, UnpackedChunkData, WarmCache, WarmCacheKey,
+    WorldWrap,
+};
+use crate::render::{constants::*, Material};
+use crate::util::{self, prelude::*};
 use array_macro::array;
 use lz4::{Decoder, EncoderBuilder};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::ops::Deref;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 pub type ChunkStorageCoord = (isize, isize, isize);
 
 const HEADER_SIZE: u64 = 16;
 const NUM_BUFFERS: usize = 256;
 
+/// The data of a chunk returned by load_packed_chunks_or_placeholders.
+pub enum LoadedChunk<'a> {
+    /// The chunk was already in memory, or is the placeholder.
+    Shared(Arc<PackedChunkData>),
+    /// The chunk was read from disk into one of the caller's buffers.
+    Read(&'a PackedChunkData),
+}
+
+impl<'a> Deref for LoadedChunk<'a> {
+    type Target = PackedChunkData;
+
+    fn deref(&self) -> &PackedChunkData {
+        match self {
+            LoadedChunk::Shared(data) => data,
+            LoadedChunk::Read(data) => data,
+        }
+    }
+}
+
 pub struct ChunkStorage {
     storage_dir: PathBuf,
     uc_buffers: [UnpackedChunkData; NUM_BUFFERS],
     available_uc_buffers: Vec<usize>,
     pc_buffers: [PackedChunkData; NUM_BUFFERS],
     available_pc_buffers: Vec<usize>,
+    // Chunks which have been modified since they were last uploaded to the GPU.
+    dirty_chunks: HashSet<ChunkStorageCoord>,
+    // The contents of chunks which have been modified since they were last saved. These are
+    // read instead of the files until the autosaver has replaced the files.
+    unsaved_chunks: HashMap<ChunkStorageCoord, Arc<PackedChunkData>>,
+    autosaver: Autosaver,
+    provider: ChunkProvider,
+    // Shared with the autosaver and the provider so that generated chunks never replace saved ones.
+    file_lock: ChunkFileLock,
+    // Returned in place of chunks which are still being generated.
+    placeholder: Arc<PackedChunkData>,
+    // Chunks which the placeholder was returned for, which need to be marked dirty once they are
+    // done being generated.
+    placeheld_chunks: HashSet<ChunkStorageCoord>,
+    // How many chunks have been generated, both here and in the background.
+    chunks_generated: usize,
+    wrap: WorldWrap,
 }
 
 impl ChunkStorage {
@@ -25,21 +71,61 @@ impl ChunkStorage {
             .join("raytrace")
             .join("world");
         std::fs::create_dir_all(&storage_dir).expect("Failed to create chunk storage directory.");
+        Self::with_storage_dir(storage_dir)
+    }
+
+    /// Stores chunks in the given directory instead of the one in the user's config folder. The
+    /// directory must already exist.
+    pub fn with_storage_dir(storage_dir: PathBuf) -> ChunkStorage {
+        if let Err(err) = autosave::recover(&storage_dir) {
+            println!("WARNING: Failed to recover from an interrupted save.");
+            println!("Caused by: {}", err);
+        }
+        let mut placeholder = PackedChunkData::new();
+        UnpackedChunkData::new().pack_into(&mut placeholder);
+        let file_lock = ChunkFileLock::default();
         ChunkStorage {
-            storage_dir,
+            provider: ChunkProvider::new(storage_dir.clone(), Arc::clone(&file_lock)),
             uc_buffers: array![UnpackedChunkData::new(); NUM_BUFFERS],
             available_uc_buffers: (0..NUM_BUFFERS).collect(),
             pc_buffers: array![PackedChunkData::new(); NUM_BUFFERS],
             available_pc_buffers: (0..NUM_BUFFERS).collect(),
+            dirty_chunks: HashSet::new(),
+            unsaved_chunks: HashMap::new(),
+            autosaver: Autosaver::new(storage_dir.clone(), Arc::clone(&file_lock)),
+            file_lock,
+            placeholder: Arc::new(placeholder),
+            placeheld_chunks: HashSet::new(),
+            chunks_generated: 0,
+            wrap: WorldWrap::default(),
+            storage_dir,
         }
     }
 
-    fn get_path_for(base: &PathBuf, coord: &ChunkStorageCoord) -> PathBuf {
+    /// Makes every chunk lookup wrap around at the given world size. Chunks are stored under the
+    /// coordinate returned by WorldWrap::wrap, so copies of a chunk all share the same data.
+    pub fn set_wrap(&mut self, wrap: WorldWrap) {
+        if wrap != self.wrap {
+            // These were recorded using the old coordinates.
+            self.dirty_chunks.clear();
+            self.placeheld_chunks.clear();
+        }
+        self.wrap = wrap;
+    }
+
+    pub fn get_wrap(&self) -> WorldWrap {
+        self.wrap
+    }
+
+    pub(super) fn get_path_for(base: &PathBuf, coord: &ChunkStorageCoord) -> PathBuf {
         let filename = format!("{:016X}{:016X}{:016X}", coord.0, coord.1, coord.2);
         base.join(filename)
     }
 
-    fn write_packed_chunk_data(path: &PathBuf, data: &PackedChunkData) -> io::Result<()> {
+    pub(super) fn write_packed_chunk_data(
+        path: &PathBuf,
+        data: &PackedChunkData,
+    ) -> io::Result<()> {
         let file = File::create(path)?;
         let mut writer = EncoderBuilder::new().level(4).build(file)?;
         unsafe {
@@ -53,7 +139,10 @@ impl ChunkStorage {
         Ok(())
     }
 
-    fn read_into_packed_chunk_data(path: &PathBuf, data: &mut PackedChunkData) -> io::Result<()> {
+    pub(super) fn read_into_packed_chunk_data(
+        path: &PathBuf,
+        data: &mut PackedChunkData,
+    ) -> io::Result<()> {
         let file = File::open(path)?;
         let mut reader = Decoder::new(file)?;
 
@@ -64,27 +153,34 @@ impl ChunkStorage {
             reader.read_exact(mat_slice_u8)?;
         }
         reader.read_exact(&mut data.minefield[..])?;
+        data.update_occupancy();
         Ok(())
     }
 
     fn has_chunk(&self, coord: &ChunkStorageCoord) -> bool {
-        Self::get_path_for(&self.storage_dir, coord).exists()
+        self.unsaved_chunks.contains_key(coord)
+            || Self::get_path_for(&self.storage_dir, coord).exists()
     }
 
     fn generate_and_store_chunk(&mut self, coord: &ChunkStorageCoord) -> (usize, usize) {
         let pc_buffer_index = self.available_pc_buffers.pop().unwrap();
         let uc_buffer_index = self.available_uc_buffers.pop().unwrap();
 
+        self.chunks_generated += 1;
         let mut heightmap = Heightmap::new();
         super::generate_heightmap(&mut heightmap, &(coord.0, coord.1));
         let unpacked_data = &mut self.uc_buffers[uc_buffer_index];
         super::generate_chunk(unpacked_data, &(coord.0, coord.1, coord.2), &heightmap);
         let packed_data = &mut self.pc_buffers[pc_buffer_index];
         unpacked_data.pack_into(packed_data);
-        if let Err(err) = Self::write_packed_chunk_data(
-            &Self::get_path_for(&self.storage_dir, coord),
-            &self.pc_buffers[pc_buffer_index],
-        ) {
+        let path = Self::get_path_for(&self.storage_dir, coord);
+        let data = &self.pc_buffers[pc_buffer_index];
+        // This only happens when the file is missing or can't be read, so it is replaced. The
+        // chunk can't be in the middle of being saved, it would still be in unsaved_chunks.
+        let result = autosave::write_atomically_locked(&path, &self.file_lock, |temp_path| {
+            Self::write_packed_chunk_data(temp_path, data)
+        });
+        if let Err(err) = result {
             println!("WARNING: Failed to write chunk data for {:?}.", coord);
             println!("Caused by: {}", err);
         }
@@ -93,7 +189,13 @@ impl ChunkStorage {
     }
 
     fn load_chunk_data(&mut self, coord: &ChunkStorageCoord) -> (usize, usize) {
-        if self.has_chunk(coord) {
+        if let Some(data) = self.unsaved_chunks.get(coord) {
+            let pc_buffer_index = self.available_pc_buffers.pop().unwrap();
+            let uc_buffer_index = self.available_uc_buffers.pop().unwrap();
+            self.pc_buffers[pc_buffer_index].clone_from(data);
+            data.unpack_into(&mut self.uc_buffers[uc_buffer_index]);
+            (pc_buffer_index, uc_buffer_index)
+        } else if self.has_chunk(coord) {
             let pc_buffer_index = self.available_pc_buffers.pop().unwrap();
             let uc_buffer_index = self.available_uc_buffers.pop().unwrap();
 
@@ -120,7 +222,11 @@ impl ChunkStorage {
     }
 
     fn load_packed_chunk_data(&mut self, coord: &ChunkStorageCoord) -> usize {
-        if self.has_chunk(coord) {
+        if let Some(data) = self.unsaved_chunks.get(coord) {
+            let pc_buffer_index = self.available_pc_buffers.pop().unwrap();
+            self.pc_buffers[pc_buffer_index].clone_from(data);
+            pc_buffer_index
+        } else if self.has_chunk(coord) {
             let pc_buffer_index = self.available_pc_buffers.pop().unwrap();
 
             match Self::read_into_packed_chunk_data(
@@ -145,25 +251,364 @@ impl ChunkStorage {
     }
 
     pub fn borrow_packed_chunk_data(&mut self, coord: &ChunkStorageCoord) -> &PackedChunkData {
+        let coord = &self.wrap.wrap(*coord);
         let index = self.load_packed_chunk_data(coord);
         self.available_pc_buffers.push(index);
         &self.pc_buffers[index]
     }
+
+    /// Like borrow_packed_chunk_data, but chunks which have not been generated yet are requested
+    /// from a background thread instead of being generated immediately. An empty placeholder is
+    /// returned in the meantime, and the chunk will be marked dirty once it is ready. Lower
+    /// priority values are generated first.
+    pub fn borrow_packed_chunk_data_or_placeholder(
+        &mut self,
+        coord: &ChunkStorageCoord,
+        priority: u32,
+    ) -> &PackedChunkData {
+        self.poll_provider();
+        let coord = &self.wrap.wrap(*coord);
+        if self.has_chunk(coord) {
+            return self.borrow_packed_chunk_data(coord);
+        }
+        self.provider.request(coord, priority);
+        self.placeheld_chunks.insert(*coord);
+        &self.placeholder
+    }
+
+    /// Like borrow_packed_chunk_data_or_placeholder, but the data stays around after the storage
+    /// is used again, so that several chunks can be read at once. Chunks are read straight into
+    /// their own buffers instead of being copied out of the storage's.
+    pub fn share_packed_chunk_data_or_placeholder(
+        &mut self,
+        coord: &ChunkStorageCoord,
+        priority: u32,
+    ) -> Arc<PackedChunkData> {
+        self.poll_provider();
+        let coord = &self.wrap.wrap(*coord);
+        if let Some(data) = self.unsaved_chunks.get(coord) {
+            return Arc::clone(data);
+        }
+        if !self.has_chunk(coord) {
+            self.provider.request(coord, priority);
+            self.placeheld_chunks.insert(*coord);
+            return Arc::clone(&self.placeholder);
+        }
+        let mut data = PackedChunkData::new();
+        let path = Self::get_path_for(&self.storage_dir, coord);
+        match Self::read_into_packed_chunk_data(&path, &mut data) {
+            Ok(..) => Arc::new(data),
+            // Warns about the chunk and generates it again.
+            Err(..) => Arc::new(self.borrow_packed_chunk_data(coord).clone()),
+        }
+    }
+
+    /// Like share_packed_chunk_data_or_placeholder for several chunks at once, each with its own
+    /// priority. Chunks stored on disk are read in parallel into buffers, which are reused by
+    /// later calls instead of allocating new ones. The results are in the same order as chunks.
+    pub fn load_packed_chunks_or_placeholders<'a>(
+        &mut self,
+        chunks: &[(ChunkStorageCoord, u32)],
+        buffers: &'a mut Vec<PackedChunkData>,
+    ) -> Vec<LoadedChunk<'a>> {
+        self.poll_provider();
+        // None for chunks which have to be read from disk.
+        let mut shared = Vec::with_capacity(chunks.len());
+        let mut to_read = Vec::new();
+        for (coord, priority) in chunks {
+            let coord = self.wrap.wrap(*coord);
+            if let Some(data) = self.unsaved_chunks.get(&coord) {
+                shared.push(Some(Arc::clone(data)));
+            } else if self.has_chunk(&coord) {
+                shared.push(None);
+                to_read.push(coord);
+            } else {
+                self.provider.request(&coord, *priority);
+                self.placeheld_chunks.insert(coord);
+                shared.push(Some(Arc::clone(&self.placeholder)));
+            }
+        }
+        if buffers.len() < to_read.len() {
+            buffers.resize_with(to_read.len(), PackedChunkData::new);
+        }
+        let storage_dir = &self.storage_dir;
+        let results: Vec<_> = to_read
+            .par_iter()
+            .zip(buffers.par_iter_mut())
+            .map(|(coord, buffer)| {
+                let path = Self::get_path_for(storage_dir, coord);
+                Self::read_into_packed_chunk_data(&path, buffer)
+            })
+            .collect();
+        for ((coord, result), buffer) in to_read.iter().zip(results).zip(buffers.iter_mut()) {
+            if result.is_err() {
+                // Warns about the chunk and generates it again.
+                buffer.clone_from(self.borrow_packed_chunk_data(coord));
+            }
+        }
+        let mut read = buffers.iter();
+        shared
+            .into_iter()
+            .map(|data| match data {
+                Some(data) => LoadedChunk::Shared(data),
+                None => LoadedChunk::Read(read.next().unwrap()),
+            })
+            .collect()
+    }
+
+    /// Requests a chunk which a placeholder was returned for again with the given priority, so
+    /// that it is generated sooner if the priority is more urgent than before.
+    pub fn prioritize_placeholder(&mut self, coord: &ChunkStorageCoord, priority: u32) {
+        self.poll_provider();
+        let coord = &self.wrap.wrap(*coord);
+        if self.placeheld_chunks.contains(coord) {
+            self.provider.request(coord, priority);
+        }
+    }
+
+    /// How many chunks have been generated since the storage was created. Chunks loaded from disk
+    /// are not counted.
+    pub fn get_chunks_generated(&self) -> usize {
+        self.chunks_generated
+    }
+
+    /// True if any chunks are still being generated in the background.
+    pub fn is_generating(&self) -> bool {
+        self.provider.num_pending() > 0
+    }
+
+    /// How many chunks are waiting to be generated in the background.
+    pub fn get_chunks_pending(&self) -> usize {
+        self.provider.num_pending()
+    }
+
+    /// True once nothing is being generated and every chunk which changed has been taken by
+    /// take_dirty_chunks.
+    pub fn is_settled(&self) -> bool {
+        !self.is_generating() && self.dirty_chunks.len() == 0
+    }
+
+    /// Marks chunks which were substituted with a placeholder as dirty once they are ready.
+    fn poll_provider(&mut self) {
+        for (coord, generated) in self.provider.poll_completed() {
+            if generated {
+                self.chunks_generated += 1;
+            }
+            if self.placeheld_chunks.remove(&coord) {
+                self.dirty_chunks.insert(coord);
+            }
+        }
+    }
+
+    fn get_warm_cache_path(&self) -> PathBuf {
+        self.storage_dir.join("warm_cache")
+    }
+
+    /// Returns the warm cache saved by save_warm_cache if there is one for the given key and no
+    /// chunks have been edited since it was saved.
+    pub fn load_warm_cache(&self, key: WarmCacheKey) -> Option<WarmCache> {
+        let path = self.get_warm_cache_path();
+        if !path.exists() {
+            return None;
+        }
+        match WarmCache::read(&path, key) {
+            Ok(cache) => cache,
+            Err(err) => {
+                println!("WARNING: Failed to read the warm cache.");
+                println!("Caused by: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn save_warm_cache(&self, key: WarmCacheKey, cache: &WarmCache) {
+        if let Err(err) = cache.write(&self.get_warm_cache_path(), key) {
+            println!("WARNING: Failed to write the warm cache.");
+            println!("Caused by: {}", err);
+        }
+    }
+
+    /// Loads a chunk, lets edit modify its contents, then keeps the result until the next autosave
+    /// and marks the chunk as dirty so that it will be uploaded to the GPU again.
+    fn edit_chunk(&mut self, coord: &ChunkStorageCoord, edit: impl FnOnce(&mut UnpackedChunkData)) {
+        let coord = &self.wrap.wrap(*coord);
+        let (pc_buffer_index, uc_buffer_index) = self.load_chunk_data(coord);
+        edit(&mut self.uc_buffers[uc_buffer_index]);
+        // The cache could hold the old contents of the chunk. Nothing needs doing if there is no
+        // cache to remove.
+        let _ = std::fs::remove_file(self.get_warm_cache_path());
+        self.uc_buffers[uc_buffer_index].pack_into(&mut self.pc_buffers[pc_buffer_index]);
+        let data = Arc::new(self.pc_buffers[pc_buffer_index].clone());
+        self.unsaved_chunks.insert(*coord, data);
+        self.available_pc_buffers.push(pc_buffer_index);
+        self.available_uc_buffers.push(uc_buffer_index);
+        self.dirty_chunks.insert(*coord);
+    }
+
+    /// Splits a coordinate in world blocks into the chunk it is in and where it is in that chunk.
+    fn split_block_coord(coord: &SignedCoord3D) -> (ChunkStorageCoord, Coord3D) {
+        let size = CHUNK_SIZE as isize;
+        let chunk_coord = (
+            coord.0.div_euclid(size),
+            coord.1.div_euclid(size),
+            coord.2.div_euclid(size),
+        );
+        let local_coord = (
+            coord.0.rem_euclid(size) as usize,
+            coord.1.rem_euclid(size) as usize,
+            coord.2.rem_euclid(size) as usize,
+        );
+        (chunk_coord, local_coord)
+    }
+
+    /// Returns a single block, specified in world coordinates. Chunks which have not been
+    /// generated yet are treated as empty.
+    pub fn get_block(&mut self, coord: &SignedCoord3D) -> Material {
+        let (chunk_coord, local_coord) = Self::split_block_coord(coord);
+        let chunk = self.borrow_packed_chunk_data_or_placeholder(&chunk_coord, 0);
+        Material::unpack(chunk.materials[local_coord.to_index(CHUNK_SIZE.repeat())])
+    }
+
+    /// Like get_block, but each chunk is only loaded once no matter how many of the blocks are in
+    /// it. The results are in the same order as coords.
+    pub fn get_blocks(&mut self, coords: &[SignedCoord3D]) -> Vec<Material> {
+        let mut by_chunk: HashMap<ChunkStorageCoord, Vec<usize>> = HashMap::new();
+        for (index, coord) in coords.iter().enumerate() {
+            let (chunk_coord, _) = Self::split_block_coord(coord);
+            by_chunk.entry(chunk_coord).or_default().push(index);
+        }
+        let mut blocks = vec![Material::air(); coords.len()];
+        for (chunk_coord, indexes) in by_chunk {
+            let chunk = self.borrow_packed_chunk_data_or_placeholder(&chunk_coord, 0);
+            for index in indexes {
+                let (_, local_coord) = Self::split_block_coord(&coords[index]);
+                let packed = chunk.materials[local_coord.to_index(CHUNK_SIZE.repeat())];
+                blocks[index] = Material::unpack(packed);
+            }
+        }
+        blocks
+    }
+
+    /// Changes a single block, specified in world coordinates.
+    pub fn set_block(&mut self, coord: &SignedCoord3D, value: Material) {
+        let (chunk_coord, local_coord) = Self::split_block_coord(coord);
+        self.edit_chunk(&chunk_coord, |data| data.set_block(&local_coord, value));
+    }
+
+    /// Like set_block, but each chunk is only stored and marked dirty once no matter how many of
+    /// the blocks are in it.
+    pub fn set_blocks(&mut self, blocks: &[(SignedCoord3D, Material)]) {
+        let mut by_chunk: HashMap<ChunkStorageCoord, Vec<(Coord3D, &Material)>> = HashMap::new();
+        for (coord, value) in blocks {
+            let (chunk_coord, local_coord) = Self::split_block_coord(coord);
+            by_chunk
+                .entry(chunk_coord)
+                .or_default()
+                .push((local_coord, value));
+        }
+        for (chunk_coord, blocks) in by_chunk {
+            self.edit_chunk(&chunk_coord, |data| {
+                for (local_coord, value) in blocks {
+                    data.set_block(&local_coord, value.clone());
+                }
+            });
+        }
+    }
+
+    /// Sets every block within radius of center (in world coordinates) to the given value.
+    pub fn fill_sphere(&mut self, center: &SignedCoord3D, radius: isize, value: &Material) {
+        let size = CHUNK_SIZE as isize;
+        let min = center.sub(radius.repeat());
+        let max = center.add(radius.repeat());
+        let min_chunk = (
+            min.0.div_euclid(size),
+            min.1.div_euclid(size),
+            min.2.div_euclid(size),
+        );
+        let max_chunk = (
+            max.0.div_euclid(size),
+            max.1.div_euclid(size),
+            max.2.div_euclid(size),
+        );
+        for cz in min_chunk.2..=max_chunk.2 {
+            for cy in min_chunk.1..=max_chunk.1 {
+                for cx in min_chunk.0..=max_chunk.0 {
+                    let origin = (cx, cy, cz).scale(size);
+                    self.edit_chunk(&(cx, cy, cz), |data| {
+                        for local in util::coord_iter_3d(CHUNK_SIZE) {
+                            let delta = local.signed().add(origin).sub(*center);
+                            let distance2 =
+                                delta.0 * delta.0 + delta.1 * delta.1 + delta.2 * delta.2;
+                            if distance2 <= radius * radius {
+                                data.set_block(&local, value.clone());
+                            }
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    /// Forgets about unsaved chunks which the autosaver has finished saving, unless they have been
+    /// modified again since it started.
+    fn poll_autosaver(&mut self, saved: autosave::SavedChunks) {
+        for (coord, data) in saved {
+            if let Some(unsaved) = self.unsaved_chunks.get(&coord) {
+                if Arc::ptr_eq(unsaved, &data) {
+                    self.unsaved_chunks.remove(&coord);
+                }
+            }
+        }
+    }
+
+    /// Starts saving every chunk which has been modified since the last save on a background
+    /// thread. Nothing happens if the last save is still in progress. Each save either happens
+    /// completely or not at all, see autosave::save_chunks.
+    pub fn autosave(&mut self) {
+        let saved = self.autosaver.poll_completed();
+        self.poll_autosaver(saved);
+        if self.autosaver.is_saving() || self.unsaved_chunks.len() == 0 {
+            return;
+        }
+        let chunks = self
+            .unsaved_chunks
+            .iter()
+            .map(|(coord, data)| (*coord, Arc::clone(data)))
+            .collect();
+        self.autosaver.save(chunks);
+    }
+
+    /// Saves every modified chunk and waits for it to finish, for when the game is closing.
+    pub fn save_and_wait(&mut self) {
+        let saved = self.autosaver.wait_for_completed();
+        self.poll_autosaver(saved);
+        self.autosave();
+        let saved = self.autosaver.wait_for_completed();
+        self.poll_autosaver(saved);
+    }
+
+    /// How many chunks have been modified since they were last saved.
+    pub fn get_num_unsaved_chunks(&self) -> usize {
+        self.unsaved_chunks.len()
+    }
+
+    /// Returns up to max_count chunks which have been modified since the last time they were
+    /// returned by this function. If the world wraps, these are the coordinates the chunks are
+    /// stored under, see WorldWrap::copies_from.
+    pub fn take_dirty_chunks(&mut self, max_count: usize) -> Vec<ChunkStorageCoord> {
+        self.poll_provider();
+        let taken: Vec<_> = self.dirty_chunks.iter().take(max_count).cloned().collect();
+        for coord in &taken {
+            self.dirty_chunks.remove(coord);
+        }
+        taken
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rand::RngCore;
-
-    fn make_temp_dir() -> PathBuf {
-        let path = std::env::temp_dir().join(format!(
-            "raytraceTestDir{:08X}",
-            rand::thread_rng().next_u32()
-        ));
-        std::fs::create_dir(&path).unwrap();
-        path
-    }
+    use crate::world::test_util::make_temp_dir;
 
     fn cleanup(dir: PathBuf) {
         std::fs::remove_dir_all(dir).unwrap();
@@ -180,4 +625,116 @@ mod tests {
 
         cleanup(storage.storage_dir);
     }
+
+    #[test]
+    fn shared_chunks_match_borrowed_ones() {
+        let storage_dir = make_temp_dir();
+        let mut storage = ChunkStorage::with_storage_dir(storage_dir.clone());
+        let borrowed = storage.borrow_packed_chunk_data(&(0, 0, 0)).clone();
+        let shared = storage.share_packed_chunk_data_or_placeholder(&(0, 0, 0), 0);
+        assert!(*shared == borrowed);
+        // Loading another chunk must not change the shared one.
+        storage.borrow_packed_chunk_data(&(1, 0, 0));
+        assert!(*shared == borrowed);
+        drop(storage);
+        cleanup(storage_dir);
+    }
+
+    #[test]
+    fn loaded_chunks_match_borrowed_ones() {
+        let storage_dir = make_temp_dir();
+        let mut storage = ChunkStorage::with_storage_dir(storage_dir.clone());
+        let stored = storage.borrow_packed_chunk_data(&(0, 0, 0)).clone();
+        storage.set_block(&(CHUNK_SIZE as isize, 0, 0), Material::air());
+        let unsaved = storage.borrow_packed_chunk_data(&(1, 0, 0)).clone();
+        let mut buffers = Vec::new();
+        let chunks = [
+            ((0, 0, 0), 0),
+            ((1, 0, 0), 0),
+            ((9, 9, 9), 0),
+            ((0, 0, 0), 0),
+        ];
+        let loaded = storage.load_packed_chunks_or_placeholders(&chunks, &mut buffers);
+        assert!(*loaded[0] == stored);
+        assert!(*loaded[1] == unsaved);
+        assert!(*loaded[2] == *storage.placeholder);
+        assert!(*loaded[3] == stored);
+        drop(storage);
+        cleanup(storage_dir);
+    }
+
+    #[test]
+    fn set_block_marks_dirty() {
+        let mut storage = ChunkStorage {
+            storage_dir: make_temp_dir(),
+            ..ChunkStorage::new()
+        };
+
+        let material = Material {
+            albedo: (1, 2, 3),
+            emission: (0, 0, 0),
+            roughness: 127,
+            solid: true,
+            transparent: false,
+            emitter: 0,
+        };
+        storage.set_block(&(-1, 2, 3), material.clone());
+        assert_eq!(storage.take_dirty_chunks(8), vec![(-1, 0, 0)]);
+        assert_eq!(storage.take_dirty_chunks(8), vec![]);
+
+        let index = util::coord_to_index_3d(&(CHUNK_SIZE - 1, 2, 3), CHUNK_SIZE);
+        let chunk = storage.borrow_packed_chunk_data(&(-1, 0, 0));
+        assert_eq!(chunk.materials[index], material.pack());
+        assert_eq!(chunk.minefield[index], 0);
+
+        cleanup(storage.storage_dir);
+    }
+
+    #[test]
+    fn edits_are_kept_until_saved() {
+        let storage_dir = make_temp_dir();
+        let mut storage = ChunkStorage::with_storage_dir(storage_dir.clone());
+        let material = Material {
+            solid: true,
+            albedo: (7, 8, 9),
+            ..Material::air()
+        };
+        storage.set_block(&(1, 2, 3), material.clone());
+        assert_eq!(storage.get_num_unsaved_chunks(), 1);
+        assert_eq!(storage.get_block(&(1, 2, 3)).pack(), material.pack());
+        storage.save_and_wait();
+        assert_eq!(storage.get_num_unsaved_chunks(), 0);
+        drop(storage);
+
+        let mut storage = ChunkStorage::with_storage_dir(storage_dir.clone());
+        assert_eq!(storage.get_block(&(1, 2, 3)).pack(), material.pack());
+
+        cleanup(storage_dir);
+    }
+
+    #[test]
+    fn wrapped_lookups_share_chunks() {
+        let mut storage = ChunkStorage {
+            storage_dir: make_temp_dir(),
+            ..ChunkStorage::new()
+        };
+        storage.set_wrap(WorldWrap((2, 0, 0)));
+
+        let size = CHUNK_SIZE as isize;
+        let material = Material {
+            albedo: (4, 5, 6),
+            emission: (0, 0, 0),
+            roughness: 64,
+            solid: true,
+            transparent: false,
+            emitter: 0,
+        };
+        storage.set_block(&(-1, 2, 3), material.clone());
+        assert_eq!(storage.take_dirty_chunks(8), vec![(1, 0, 0)]);
+        let packed = material.pack();
+        assert_eq!(storage.get_block(&(2 * size - 1, 2, 3)).pack(), packed);
+        assert_eq!(storage.get_block(&(4 * size - 1, 2, 3)).pack(), packed);
+
+        cleanup(storage.storage_dir);
+    }
 }
@@ -0,0 +1,263 @@
+use super::ChunkStorage;
+use crate::render::Material;
+use crate::util::prelude::*;
+use cgmath::{InnerSpace, Vector3};
+use lazy_static::lazy_static;
+use noise::{NoiseFn, OpenSimplex};
+use rand::prelude::*;
+
+lazy_static! {
+    static ref CRATER_NOISE: OpenSimplex = OpenSimplex::new();
+}
+
+/// How far the edge of a crater moves in or out, as a fraction of its radius.
+const CRATER_ROUGHNESS: f64 = 0.35;
+/// How quickly the edge of a crater moves in and out, in cycles per block.
+const CRATER_NOISE_SCALE: f64 = 0.15;
+/// The chance of each carved block turning into debris at a power of 1.
+const DEBRIS_CHANCE: f32 = 0.05;
+/// More debris than this is not spawned by a single explosion, no matter how big it is.
+const MAX_DEBRIS: usize = 256;
+/// How fast debris flies at a power of 1, in blocks per second.
+const DEBRIS_SPEED: f32 = 12.0;
+/// Debris never moves faster than this, so that it can't skip over a block in a single step of
+/// DebrisSim at DEBRIS_TICK_RATE.
+const MAX_DEBRIS_SPEED: f32 = 30.0;
+/// How many times per second debris moves.
+pub const DEBRIS_TICK_RATE: f32 = 30.0;
+/// In blocks per second per second.
+const GRAVITY: f32 = 30.0;
+/// Debris which is still flying after this many seconds is removed.
+const MAX_DEBRIS_AGE: f32 = 10.0;
+
+/// Every block which is carved out by an explosion at center. The edge of the crater is moved in
+/// and out by noise so that it does not look like a perfect sphere.
+pub fn crater_blocks(center: SignedCoord3D, radius: f32) -> Vec<SignedCoord3D> {
+    let radius = radius as f64;
+    let extent = (radius * (1.0 + CRATER_ROUGHNESS)).ceil() as isize;
+    let mut blocks = Vec::new();
+    for z in -extent..=extent {
+        for y in -extent..=extent {
+            for x in -extent..=extent {
+                let block = center.add((x, y, z));
+                let scaled = |value: isize| value as f64 * CRATER_NOISE_SCALE;
+                let noise = CRATER_NOISE.get([scaled(block.0), scaled(block.1), scaled(block.2)]);
+                let edge = radius * (1.0 + CRATER_ROUGHNESS * noise.max(-1.0).min(1.0));
+                let distance2 = (x * x + y * y + z * z) as f64;
+                if distance2 <= edge * edge {
+                    blocks.push(block);
+                }
+            }
+        }
+    }
+    blocks
+}
+
+/// A block thrown out of a crater. While it is flying it is drawn as a single block in the world,
+/// which moves every step of DebrisSim.
+#[derive(Clone, Debug)]
+pub struct Debris {
+    pub position: Vector3<f32>,
+    pub velocity: Vector3<f32>,
+    pub material: Material,
+    // Seconds since the explosion.
+    age: f32,
+    // Where the debris is currently stored in the world, None until it has moved once.
+    drawn_at: Option<SignedCoord3D>,
+}
+
+/// What an explosion changed.
+pub struct Crater {
+    /// Every block which was solid before the explosion and is empty now.
+    pub carved: Vec<SignedCoord3D>,
+    pub debris: Vec<Debris>,
+}
+
+impl ChunkStorage {
+    /// Carves a crater of roughly the given radius around center and throws some of the carved
+    /// blocks out as debris. Power makes debris more common and faster. Every chunk the crater
+    /// touches is stored once and marked dirty, which also rebuilds its minefield.
+    pub fn explode(&mut self, center: SignedCoord3D, radius: f32, power: f32) -> Crater {
+        let blocks = crater_blocks(center, radius);
+        let materials = self.get_blocks(&blocks);
+        let mut random = StdRng::seed_from_u64(
+            (center.0 as u64)
+                .wrapping_mul(0x9E37_79B9_7F4A_7C15)
+                .rotate_left(21)
+                ^ (center.1 as u64).rotate_left(42)
+                ^ center.2 as u64,
+        );
+        let chance = (DEBRIS_CHANCE * power).min(1.0);
+        let mut carved = Vec::new();
+        let mut debris = Vec::new();
+        for (block, material) in blocks.into_iter().zip(materials.into_iter()) {
+            if !material.solid {
+                continue;
+            }
+            carved.push(block);
+            if debris.len() >= MAX_DEBRIS || random.gen::<f32>() >= chance {
+                continue;
+            }
+            let offset = block.sub(center);
+            let outward = Vector3::new(offset.0 as f32, offset.1 as f32, offset.2 as f32 + 0.5);
+            let outward = outward.normalize() + Vector3::unit_z() * 0.5;
+            let speed = power * DEBRIS_SPEED * random.gen_range(0.5, 1.0);
+            let velocity = outward.normalize() * speed.min(MAX_DEBRIS_SPEED);
+            debris.push(Debris {
+                position: Vector3::new(block.0 as f32, block.1 as f32, block.2 as f32)
+                    + Vector3::new(0.5, 0.5, 0.5),
+                velocity,
+                material,
+                age: 0.0,
+                drawn_at: None,
+            });
+        }
+        let air: Vec<_> = carved
+            .iter()
+            .map(|block| (*block, Material::air()))
+            .collect();
+        self.set_blocks(&air);
+        Crater { carved, debris }
+    }
+}
+
+/// Moves debris through the world until it lands on something, where it is left behind as a
+/// block. Each step stores every chunk a piece of debris moved in, so lots of debris is a good
+/// way to stress test uploading changed chunks.
+pub struct DebrisSim {
+    debris: Vec<Debris>,
+}
+
+impl DebrisSim {
+    pub fn new() -> Self {
+        Self { debris: Vec::new() }
+    }
+
+    pub fn add(&mut self, debris: Vec<Debris>) {
+        self.debris.extend(debris);
+    }
+
+    /// How many pieces of debris are still flying.
+    pub fn get_num_flying(&self) -> usize {
+        self.debris.len()
+    }
+
+    pub fn step(&mut self, world: &mut ChunkStorage, dt: f32) {
+        if self.debris.len() == 0 {
+            return;
+        }
+        for debris in &mut self.debris {
+            debris.age += dt;
+            debris.velocity.z -= GRAVITY * dt;
+            let speed = debris.velocity.magnitude();
+            if speed > MAX_DEBRIS_SPEED {
+                debris.velocity *= MAX_DEBRIS_SPEED / speed;
+            }
+            debris.position += debris.velocity * dt;
+        }
+        let targets: Vec<_> = self
+            .debris
+            .iter()
+            .map(|debris| {
+                let position = debris.position;
+                (
+                    position.x.floor() as isize,
+                    position.y.floor() as isize,
+                    position.z.floor() as isize,
+                )
+            })
+            .collect();
+        let target_materials = world.get_blocks(&targets);
+
+        let mut blocks = Vec::new();
+        let mut flying = Vec::with_capacity(self.debris.len());
+        let moves = targets.into_iter().zip(target_materials.into_iter());
+        for (mut debris, (target, target_material)) in self.debris.drain(..).zip(moves) {
+            if Some(target) == debris.drawn_at {
+                flying.push(debris);
+                continue;
+            }
+            if target_material.solid {
+                // Landed, so it stays wherever it was last drawn.
+                continue;
+            }
+            if let Some(drawn_at) = debris.drawn_at {
+                blocks.push((drawn_at, Material::air()));
+            }
+            if debris.age > MAX_DEBRIS_AGE {
+                continue;
+            }
+            blocks.push((target, debris.material.clone()));
+            debris.drawn_at = Some(target);
+            flying.push(debris);
+        }
+        self.debris = flying;
+        world.set_blocks(&blocks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::MATERIALS;
+    use crate::world::test_util::make_temp_dir;
+
+    #[test]
+    fn crater_edge_is_within_roughness() {
+        let center = (100, -40, 12);
+        let radius = 6.0;
+        let blocks = crater_blocks(center, radius);
+        assert_eq!(blocks, crater_blocks(center, radius));
+        let inner = radius as f64 * (1.0 - CRATER_ROUGHNESS);
+        let outer = radius as f64 * (1.0 + CRATER_ROUGHNESS);
+        let extent = outer.ceil() as isize + 1;
+        for z in -extent..=extent {
+            for y in -extent..=extent {
+                for x in -extent..=extent {
+                    let distance = ((x * x + y * y + z * z) as f64).sqrt();
+                    let carved = blocks.contains(&center.add((x, y, z)));
+                    if distance <= inner {
+                        assert!(carved);
+                    } else if distance > outer {
+                        assert!(!carved);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn debris_lands_on_the_ground() {
+        let dir = make_temp_dir();
+        let mut world = ChunkStorage::with_storage_dir(dir.clone());
+        // High enough up that the generated terrain is all air.
+        let floor_z = 8 * 64;
+        let stone = MATERIALS[4].clone();
+        let mut floor = Vec::new();
+        for y in 0..16 {
+            for x in 0..16 {
+                floor.push(((x, y, floor_z), stone.clone()));
+            }
+        }
+        world.set_blocks(&floor);
+
+        let mut sim = DebrisSim::new();
+        sim.add(vec![Debris {
+            position: Vector3::new(8.5, 8.5, floor_z as f32 + 10.5),
+            velocity: Vector3::new(0.0, 0.0, 0.0),
+            material: stone.clone(),
+            age: 0.0,
+            drawn_at: None,
+        }]);
+        for _ in 0..100 {
+            sim.step(&mut world, 1.0 / DEBRIS_TICK_RATE);
+        }
+        assert_eq!(sim.get_num_flying(), 0);
+        assert_eq!(world.get_block(&(8, 8, floor_z + 1)).pack(), stone.pack());
+        for z in floor_z + 2..floor_z + 12 {
+            assert!(!world.get_block(&(8, 8, z)).solid);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
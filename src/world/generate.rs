@@ -10,7 +10,14 @@ lazy_static! {
 
 const SCALE: f64 = 0600.0;
 
-fn height(x: isize, y: isize) -> isize {
+/// Increase this whenever the terrain this file generates changes, so that caches of terrain
+/// generated by older versions are not used. See WarmCacheKey.
+pub const GENERATOR_VERSION: u32 = 2;
+/// Everything random about the terrain is derived from this, so the same world is generated every
+/// time. The noise functions use their own default seed.
+pub const WORLD_SEED: u64 = 0;
+
+pub(super) fn height(x: isize, y: isize) -> isize {
     (MOUNTAIN_NOISE.get(x as f64 / SCALE, y as f64 / SCALE) * SCALE * 0.2 + 10.0) as isize
 }
 
@@ -28,7 +35,19 @@ pub fn generate_heightmap(
     }
 }
 
-fn material(random: &mut ThreadRng, height: isize) -> usize {
+/// A random number generator which gives the same numbers every time a particular chunk is
+/// generated, no matter which order chunks are generated in.
+fn chunk_random(chunk_coord: &util::SignedCoord3D) -> StdRng {
+    let mut seed = WORLD_SEED;
+    for value in &[chunk_coord.0, chunk_coord.1, chunk_coord.2] {
+        seed = (seed ^ *value as u64)
+            .wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            .rotate_left(31);
+    }
+    StdRng::seed_from_u64(seed)
+}
+
+fn material(random: &mut impl Rng, height: isize) -> usize {
     if height < 20 {
         2
     } else if height < 80 {
@@ -58,7 +77,7 @@ pub fn generate_chunk(
     let size = CHUNK_SIZE as isize;
     let origin = chunk_coord.scale(size);
 
-    let mut random = rand::thread_rng();
+    let mut random = chunk_random(chunk_coord);
 
     if origin.2 + size < 12 {
         data.fill(&MATERIALS[2]);
@@ -83,3 +102,41 @@ pub fn generate_chunk(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::PackedChunkData;
+
+    // Tall enough that every chunk from z = 0 up to 160 has randomly picked materials.
+    fn tall_heightmap() -> Heightmap {
+        Heightmap {
+            data: vec![200; CHUNK_SIZE * CHUNK_SIZE],
+        }
+    }
+
+    fn generate_packed(chunk_coord: &util::SignedCoord3D) -> PackedChunkData {
+        let mut unpacked = UnpackedChunkData::new();
+        let mut packed = PackedChunkData::new();
+        generate_chunk(&mut unpacked, chunk_coord, &tall_heightmap());
+        unpacked.pack_into(&mut packed);
+        packed
+    }
+
+    #[test]
+    fn chunks_are_identical_every_time() {
+        for chunk_coord in &[(0, 0, 1), (-3, 7, 1), (12, -5, 2)] {
+            let first = generate_packed(chunk_coord);
+            let second = generate_packed(chunk_coord);
+            assert!(first.materials == second.materials);
+            assert!(first.minefield == second.minefield);
+        }
+    }
+
+    #[test]
+    fn chunks_are_not_copies_of_each_other() {
+        let first = generate_packed(&(0, 0, 1));
+        assert!(first.materials != generate_packed(&(1, 0, 1)).materials);
+        assert!(first.materials != generate_packed(&(0, 1, 1)).materials);
+    }
+}
@@ -0,0 +1,238 @@
+use super::ChunkStorage;
+use crate::render::{Material, MATERIALS};
+use crate::util::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+/// The index of water in MATERIALS.
+pub const WATER_MATERIAL: usize = 9;
+/// How much water a single block can hold.
+pub const MAX_WATER_LEVEL: u8 = 8;
+
+const BELOW: SignedCoord3D = (0, 0, -1);
+const SIDES: [SignedCoord3D; 4] = [(1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0)];
+const NEIGHBORS: [SignedCoord3D; 6] = [
+    (1, 0, 0),
+    (-1, 0, 0),
+    (0, 1, 0),
+    (0, -1, 0),
+    (0, 0, 1),
+    (0, 0, -1),
+];
+
+/// A cellular automaton which moves water around the world. Each block holds from 0 to
+/// MAX_WATER_LEVEL units of water. Water falls as far as it can, then spreads one unit at a time
+/// to neighbors with less water until the levels are within one unit of each other. The amount of
+/// water never changes, so a single block of water spreads out into a puddle and then stops.
+///
+/// Any block with water in it is stored as MATERIALS[WATER_MATERIAL] in the world, so every block
+/// which changes is uploaded again through ChunkStorage's dirty chunks. Only water placed through
+/// pour is simulated, water blocks loaded from disk are treated like air.
+pub struct LiquidSim {
+    levels: HashMap<SignedCoord3D, u8>,
+    // Blocks which could move on the next step. Water which did not move is left out until
+    // something changes around it.
+    active: HashSet<SignedCoord3D>,
+}
+
+impl LiquidSim {
+    pub fn new() -> Self {
+        Self {
+            levels: HashMap::new(),
+            active: HashSet::new(),
+        }
+    }
+
+    /// How much water is in a block, 0 if there is none.
+    pub fn get_level(&self, coord: &SignedCoord3D) -> u8 {
+        self.levels.get(coord).cloned().unwrap_or(0)
+    }
+
+    /// True if no water will move on the next step.
+    pub fn is_settled(&self) -> bool {
+        self.active.len() == 0
+    }
+
+    fn wake(&mut self, coord: SignedCoord3D) {
+        self.active.insert(coord);
+        for offset in NEIGHBORS.iter() {
+            self.active.insert(coord.add(*offset));
+        }
+    }
+
+    /// Starts simulating water in a block which has been set to MATERIALS[WATER_MATERIAL].
+    pub fn pour(&mut self, coord: SignedCoord3D, level: u8) {
+        let level = level.min(MAX_WATER_LEVEL);
+        if level > 0 {
+            self.levels.insert(coord, level);
+        }
+        self.wake(coord);
+    }
+
+    /// Called when a block is replaced with something other than water, so that water stops
+    /// being simulated there and water around it can flow into it if it is now empty.
+    pub fn on_block_changed(&mut self, coord: SignedCoord3D) {
+        self.levels.remove(&coord);
+        self.wake(coord);
+    }
+
+    fn transfer(&mut self, from: SignedCoord3D, to: SignedCoord3D, amount: u8) {
+        let from_level = self.get_level(&from) - amount;
+        if from_level == 0 {
+            self.levels.remove(&from);
+        } else {
+            self.levels.insert(from, from_level);
+        }
+        *self.levels.entry(to).or_insert(0) += amount;
+    }
+
+    /// Moves the water once and stores every block which changed in the world.
+    pub fn step(&mut self, world: &mut ChunkStorage) {
+        let levels = &self.levels;
+        let mut cells: Vec<_> = self
+            .active
+            .drain()
+            .filter(|cell| levels.contains_key(cell))
+            .collect();
+        // Bottom first, so that water which falls makes room for the water above it.
+        cells.sort_by_key(|cell| (cell.2, cell.1, cell.0));
+
+        let mut candidates: Vec<_> = cells.iter().map(|cell| cell.add(BELOW)).collect();
+        for cell in &cells {
+            candidates.extend(SIDES.iter().map(|side| cell.add(*side)));
+        }
+        candidates.sort();
+        candidates.dedup();
+        let water = MATERIALS[WATER_MATERIAL].pack();
+        let blocked: HashSet<_> = world
+            .get_blocks(&candidates)
+            .into_iter()
+            .zip(candidates.into_iter())
+            .filter(|(material, _)| material.solid && material.pack() != water)
+            .map(|(_, coord)| coord)
+            .collect();
+
+        let mut changed = HashSet::new();
+        for cell in cells {
+            let start_level = self.get_level(&cell);
+            let mut level = start_level;
+            let below = cell.add(BELOW);
+            if !blocked.contains(&below) {
+                let amount = level.min(MAX_WATER_LEVEL - self.get_level(&below));
+                if amount > 0 {
+                    self.transfer(cell, below, amount);
+                    changed.insert(below);
+                    level -= amount;
+                }
+            }
+            for side in SIDES.iter() {
+                let side = cell.add(*side);
+                if level > 1 && !blocked.contains(&side) && self.get_level(&side) + 1 < level {
+                    self.transfer(cell, side, 1);
+                    changed.insert(side);
+                    level -= 1;
+                }
+            }
+            if level != start_level {
+                changed.insert(cell);
+            }
+        }
+
+        let mut blocks = Vec::with_capacity(changed.len());
+        for cell in changed {
+            self.wake(cell);
+            let material = if self.levels.contains_key(&cell) {
+                MATERIALS[WATER_MATERIAL].clone()
+            } else {
+                Material::air()
+            };
+            blocks.push((cell, material));
+        }
+        world.set_blocks(&blocks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_util::make_temp_dir;
+
+    // High enough up that the generated terrain is all air.
+    const FLOOR_Z: isize = 8 * 64;
+
+    /// Builds a floor with walls around the square from (1, 1) to (size, size).
+    fn build_basin(world: &mut ChunkStorage, size: isize) {
+        let mut blocks = Vec::new();
+        for y in 0..size + 2 {
+            for x in 0..size + 2 {
+                blocks.push(((x, y, FLOOR_Z), MATERIALS[4].clone()));
+                if x == 0 || y == 0 || x == size + 1 || y == size + 1 {
+                    blocks.push(((x, y, FLOOR_Z + 1), MATERIALS[4].clone()));
+                }
+            }
+        }
+        world.set_blocks(&blocks);
+    }
+
+    fn run_until_settled(sim: &mut LiquidSim, world: &mut ChunkStorage) {
+        for _ in 0..100 {
+            if sim.is_settled() {
+                return;
+            }
+            sim.step(world);
+        }
+        panic!("The water never stopped moving.");
+    }
+
+    #[test]
+    fn water_falls_to_the_floor() {
+        let dir = make_temp_dir();
+        let mut world = ChunkStorage::with_storage_dir(dir.clone());
+        build_basin(&mut world, 1);
+        let start = (1, 1, FLOOR_Z + 4);
+        world.set_block(&start, MATERIALS[WATER_MATERIAL].clone());
+        let mut sim = LiquidSim::new();
+        sim.pour(start, MAX_WATER_LEVEL);
+        run_until_settled(&mut sim, &mut world);
+
+        let end = (1, 1, FLOOR_Z + 1);
+        assert_eq!(sim.get_level(&start), 0);
+        assert_eq!(sim.get_level(&end), MAX_WATER_LEVEL);
+        assert!(!world.get_block(&start).solid);
+        let water = MATERIALS[WATER_MATERIAL].pack();
+        assert_eq!(world.get_block(&end).pack(), water);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn water_spreads_until_level() {
+        let dir = make_temp_dir();
+        let mut world = ChunkStorage::with_storage_dir(dir.clone());
+        build_basin(&mut world, 3);
+        let start = (2, 2, FLOOR_Z + 1);
+        world.set_block(&start, MATERIALS[WATER_MATERIAL].clone());
+        let mut sim = LiquidSim::new();
+        sim.pour(start, MAX_WATER_LEVEL);
+        run_until_settled(&mut sim, &mut world);
+
+        let cells: Vec<_> = (1..=3)
+            .flat_map(|y| (1..=3).map(move |x| (x, y, FLOOR_Z + 1)))
+            .collect();
+        let total: u32 = cells.iter().map(|cell| sim.get_level(cell) as u32).sum();
+        assert_eq!(total, MAX_WATER_LEVEL as u32);
+        assert!(sim.get_level(&start) < MAX_WATER_LEVEL);
+        // Neighbors inside the basin are within one unit of each other.
+        for cell in &cells {
+            for side in SIDES.iter().map(|side| cell.add(*side)) {
+                if cells.contains(&side) {
+                    assert!(sim.get_level(cell) <= sim.get_level(&side) + 1);
+                }
+            }
+        }
+        // Nothing leaked over the walls.
+        assert_eq!(sim.get_level(&(0, 2, FLOOR_Z + 1)), 0);
+        assert_eq!(sim.get_level(&(2, 2, FLOOR_Z + 2)), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use super::generate;
+use crate::render::MATERIALS;
+use crate::util::prelude::*;
+use std::io;
+use std::path::Path;
+
+/// The material most of the surface at a given height is made of. This follows the distribution
+/// used when generating chunks, but without the random mixing between layers.
+fn surface_material(height: isize) -> usize {
+    if height < 50 {
+        2
+    } else if height < 120 {
+        5
+    } else {
+        6
+    }
+}
+
+/// Renders a top-down view of the terrain as an RGBA8 image which is size pixels wide and tall,
+/// centered on the given block. The +Y axis points towards the top of the image. This uses the
+/// generated heightmap, so edits made to the world are not shown.
+pub fn render_map(center: SignedCoord2D, size: usize, blocks_per_pixel: usize) -> Vec<u8> {
+    let step = blocks_per_pixel as isize;
+    let left = center.0 - size as isize / 2 * step;
+    let top = center.1 + size as isize / 2 * step;
+    // One extra row and column so that every pixel has a neighbor to compute shading from.
+    let heights: Vec<isize> = (0..size as isize + 1)
+        .flat_map(|py| (0..size as isize + 1).map(move |px| (px, py)))
+        .map(|(px, py)| generate::height(left + (px - 1) * step, top - (py - 1) * step))
+        .collect();
+
+    let mut image = vec![0; size * size * 4];
+    for py in 0..size {
+        for px in 0..size {
+            let height = heights[(py + 1) * (size + 1) + px + 1];
+            // Light comes from the top left, so slopes facing it are brighter.
+            let neighbor = heights[py * (size + 1) + px];
+            let slope = (height - neighbor) as f32 / blocks_per_pixel as f32;
+            let shade = (1.0 + slope * 0.15).max(0.5).min(1.5);
+            let albedo = MATERIALS[surface_material(height)].albedo;
+            // Albedo values in MATERIALS range from 0 to 127.
+            let channel = |value: u16| (value as f32 * 2.0 * shade).min(255.0) as u8;
+            let index = (py * size + px) * 4;
+            image[index + 0] = channel(albedo.0);
+            image[index + 1] = channel(albedo.1);
+            image[index + 2] = channel(albedo.2);
+            image[index + 3] = 255;
+        }
+    }
+    image
+}
+
+/// A coarse heightmap of the terrain which the renderer falls back to beyond the loaded region.
+pub struct DistantTerrain {
+    /// Two values for each texel, row by row starting at the -X -Y corner. The first is the
+    /// height of the top of the column as an i32, the second is the packed material of its top.
+    pub texels: Vec<u32>,
+    /// The highest column, so that rays above it can stop early.
+    pub max_height: isize,
+}
+
+/// Builds a size by size heightmap centered on the given block, where each texel covers
+/// blocks_per_texel by blocks_per_texel blocks. Each column is as tall as the highest of the
+/// corners and center of its texel, which keeps most peaks without sampling every block. This uses
+/// the generated heightmap, so edits made to the world are not shown.
+pub fn build_distant_terrain(
+    center: SignedCoord2D,
+    size: usize,
+    blocks_per_texel: usize,
+) -> DistantTerrain {
+    let step = blocks_per_texel as isize;
+    let left = center.0 - size as isize / 2 * step;
+    let bottom = center.1 - size as isize / 2 * step;
+    // One extra row and column so that every texel has all four corners.
+    let corners: Vec<isize> = (0..size as isize + 1)
+        .flat_map(|ty| (0..size as isize + 1).map(move |tx| (tx, ty)))
+        .map(|(tx, ty)| generate::height(left + tx * step, bottom + ty * step))
+        .collect();
+
+    let mut texels = Vec::with_capacity(size * size * 2);
+    let mut max_height = isize::min_value();
+    for ty in 0..size {
+        for tx in 0..size {
+            let corner = |dx: usize, dy: usize| corners[(ty + dy) * (size + 1) + tx + dx];
+            let middle = generate::height(
+                left + tx as isize * step + step / 2,
+                bottom + ty as isize * step + step / 2,
+            );
+            let height = middle
+                .max(corner(0, 0))
+                .max(corner(1, 0))
+                .max(corner(0, 1))
+                .max(corner(1, 1));
+            max_height = max_height.max(height);
+            texels.push(height as i32 as u32);
+            texels.push(MATERIALS[surface_material(height)].pack());
+        }
+    }
+    DistantTerrain { texels, max_height }
+}
+
+/// Renders a map of the terrain and saves it as a PNG file.
+pub fn export_map(
+    path: &Path,
+    center: SignedCoord2D,
+    size: usize,
+    blocks_per_pixel: usize,
+) -> io::Result<()> {
+    let image = render_map(center, size, blocks_per_pixel);
+    image::save_buffer(
+        path,
+        &image,
+        size as u32,
+        size as u32,
+        image::ColorType::RGBA(8),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surface_material_follows_height() {
+        assert_eq!(surface_material(-10), 2);
+        assert_eq!(surface_material(80), 5);
+        assert_eq!(surface_material(400), 6);
+    }
+
+    #[test]
+    fn distant_terrain_keeps_highest_point() {
+        let terrain = build_distant_terrain((0, 0), 4, 16);
+        assert_eq!(terrain.texels.len(), 4 * 4 * 2);
+        let heights = terrain
+            .texels
+            .iter()
+            .step_by(2)
+            .map(|height| *height as i32 as isize);
+        assert_eq!(heights.max(), Some(terrain.max_height));
+        // The four texels in the middle share a corner at the center of the map.
+        let center = generate::height(0, 0);
+        for index in [5, 6, 9, 10].iter() {
+            assert!(terrain.texels[index * 2] as i32 as isize >= center);
+        }
+    }
+
+    #[test]
+    fn map_is_opaque_rgba() {
+        let image = render_map((0, 0), 16, 4);
+        assert_eq!(image.len(), 16 * 16 * 4);
+        assert!(image.chunks(4).all(|pixel| pixel[3] == 255));
+    }
+}
@@ -1,10 +1,28 @@
+mod autosave;
+mod biome;
 mod chunk;
+mod chunk_provider;
 mod chunk_storage;
+mod explosion;
 pub(self) mod functions;
 mod generate;
 mod heightmap;
+mod liquid;
+pub mod map;
+mod raycast;
+#[cfg(test)]
+pub(crate) mod test_util;
+mod warm_cache;
+mod wrap;
 
+pub use biome::*;
 pub use chunk::*;
+pub use chunk_provider::*;
 pub use chunk_storage::*;
+pub use explosion::*;
 pub use generate::*;
 pub use heightmap::*;
+pub use liquid::*;
+pub use raycast::*;
+pub use warm_cache::*;
+pub use wrap::*;
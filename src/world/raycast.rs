@@ -0,0 +1,164 @@
+use super::ChunkStorage;
+use crate::render::constants::*;
+use crate::util::prelude::*;
+use cgmath::{InnerSpace, Vector3};
+
+/// The first solid block found by a raycast.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaycastHit {
+    /// The block that was hit, in world coordinates.
+    pub block: SignedCoord3D,
+    /// Points out of the face of the block that was hit. Adding it to block gives the empty block
+    /// the ray was in right before hitting it. This is zero if the ray started inside a solid
+    /// block.
+    pub normal: SignedCoord3D,
+}
+
+/// Steps through every block along a ray until is_solid returns true for one of them, or the ray
+/// travels further than max_distance.
+pub fn raycast(
+    origin: Vector3<f64>,
+    direction: Vector3<f64>,
+    max_distance: f64,
+    mut is_solid: impl FnMut(&SignedCoord3D) -> bool,
+) -> Option<RaycastHit> {
+    let direction = direction.normalize();
+    let origin = [origin.x, origin.y, origin.z];
+    let direction = [direction.x, direction.y, direction.z];
+    let mut block = [
+        origin[0].floor() as isize,
+        origin[1].floor() as isize,
+        origin[2].floor() as isize,
+    ];
+    // Which way the ray steps along each axis.
+    let mut step = [0; 3];
+    // How far along the ray the next block boundary on each axis is.
+    let mut next_boundary = [std::f64::INFINITY; 3];
+    // How far along the ray it takes to cross an entire block on each axis.
+    let mut block_length = [std::f64::INFINITY; 3];
+    for axis in 0..3 {
+        if direction[axis] > 0.0 {
+            step[axis] = 1;
+            next_boundary[axis] = (block[axis] as f64 + 1.0 - origin[axis]) / direction[axis];
+            block_length[axis] = 1.0 / direction[axis];
+        } else if direction[axis] < 0.0 {
+            step[axis] = -1;
+            next_boundary[axis] = (block[axis] as f64 - origin[axis]) / direction[axis];
+            block_length[axis] = -1.0 / direction[axis];
+        }
+    }
+    let mut normal = [0; 3];
+    loop {
+        let coord = (block[0], block[1], block[2]);
+        if is_solid(&coord) {
+            return Some(RaycastHit {
+                block: coord,
+                normal: (normal[0], normal[1], normal[2]),
+            });
+        }
+        let axis = if next_boundary[0] < next_boundary[1] {
+            if next_boundary[0] < next_boundary[2] {
+                0
+            } else {
+                2
+            }
+        } else if next_boundary[1] < next_boundary[2] {
+            1
+        } else {
+            2
+        };
+        if next_boundary[axis] > max_distance {
+            return None;
+        }
+        block[axis] += step[axis];
+        next_boundary[axis] += block_length[axis];
+        normal = [0; 3];
+        normal[axis] = -step[axis];
+    }
+}
+
+impl ChunkStorage {
+    /// Finds the first solid block along a ray, with the origin in world coordinates. Chunks which
+    /// have not been generated yet are treated as empty.
+    pub fn raycast(
+        &mut self,
+        origin: Vector3<f64>,
+        direction: Vector3<f64>,
+        max_distance: f64,
+    ) -> Option<RaycastHit> {
+        let size = CHUNK_SIZE as isize;
+        // The chunk the ray is currently in, so that it does not have to be loaded again for
+        // every block. It is shared instead of copied, which means chunks with unsaved edits are
+        // read where they are.
+        let mut loaded_chunk = None;
+        raycast(origin, direction, max_distance, |block| {
+            let chunk_coord = (
+                block.0.div_euclid(size),
+                block.1.div_euclid(size),
+                block.2.div_euclid(size),
+            );
+            if loaded_chunk.as_ref().map(|(coord, _)| *coord) != Some(chunk_coord) {
+                let chunk = self.share_packed_chunk_data_or_placeholder(&chunk_coord, 0);
+                loaded_chunk = Some((chunk_coord, chunk));
+            }
+            let chunk = &loaded_chunk.as_ref().unwrap().1;
+            let local_coord = (
+                block.0.rem_euclid(size) as usize,
+                block.1.rem_euclid(size) as usize,
+                block.2.rem_euclid(size) as usize,
+            );
+            // A minefield value of zero means the block itself is solid.
+            chunk.minefield[local_coord.to_index(CHUNK_SIZE.repeat())] == 0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hits_first_solid_block() {
+        let hit = raycast(
+            Vector3::new(0.5, 0.5, 0.5),
+            Vector3::new(1.0, 0.0, 0.0),
+            10.0,
+            |block| block.0 >= 3,
+        );
+        assert_eq!(
+            hit,
+            Some(RaycastHit {
+                block: (3, 0, 0),
+                normal: (-1, 0, 0),
+            })
+        );
+    }
+
+    #[test]
+    fn negative_direction_and_coordinates() {
+        let hit = raycast(
+            Vector3::new(-0.5, 0.5, 10.5),
+            Vector3::new(0.0, 0.0, -1.0),
+            20.0,
+            |block| block.2 < -2,
+        );
+        assert_eq!(
+            hit,
+            Some(RaycastHit {
+                block: (-1, 0, -3),
+                normal: (0, 0, 1),
+            })
+        );
+    }
+
+    #[test]
+    fn stops_at_max_distance() {
+        let hit = raycast(
+            Vector3::new(0.5, 0.5, 0.5),
+            Vector3::new(0.0, 1.0, 0.0),
+            4.0,
+            |block| block.1 >= 8,
+        );
+        assert_eq!(hit, None);
+    }
+}
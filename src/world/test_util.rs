@@ -0,0 +1,13 @@
+use rand::RngCore;
+use std::path::PathBuf;
+
+/// Creates an empty directory with a random name for a test to store chunks in. The test should
+/// remove it once it is done.
+pub fn make_temp_dir() -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "raytraceTestDir{:08X}",
+        rand::thread_rng().next_u32()
+    ));
+    std::fs::create_dir(&path).unwrap();
+    path
+}
@@ -0,0 +1,119 @@
+use super::autosave;
+use super::{WorldWrap, GENERATOR_VERSION};
+use crate::render::constants::CHUNK_SIZE;
+use lz4::{Decoder, EncoderBuilder};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Identifies the terrain a warm cache holds, which is only used if every field matches. There
+/// is no seed to include since the generator always uses WORLD_SEED.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WarmCacheKey {
+    pub generator_version: u32,
+    pub root_chunk_size: usize,
+    pub wrap: WorldWrap,
+}
+
+impl WarmCacheKey {
+    pub fn new(root_chunk_size: usize, wrap: WorldWrap) -> Self {
+        Self {
+            generator_version: GENERATOR_VERSION,
+            root_chunk_size,
+            wrap,
+        }
+    }
+
+    fn to_header(self) -> [u64; 5] {
+        let (x, y, z) = self.wrap.0;
+        [
+            self.generator_version as u64,
+            self.root_chunk_size as u64,
+            x as u64,
+            y as u64,
+            z as u64,
+        ]
+    }
+}
+
+/// The contents of the world images for the region they start out holding, so that it does not
+/// have to be put together from individual chunks every time the game starts.
+pub struct WarmCache {
+    pub materials: Vec<u32>,
+    pub minefield: Vec<u8>,
+}
+
+impl WarmCache {
+    /// Written atomically, so that a crash while writing never leaves a truncated cache behind.
+    pub fn write(&self, path: &Path, key: WarmCacheKey) -> io::Result<()> {
+        autosave::write_atomically(path, |temp_path| {
+            let file = File::create(temp_path)?;
+            let mut writer = EncoderBuilder::new().level(4).build(file)?;
+            for value in key.to_header().iter() {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            for material in &self.materials {
+                writer.write_all(&material.to_le_bytes())?;
+            }
+            writer.write_all(&self.minefield)?;
+            writer.finish().1?;
+            Ok(())
+        })
+    }
+
+    /// Returns None if the cache was made for different terrain than the key describes.
+    pub fn read(path: &Path, key: WarmCacheKey) -> io::Result<Option<WarmCache>> {
+        let mut reader = Decoder::new(File::open(path)?)?;
+        let mut value = [0; 8];
+        for expected in key.to_header().iter() {
+            reader.read_exact(&mut value)?;
+            if u64::from_le_bytes(value) != *expected {
+                return Ok(None);
+            }
+        }
+        let size = key.root_chunk_size * CHUNK_SIZE;
+        let volume = size * size * size;
+        let mut bytes = vec![0; volume * 4];
+        reader.read_exact(&mut bytes)?;
+        let materials = bytes
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+        let mut minefield = vec![0; volume];
+        reader.read_exact(&mut minefield)?;
+        Ok(Some(WarmCache {
+            materials,
+            minefield,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_util::make_temp_dir;
+
+    #[test]
+    fn only_matching_caches_are_read() {
+        let dir = make_temp_dir();
+        let path = dir.join("warm_cache");
+        let key = WarmCacheKey::new(1, WorldWrap::default());
+        let volume = 64 * 64 * 64;
+        let cache = WarmCache {
+            materials: (0..volume as u32).collect(),
+            minefield: vec![3; volume],
+        };
+        cache.write(&path, key).unwrap();
+        let read = WarmCache::read(&path, key).unwrap().unwrap();
+        assert_eq!(read.materials, cache.materials);
+        assert_eq!(read.minefield, cache.minefield);
+        let other_key = WarmCacheKey {
+            generator_version: GENERATOR_VERSION + 1,
+            ..key
+        };
+        assert!(WarmCache::read(&path, other_key).unwrap().is_none());
+        // Only the cache is left, not the temporary file it was written to.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use super::ChunkStorageCoord;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// How many chunks the world spans along each axis before it wraps around to the other side, or
+/// zero for axes which go on forever. In the settings file this is written like `4 4 0` or `off`.
+/// Chunks are generated as if the world did not wrap, so terrain does not line up across the seam
+/// unless it is edited to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WorldWrap(pub (usize, usize, usize));
+
+impl WorldWrap {
+    pub fn is_off(self) -> bool {
+        self.0 == (0, 0, 0)
+    }
+
+    fn axes(self) -> [usize; 3] {
+        [(self.0).0, (self.0).1, (self.0).2]
+    }
+
+    /// Returns the coordinate chunks which are copies of the given one are stored at.
+    pub fn wrap(self, coord: ChunkStorageCoord) -> ChunkStorageCoord {
+        let [x, y, z] = self.axes();
+        let wrap_axis = |value: isize, size: usize| {
+            if size == 0 {
+                value
+            } else {
+                value.rem_euclid(size as isize)
+            }
+        };
+        (
+            wrap_axis(coord.0, x),
+            wrap_axis(coord.1, y),
+            wrap_axis(coord.2, z),
+        )
+    }
+
+    /// Returns the first copy of the given chunk at or after start along each axis, along with
+    /// the copies one world size further along each axis which wraps. A region at most one chunk
+    /// wider than the world which begins in the start chunk can only contain these copies.
+    pub fn copies_from(
+        self,
+        coord: ChunkStorageCoord,
+        start: ChunkStorageCoord,
+    ) -> Vec<ChunkStorageCoord> {
+        let values = [coord.0, coord.1, coord.2];
+        let starts = [start.0, start.1, start.2];
+        let mut copies = vec![(0, 0, 0)];
+        for (axis, size) in self.axes().iter().enumerate() {
+            let size = *size as isize;
+            let options = if size == 0 {
+                vec![values[axis]]
+            } else {
+                let first = starts[axis] + (values[axis] - starts[axis]).rem_euclid(size);
+                vec![first, first + size]
+            };
+            copies = copies
+                .iter()
+                .flat_map(|copy| {
+                    options.iter().map(move |value| match axis {
+                        0 => (*value, copy.1, copy.2),
+                        1 => (copy.0, *value, copy.2),
+                        _ => (copy.0, copy.1, *value),
+                    })
+                })
+                .collect();
+        }
+        copies
+    }
+
+    /// A bit for each axis along which the world wraps exactly at the edge of a region of the
+    /// given size, meaning rays can carry on from one side of the region to the other. Must match
+    /// wrapped_axes in raytrace_common.glsl.
+    pub fn seamless_axes(self, root_chunk_size: usize) -> u32 {
+        let mut axes = 0;
+        for (index, size) in self.axes().iter().enumerate() {
+            if *size == root_chunk_size {
+                axes |= 1 << index;
+            }
+        }
+        axes
+    }
+
+    /// Returns a description of the problem if the world would be narrower than the region of
+    /// it stored on the GPU, which would need more than one copy of a chunk to be loaded at once.
+    pub fn validate(self, root_chunk_size: usize) -> Result<(), String> {
+        for size in self.axes().iter() {
+            if *size != 0 && *size < root_chunk_size {
+                return Err(format!(
+                    "world_wrap ({}) must be 0 or at least root_chunk_size ({}) on each axis.",
+                    self, root_chunk_size
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for WorldWrap {
+    type Err = String;
+
+    /// Sizes can be separated by commas or spaces.
+    fn from_str(text: &str) -> Result<WorldWrap, String> {
+        let text = text.trim();
+        if text == "off" {
+            return Ok(WorldWrap::default());
+        }
+        let mut sizes = Vec::new();
+        for size in text.split(|c: char| c == ',' || c.is_whitespace()) {
+            if size.len() == 0 {
+                continue;
+            }
+            match size.parse() {
+                Ok(size) => sizes.push(size),
+                Err(..) => return Err(format!("'{}' is not a number of chunks.", size)),
+            }
+        }
+        if sizes.len() != 3 {
+            return Err("The world wrap needs a size for each of the X, Y and Z axes.".to_owned());
+        }
+        Ok(WorldWrap((sizes[0], sizes[1], sizes[2])))
+    }
+}
+
+impl Display for WorldWrap {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.is_off() {
+            return write!(f, "off");
+        }
+        let [x, y, z] = self.axes();
+        write!(f, "{} {} {}", x, y, z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_each_axis_separately() {
+        let wrap = WorldWrap((4, 0, 8));
+        assert_eq!(wrap.wrap((-1, -1, 9)), (3, -1, 1));
+        let copies = wrap.copies_from((3, -1, 1), (-2, 5, 20));
+        assert_eq!(
+            copies,
+            vec![(-1, -1, 25), (-1, -1, 33), (3, -1, 25), (3, -1, 33)]
+        );
+        assert_eq!(wrap.seamless_axes(4), 0b001);
+        assert!(wrap.validate(4).is_ok());
+        assert!(wrap.validate(8).is_err());
+    }
+
+    #[test]
+    fn parse_world_wrap() {
+        let wrap: WorldWrap = "4, 4 0".parse().unwrap();
+        assert_eq!(wrap, WorldWrap((4, 4, 0)));
+        assert_eq!(wrap.to_string().parse(), Ok(wrap));
+        assert_eq!("off".parse(), Ok(WorldWrap::default()));
+        assert!("4 4".parse::<WorldWrap>().is_err());
+        assert!("4 -1 0".parse::<WorldWrap>().is_err());
+    }
+}